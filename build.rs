@@ -1,3 +1,5 @@
 fn main() -> shadow_rs::SdResult<()> {
+    tonic_build::compile_protos("proto/crible.proto")
+        .expect("Failed to compile protobuf definitions");
     shadow_rs::new()
 }
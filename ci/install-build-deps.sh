@@ -8,6 +8,8 @@ pkgs=(
     g++
     libc6-dev
     libclang-dev
+    # Required to compile the gRPC protobuf definitions.
+    protobuf-compiler
 )
 
 apt_opts=(
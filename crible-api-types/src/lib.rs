@@ -0,0 +1,658 @@
+//! Request and response bodies of the crible HTTP API, shared by the server
+//! and `crible-client`.
+//!
+//! Fields only set by the server, e.g. cost limits, are not part of these
+//! types and are attached by the server when handling a request.
+
+#![deny(unstable_features)]
+#![forbid(unsafe_code)]
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+
+use serde_derive::{Deserialize, Serialize};
+
+/// Run a query against the index. The result will include all unique
+/// elements matching the query and optionally (if `include_cardinalities` is
+/// provided and true) a map containing the cardinality of the intersection of
+/// the query and every property included in the index.
+///
+/// When `facets` is provided and true, the same cardinalities are returned
+/// grouped by field, see `Facet`.
+///
+/// When `include_stats` is provided and true, the result includes the
+/// cardinality, minimum and maximum of the matching elements. Setting
+/// `include_values` to false omits the elements themselves, e.g. when only
+/// the stats are needed.
+///
+/// `ranks` asks for the position of some elements within the result and
+/// `percentiles` for the elements at these percentiles of the result, e.g.
+/// when elements are time-ordered ids.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    pub query: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_cardinalities: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub facets: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_stats: Option<bool>,
+    /// Defaults to true.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_values: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ranks: Option<Vec<u32>>,
+    /// Between 0 and 100, higher values are treated as 100.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub percentiles: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct QueryResult {
+    /// Empty when values are not included.
+    #[serde(default)]
+    pub values: Vec<u32>,
+    pub cardinalities: Option<HashMap<String, u64>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub facets: Option<Vec<Facet>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<Stats>,
+    /// Number of matching elements lower than or equal to each requested
+    /// element, i.e. its 1-based position if it matches.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ranks: Option<BTreeMap<u32, u64>>,
+    /// Nearest-rank percentiles of the matching elements, empty if there are
+    /// none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub percentiles: Option<BTreeMap<u8, u32>>,
+}
+
+/// Cardinalities of the properties sharing the same field, e.g. `color` for
+/// `color:red` and `color:blue` when the server's facet delimiter is `:`.
+/// Properties without the delimiter are not part of any facet.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Facet {
+    pub field: String,
+    /// Sorted by decreasing count, then by value.
+    pub buckets: Vec<FacetBucket>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FacetBucket {
+    pub value: String,
+    pub count: u64,
+}
+
+/// Count the elements matching a query.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Count {
+    pub query: String,
+}
+
+/// Values given either as a list or as a map of names to values. Batch
+/// results use the same shape as the request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Batch<T> {
+    List(Vec<T>),
+    Named(HashMap<String, T>),
+}
+
+impl<T> Batch<T> {
+    /// Values in no particular order for named batches.
+    pub fn values(&self) -> Vec<&T> {
+        match self {
+            Self::List(values) => values.iter().collect(),
+            Self::Named(values) => values.values().collect(),
+        }
+    }
+
+    pub fn map<U, F: FnMut(T) -> U>(self, mut f: F) -> Batch<U> {
+        match self {
+            Self::List(values) => {
+                Batch::List(values.into_iter().map(f).collect())
+            }
+            Self::Named(values) => Batch::Named(
+                values.into_iter().map(|(k, v)| (k, f(v))).collect(),
+            ),
+        }
+    }
+}
+
+/// Count the elements matching each query against the same version of the
+/// index. A query failing does not prevent the others from being counted,
+/// unless the combined cost of all queries is too high.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CountMany {
+    pub queries: Batch<String>,
+}
+
+/// Result of a single query of `CountMany`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CountOutcome {
+    Count(u64),
+    Error(ErrorBody),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CountManyResult {
+    pub counts: Batch<CountOutcome>,
+}
+
+/// Compare the results of two queries. The result includes the number of
+/// elements matching both queries and only one of them, optionally along
+/// with up to `sample` elements from each bucket.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Compare {
+    pub left: String,
+    pub right: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CompareBucket {
+    pub count: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample: Option<Vec<u32>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CompareResult {
+    pub intersection: CompareBucket,
+    pub only_left: CompareBucket,
+    pub only_right: CompareBucket,
+}
+
+/// Similarity of the results of two queries, see `SimilarityResult`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Similarity {
+    pub left: String,
+    pub right: String,
+}
+
+/// Coefficients are 0 when their denominator is 0, e.g. when both queries
+/// match nothing.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SimilarityResult {
+    /// Number of elements matching `left`.
+    pub left: u64,
+    /// Number of elements matching `right`.
+    pub right: u64,
+    pub intersection: u64,
+    pub union: u64,
+    /// `intersection / union`.
+    pub jaccard: f64,
+    /// `intersection / min(left, right)`.
+    pub overlap: f64,
+    /// Fraction of `left` also matching `right`, `intersection / left`.
+    pub containment_left: f64,
+    /// Fraction of `right` also matching `left`, `intersection / right`.
+    pub containment_right: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct Stats {
+    pub cardinality: u64,
+    pub minimum: Option<u32>,
+    pub maximum: Option<u32>,
+    /// Only included for properties when requested, see
+    /// `StatsRequest::include_containers`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub containers: Option<ContainerStats>,
+}
+
+/// Storage of a bitmap by roaring container type.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct ContainerStats {
+    pub array_containers: u64,
+    pub run_containers: u64,
+    pub bitset_containers: u64,
+    pub array_bytes: u64,
+    pub run_bytes: u64,
+    pub bitset_bytes: u64,
+}
+
+/// Options of `/stats/distribution`, the request body is optional.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct DistributionRequest {
+    /// Only include properties starting with this prefix.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    /// Inclusive upper bounds of the histogram buckets, defaults to powers
+    /// of 10 up to 10^9.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub buckets: Option<Vec<u64>>,
+}
+
+/// Distribution of the cardinalities of the properties.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DistributionResult {
+    pub properties: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub maximum: u64,
+    /// In increasing order, the last bucket counts the properties above
+    /// every bound.
+    pub buckets: Vec<DistributionBucket>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DistributionBucket {
+    /// Inclusive upper bound, `None` for the last bucket.
+    pub le: Option<u64>,
+    pub count: u64,
+}
+
+/// Options of `/admin/bitmap-stats`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct BitmapStatsRequest {
+    /// Only include properties starting with this prefix.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BitmapStatsResult {
+    /// Sum over the included properties.
+    pub total: ContainerStats,
+    pub properties: HashMap<String, ContainerStats>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FlushStatus {
+    /// Number of writes not yet persisted to the backend.
+    pub pending_writes: usize,
+    /// Whether the last flush failed after exhausting its retries, meaning
+    /// pending writes would be lost if the server stopped.
+    pub failing: bool,
+}
+
+/// Options of `/stats`, everything is included by default. Computing the
+/// stats of the root requires going through every property.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct StatsRequest {
+    /// Only include properties starting with this prefix.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    /// Only include these properties, missing ones are omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub properties: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_root: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_properties: Option<bool>,
+    /// Include the storage of each property by container type, see
+    /// `ContainerStats`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_containers: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StatsResult {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<Stats>,
+    /// Empty when properties are not included.
+    #[serde(default)]
+    pub properties: HashMap<String, Stats>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flush: Option<FlushStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<LastModified>,
+}
+
+/// When the index was last modified, by a write or a reload, as RFC 3339
+/// timestamps.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LastModified {
+    pub index: String,
+    /// Same keys as `StatsResult::properties`.
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
+}
+
+/// Options of `/properties`, passed as query parameters. Without filters
+/// every property is listed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct ListProperties {
+    /// Only include properties starting with this prefix.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    /// Only include properties matching this glob pattern, where `*` matches
+    /// any sequence of characters, e.g. `country:*` or `*:error`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_stats: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub include_metadata: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PropertiesResult {
+    /// Sorted property names.
+    pub properties: Vec<String>,
+    /// Stats of the listed properties, only when `include_stats` is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<HashMap<String, Stats>>,
+    /// Metadata of the listed properties which have some, only when
+    /// `include_metadata` is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<HashMap<String, serde_json::Value>>,
+}
+
+/// Options of `GET /changes`, passed as query parameters.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct ChangesRequest {
+    /// Generation of the last change processed by the caller, only changes
+    /// published after it are streamed. Defaults to the current generation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<u64>,
+}
+
+/// Changes a replica needs to catch up with the server, see `SyncResult`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct SyncRequest {
+    /// `log` of the last `SyncResult` applied by the replica, omitted on
+    /// the first sync.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log: Option<String>,
+    /// `generation` of the last `SyncResult` applied by the replica.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SyncResult {
+    /// Identifies the change log of the server, generations of different
+    /// logs are unrelated.
+    pub log: String,
+    /// Generation of the last change included, to send with the next
+    /// request.
+    pub generation: u64,
+    /// The server cannot tell what changed since the replica's generation,
+    /// the replica must reload the whole index. The properties are then
+    /// empty.
+    pub reload: bool,
+    /// Base64 encoded bitmaps in the portable roaring format of the
+    /// properties which changed.
+    #[serde(default)]
+    pub bitmaps: HashMap<String, String>,
+    /// Properties which were deleted.
+    #[serde(default)]
+    pub deleted: Vec<String>,
+}
+
+/// Options of `GET /properties/meta`, passed as query parameters.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct ListMetadata {
+    /// Only include properties starting with this prefix.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+}
+
+/// Properties for which a bit is set.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GetBit {
+    pub bit: u32,
+}
+
+/// Properties for which each bit is set, the result maps every requested bit
+/// to its properties.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GetBits {
+    pub bits: Vec<u32>,
+}
+
+/// Bits of each of `properties`, e.g. for clients combining bitmaps on their
+/// side. Properties which do not exist are omitted from the result.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GetProperties {
+    pub properties: Vec<String>,
+    /// Return base64 encoded bitmaps in the portable roaring format instead
+    /// of lists of bits, which is usually much smaller.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serialized: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct GetPropertiesResult {
+    /// Empty when `GetProperties::serialized` is true.
+    #[serde(default)]
+    pub values: HashMap<String, Vec<u32>>,
+    /// Only set when `GetProperties::serialized` is true.
+    #[serde(default)]
+    pub bitmaps: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Set {
+    pub property: String,
+    pub bit: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SetMany {
+    pub values: HashMap<String, Vec<u32>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Unset {
+    pub property: String,
+    pub bit: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UnsetMany {
+    pub values: HashMap<String, Vec<u32>>,
+}
+
+/// Set `bit` for exactly `properties`, unsetting it everywhere else.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SetBit {
+    pub bit: u32,
+    pub properties: Vec<String>,
+    /// What to do with `properties` which do not exist, defaults to
+    /// `ignore`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub missing: Option<MissingProperties>,
+}
+
+/// Same as `SetBit` for many bits at once, mapping each bit to the exact
+/// list of properties it should be set for.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SetBits {
+    pub bits: HashMap<u32, Vec<String>>,
+    /// See `SetBit::missing`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub missing: Option<MissingProperties>,
+}
+
+/// How `SetBit` handles properties which do not exist.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingProperties {
+    /// Skip them, the bit is only set for existing properties.
+    #[default]
+    Ignore,
+    /// Create them with only the bit set.
+    Create,
+    /// Fail without modifying the index.
+    Reject,
+}
+
+/// Unset `bits` from every property.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeleteBits {
+    pub bits: Vec<u32>,
+}
+
+/// Unset every bit from `start` (included) to `end` (excluded), from
+/// `properties` when provided or from every property otherwise.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeleteRange {
+    pub start: u32,
+    pub end: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub properties: Option<Vec<String>>,
+}
+
+/// Any single mutation, as part of a `Transaction`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Mutation {
+    Set(Set),
+    SetMany(SetMany),
+    Unset(Unset),
+    UnsetMany(UnsetMany),
+    SetBit(SetBit),
+    SetBits(SetBits),
+    DeleteBits(DeleteBits),
+}
+
+/// Apply multiple mutations atomically. Mutations are applied in order and
+/// persisted immediately regardless of the flush policy; if persisting fails
+/// the index is rolled back. When `expected_version` is provided, the
+/// transaction is rejected if the index version does not match.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct Transaction {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected_version: Option<u64>,
+    pub operations: Vec<Mutation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TransactionResult {
+    /// Index version after applying the transaction.
+    pub version: u64,
+    /// Whether any of the mutations modified the index.
+    pub changed: bool,
+}
+
+/// Line of the NDJSON body of `/ingest` requests, `values` are set for
+/// `property`. The same property can appear in multiple records.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IngestRecord {
+    pub property: String,
+    pub values: Vec<u32>,
+}
+
+/// Summary of a successful `/ingest` request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct IngestResult {
+    /// Number of records applied.
+    pub records: u64,
+    /// Number of values across all records, including already set ones.
+    pub values: u64,
+    /// Number of write tasks the records were applied in.
+    pub chunks: u64,
+    /// Index version after applying the last chunk.
+    pub version: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReadinessChecks {
+    /// `true` or an object describing the backend error.
+    pub backend: serde_json::Value,
+    pub executor: bool,
+    pub flushed: bool,
+}
+
+/// Body of `/readyz` responses.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: ReadinessChecks,
+}
+
+/// Body of error responses.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ErrorBody {
+    pub error: String,
+    /// Machine readable code for query and index errors, e.g.
+    /// `property_does_not_exist`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+}
+
+/// Sent by `/subscribe` clients to (re)define what they want to be notified
+/// about. Every message replaces the previous subscription.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct SubscriptionRequest {
+    /// Notify about mutations affecting properties starting with any of
+    /// these prefixes.
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+    /// Saved queries by name, notified whenever their count changes.
+    #[serde(default)]
+    pub queries: BTreeMap<String, String>,
+}
+
+/// Messages sent to `/subscribe` clients.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Notification {
+    PropertyChanged {
+        operation: String,
+        /// Matching properties, `None` when the mutation may have affected
+        /// any property.
+        properties: Option<Vec<String>>,
+        bits: Vec<u32>,
+        /// Range of affected bits, `end` excluded, for mutations which do
+        /// not list individual bits.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        range: Option<Range<u32>>,
+    },
+    CountChanged {
+        query: String,
+        count: u64,
+    },
+    IndexReloaded,
+    /// The client was too slow to keep up and missed some changes. Counts
+    /// are refreshed but property notifications were lost.
+    Lagged {
+        missed: u64,
+    },
+    Error {
+        message: String,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mutation, Notification, Set, Transaction};
+
+    #[test]
+    fn test_mutation_tags() {
+        let transaction = Transaction {
+            expected_version: None,
+            operations: vec![Mutation::Set(Set {
+                property: "foo".to_owned(),
+                bit: 1,
+            })],
+        };
+        assert_eq!(
+            serde_json::to_string(&transaction).unwrap(),
+            r#"{"operations":[{"type":"set","property":"foo","bit":1}]}"#
+        );
+    }
+
+    #[test]
+    fn test_notification_tags() {
+        assert_eq!(
+            serde_json::to_string(&Notification::IndexReloaded).unwrap(),
+            r#"{"type":"index_reloaded"}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Notification>(
+                r#"{"type":"lagged","missed":3}"#
+            )
+            .unwrap(),
+            Notification::Lagged { missed: 3 }
+        );
+    }
+}
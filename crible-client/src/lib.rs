@@ -0,0 +1,1076 @@
+//! Async client for the crible HTTP API.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), crible_client::Error> {
+//! let client = crible_client::Client::builder("http://localhost:3000")?
+//!     .token("secret")
+//!     .build()?;
+//! client.set("foo", 1).await?;
+//! assert_eq!(client.query("foo").await?, vec![1]);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Requests which were rejected before being handled (`429 Too Many
+//! Requests` or connection failures) are retried with an exponential backoff.
+//! Read requests are also retried on timeouts and `503 Service Unavailable`.
+
+#![deny(unstable_features)]
+#![forbid(unsafe_code)]
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::time::Duration;
+
+use reqwest::header::HeaderValue;
+use reqwest::{Method, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use thiserror::Error;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use url::Url;
+
+mod subscribe;
+
+pub use crible_api_types as types;
+
+pub use self::subscribe::Subscription;
+use self::types::{
+    Batch, BitmapStatsResult, Compare, CompareResult, Count, CountMany,
+    CountManyResult, CountOutcome, DeleteBits, DeleteRange,
+    DistributionRequest, DistributionResult, ErrorBody, GetBit, GetBits,
+    GetProperties, GetPropertiesResult, ListProperties, PropertiesResult,
+    Query, QueryResult, Readiness, Set, SetBit, SetBits, SetMany, Similarity,
+    SimilarityResult, StatsRequest, StatsResult, SubscriptionRequest,
+    SyncRequest, SyncResult, Transaction, TransactionResult, Unset, UnsetMany,
+};
+
+/// Response header carrying the index version.
+static INDEX_VERSION: &str = "x-index-version";
+
+#[derive(Error, Debug)]
+pub enum Error {
+    /// The server answered with an error status.
+    #[error("{status}: {message}")]
+    Api {
+        status: StatusCode,
+        message: String,
+        /// Machine readable code for query and index errors, e.g.
+        /// `property_does_not_exist`.
+        code: Option<String>,
+    },
+    #[error("invalid url: {0}")]
+    Url(#[from] url::ParseError),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+    #[error("invalid message: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl Error {
+    /// HTTP status of the response, if one was received.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Self::Api { status, .. } => Some(*status),
+            Self::Http(e) => e.status(),
+            _ => None,
+        }
+    }
+
+    pub fn code(&self) -> Option<&str> {
+        match self {
+            Self::Api { code, .. } => code.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// Outcome of a single mutation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteResult {
+    /// Whether the index was modified, always `true` for bulk operations.
+    pub changed: bool,
+    /// Index version after the write, if reported by the server.
+    pub version: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    url: Url,
+    token: Option<String>,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    max_retries: u32,
+    retry_backoff: Duration,
+}
+
+impl ClientBuilder {
+    /// Bearer token sent with every request.
+    pub fn token<S: Into<String>>(mut self, token: S) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Total time allowed for a single attempt, 30s by default.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Number of retries after the first attempt, 2 by default. Set to 0 to
+    /// disable retries.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Delay before the first retry, doubled on every subsequent one.
+    pub fn retry_backoff(mut self, backoff: Duration) -> Self {
+        self.retry_backoff = backoff;
+        self
+    }
+
+    pub fn build(self) -> Result<Client, Error> {
+        let mut http = reqwest::Client::builder();
+        if let Some(timeout) = self.timeout {
+            http = http.timeout(timeout);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            http = http.connect_timeout(timeout);
+        }
+        Ok(Client {
+            http: http.build()?,
+            url: self.url,
+            token: self.token,
+            max_retries: self.max_retries,
+            retry_backoff: self.retry_backoff,
+        })
+    }
+}
+
+/// Ensure relative routes are resolved under the path of `url`.
+fn base_url(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    url
+}
+
+/// Percent-encode `value` so that it can be used as a single path segment.
+fn path_segment(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"-._~:".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect()
+}
+
+/// Client for a single index, see `Client::tenant` to target the other
+/// indices served by the same server. Cloning is cheap and clones share
+/// connection pools.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    url: Url,
+    token: Option<String>,
+    max_retries: u32,
+    retry_backoff: Duration,
+}
+
+impl Client {
+    /// Client for the server at `url` with the default options.
+    pub fn new(url: &str) -> Result<Self, Error> {
+        Self::builder(url)?.build()
+    }
+
+    pub fn builder(url: &str) -> Result<ClientBuilder, Error> {
+        Ok(ClientBuilder {
+            url: base_url(url.parse()?),
+            token: None,
+            timeout: Some(Duration::from_secs(30)),
+            connect_timeout: None,
+            max_retries: 2,
+            retry_backoff: Duration::from_millis(100),
+        })
+    }
+
+    /// Client for the index served under `/<name>/` by the same server.
+    pub fn tenant(&self, name: &str) -> Result<Self, Error> {
+        Ok(Self { url: base_url(self.url.join(name)?), ..self.clone() })
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    async fn send<B: Serialize>(
+        &self,
+        method: Method,
+        route: &str,
+        body: Option<&B>,
+        read: bool,
+    ) -> Result<Response, Error> {
+        let url = self.url.join(route)?;
+        let mut attempt = 0;
+        loop {
+            let mut request = self.http.request(method.clone(), url.clone());
+            if let Some(token) = &self.token {
+                request = request.bearer_auth(token);
+            }
+            if let Some(body) = body {
+                request = request.json(body);
+            }
+
+            let result = request.send().await;
+            let retry = match &result {
+                Ok(response) => {
+                    response.status() == StatusCode::TOO_MANY_REQUESTS
+                        || (read
+                            && response.status()
+                                == StatusCode::SERVICE_UNAVAILABLE)
+                }
+                Err(e) => e.is_connect() || (read && e.is_timeout()),
+            };
+            if retry && attempt < self.max_retries {
+                tokio::time::sleep(self.retry_backoff * 2u32.pow(attempt))
+                    .await;
+                attempt += 1;
+                continue;
+            }
+
+            return check_status(result?).await;
+        }
+    }
+
+    async fn read<B: Serialize, T: DeserializeOwned>(
+        &self,
+        route: &str,
+        body: &B,
+    ) -> Result<T, Error> {
+        Ok(self
+            .send(Method::POST, route, Some(body), true)
+            .await?
+            .json()
+            .await?)
+    }
+
+    async fn write<B: Serialize>(
+        &self,
+        route: &str,
+        body: &B,
+    ) -> Result<WriteResult, Error> {
+        let response =
+            self.send(Method::POST, route, Some(body), false).await?;
+        Ok(WriteResult {
+            changed: response.status() != StatusCode::NO_CONTENT,
+            version: index_version(response.headers().get(INDEX_VERSION)),
+        })
+    }
+
+    /// Values matching `query`.
+    pub async fn query(&self, query: &str) -> Result<Vec<u32>, Error> {
+        let body = Query {
+            query: query.to_owned(),
+            include_cardinalities: None,
+            facets: None,
+            include_stats: None,
+            include_values: None,
+            ranks: None,
+            percentiles: None,
+        };
+        Ok(self.read::<_, QueryResult>("query", &body).await?.values)
+    }
+
+    /// Values matching `query` along with the cardinality of their
+    /// intersection with every property.
+    pub async fn query_with_cardinalities(
+        &self,
+        query: &str,
+    ) -> Result<QueryResult, Error> {
+        let body = Query {
+            query: query.to_owned(),
+            include_cardinalities: Some(true),
+            facets: None,
+            include_stats: None,
+            include_values: None,
+            ranks: None,
+            percentiles: None,
+        };
+        self.read("query", &body).await
+    }
+
+    /// Values matching `query` along with the cardinality of their
+    /// intersection with every property grouped into facets, see
+    /// `types::Facet`.
+    pub async fn query_with_facets(
+        &self,
+        query: &str,
+    ) -> Result<QueryResult, Error> {
+        let body = Query {
+            query: query.to_owned(),
+            include_cardinalities: None,
+            facets: Some(true),
+            include_stats: None,
+            include_values: None,
+            ranks: None,
+            percentiles: None,
+        };
+        self.read("query", &body).await
+    }
+
+    /// Stats of the values matching `query`, along with the values unless
+    /// `include_values` is false.
+    pub async fn query_with_stats(
+        &self,
+        query: &str,
+        include_values: bool,
+    ) -> Result<QueryResult, Error> {
+        let body = Query {
+            query: query.to_owned(),
+            include_cardinalities: None,
+            facets: None,
+            include_stats: Some(true),
+            include_values: Some(include_values),
+            ranks: None,
+            percentiles: None,
+        };
+        self.read("query", &body).await
+    }
+
+    /// Position of each of `bits` within the values matching `query` and
+    /// the values at each of `percentiles`, without the values themselves.
+    pub async fn query_positions(
+        &self,
+        query: &str,
+        bits: &[u32],
+        percentiles: &[u8],
+    ) -> Result<QueryResult, Error> {
+        let body = Query {
+            query: query.to_owned(),
+            include_cardinalities: None,
+            facets: None,
+            include_stats: None,
+            include_values: Some(false),
+            ranks: Some(bits.to_vec()),
+            percentiles: Some(percentiles.to_vec()),
+        };
+        self.read("query", &body).await
+    }
+
+    pub async fn count(&self, query: &str) -> Result<u64, Error> {
+        self.read("count", &Count { query: query.to_owned() }).await
+    }
+
+    /// Count elements matching each query against the same version of the
+    /// index, see `types::CountMany`.
+    pub async fn count_many(
+        &self,
+        queries: Batch<String>,
+    ) -> Result<Batch<CountOutcome>, Error> {
+        let result: CountManyResult =
+            self.read("count-many", &CountMany { queries }).await?;
+        Ok(result.counts)
+    }
+
+    pub async fn compare(
+        &self,
+        left: &str,
+        right: &str,
+        sample: Option<usize>,
+    ) -> Result<CompareResult, Error> {
+        let body =
+            Compare { left: left.to_owned(), right: right.to_owned(), sample };
+        self.read("compare", &body).await
+    }
+
+    /// Similarity coefficients of the results of two queries, see
+    /// `types::SimilarityResult`.
+    pub async fn similarity(
+        &self,
+        left: &str,
+        right: &str,
+    ) -> Result<SimilarityResult, Error> {
+        let body =
+            Similarity { left: left.to_owned(), right: right.to_owned() };
+        self.read("similarity", &body).await
+    }
+
+    /// Properties changed since the generation of the last sync, see
+    /// `types::SyncResult`.
+    pub async fn sync(
+        &self,
+        request: &SyncRequest,
+    ) -> Result<SyncResult, Error> {
+        self.read("sync", request).await
+    }
+
+    pub async fn stats(&self) -> Result<StatsResult, Error> {
+        self.stats_with(&StatsRequest::default()).await
+    }
+
+    /// Stats of a subset of the index, see `types::StatsRequest`.
+    pub async fn stats_with(
+        &self,
+        request: &StatsRequest,
+    ) -> Result<StatsResult, Error> {
+        self.read("stats", request).await
+    }
+
+    /// Distribution of the cardinalities of the properties, see
+    /// `types::DistributionRequest`.
+    pub async fn stats_distribution(
+        &self,
+        request: &DistributionRequest,
+    ) -> Result<DistributionResult, Error> {
+        self.read("stats/distribution", request).await
+    }
+
+    /// Sorted properties matching `request`, see `types::ListProperties`.
+    pub async fn properties(
+        &self,
+        request: &ListProperties,
+    ) -> Result<PropertiesResult, Error> {
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        if let Some(prefix) = &request.prefix {
+            query.append_pair("prefix", prefix);
+        }
+        if let Some(pattern) = &request.pattern {
+            query.append_pair("pattern", pattern);
+        }
+        if let Some(include_stats) = request.include_stats {
+            query.append_pair("include_stats", &include_stats.to_string());
+        }
+        if let Some(include_metadata) = request.include_metadata {
+            query
+                .append_pair("include_metadata", &include_metadata.to_string());
+        }
+        let route = format!("properties?{}", query.finish());
+        let response = self.send(Method::GET, &route, None::<&()>, true);
+        Ok(response.await?.json().await?)
+    }
+
+    /// Metadata of the properties starting with `prefix`, or of every
+    /// property when `None`.
+    pub async fn properties_metadata(
+        &self,
+        prefix: Option<&str>,
+    ) -> Result<HashMap<String, serde_json::Value>, Error> {
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        if let Some(prefix) = prefix {
+            query.append_pair("prefix", prefix);
+        }
+        let route = format!("properties/meta?{}", query.finish());
+        let response = self.send(Method::GET, &route, None::<&()>, true);
+        Ok(response.await?.json().await?)
+    }
+
+    /// Metadata of `property`, `None` when it has none.
+    pub async fn property_metadata(
+        &self,
+        property: &str,
+    ) -> Result<Option<serde_json::Value>, Error> {
+        let route = format!("properties/{}/meta", path_segment(property));
+        match self.send(Method::GET, &route, None::<&()>, true).await {
+            Ok(response) => Ok(Some(response.json().await?)),
+            Err(e) if e.status() == Some(StatusCode::NOT_FOUND) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replace the metadata of `property`.
+    pub async fn set_property_metadata(
+        &self,
+        property: &str,
+        value: &serde_json::Value,
+    ) -> Result<(), Error> {
+        let route = format!("properties/{}/meta", path_segment(property));
+        self.send(Method::PUT, &route, Some(value), false).await?;
+        Ok(())
+    }
+
+    /// Remove the metadata of `property`, returns whether it had any.
+    pub async fn delete_property_metadata(
+        &self,
+        property: &str,
+    ) -> Result<bool, Error> {
+        let route = format!("properties/{}/meta", path_segment(property));
+        let response =
+            self.send(Method::DELETE, &route, None::<&()>, false).await?;
+        Ok(response.status() != StatusCode::NO_CONTENT)
+    }
+
+    /// Properties for which `bit` is set.
+    pub async fn get_bit(&self, bit: u32) -> Result<Vec<String>, Error> {
+        self.read("get-bit", &GetBit { bit }).await
+    }
+
+    /// Properties for which each of `bits` is set.
+    pub async fn get_bits(
+        &self,
+        bits: Vec<u32>,
+    ) -> Result<HashMap<u32, Vec<String>>, Error> {
+        self.read("get-bits", &GetBits { bits }).await
+    }
+
+    /// Bits of each of `properties`, properties which do not exist are
+    /// omitted. Use `get_properties_with` for serialized bitmaps.
+    pub async fn get_properties(
+        &self,
+        properties: Vec<String>,
+    ) -> Result<HashMap<String, Vec<u32>>, Error> {
+        let request = GetProperties { properties, serialized: None };
+        Ok(self.get_properties_with(&request).await?.values)
+    }
+
+    pub async fn get_properties_with(
+        &self,
+        request: &GetProperties,
+    ) -> Result<GetPropertiesResult, Error> {
+        self.read("get-properties", request).await
+    }
+
+    pub async fn set(
+        &self,
+        property: &str,
+        bit: u32,
+    ) -> Result<WriteResult, Error> {
+        self.write("set", &Set { property: property.to_owned(), bit }).await
+    }
+
+    pub async fn set_many(
+        &self,
+        values: HashMap<String, Vec<u32>>,
+    ) -> Result<WriteResult, Error> {
+        self.write("set-many", &SetMany { values }).await
+    }
+
+    pub async fn unset(
+        &self,
+        property: &str,
+        bit: u32,
+    ) -> Result<WriteResult, Error> {
+        self.write("unset", &Unset { property: property.to_owned(), bit }).await
+    }
+
+    pub async fn unset_many(
+        &self,
+        values: HashMap<String, Vec<u32>>,
+    ) -> Result<WriteResult, Error> {
+        self.write("unset-many", &UnsetMany { values }).await
+    }
+
+    /// Set `bit` for exactly `properties`, unsetting it everywhere else.
+    /// Properties which do not exist are ignored.
+    pub async fn set_bit(
+        &self,
+        bit: u32,
+        properties: Vec<String>,
+    ) -> Result<WriteResult, Error> {
+        self.set_bit_with(&SetBit { bit, properties, missing: None }).await
+    }
+
+    /// Same as `set_bit` but controlling how properties which do not exist
+    /// are handled, see `types::MissingProperties`.
+    pub async fn set_bit_with(
+        &self,
+        request: &SetBit,
+    ) -> Result<WriteResult, Error> {
+        self.write("set-bit", request).await
+    }
+
+    /// Same as `set_bit` for many bits at once, mapping each bit to the
+    /// properties it should be set for.
+    pub async fn set_bits(
+        &self,
+        bits: HashMap<u32, Vec<String>>,
+    ) -> Result<WriteResult, Error> {
+        self.set_bits_with(&SetBits { bits, missing: None }).await
+    }
+
+    /// Same as `set_bits` but controlling how properties which do not exist
+    /// are handled, see `types::MissingProperties`.
+    pub async fn set_bits_with(
+        &self,
+        request: &SetBits,
+    ) -> Result<WriteResult, Error> {
+        self.write("set-bits", request).await
+    }
+
+    pub async fn delete_bits(
+        &self,
+        bits: Vec<u32>,
+    ) -> Result<WriteResult, Error> {
+        self.write("delete-bits", &DeleteBits { bits }).await
+    }
+
+    /// Unset every bit in `range` from `properties`, or from every property
+    /// when `None`.
+    pub async fn delete_range(
+        &self,
+        range: Range<u32>,
+        properties: Option<Vec<String>>,
+    ) -> Result<WriteResult, Error> {
+        let body =
+            DeleteRange { start: range.start, end: range.end, properties };
+        self.write("delete-range", &body).await
+    }
+
+    pub async fn transaction(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<TransactionResult, Error> {
+        let response =
+            self.send(Method::POST, "transaction", Some(transaction), false);
+        Ok(response.await?.json().await?)
+    }
+
+    /// Persist pending writes to the backend.
+    pub async fn flush(&self) -> Result<(), Error> {
+        self.send(Method::POST, "flush", None::<&()>, false).await?;
+        Ok(())
+    }
+
+    /// Run a GraphQL request, the server must be started with `--graphql`.
+    pub async fn graphql(
+        &self,
+        query: &str,
+        variables: serde_json::Value,
+    ) -> Result<serde_json::Value, Error> {
+        let body =
+            serde_json::json!({ "query": query, "variables": variables });
+        self.read("graphql", &body).await
+    }
+
+    pub async fn healthz(&self) -> Result<(), Error> {
+        self.send(Method::GET, "healthz", None::<&()>, true).await?;
+        Ok(())
+    }
+
+    /// Readiness checks, returned as is when the server is not ready rather
+    /// than as an error.
+    pub async fn readyz(&self) -> Result<Readiness, Error> {
+        let url = self.url.join("readyz")?;
+        let response = self.http.get(url).send().await?;
+        Ok(response.json().await?)
+    }
+
+    /// Metrics in the Prometheus text format.
+    pub async fn metrics(&self) -> Result<String, Error> {
+        let response = self.send(Method::GET, "metrics", None::<&()>, true);
+        Ok(response.await?.text().await?)
+    }
+
+    pub async fn admin_executor(&self) -> Result<serde_json::Value, Error> {
+        let response =
+            self.send(Method::GET, "admin/executor", None::<&()>, true);
+        Ok(response.await?.json().await?)
+    }
+
+    /// Storage of each property by container type, optionally limited to
+    /// properties starting with `prefix`.
+    pub async fn admin_bitmap_stats(
+        &self,
+        prefix: Option<&str>,
+    ) -> Result<BitmapStatsResult, Error> {
+        let mut query = url::form_urlencoded::Serializer::new(String::new());
+        if let Some(prefix) = prefix {
+            query.append_pair("prefix", prefix);
+        }
+        let route = format!("admin/bitmap-stats?{}", query.finish());
+        let response = self.send(Method::GET, &route, None::<&()>, true);
+        Ok(response.await?.json().await?)
+    }
+
+    /// Optimize the storage of the whole index immediately.
+    pub async fn admin_optimize(&self) -> Result<serde_json::Value, Error> {
+        let response =
+            self.send(Method::POST, "admin/optimize", None::<&()>, false);
+        Ok(response.await?.json().await?)
+    }
+
+    /// Most recent audit log entries, oldest first.
+    pub async fn admin_audit(
+        &self,
+        limit: Option<usize>,
+    ) -> Result<Vec<serde_json::Value>, Error> {
+        let route = match limit {
+            Some(limit) => format!("admin/audit?limit={}", limit),
+            None => "admin/audit".to_owned(),
+        };
+        let response = self.send(Method::GET, &route, None::<&()>, true);
+        Ok(response.await?.json().await?)
+    }
+
+    /// Re-read the server configuration file.
+    pub async fn admin_reload(&self) -> Result<(), Error> {
+        self.send(Method::POST, "admin/reload", None::<&()>, false).await?;
+        Ok(())
+    }
+
+    /// Open a `/subscribe` connection and stream its notifications.
+    pub async fn subscribe(
+        &self,
+        request: &SubscriptionRequest,
+    ) -> Result<Subscription, Error> {
+        let mut url = self.url.join("subscribe")?;
+        let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+        // Only fails for schemes which cannot be base urls.
+        url.set_scheme(scheme).ok();
+
+        let mut ws_request = url.into_client_request()?;
+        if let Some(token) = &self.token {
+            if let Ok(value) =
+                HeaderValue::from_str(&format!("Bearer {}", token))
+            {
+                ws_request.headers_mut().insert("authorization", value);
+            }
+        }
+
+        let (socket, _) = tokio_tungstenite::connect_async(ws_request).await?;
+        let mut subscription = Subscription::new(socket);
+        subscription.update(request).await?;
+        Ok(subscription)
+    }
+}
+
+fn index_version(value: Option<&HeaderValue>) -> Option<u64> {
+    value?.to_str().ok()?.parse().ok()
+}
+
+async fn check_status(response: Response) -> Result<Response, Error> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let text = response.text().await?;
+    let (message, code) = match serde_json::from_str::<ErrorBody>(&text) {
+        Ok(body) => (body.error, body.code),
+        Err(_) => (text, None),
+    };
+    Err(Error::Api { status, message, code })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use crible::test_support::TestServer;
+    use crible_lib::Index;
+    use futures_util::StreamExt;
+    use reqwest::StatusCode;
+    use rstest::*;
+
+    use super::types::{
+        Batch, CountOutcome, ErrorBody, ListProperties, MissingProperties,
+        Mutation, Notification, Set, SetBit, StatsRequest, SubscriptionRequest,
+    };
+    use super::{base_url, Client, Error};
+
+    async fn server() -> TestServer {
+        TestServer::start(Index::of([("foo", vec![1, 2, 3]), ("bar", vec![3])]))
+            .await
+            .unwrap()
+    }
+
+    #[rstest]
+    #[case("http://localhost:3000", "http://localhost:3000/query")]
+    #[case(
+        "http://localhost:3000/tenant",
+        "http://localhost:3000/tenant/query"
+    )]
+    #[case("http://host/tenant/", "http://host/tenant/query")]
+    fn test_base_url(#[case] url: &str, #[case] expected: &str) {
+        let url = base_url(url.parse().unwrap());
+        assert_eq!(url.join("query").unwrap().as_str(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_reads() {
+        let server = server().await;
+        let client = Client::new(server.url().as_str()).unwrap();
+
+        assert_eq!(client.query("foo - bar").await.unwrap(), vec![1, 2]);
+        assert_eq!(client.count("foo or bar").await.unwrap(), 3);
+        assert_eq!(
+            client
+                .count_many(Batch::List(vec![
+                    "foo".to_owned(),
+                    "qux".to_owned()
+                ]))
+                .await
+                .unwrap(),
+            Batch::List(vec![
+                CountOutcome::Count(3),
+                CountOutcome::Error(ErrorBody {
+                    error: "Property qux does not exist".to_owned(),
+                    code: Some("property_does_not_exist".to_owned()),
+                }),
+            ])
+        );
+        assert_eq!(
+            client.query_with_cardinalities("bar").await.unwrap().cardinalities,
+            Some(HashMap::from([("foo".to_owned(), 1), ("bar".to_owned(), 1)]))
+        );
+        let result = client.query_with_stats("foo", false).await.unwrap();
+        assert!(result.values.is_empty());
+        assert_eq!(result.stats.map(|s| s.maximum), Some(Some(3)));
+
+        let result = client.query_positions("foo", &[2], &[50]).await.unwrap();
+        assert!(result.values.is_empty());
+        assert_eq!(result.ranks, Some(BTreeMap::from([(2, 2)])));
+        assert_eq!(result.percentiles, Some(BTreeMap::from([(50, 2)])));
+
+        let compare = client.compare("foo", "bar", Some(1)).await.unwrap();
+        assert_eq!(compare.intersection.count, 1);
+        assert_eq!(compare.only_left.sample, Some(vec![1]));
+
+        let similarity = client.similarity("foo", "bar").await.unwrap();
+        assert_eq!(similarity.union, 3);
+        assert_eq!(similarity.containment_right, 1.0);
+
+        let sync = client.sync(&SyncRequest::default()).await.unwrap();
+        assert!(sync.reload);
+        let sync = client
+            .sync(&SyncRequest {
+                log: Some(sync.log),
+                generation: Some(sync.generation),
+            })
+            .await
+            .unwrap();
+        assert!(!sync.reload && sync.bitmaps.is_empty());
+
+        let stats = client.stats().await.unwrap();
+        assert_eq!(stats.root.unwrap().cardinality, 3);
+        assert_eq!(stats.properties["foo"].maximum, Some(3));
+        let stats = client
+            .stats_with(&StatsRequest {
+                prefix: Some("f".to_owned()),
+                include_root: Some(false),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert!(stats.root.is_none());
+        assert_eq!(stats.properties.keys().collect::<Vec<_>>(), ["foo"]);
+
+        let distribution = client
+            .stats_distribution(&DistributionRequest {
+                buckets: Some(vec![1]),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(distribution.properties, 2);
+        assert_eq!(distribution.buckets[1].count, 1);
+
+        let stats = client.admin_bitmap_stats(Some("f")).await.unwrap();
+        assert_eq!(stats.properties.keys().collect::<Vec<_>>(), ["foo"]);
+        assert_eq!(stats.total.array_containers, 1);
+
+        let properties = client
+            .properties(&ListProperties {
+                pattern: Some("*o*".to_owned()),
+                include_stats: Some(true),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(properties.properties, ["foo"]);
+        assert_eq!(properties.stats.unwrap()["foo"].cardinality, 3);
+
+        let mut properties = client.get_bit(3).await.unwrap();
+        properties.sort();
+        assert_eq!(properties, vec!["bar", "foo"]);
+        assert_eq!(
+            client.get_bits(vec![1, 3]).await.unwrap(),
+            HashMap::from([
+                (1, vec!["foo".to_owned()]),
+                (3, vec!["bar".to_owned(), "foo".to_owned()]),
+            ])
+        );
+        assert_eq!(
+            client
+                .get_properties(vec!["foo".to_owned(), "qux".to_owned()])
+                .await
+                .unwrap(),
+            HashMap::from([("foo".to_owned(), vec![1, 2, 3])])
+        );
+
+        client.healthz().await.unwrap();
+        assert!(client.readyz().await.unwrap().ready);
+    }
+
+    #[tokio::test]
+    async fn test_metadata() {
+        let server = server().await;
+        let client = Client::new(server.url().as_str()).unwrap();
+
+        let label = serde_json::json!({"label": "Foo / Bar", "order": 1});
+        assert_eq!(client.property_metadata("foo").await.unwrap(), None);
+        client.set_property_metadata("foo", &label).await.unwrap();
+        client
+            .set_property_metadata("country:fr", &serde_json::json!("France"))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.property_metadata("foo").await.unwrap(),
+            Some(label.clone())
+        );
+        assert_eq!(
+            client.properties_metadata(Some("country:")).await.unwrap(),
+            HashMap::from([(
+                "country:fr".to_owned(),
+                serde_json::json!("France")
+            )])
+        );
+
+        let properties = client
+            .properties(&ListProperties {
+                include_metadata: Some(true),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            properties.metadata.unwrap(),
+            HashMap::from([("foo".to_owned(), label)])
+        );
+
+        assert!(client.delete_property_metadata("foo").await.unwrap());
+        assert!(!client.delete_property_metadata("foo").await.unwrap());
+        assert_eq!(client.property_metadata("foo").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_writes() {
+        let server = server().await;
+        let client = Client::new(server.url().as_str()).unwrap();
+
+        let first = client.set("baz", 4).await.unwrap();
+        assert!(first.changed);
+        let second = client.set("baz", 4).await.unwrap();
+        assert!(!second.changed);
+        assert_eq!(second.version, first.version);
+
+        client
+            .set_many(HashMap::from([("baz".to_owned(), vec![5, 6])]))
+            .await
+            .unwrap();
+        assert!(client.unset("baz", 6).await.unwrap().changed);
+        client.set_bit(1, vec!["bar".to_owned()]).await.unwrap();
+        client.delete_bits(vec![2]).await.unwrap();
+        client.delete_range(0..5, Some(vec!["baz".to_owned()])).await.unwrap();
+        let result = client
+            .set_bits(HashMap::from([
+                (3, vec!["foo".to_owned(), "bar".to_owned()]),
+                (9, vec!["foo".to_owned()]),
+            ]))
+            .await
+            .unwrap();
+        assert!(result.changed);
+
+        let result = client
+            .transaction(&super::types::Transaction {
+                expected_version: None,
+                operations: vec![Mutation::Set(Set {
+                    property: "qux".to_owned(),
+                    bit: 7,
+                })],
+            })
+            .await
+            .unwrap();
+        assert!(result.changed);
+        client.flush().await.unwrap();
+
+        assert!(
+            *server.index()
+                == Index::of([
+                    ("foo", vec![3, 9]),
+                    ("bar", vec![1, 3]),
+                    ("baz", vec![5]),
+                    ("qux", vec![7]),
+                ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let server = server().await;
+        let client = Client::builder(server.url().as_str())
+            .unwrap()
+            .max_retries(0)
+            .build()
+            .unwrap();
+
+        match client.count("foo and").await.unwrap_err() {
+            Error::Api { status, code, .. } => {
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+                assert_eq!(code.as_deref(), Some("invalid_query"));
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        let e = client.count("qux").await.unwrap_err();
+        assert_eq!(e.code(), Some("property_does_not_exist"));
+
+        let e = client
+            .set_bit_with(&SetBit {
+                bit: 4,
+                properties: vec!["foo".to_owned(), "qux".to_owned()],
+                missing: Some(MissingProperties::Reject),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(e.code(), Some("property_does_not_exist"));
+        assert!(
+            *server.index()
+                == Index::of([("foo", vec![1, 2, 3]), ("bar", vec![3])])
+        );
+
+        let e = client.tenant("missing").unwrap().count("foo").await;
+        assert_eq!(e.unwrap_err().status(), Some(StatusCode::NOT_FOUND));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let server = server().await;
+        let client = Client::new(server.url().as_str()).unwrap();
+
+        let mut subscription = client
+            .subscribe(&SubscriptionRequest {
+                prefixes: vec!["ba".to_owned()],
+                queries: [("all".to_owned(), "foo or bar".to_owned())].into(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            subscription.next().await.unwrap().unwrap(),
+            Notification::CountChanged { query: "all".to_owned(), count: 3 }
+        );
+
+        client.set("baz", 4).await.unwrap();
+        assert_eq!(
+            subscription.next().await.unwrap().unwrap(),
+            Notification::PropertyChanged {
+                operation: "set".to_owned(),
+                properties: Some(vec!["baz".to_owned()]),
+                bits: vec![4],
+                range: None,
+            }
+        );
+
+        subscription.close().await.unwrap();
+    }
+}
@@ -0,0 +1,60 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::{ready, SinkExt, Stream, StreamExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::types::{Notification, SubscriptionRequest};
+use crate::Error;
+
+/// Stream of notifications from the `/subscribe` route, see
+/// `Client::subscribe`. It ends when the server closes the connection.
+pub struct Subscription {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl Subscription {
+    pub(crate) fn new(
+        socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    ) -> Self {
+        Self { socket }
+    }
+
+    /// Replace the current subscription. Counts for the new saved queries are
+    /// sent right away.
+    pub async fn update(
+        &mut self,
+        request: &SubscriptionRequest,
+    ) -> Result<(), Error> {
+        let message = Message::Text(serde_json::to_string(request)?);
+        Ok(self.socket.send(message).await?)
+    }
+
+    pub async fn close(mut self) -> Result<(), Error> {
+        Ok(self.socket.close(None).await?)
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Result<Notification, Error>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        loop {
+            let text = match ready!(self.socket.poll_next_unpin(cx)) {
+                None | Some(Ok(Message::Close(_))) => return Poll::Ready(None),
+                Some(Ok(Message::Text(text))) => text,
+                // Pings are answered by tungstenite.
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+            };
+            return Poll::Ready(Some(
+                serde_json::from_str(&text).map_err(Error::from),
+            ));
+        }
+    }
+}
@@ -0,0 +1,12 @@
+fn main() {
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+
+    let config =
+        cbindgen::Config::from_file(format!("{}/cbindgen.toml", crate_dir))
+            .expect("Invalid cbindgen.toml");
+    cbindgen::generate_with_config(&crate_dir, config)
+        .expect("Unable to generate C bindings")
+        .write_to_file(format!("{}/include/crible.h", crate_dir));
+}
@@ -0,0 +1,217 @@
+#ifndef CRIBLE_H
+#define CRIBLE_H
+
+/* Generated by cbindgen from crible-ffi, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Result of fallible calls, anything other than `CRIBLE_STATUS_OK` means
+ * that the call failed and had no effect.
+ */
+typedef enum CribleStatus {
+  CRIBLE_STATUS_OK = 0,
+  /**
+   * A pointer was NULL or a string was not valid UTF-8.
+   */
+  CRIBLE_STATUS_INVALID_ARGUMENT = 1,
+  CRIBLE_STATUS_INVALID_QUERY = 2,
+  CRIBLE_STATUS_PROPERTY_DOES_NOT_EXIST = 3,
+  /**
+   * The index could not be read or written.
+   */
+  CRIBLE_STATUS_ENCODING = 4,
+  /**
+   * The library panicked, this is always a bug.
+   */
+  CRIBLE_STATUS_PANIC = 5,
+} CribleStatus;
+
+/**
+ * Opaque handle to an index, created with `crible_index_new()` or
+ * `crible_index_load()` and released with `crible_index_free()`.
+ */
+typedef struct CribleIndex CribleIndex;
+
+/**
+ * Bits returned by `crible_index_execute()`, in increasing order.
+ */
+typedef struct CribleBits {
+  uint32_t *data;
+  size_t len;
+} CribleBits;
+
+typedef struct CribleCardinality {
+  char *property;
+  uint64_t cardinality;
+} CribleCardinality;
+
+/**
+ * Returned by `crible_index_cardinalities()`, sorted by property.
+ */
+typedef struct CribleCardinalities {
+  struct CribleCardinality *data;
+  size_t len;
+} CribleCardinalities;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Description of the last failure on the current thread, or NULL. The
+ * string is owned by the library and valid until the next failing call on
+ * the same thread.
+ */
+const char *crible_last_error(void);
+
+/**
+ * Create an empty index.
+ */
+struct CribleIndex *crible_index_new(void);
+
+/**
+ * Release an index, NULL is ignored.
+ *
+ * # Safety
+ *
+ * `index` must come from this library and not have been released before.
+ */
+void crible_index_free(struct CribleIndex *index);
+
+/**
+ * Load an index from a file in the given format, `bin` or `json`. The new
+ * index is stored in `out` on success.
+ *
+ * # Safety
+ *
+ * `path` and `format` must be valid strings and `out` a valid pointer.
+ */
+enum CribleStatus crible_index_load(const char *path,
+                                    const char *format,
+                                    struct CribleIndex **out);
+
+/**
+ * Write an index to a file in the given format, `bin` or `json`.
+ *
+ * # Safety
+ *
+ * `index` must be a valid index and `path` and `format` valid strings.
+ */
+enum CribleStatus crible_index_save(const struct CribleIndex *index,
+                                    const char *path,
+                                    const char *format);
+
+/**
+ * Number of properties in the index, 0 for NULL.
+ *
+ * # Safety
+ *
+ * `index` must be a valid index or NULL.
+ */
+size_t crible_index_len(const struct CribleIndex *index);
+
+/**
+ * Set a bit for a property, creating the property if needed.
+ *
+ * # Safety
+ *
+ * `index` must be a valid index and `property` a valid string.
+ */
+enum CribleStatus crible_index_set(struct CribleIndex *index,
+                                   const char *property,
+                                   uint32_t bit);
+
+/**
+ * Set `len` bits for a property, creating the property if needed.
+ *
+ * # Safety
+ *
+ * `index` must be a valid index, `property` a valid string and `bits` must
+ * point to at least `len` values.
+ */
+enum CribleStatus crible_index_set_many(struct CribleIndex *index,
+                                        const char *property,
+                                        const uint32_t *bits,
+                                        size_t len);
+
+/**
+ * Unset a bit for a property, unknown properties are ignored.
+ *
+ * # Safety
+ *
+ * `index` must be a valid index and `property` a valid string.
+ */
+enum CribleStatus crible_index_unset(struct CribleIndex *index,
+                                     const char *property,
+                                     uint32_t bit);
+
+/**
+ * Run a query and store the matching bits in `out`, which must be released
+ * with `crible_bits_free()`.
+ *
+ * # Safety
+ *
+ * `index` must be a valid index, `query` a valid string and `out` a valid
+ * pointer.
+ */
+enum CribleStatus crible_index_execute(const struct CribleIndex *index,
+                                       const char *query,
+                                       struct CribleBits *out);
+
+/**
+ * Count the bits matching a query.
+ *
+ * # Safety
+ *
+ * `index` must be a valid index, `query` a valid string and `out` a valid
+ * pointer.
+ */
+enum CribleStatus crible_index_count(const struct CribleIndex *index,
+                                     const char *query,
+                                     uint64_t *out);
+
+/**
+ * Count the bits matching a query for every property starting with
+ * `prefix`, or every property when `prefix` is NULL. Properties without any
+ * matching bit are omitted. The result must be released with
+ * `crible_cardinalities_free()`.
+ *
+ * # Safety
+ *
+ * `index` must be a valid index, `query` a valid string, `prefix` a valid
+ * string or NULL and `out` a valid pointer.
+ */
+enum CribleStatus crible_index_cardinalities(const struct CribleIndex *index,
+                                             const char *query,
+                                             const char *prefix,
+                                             struct CribleCardinalities *out);
+
+/**
+ * Release bits returned by `crible_index_execute()`.
+ *
+ * # Safety
+ *
+ * `bits` must come from this library and not have been released before.
+ */
+void crible_bits_free(struct CribleBits bits);
+
+/**
+ * Release cardinalities returned by `crible_index_cardinalities()`.
+ *
+ * # Safety
+ *
+ * `cardinalities` must come from this library and not have been released
+ * before.
+ */
+void crible_cardinalities_free(struct CribleCardinalities cardinalities);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* CRIBLE_H */
@@ -0,0 +1,579 @@
+//! C bindings for crible-lib, see `include/crible.h` which is generated from
+//! this file when building the crate.
+//!
+//! Functions returning a `CribleStatus` store a description of any failure,
+//! retrieved through `crible_last_error()` on the same thread. Strings are
+//! NUL terminated UTF-8 and are never retained after a call returns. Memory
+//! handed out by the library must be released through the matching
+//! `crible_*_free()` function.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::str::FromStr;
+
+use crible_lib::{Encoder, Expression, Index};
+
+/// Result of fallible calls, anything other than `CRIBLE_STATUS_OK` means
+/// that the call failed and had no effect.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CribleStatus {
+    Ok = 0,
+    /// A pointer was NULL or a string was not valid UTF-8.
+    InvalidArgument = 1,
+    InvalidQuery = 2,
+    PropertyDoesNotExist = 3,
+    /// The index could not be read or written.
+    Encoding = 4,
+    /// The library panicked, this is always a bug.
+    Panic = 5,
+}
+
+/// Opaque handle to an index, created with `crible_index_new()` or
+/// `crible_index_load()` and released with `crible_index_free()`.
+pub struct CribleIndex(Index);
+
+/// Bits returned by `crible_index_execute()`, in increasing order.
+#[repr(C)]
+pub struct CribleBits {
+    pub data: *mut u32,
+    pub len: usize,
+}
+
+#[repr(C)]
+pub struct CribleCardinality {
+    pub property: *mut c_char,
+    pub cardinality: u64,
+}
+
+/// Returned by `crible_index_cardinalities()`, sorted by property.
+#[repr(C)]
+pub struct CribleCardinalities {
+    pub data: *mut CribleCardinality,
+    pub len: usize,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+struct Failure(CribleStatus, String);
+
+impl Failure {
+    fn invalid_argument(message: String) -> Self {
+        Self(CribleStatus::InvalidArgument, message)
+    }
+}
+
+impl From<crible_lib::expression::Error> for Failure {
+    fn from(e: crible_lib::expression::Error) -> Self {
+        Self(CribleStatus::InvalidQuery, e.to_string())
+    }
+}
+
+impl From<crible_lib::index::Error> for Failure {
+    fn from(e: crible_lib::index::Error) -> Self {
+        Self(CribleStatus::PropertyDoesNotExist, e.to_string())
+    }
+}
+
+impl From<crible_lib::encoding::Error> for Failure {
+    fn from(e: crible_lib::encoding::Error) -> Self {
+        let mut message = e.to_string();
+        let mut source = std::error::Error::source(&e);
+        while let Some(e) = source {
+            message.push_str(&format!(": {}", e));
+            source = e.source();
+        }
+        Self(CribleStatus::Encoding, message)
+    }
+}
+
+fn set_last_error(message: String) {
+    // Messages come from Display implementations which never contain NUL.
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+/// Run `f`, turning failures and panics into a status.
+fn guard<F: FnOnce() -> Result<(), Failure>>(f: F) -> CribleStatus {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => CribleStatus::Ok,
+        Ok(Err(Failure(status, message))) => {
+            set_last_error(message);
+            status
+        }
+        Err(_) => {
+            set_last_error("crible-lib panicked".to_owned());
+            CribleStatus::Panic
+        }
+    }
+}
+
+unsafe fn str_arg<'a>(
+    ptr: *const c_char,
+    name: &str,
+) -> Result<&'a str, Failure> {
+    if ptr.is_null() {
+        return Err(Failure::invalid_argument(format!("{} is NULL", name)));
+    }
+    CStr::from_ptr(ptr).to_str().map_err(|_| {
+        Failure::invalid_argument(format!("{} is not valid UTF-8", name))
+    })
+}
+
+unsafe fn index_arg<'a>(ptr: *const CribleIndex) -> Result<&'a Index, Failure> {
+    ptr.as_ref()
+        .map(|index| &index.0)
+        .ok_or_else(|| Failure::invalid_argument("index is NULL".to_owned()))
+}
+
+unsafe fn index_mut_arg<'a>(
+    ptr: *mut CribleIndex,
+) -> Result<&'a mut Index, Failure> {
+    ptr.as_mut()
+        .map(|index| &mut index.0)
+        .ok_or_else(|| Failure::invalid_argument("index is NULL".to_owned()))
+}
+
+unsafe fn out_arg<'a, T>(ptr: *mut T) -> Result<&'a mut T, Failure> {
+    ptr.as_mut()
+        .ok_or_else(|| Failure::invalid_argument("out is NULL".to_owned()))
+}
+
+fn encoder_arg(format: &str) -> Result<Encoder, Failure> {
+    Encoder::from_str(format)
+        .map_err(|e| Failure::invalid_argument(e.to_string()))
+}
+
+fn into_raw_parts<T>(values: Vec<T>) -> (*mut T, usize) {
+    let len = values.len();
+    (Box::into_raw(values.into_boxed_slice()) as *mut T, len)
+}
+
+unsafe fn from_raw_parts<T>(data: *mut T, len: usize) -> Box<[T]> {
+    Box::from_raw(std::ptr::slice_from_raw_parts_mut(data, len))
+}
+
+/// Description of the last failure on the current thread, or NULL. The
+/// string is owned by the library and valid until the next failing call on
+/// the same thread.
+#[no_mangle]
+pub extern "C" fn crible_last_error() -> *const c_char {
+    LAST_ERROR
+        .with(|e| e.borrow().as_ref().map_or(std::ptr::null(), |e| e.as_ptr()))
+}
+
+/// Create an empty index.
+#[no_mangle]
+pub extern "C" fn crible_index_new() -> *mut CribleIndex {
+    Box::into_raw(Box::new(CribleIndex(Index::default())))
+}
+
+/// Release an index, NULL is ignored.
+///
+/// # Safety
+///
+/// `index` must come from this library and not have been released before.
+#[no_mangle]
+pub unsafe extern "C" fn crible_index_free(index: *mut CribleIndex) {
+    if !index.is_null() {
+        drop(Box::from_raw(index));
+    }
+}
+
+/// Load an index from a file in the given format, `bin` or `json`. The new
+/// index is stored in `out` on success.
+///
+/// # Safety
+///
+/// `path` and `format` must be valid strings and `out` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn crible_index_load(
+    path: *const c_char,
+    format: *const c_char,
+    out: *mut *mut CribleIndex,
+) -> CribleStatus {
+    guard(|| {
+        let path = str_arg(path, "path")?;
+        let encoder = encoder_arg(str_arg(format, "format")?)?;
+        let out = out_arg(out)?;
+        let index = encoder.load_index_from_file(path)?;
+        *out = Box::into_raw(Box::new(CribleIndex(index)));
+        Ok(())
+    })
+}
+
+/// Write an index to a file in the given format, `bin` or `json`.
+///
+/// # Safety
+///
+/// `index` must be a valid index and `path` and `format` valid strings.
+#[no_mangle]
+pub unsafe extern "C" fn crible_index_save(
+    index: *const CribleIndex,
+    path: *const c_char,
+    format: *const c_char,
+) -> CribleStatus {
+    guard(|| {
+        let index = index_arg(index)?;
+        let path = str_arg(path, "path")?;
+        let encoder = encoder_arg(str_arg(format, "format")?)?;
+        Ok(encoder.save_index_from_file(path, index)?)
+    })
+}
+
+/// Number of properties in the index, 0 for NULL.
+///
+/// # Safety
+///
+/// `index` must be a valid index or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn crible_index_len(index: *const CribleIndex) -> usize {
+    index.as_ref().map_or(0, |index| index.0.len())
+}
+
+/// Set a bit for a property, creating the property if needed.
+///
+/// # Safety
+///
+/// `index` must be a valid index and `property` a valid string.
+#[no_mangle]
+pub unsafe extern "C" fn crible_index_set(
+    index: *mut CribleIndex,
+    property: *const c_char,
+    bit: u32,
+) -> CribleStatus {
+    crible_index_set_many(index, property, &bit, 1)
+}
+
+/// Set `len` bits for a property, creating the property if needed.
+///
+/// # Safety
+///
+/// `index` must be a valid index, `property` a valid string and `bits` must
+/// point to at least `len` values.
+#[no_mangle]
+pub unsafe extern "C" fn crible_index_set_many(
+    index: *mut CribleIndex,
+    property: *const c_char,
+    bits: *const u32,
+    len: usize,
+) -> CribleStatus {
+    guard(|| {
+        let index = index_mut_arg(index)?;
+        let property = str_arg(property, "property")?;
+        if !crible_lib::expression::validate_property_name(property) {
+            return Err(Failure::invalid_argument(format!(
+                "Invalid property {:?}",
+                property
+            )));
+        }
+        if bits.is_null() && len > 0 {
+            return Err(Failure::invalid_argument("bits is NULL".to_owned()));
+        }
+        let bits: &[u32] =
+            if len == 0 { &[] } else { std::slice::from_raw_parts(bits, len) };
+        index.set_many(property, bits);
+        Ok(())
+    })
+}
+
+/// Unset a bit for a property, unknown properties are ignored.
+///
+/// # Safety
+///
+/// `index` must be a valid index and `property` a valid string.
+#[no_mangle]
+pub unsafe extern "C" fn crible_index_unset(
+    index: *mut CribleIndex,
+    property: *const c_char,
+    bit: u32,
+) -> CribleStatus {
+    guard(|| {
+        let index = index_mut_arg(index)?;
+        index.unset(str_arg(property, "property")?, bit);
+        Ok(())
+    })
+}
+
+/// Run a query and store the matching bits in `out`, which must be released
+/// with `crible_bits_free()`.
+///
+/// # Safety
+///
+/// `index` must be a valid index, `query` a valid string and `out` a valid
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn crible_index_execute(
+    index: *const CribleIndex,
+    query: *const c_char,
+    out: *mut CribleBits,
+) -> CribleStatus {
+    guard(|| {
+        let index = index_arg(index)?;
+        let expression = Expression::parse(str_arg(query, "query")?)?;
+        let out = out_arg(out)?;
+        let (data, len) =
+            into_raw_parts(index.execute_borrowed(&expression)?.to_vec());
+        *out = CribleBits { data, len };
+        Ok(())
+    })
+}
+
+/// Count the bits matching a query.
+///
+/// # Safety
+///
+/// `index` must be a valid index, `query` a valid string and `out` a valid
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn crible_index_count(
+    index: *const CribleIndex,
+    query: *const c_char,
+    out: *mut u64,
+) -> CribleStatus {
+    guard(|| {
+        let index = index_arg(index)?;
+        let expression = Expression::parse(str_arg(query, "query")?)?;
+        let out = out_arg(out)?;
+        *out = index.execute_borrowed(&expression)?.cardinality();
+        Ok(())
+    })
+}
+
+/// Count the bits matching a query for every property starting with
+/// `prefix`, or every property when `prefix` is NULL. Properties without any
+/// matching bit are omitted. The result must be released with
+/// `crible_cardinalities_free()`.
+///
+/// # Safety
+///
+/// `index` must be a valid index, `query` a valid string, `prefix` a valid
+/// string or NULL and `out` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn crible_index_cardinalities(
+    index: *const CribleIndex,
+    query: *const c_char,
+    prefix: *const c_char,
+    out: *mut CribleCardinalities,
+) -> CribleStatus {
+    guard(|| {
+        let index = index_arg(index)?;
+        let expression = Expression::parse(str_arg(query, "query")?)?;
+        let prefix = if prefix.is_null() {
+            None
+        } else {
+            Some(str_arg(prefix, "prefix")?)
+        };
+        let out = out_arg(out)?;
+
+        let source = index.execute_borrowed(&expression)?;
+        let mut cardinalities: Vec<(String, u64)> =
+            index.cardinalities(&source, prefix).into_iter().collect();
+        cardinalities.sort_unstable();
+
+        let (data, len) = into_raw_parts(
+            cardinalities
+                .into_iter()
+                .map(|(property, cardinality)| CribleCardinality {
+                    // Property names are valid in queries and never contain
+                    // NUL.
+                    property: CString::new(property)
+                        .unwrap_or_default()
+                        .into_raw(),
+                    cardinality,
+                })
+                .collect(),
+        );
+        *out = CribleCardinalities { data, len };
+        Ok(())
+    })
+}
+
+/// Release bits returned by `crible_index_execute()`.
+///
+/// # Safety
+///
+/// `bits` must come from this library and not have been released before.
+#[no_mangle]
+pub unsafe extern "C" fn crible_bits_free(bits: CribleBits) {
+    if !bits.data.is_null() {
+        drop(from_raw_parts(bits.data, bits.len));
+    }
+}
+
+/// Release cardinalities returned by `crible_index_cardinalities()`.
+///
+/// # Safety
+///
+/// `cardinalities` must come from this library and not have been released
+/// before.
+#[no_mangle]
+pub unsafe extern "C" fn crible_cardinalities_free(
+    cardinalities: CribleCardinalities,
+) {
+    if cardinalities.data.is_null() {
+        return;
+    }
+    for c in from_raw_parts(cardinalities.data, cardinalities.len).iter() {
+        drop(CString::from_raw(c.property));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{CStr, CString};
+
+    use super::*;
+
+    fn c(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(crible_last_error()) }
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn test_index() {
+        unsafe {
+            let index = crible_index_new();
+            let bits = [1, 2, 3];
+            assert_eq!(
+                crible_index_set_many(
+                    index,
+                    c("foo").as_ptr(),
+                    bits.as_ptr(),
+                    3
+                ),
+                CribleStatus::Ok
+            );
+            assert_eq!(
+                crible_index_set(index, c("bar").as_ptr(), 3),
+                CribleStatus::Ok
+            );
+            assert_eq!(crible_index_len(index), 2);
+
+            let mut out = CribleBits { data: std::ptr::null_mut(), len: 0 };
+            assert_eq!(
+                crible_index_execute(index, c("foo - bar").as_ptr(), &mut out),
+                CribleStatus::Ok
+            );
+            assert_eq!(std::slice::from_raw_parts(out.data, out.len), &[1, 2]);
+            crible_bits_free(out);
+
+            let mut count = 0;
+            assert_eq!(
+                crible_index_count(index, c("foo or bar").as_ptr(), &mut count),
+                CribleStatus::Ok
+            );
+            assert_eq!(count, 3);
+
+            let mut out =
+                CribleCardinalities { data: std::ptr::null_mut(), len: 0 };
+            assert_eq!(
+                crible_index_cardinalities(
+                    index,
+                    c("foo").as_ptr(),
+                    std::ptr::null(),
+                    &mut out
+                ),
+                CribleStatus::Ok
+            );
+            let cardinalities = std::slice::from_raw_parts(out.data, out.len)
+                .iter()
+                .map(|x| {
+                    (
+                        CStr::from_ptr(x.property).to_str().unwrap().to_owned(),
+                        x.cardinality,
+                    )
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                cardinalities,
+                vec![("bar".to_owned(), 1), ("foo".to_owned(), 3)]
+            );
+            crible_cardinalities_free(out);
+
+            crible_index_free(index);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        unsafe {
+            let index = crible_index_new();
+            let mut count = 0;
+
+            assert_eq!(
+                crible_index_count(index, c("foo and").as_ptr(), &mut count),
+                CribleStatus::InvalidQuery
+            );
+            assert_eq!(
+                crible_index_count(index, c("foo").as_ptr(), &mut count),
+                CribleStatus::PropertyDoesNotExist
+            );
+            assert_eq!(last_error(), "property \"foo\" does not exist");
+            assert_eq!(
+                crible_index_set(index, std::ptr::null(), 1),
+                CribleStatus::InvalidArgument
+            );
+            assert_eq!(last_error(), "property is NULL");
+            assert_eq!(
+                crible_index_set(index, c("1foo").as_ptr(), 1),
+                CribleStatus::InvalidArgument
+            );
+            assert_eq!(crible_index_len(index), 0);
+
+            let mut out = std::ptr::null_mut();
+            assert_eq!(
+                crible_index_load(
+                    c("/does/not/exist").as_ptr(),
+                    c("json").as_ptr(),
+                    &mut out
+                ),
+                CribleStatus::Encoding
+            );
+            assert!(out.is_null());
+
+            crible_index_free(index);
+        }
+    }
+
+    #[test]
+    fn test_load_save() {
+        let path = std::env::temp_dir()
+            .join(format!("crible-ffi-{}.json", std::process::id()));
+        let path = c(path.to_str().unwrap());
+
+        unsafe {
+            let index = crible_index_new();
+            crible_index_set(index, c("foo").as_ptr(), 1);
+            assert_eq!(
+                crible_index_save(index, path.as_ptr(), c("json").as_ptr()),
+                CribleStatus::Ok
+            );
+            crible_index_free(index);
+
+            let mut loaded = std::ptr::null_mut();
+            assert_eq!(
+                crible_index_load(
+                    path.as_ptr(),
+                    c("json").as_ptr(),
+                    &mut loaded
+                ),
+                CribleStatus::Ok
+            );
+            assert_eq!(crible_index_len(loaded), 1);
+            crible_index_free(loaded);
+        }
+
+        std::fs::remove_file(path.to_str().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+
+use crible_lib::index::PropertyMap;
+use crible_lib::{Expression, Index};
+use criterion::{
+    black_box, criterion_group, criterion_main, BenchmarkId, Criterion,
+};
+use croaring::Bitmap;
+
+static SIZES: [usize; 3] = [10, 100, 1000];
+
+fn property(n: usize) -> String {
+    format!("property-{}", n)
+}
+
+fn bitmap(n: usize) -> Bitmap {
+    let start = (n * 100) as u32;
+    Bitmap::of(&(start..start + 1000).collect::<Vec<_>>())
+}
+
+fn index(size: usize) -> Index {
+    Index::new((0..size).map(|n| (property(n), bitmap(n))).collect())
+}
+
+fn wide_query(size: usize, operator: &str) -> Expression {
+    (0..size).map(property).collect::<Vec<_>>().join(operator).parse().unwrap()
+}
+
+/// Compare the default SipHash based map with the one used by the index.
+fn bench_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lookup");
+    for size in SIZES {
+        let keys = (0..size).map(property).collect::<Vec<_>>();
+        let std: HashMap<String, Bitmap> =
+            (0..size).map(|n| (property(n), bitmap(n))).collect();
+        let ahash: PropertyMap =
+            (0..size).map(|n| (property(n), bitmap(n))).collect();
+
+        group.bench_with_input(BenchmarkId::new("std", size), &keys, |b, k| {
+            b.iter(|| k.iter().filter(|k| std.contains_key(*k)).count())
+        });
+        group.bench_with_input(
+            BenchmarkId::new("ahash", size),
+            &keys,
+            |b, k| {
+                b.iter(|| k.iter().filter(|k| ahash.contains_key(*k)).count())
+            },
+        );
+    }
+    group.finish();
+}
+
+fn bench_execute(c: &mut Criterion) {
+    let mut group = c.benchmark_group("execute");
+    for size in SIZES {
+        let index = index(size);
+        for (name, operator) in [("or", " or "), ("and", " and ")] {
+            let query = wide_query(size, operator);
+            group.bench_with_input(
+                BenchmarkId::new(name, size),
+                &query,
+                |b, q| b.iter(|| index.execute(black_box(q)).unwrap()),
+            );
+        }
+        let query = Expression::property(&property(0));
+        group.bench_with_input(
+            BenchmarkId::new("count", size),
+            &query,
+            |b, q| {
+                b.iter(|| {
+                    index.execute_borrowed(black_box(q)).unwrap().cardinality()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_lookup, bench_execute);
+criterion_main!(benches);
@@ -0,0 +1,251 @@
+//! Conversions between indices and Arrow record batches, for analytical
+//! tools (DataFusion, pandas, etc.) which can consume them without copying.
+//!
+//! Indices are represented with one row per property: a non-nullable `Utf8`
+//! `property` column and a non-nullable `values` column containing the list
+//! of bits set for the property as `UInt32`, in increasing order.
+
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use arrow::array::{
+    Array, ArrayRef, ListArray, ListBuilder, StringArray, UInt32Array,
+    UInt32Builder,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::error::ArrowError;
+use arrow::ipc::reader::StreamReader;
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+use croaring::Bitmap;
+use thiserror::Error;
+
+use crate::expression::validate_property_name;
+use crate::index::Index;
+
+/// Errors about a specific row carry its 1-based position in the batch.
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("arrow error")]
+    Arrow(#[from] ArrowError),
+    #[error("missing or invalid column {0:?}")]
+    InvalidColumn(&'static str),
+    #[error("null value in row {row}")]
+    Null { row: usize },
+    #[error("duplicate property {property:?} in row {row}")]
+    DuplicateProperty { property: String, row: usize },
+    #[error("invalid property {property:?} in row {row}")]
+    InvalidProperty { property: String, row: usize },
+}
+
+impl Error {
+    /// Stable identifier of the kind of error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Arrow(_) => "invalid_arrow",
+            Self::InvalidColumn(_) => "invalid_column",
+            Self::Null { .. } => "null_value",
+            Self::DuplicateProperty { .. } => "duplicate_property",
+            Self::InvalidProperty { .. } => "invalid_property",
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn values_type() -> DataType {
+    DataType::List(Box::new(Field::new("item", DataType::UInt32, true)))
+}
+
+/// Schema of the batches produced by `Index::to_arrow`.
+pub fn index_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("property", DataType::Utf8, false),
+        Field::new("values", values_type(), false),
+    ]))
+}
+
+/// Schema of the batches produced by `values_batch`.
+pub fn values_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![Field::new("values", DataType::UInt32, false)]))
+}
+
+/// Single column batch containing `values`, e.g. the result of a query.
+pub fn values_batch(values: Vec<u32>) -> Result<RecordBatch> {
+    let column: ArrayRef = Arc::new(UInt32Array::from(values));
+    Ok(RecordBatch::try_new(values_schema(), vec![column])?)
+}
+
+/// Write `batch` in the Arrow IPC streaming format.
+pub fn write_ipc<W: Write>(w: W, batch: &RecordBatch) -> Result<()> {
+    let mut writer = StreamWriter::try_new(w, &batch.schema())?;
+    writer.write(batch)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Read all batches from an Arrow IPC stream.
+pub fn read_ipc<R: Read>(r: R) -> Result<Vec<RecordBatch>> {
+    Ok(StreamReader::try_new(r, None)?
+        .collect::<std::result::Result<_, _>>()?)
+}
+
+fn column<'a, T: 'static>(
+    batch: &'a RecordBatch,
+    name: &'static str,
+) -> Result<&'a T> {
+    let position = batch
+        .schema()
+        .index_of(name)
+        .map_err(|_| Error::InvalidColumn(name))?;
+    batch
+        .column(position)
+        .as_any()
+        .downcast_ref::<T>()
+        .ok_or(Error::InvalidColumn(name))
+}
+
+/// `(property, values)` pairs from a record batch with a `property` and
+/// `values` column as produced by `Index::to_arrow`, other columns are
+/// ignored. Properties can appear multiple times.
+pub fn records(batch: &RecordBatch) -> Result<Vec<(String, Vec<u32>)>> {
+    let properties = column::<StringArray>(batch, "property")?;
+    let values = column::<ListArray>(batch, "values")?;
+
+    (0..batch.num_rows())
+        .map(|i| {
+            let row = i + 1;
+            if properties.is_null(i) || values.is_null(i) {
+                return Err(Error::Null { row });
+            }
+
+            let property = properties.value(i);
+            if !validate_property_name(property) {
+                return Err(Error::InvalidProperty {
+                    property: property.to_owned(),
+                    row,
+                });
+            }
+
+            let bits = values.value(i);
+            let bits = bits
+                .as_any()
+                .downcast_ref::<UInt32Array>()
+                .ok_or(Error::InvalidColumn("values"))?;
+            if bits.null_count() > 0 {
+                return Err(Error::Null { row });
+            }
+            Ok((property.to_owned(), bits.values().to_vec()))
+        })
+        .collect()
+}
+
+impl Index {
+    /// Convert the index to a record batch, properties are sorted by name so
+    /// that the output is deterministic. See `index_schema`.
+    pub fn to_arrow(&self) -> Result<RecordBatch> {
+        let mut pairs = self.iter().collect::<Vec<_>>();
+        pairs.sort_unstable_by_key(|(k, _)| *k);
+
+        let properties =
+            StringArray::from_iter_values(pairs.iter().map(|(k, _)| k));
+        let mut values =
+            ListBuilder::with_capacity(UInt32Builder::new(), pairs.len());
+        for (_, bm) in &pairs {
+            values.values().append_slice(&bm.to_vec());
+            values.append(true);
+        }
+
+        let columns: Vec<ArrayRef> =
+            vec![Arc::new(properties), Arc::new(values.finish())];
+        Ok(RecordBatch::try_new(index_schema(), columns)?)
+    }
+
+    /// Build an index from a record batch with a `property` and `values`
+    /// column as produced by `to_arrow`, see `records`. Values do not need
+    /// to be sorted.
+    pub fn from_arrow(batch: &RecordBatch) -> Result<Self> {
+        let mut index = Self::with_capacity(batch.num_rows());
+        for (i, (property, values)) in records(batch)?.into_iter().enumerate() {
+            if index.get_property(&property).is_some() {
+                return Err(Error::DuplicateProperty { property, row: i + 1 });
+            }
+            index.set_property(&property, Bitmap::of(&values));
+        }
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use arrow::array::{ArrayRef, ListArray, StringArray};
+    use arrow::datatypes::UInt32Type;
+    use arrow::record_batch::RecordBatch;
+    use rstest::*;
+
+    use super::{index_schema, read_ipc, values_batch, write_ipc, Error};
+    use crate::Index;
+
+    fn batch(properties: Vec<&str>, values: Vec<Vec<u32>>) -> RecordBatch {
+        let values = ListArray::from_iter_primitive::<UInt32Type, _, _>(
+            values.into_iter().map(|v| Some(v.into_iter().map(Some))),
+        );
+        let columns: Vec<ArrayRef> =
+            vec![Arc::new(StringArray::from(properties)), Arc::new(values)];
+        RecordBatch::try_new(index_schema(), columns).unwrap()
+    }
+
+    #[test]
+    fn test_to_arrow() {
+        let index = Index::of([("foo", vec![1, 2, 3]), ("bar", vec![3, 4])]);
+        let expected =
+            batch(vec!["bar", "foo"], vec![vec![3, 4], vec![1, 2, 3]]);
+        assert_eq!(index.to_arrow().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_from_arrow() {
+        let index = Index::from_arrow(&batch(
+            vec!["foo", "bar"],
+            vec![vec![3, 1, 2], vec![]],
+        ))
+        .unwrap();
+        assert_eq!(index, Index::of([("foo", vec![1, 2, 3]), ("bar", vec![])]));
+    }
+
+    #[test]
+    fn test_roundtrip_ipc() {
+        let index = Index::of([("foo", vec![1, 2, 3]), ("bar", vec![3, 4])]);
+        let mut out = Vec::new();
+        write_ipc(&mut out, &index.to_arrow().unwrap()).unwrap();
+        let batches = read_ipc(out.as_slice()).unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(Index::from_arrow(&batches[0]).unwrap(), index);
+    }
+
+    #[test]
+    fn test_values_batch() {
+        let batch = values_batch(vec![1, 2, 3]).unwrap();
+        assert_eq!(batch.num_rows(), 3);
+        assert_eq!(batch.num_columns(), 1);
+    }
+
+    #[rstest]
+    #[case(vec!["foo", "foo"], "duplicate_property")]
+    #[case(vec!["foo", "bar baz"], "invalid_property")]
+    fn test_from_arrow_errors(
+        #[case] properties: Vec<&str>,
+        #[case] code: &str,
+    ) {
+        let err = Index::from_arrow(&batch(properties, vec![vec![1], vec![2]]))
+            .unwrap_err();
+        assert_eq!(err.code(), code);
+        assert!(matches!(
+            err,
+            Error::DuplicateProperty { row: 2, .. }
+                | Error::InvalidProperty { row: 2, .. }
+        ));
+    }
+}
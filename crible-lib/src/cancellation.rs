@@ -0,0 +1,62 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::index::Error;
+
+/// Handle through which long running operations such as
+/// `Index::execute_cancellable` are told to give up early, either explicitly
+/// through `cancel()` or once a deadline has passed. Clones share the same
+/// state so that the caller can keep one to cancel the operation while it
+/// runs on another thread.
+///
+/// The default value is never cancelled.
+///
+/// ```
+/// # use crible_lib::cancellation::Cancellation;
+/// # use crible_lib::index::{Error, Index};
+///
+/// let index = Index::of([("foo", vec![1, 2, 3])]);
+/// let cancellation = Cancellation::default();
+/// let expr = "foo".parse().unwrap();
+///
+/// assert!(index.execute_cancellable(&expr, &cancellation).is_ok());
+///
+/// cancellation.clone().cancel();
+/// assert_eq!(
+///     index.execute_cancellable(&expr, &cancellation).unwrap_err(),
+///     Error::Cancelled,
+/// );
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Cancellation {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl Cancellation {
+    /// Cancel once `deadline` has passed, if not cancelled before.
+    pub fn with_deadline(deadline: Instant) -> Self {
+        Self { cancelled: Arc::default(), deadline: Some(deadline) }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+            || self.deadline.map_or(false, |d| Instant::now() >= d)
+    }
+
+    /// Fail with `Error::Cancelled` when cancelled, meant to be called
+    /// between units of work.
+    #[inline]
+    pub fn check(&self) -> Result<(), Error> {
+        if self.is_cancelled() { Err(Error::Cancelled) } else { Ok(()) }
+    }
+}
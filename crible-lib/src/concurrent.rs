@@ -0,0 +1,761 @@
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Range;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use croaring::Bitmap;
+use dashmap::DashMap;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+use crate::cancellation::Cancellation;
+use crate::expression::Expression;
+use crate::index::{check_bits, universe_bitmap, Error, Index, ResultIter};
+
+/// Thread-safe index where properties are locked independently: writes to
+/// different properties only contend when they fall in the same internal
+/// shard. All operations take `&self` and mirror the ones on `Index`,
+/// including its universe and sorted prefix lookups.
+///
+/// Operations spanning multiple properties (`root()`, `set_all()`,
+/// `execute()`, ...) are not atomic, they may observe concurrent writes
+/// partially. Use `snapshot()` when a consistent view is required.
+///
+/// ```
+/// # use crible_lib::concurrent::ConcurrentIndex;
+///
+/// let index = ConcurrentIndex::default();
+///
+/// std::thread::scope(|s| {
+///     s.spawn(|| index.set_many("foo", &[1, 2, 3]));
+///     s.spawn(|| index.set_many("bar", &[2, 3, 4]));
+/// });
+///
+/// assert_eq!(
+///     index.execute(&"foo and bar".parse().unwrap()).unwrap().to_vec(),
+///     vec![2, 3],
+/// );
+/// ```
+#[derive(Default)]
+pub struct ConcurrentIndex {
+    properties: DashMap<String, Bitmap, ahash::RandomState>,
+    /// Sorted names of `properties`, see `Index::iter_prefix`. Creating or
+    /// deleting a property holds the write lock while updating both so they
+    /// cannot diverge, writes to existing properties do not take it.
+    names: RwLock<BTreeSet<String>>,
+    /// See `Index::set_universe`.
+    universe: Option<u32>,
+}
+
+impl ConcurrentIndex {
+    /// See `Index::of`.
+    pub fn of<T, S>(value: T) -> Self
+    where
+        S: AsRef<str>,
+        for<'a> &'a T: IntoIterator<Item = &'a (S, Vec<u32>)>,
+    {
+        Index::of(value).into()
+    }
+
+    /// Return the number of unique properties covered by the index.
+    pub fn len(&self) -> usize {
+        self.properties.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.properties.is_empty()
+    }
+
+    /// See `Index::universe`.
+    pub fn universe(&self) -> Option<u32> {
+        self.universe
+    }
+
+    /// See `Index::set_universe`.
+    pub fn set_universe(&mut self, universe: Option<u32>) -> Result<(), Error> {
+        if let Some(universe) = universe {
+            if let Some(bit) = self
+                .properties
+                .iter()
+                .filter_map(|entry| entry.value().maximum())
+                .max()
+                .filter(|&bit| bit >= universe)
+            {
+                return Err(Error::BitOutOfRange { bit, universe });
+            }
+        }
+        self.universe = universe;
+        Ok(())
+    }
+
+    /// See `Index::check_bits`.
+    pub fn check_bits(&self, bits: &[u32]) -> Result<(), Error> {
+        check_bits(self.universe, bits)
+    }
+
+    // The names are only updated after the properties so a panic while
+    // holding the lock cannot leave them inconsistent, poisoning is ignored.
+    fn names(&self) -> RwLockReadGuard<'_, BTreeSet<String>> {
+        self.names.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn names_mut(&self) -> RwLockWriteGuard<'_, BTreeSet<String>> {
+        self.names.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Properties starting with `prefix` in lexicographic order. The names
+    /// are copied so that no lock is held once this returns.
+    fn names_with_prefix(&self, prefix: &str) -> Vec<String> {
+        self.names()
+            .range::<str, _>(prefix..)
+            .take_while(|k| k.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    /// Apply `f` to the bitmap of every property starting with `prefix`,
+    /// holding at most one shard lock at a time.
+    fn for_each_with_prefix<F>(&self, prefix: &str, mut f: F)
+    where
+        F: FnMut(&String, &Bitmap),
+    {
+        for name in self.names_with_prefix(prefix) {
+            if let Some(entry) = self.properties.get(&name) {
+                f(entry.key(), entry.value());
+            }
+        }
+    }
+
+    /// Return a Bitmap containing all values in the index.
+    pub fn root(&self) -> Bitmap {
+        // Shards are visited one at a time so that this never holds more
+        // than one shard lock.
+        let mut root = Bitmap::create();
+        for entry in self.properties.iter() {
+            root.or_inplace(entry.value());
+        }
+        root
+    }
+
+    /// See `Index::union_prefix`.
+    pub fn union_prefix(&self, prefix: &str) -> Bitmap {
+        let mut res = Bitmap::create();
+        self.for_each_with_prefix(prefix, |_, bm| res.or_inplace(bm));
+        res
+    }
+
+    /// See `Index::intersection_prefix`.
+    pub fn intersection_prefix(&self, prefix: &str) -> Bitmap {
+        let mut res: Option<Bitmap> = None;
+        self.for_each_with_prefix(prefix, |_, bm| match &mut res {
+            Some(res) => res.and_inplace(bm),
+            None => res = Some(bm.clone()),
+        });
+        res.unwrap_or_else(Bitmap::create)
+    }
+
+    /// Copy the current content into a plain `Index`.
+    pub fn snapshot(&self) -> Index {
+        let mut index = Index::new(
+            self.properties
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.value().clone()))
+                .collect(),
+        );
+        index.set_universe_unchecked(self.universe);
+        index
+    }
+
+    pub fn into_index(self) -> Index {
+        let mut index = Index::new(self.properties.into_iter().collect());
+        index.set_universe_unchecked(self.universe);
+        index
+    }
+
+    // Operate on rows.
+
+    /// Return a copy of the property's Bitmap, the property is not locked
+    /// once this returns.
+    pub fn get_property(&self, property: &str) -> Option<Bitmap> {
+        self.properties.get(property).map(|bm| bm.value().clone())
+    }
+
+    /// Apply `f` to the bitmap of `property`, creating it empty if it does
+    /// not exist.
+    fn with_property_mut<F, T>(&self, property: &str, f: F) -> T
+    where
+        F: FnOnce(&mut Bitmap) -> T,
+    {
+        if let Some(mut bm) = self.properties.get_mut(property) {
+            return f(&mut bm);
+        }
+        let mut names = self.names_mut();
+        let mut bm = self
+            .properties
+            .entry(property.to_owned())
+            .or_insert_with(Bitmap::create);
+        names.insert(property.to_owned());
+        f(&mut bm)
+    }
+
+    pub fn set_property(&self, property: &str, bm: Bitmap) {
+        let mut names = self.names_mut();
+        self.properties.insert(property.to_owned(), bm);
+        names.insert(property.to_owned());
+    }
+
+    pub fn delete_property(&self, property: &str) -> bool {
+        let mut names = self.names_mut();
+        let deleted = self.properties.remove(property).is_some();
+        names.remove(property);
+        deleted
+    }
+
+    pub fn clear(&self) {
+        let mut names = self.names_mut();
+        self.properties.clear();
+        names.clear();
+    }
+
+    pub fn optimize(&self) {
+        for mut entry in self.properties.iter_mut() {
+            entry.value_mut().run_optimize();
+        }
+    }
+
+    /// See `Index::optimize_properties`.
+    pub fn optimize_properties<T: AsRef<str>>(&self, properties: &[T]) {
+        for property in properties {
+            if let Some(mut bm) = self.properties.get_mut(property.as_ref()) {
+                bm.run_optimize();
+                bm.shrink_to_fit();
+            }
+        }
+    }
+
+    // Operate on individual bits.
+
+    /// See `Index::set`.
+    pub fn set(&self, property: &str, bit: u32) -> bool {
+        self.with_property_mut(property, |bm| bm.add_checked(bit))
+    }
+
+    /// See `Index::set_many`.
+    pub fn set_many(&self, property: &str, bits: &[u32]) {
+        self.with_property_mut(property, |bm| bm.add_many(bits));
+    }
+
+    /// See `Index::set_all`.
+    pub fn set_all(&self, bits: &[u32]) {
+        let mask = Bitmap::of(bits);
+        for mut entry in self.properties.iter_mut() {
+            entry.value_mut().or_inplace(&mask);
+        }
+    }
+
+    /// See `Index::unset`.
+    pub fn unset(&self, property: &str, bit: u32) -> bool {
+        self.properties
+            .get_mut(property)
+            .map_or(false, |mut bm| bm.remove_checked(bit))
+    }
+
+    /// See `Index::unset_many`.
+    pub fn unset_many(&self, property: &str, bits: &[u32]) {
+        if let Some(mut bm) = self.properties.get_mut(property) {
+            bm.andnot_inplace(&Bitmap::of(bits));
+        }
+    }
+
+    /// See `Index::unset_all`.
+    pub fn unset_all(&self, bits: &[u32]) {
+        let mask = Bitmap::of(bits);
+        for mut entry in self.properties.iter_mut() {
+            entry.value_mut().andnot_inplace(&mask);
+        }
+    }
+
+    /// See `Index::unset_range`.
+    pub fn unset_range(&self, range: Range<u32>) {
+        for mut entry in self.properties.iter_mut() {
+            entry.value_mut().remove_range(range.clone());
+        }
+    }
+
+    /// See `Index::unset_range_properties`.
+    pub fn unset_range_properties<T: AsRef<str>>(
+        &self,
+        range: Range<u32>,
+        properties: &[T],
+    ) {
+        for property in properties {
+            if let Some(mut bm) = self.properties.get_mut(property.as_ref()) {
+                bm.remove_range(range.clone());
+            }
+        }
+    }
+
+    // Operations on all properties for a given bit.
+
+    /// See `Index::get_properties_with_bit`.
+    pub fn get_properties_with_bit(&self, bit: u32) -> Vec<String> {
+        let mut vec: Vec<String> = self
+            .properties
+            .iter()
+            .filter(|entry| entry.value().contains(bit))
+            .map(|entry| entry.key().clone())
+            .collect();
+        vec.sort_unstable();
+        vec
+    }
+
+    /// See `Index::get_properties_with_bits`.
+    pub fn get_properties_with_bits(
+        &self,
+        bits: &[u32],
+    ) -> HashMap<u32, Vec<String>> {
+        let requested = Bitmap::of(bits);
+        let mut result: HashMap<u32, Vec<String>> =
+            bits.iter().map(|bit| (*bit, vec![])).collect();
+        for entry in self.properties.iter() {
+            if entry.value().intersect(&requested) {
+                for bit in entry.value().and(&requested).iter() {
+                    result.entry(bit).or_default().push(entry.key().clone());
+                }
+            }
+        }
+        for properties in result.values_mut() {
+            properties.sort_unstable();
+        }
+        result
+    }
+
+    /// See `Index::set_properties_with_bit`.
+    pub fn set_properties_with_bit<T: AsRef<str>>(
+        &self,
+        bit: u32,
+        properties: &[T],
+    ) -> bool {
+        let c: Vec<&str> = properties.iter().map(|x| x.as_ref()).collect();
+        self.properties.iter_mut().fold(false, |changed, mut entry| {
+            let contained = c.contains(&entry.key().as_str());
+            let bm = entry.value_mut();
+            (if !contained {
+                bm.remove_checked(bit)
+            } else {
+                bm.add_checked(bit)
+            }) || changed
+        })
+    }
+
+    // Run queries.
+
+    /// See `Index::execute`.
+    pub fn execute(&self, expression: &Expression) -> Result<Bitmap, Error> {
+        self.execute_cancellable(expression, &Cancellation::default())
+    }
+
+    /// See `Index::execute_iter`. The result is copied out of the index
+    /// before iterating so the iterator does not hold any lock.
+    pub fn execute_iter(
+        &self,
+        expression: &Expression,
+    ) -> Result<ResultIter<'static>, Error> {
+        self.execute(expression).map(|bm| ResultIter::new(Cow::Owned(bm)))
+    }
+
+    /// See `Index::execute_cancellable`.
+    pub fn execute_cancellable(
+        &self,
+        expression: &Expression,
+        cancellation: &Cancellation,
+    ) -> Result<Bitmap, Error> {
+        cancellation.check()?;
+        let execute = |i: usize, e: &Expression| {
+            self.execute_cancellable(e, cancellation)
+                .map_err(|err| err.in_operand(i))
+        };
+        match expression {
+            Expression::Root => Ok(self.root()),
+            Expression::Property(name) => self
+                .get_property(name)
+                .ok_or_else(|| Error::property_does_not_exist(name)),
+            Expression::And(inner) => {
+                let mut res: Bitmap = execute(0, &inner[0])?;
+                for (i, e) in inner.iter().enumerate().skip(1) {
+                    res.and_inplace(&execute(i, e)?)
+                }
+                Ok(res)
+            }
+            Expression::Or(inner) => {
+                let mut res: Bitmap = execute(0, &inner[0])?;
+                for (i, e) in inner.iter().enumerate().skip(1) {
+                    res.or_inplace(&execute(i, e)?)
+                }
+                Ok(res)
+            }
+            Expression::Xor(inner) => {
+                let mut res: Bitmap = execute(0, &inner[0])?;
+                for (i, e) in inner.iter().enumerate().skip(1) {
+                    res.xor_inplace(&execute(i, e)?)
+                }
+                Ok(res)
+            }
+            Expression::Sub(inner) => {
+                let mut res: Bitmap = execute(0, &inner[0])?;
+                for (i, e) in inner.iter().enumerate().skip(1) {
+                    res.andnot_inplace(&execute(i, e)?)
+                }
+                Ok(res)
+            }
+            Expression::Not(e) => {
+                let inner = execute(0, e.as_ref())?;
+                match self.universe {
+                    Some(universe) => Ok(universe_bitmap(universe) - inner),
+                    None => Ok(self.root() - inner),
+                }
+            }
+            Expression::AnyPrefix(prefix) => Ok(self.union_prefix(prefix)),
+            Expression::AllPrefix(prefix) => {
+                Ok(self.intersection_prefix(prefix))
+            }
+        }
+    }
+
+    /// See `Index::estimate_cost`.
+    pub fn estimate_cost(&self, expression: &Expression) -> u64 {
+        match expression {
+            Expression::Root => self
+                .properties
+                .iter()
+                .map(|entry| entry.value().cardinality())
+                .sum(),
+            Expression::Property(name) => {
+                self.properties.get(name).map_or(0, |bm| bm.cardinality())
+            }
+            Expression::And(inner)
+            | Expression::Or(inner)
+            | Expression::Xor(inner)
+            | Expression::Sub(inner) => {
+                inner.iter().map(|e| self.estimate_cost(e)).sum()
+            }
+            Expression::Not(e) if self.universe.is_some() => {
+                self.estimate_cost(e)
+            }
+            Expression::Not(e) => {
+                self.estimate_cost(&Expression::Root) + self.estimate_cost(e)
+            }
+            Expression::AnyPrefix(prefix) | Expression::AllPrefix(prefix) => {
+                let mut cost = 0;
+                self.for_each_with_prefix(prefix, |_, bm| {
+                    cost += bm.cardinality()
+                });
+                cost
+            }
+        }
+    }
+
+    /// See `Index::cardinalities`.
+    pub fn cardinalities(
+        &self,
+        source: &Bitmap,
+        prefix: Option<&str>,
+    ) -> HashMap<String, u64> {
+        match prefix {
+            None => self
+                .properties
+                .iter()
+                .filter_map(|entry| {
+                    _filter_map_cardinality(source, entry.pair())
+                })
+                .collect(),
+            Some(prefix) => {
+                let mut res = HashMap::new();
+                self.for_each_with_prefix(prefix, |k, v| {
+                    res.extend(_filter_map_cardinality(source, (k, v)))
+                });
+                res
+            }
+        }
+    }
+
+    /// See `Index::cardinalities_cancellable`.
+    pub fn cardinalities_cancellable(
+        &self,
+        source: &Bitmap,
+        prefix: Option<&str>,
+        cancellation: &Cancellation,
+    ) -> Result<HashMap<String, u64>, Error> {
+        match prefix {
+            None => self
+                .properties
+                .iter()
+                .map(|entry| {
+                    cancellation
+                        .check()
+                        .map(|_| _filter_map_cardinality(source, entry.pair()))
+                })
+                .filter_map(Result::transpose)
+                .collect(),
+            Some(prefix) => self
+                .names_with_prefix(prefix)
+                .iter()
+                .filter_map(|name| self.properties.get(name))
+                .map(|entry| {
+                    cancellation
+                        .check()
+                        .map(|_| _filter_map_cardinality(source, entry.pair()))
+                })
+                .filter_map(Result::transpose)
+                .collect(),
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    pub fn par_cardinalities(
+        &self,
+        source: &Bitmap,
+        prefix: Option<&str>,
+    ) -> HashMap<String, u64> {
+        match prefix {
+            None => self
+                .properties
+                .par_iter()
+                .filter_map(|entry| {
+                    _filter_map_cardinality(source, entry.pair())
+                })
+                .collect(),
+            Some(prefix) => self
+                .names_with_prefix(prefix)
+                .par_iter()
+                .filter_map(|name| {
+                    let entry = self.properties.get(name)?;
+                    _filter_map_cardinality(source, entry.pair())
+                })
+                .collect(),
+        }
+    }
+
+    /// See `Index::par_cardinalities_cancellable`.
+    #[cfg(feature = "rayon")]
+    pub fn par_cardinalities_cancellable(
+        &self,
+        source: &Bitmap,
+        prefix: Option<&str>,
+        cancellation: &Cancellation,
+    ) -> Result<HashMap<String, u64>, Error> {
+        match prefix {
+            None => self
+                .properties
+                .par_iter()
+                .map(|entry| {
+                    cancellation
+                        .check()
+                        .map(|_| _filter_map_cardinality(source, entry.pair()))
+                })
+                .filter_map(Result::transpose)
+                .collect(),
+            Some(prefix) => self
+                .names_with_prefix(prefix)
+                .par_iter()
+                .map(|name| {
+                    cancellation.check().map(|_| {
+                        let entry = self.properties.get(name)?;
+                        _filter_map_cardinality(source, entry.pair())
+                    })
+                })
+                .filter_map(Result::transpose)
+                .collect(),
+        }
+    }
+}
+
+#[inline]
+fn _filter_map_cardinality(
+    source: &Bitmap,
+    (k, v): (&String, &Bitmap),
+) -> Option<(String, u64)> {
+    let x = source.and_cardinality(v);
+    if x > 0 { Some((k.clone(), x)) } else { None }
+}
+
+impl std::fmt::Debug for ConcurrentIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ConcurrentIndex [{} properties]", self.properties.len())
+    }
+}
+
+impl From<Index> for ConcurrentIndex {
+    fn from(index: Index) -> Self {
+        let universe = index.universe();
+        let properties: DashMap<_, _, _> =
+            index.into_inner().into_iter().collect();
+        let names = properties.iter().map(|e| e.key().clone()).collect();
+        Self { properties, names: RwLock::new(names), universe }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn index() -> Index {
+        Index::of([
+            ("foo", vec![1, 2, 3, 4, 9]),
+            ("bar", vec![1, 3, 5, 6, 7]),
+            ("baz", vec![4, 6, 8, 9]),
+            ("qux", vec![2, 10]),
+        ])
+    }
+
+    #[rstest]
+    #[case("*")]
+    #[case("foo")]
+    #[case("not foo")]
+    #[case("foo and bar and qux")]
+    #[case("foo or bar or qux")]
+    #[case("foo xor bar xor baz")]
+    #[case("foo - (bar and baz) - (foo xor bar)")]
+    #[case("foo and any(ba)")]
+    #[case("all(ba) or all(qux)")]
+    #[case("not any(ba)")]
+    #[case("not all(ba) and not qux")]
+    #[case("any(b) xor any(q)")]
+    #[case("any(nope) or all(nope)")]
+    fn test_queries_match_index(
+        #[case] input: &str,
+        #[values(None, Some(16))] universe: Option<u32>,
+    ) {
+        let mut index = index();
+        index.set_universe(universe).unwrap();
+        let concurrent = ConcurrentIndex::from(index.clone());
+        let expr = input.parse().unwrap();
+        assert_eq!(
+            concurrent.execute(&expr).unwrap().to_vec(),
+            index.execute(&expr).unwrap().to_vec()
+        );
+        assert_eq!(
+            concurrent.execute_iter(&expr).unwrap().collect::<Vec<_>>(),
+            index.execute_iter(&expr).unwrap().collect::<Vec<_>>()
+        );
+        assert_eq!(concurrent.estimate_cost(&expr), index.estimate_cost(&expr));
+    }
+
+    #[test]
+    fn test_not_stays_within_universe() {
+        let mut index = ConcurrentIndex::of([("foo", vec![1, 2])]);
+        index.set_universe(Some(5)).unwrap();
+        index.set("foo", 7);
+
+        let res = index.execute(&"not foo".parse().unwrap()).unwrap();
+        assert_eq!(res.to_vec(), vec![0, 3, 4]);
+        assert_eq!(
+            index.set_universe(Some(7)),
+            Err(Error::BitOutOfRange { bit: 7, universe: 7 })
+        );
+    }
+
+    #[test]
+    fn test_prefixes_follow_changes() {
+        let mut index = index();
+        let concurrent = ConcurrentIndex::from(index.clone());
+        index.set("bam", 11);
+        concurrent.set("bam", 11);
+        index.set_many("bax", &[12]);
+        concurrent.set_many("bax", &[12]);
+        index.set_property("bay", Bitmap::of(&[13]));
+        concurrent.set_property("bay", Bitmap::of(&[13]));
+        assert!(index.delete_property("bar"));
+        assert!(concurrent.delete_property("bar"));
+        assert!(!concurrent.delete_property("bar"));
+
+        for prefix in ["ba", "bam", "q", ""] {
+            assert_eq!(
+                concurrent.union_prefix(prefix).to_vec(),
+                index.union_prefix(prefix).to_vec()
+            );
+            assert_eq!(
+                concurrent.intersection_prefix(prefix).to_vec(),
+                index.intersection_prefix(prefix).to_vec()
+            );
+            assert_eq!(
+                concurrent.cardinalities(&index.root(), Some(prefix)),
+                index.cardinalities(&index.root(), Some(prefix))
+            );
+        }
+        assert!(concurrent.snapshot() == index);
+
+        concurrent.clear();
+        assert!(concurrent.union_prefix("").is_empty());
+    }
+
+    #[test]
+    fn test_cancelled() {
+        let index =
+            ConcurrentIndex::of([("foo", vec![1, 2]), ("bar", vec![3])]);
+        let cancellation = Cancellation::default();
+        cancellation.cancel();
+
+        assert_eq!(
+            index
+                .execute_cancellable(
+                    &"foo or bar".parse().unwrap(),
+                    &cancellation
+                )
+                .unwrap_err(),
+            Error::Cancelled
+        );
+        assert_eq!(
+            index.cardinalities_cancellable(&index.root(), None, &cancellation),
+            Err(Error::Cancelled)
+        );
+        assert_eq!(
+            index.cardinalities_cancellable(
+                &index.root(),
+                Some("ba"),
+                &Cancellation::default()
+            ),
+            Ok(index.cardinalities(&index.root(), Some("ba")))
+        );
+    }
+
+    #[rstest]
+    #[case(None)]
+    #[case(Some("ba"))]
+    fn test_cardinalities_match_index(#[case] prefix: Option<&str>) {
+        let index = index();
+        let concurrent = ConcurrentIndex::from(index.clone());
+        let source = index.execute(&"foo or bar".parse().unwrap()).unwrap();
+        assert_eq!(
+            concurrent.cardinalities(&source, prefix),
+            index.cardinalities(&source, prefix)
+        );
+        #[cfg(feature = "rayon")]
+        assert_eq!(
+            concurrent.par_cardinalities(&source, prefix),
+            index.cardinalities(&source, prefix)
+        );
+    }
+
+    #[test]
+    fn test_concurrent_writes() {
+        let index = ConcurrentIndex::default();
+
+        std::thread::scope(|s| {
+            for n in 0..8 {
+                let index = &index;
+                s.spawn(move || {
+                    for bit in 0..100 {
+                        index.set(&format!("p{}", n % 4), n * 100 + bit);
+                    }
+                });
+            }
+        });
+
+        assert_eq!(index.len(), 4);
+        assert_eq!(index.root().cardinality(), 800);
+        assert_eq!(index.get_property("p0").unwrap().cardinality(), 200);
+    }
+}
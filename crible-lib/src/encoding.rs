@@ -1,31 +1,62 @@
-use std::io::{BufRead, BufReader, Read, Write};
-use std::path::Path;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+use croaring::Bitmap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_derive::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::expression::validate_property_name;
 use crate::index::Index;
 
+/// Errors about a specific record carry its 1-based position: the line for
+/// the `Json` encoding and the position in the sequence otherwise.
 #[derive(Error, Debug)]
 pub enum Error {
-    #[error("invalid json line")]
+    #[error("invalid json")]
     Json(#[from] serde_json::Error),
+    #[error("invalid json on line {line}")]
+    InvalidRecord {
+        line: usize,
+        #[source]
+        source: serde_json::Error,
+    },
     #[error("invalid bincode data")]
     Bincode(#[from] bincode::Error),
     #[error("io error")]
     IO(#[from] std::io::Error),
-    #[error("duplicate property {0:?}")]
-    DuplicateProperty(String),
-    #[error("invalid property {0:?}")]
-    InvalidProperty(String),
-    #[error("invalid bitmap for property {0:?}")]
-    InvalidBitmap(String),
+    #[error("io error on {path:?}")]
+    File {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("duplicate property {property:?} in record {record}")]
+    DuplicateProperty { property: String, record: usize },
+    #[error("invalid property {property:?} in record {record}")]
+    InvalidProperty { property: String, record: usize },
+    #[error("invalid bitmap for property {property:?} in record {record}")]
+    InvalidBitmap { property: String, record: usize },
     #[error("unknown encoder {0}")]
     UnknownEncoder(String),
 }
 
+impl Error {
+    /// Stable identifier of the kind of error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Json(_) | Self::InvalidRecord { .. } => "invalid_json",
+            Self::Bincode(_) => "invalid_bincode",
+            Self::IO(_) | Self::File { .. } => "io",
+            Self::DuplicateProperty { .. } => "duplicate_property",
+            Self::InvalidProperty { .. } => "invalid_property",
+            Self::InvalidBitmap { .. } => "invalid_bitmap",
+            Self::UnknownEncoder(_) => "unknown_encoder",
+        }
+    }
+}
+
 type Result<T> = std::result::Result<T, Error>;
 
 /// Encoding formats for the index.
@@ -73,7 +104,11 @@ impl Encoder {
         self,
         path: P,
     ) -> Result<Index> {
-        let f = std::fs::OpenOptions::new().read(true).open(path.as_ref())?;
+        let path = path.as_ref();
+        let f = std::fs::OpenOptions::new()
+            .read(true)
+            .open(path)
+            .map_err(|source| Error::File { path: path.into(), source })?;
         self.decode(f)
     }
 
@@ -82,10 +117,12 @@ impl Encoder {
         path: P,
         index: &Index,
     ) -> Result<()> {
+        let path = path.as_ref();
         let f = std::fs::OpenOptions::new()
             .write(true)
             .create(true)
-            .open(path.as_ref())?;
+            .open(path)
+            .map_err(|source| Error::File { path: path.into(), source })?;
         self.encode(f, index)
     }
 }
@@ -108,17 +145,34 @@ struct JsonLineRecordIn {
     values: Vec<u32>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Serialize)]
 struct JsonLineRecordOut<'a> {
-    property: &'a String,
-    values: Vec<u32>,
+    property: &'a str,
+    values: Values<'a>,
 }
 
-fn decode_ndjson_line(index: &mut Index, bytes: &[u8]) -> Result<()> {
-    let record: JsonLineRecordIn = serde_json::from_slice(bytes)?;
+/// Serialize the values of a bitmap without collecting them first.
+struct Values<'a>(&'a Bitmap);
+
+impl Serialize for Values<'_> {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter())
+    }
+}
 
+fn insert_record(
+    index: &mut Index,
+    record: JsonLineRecordIn,
+    position: usize,
+) -> Result<()> {
     if !validate_property_name(record.property.as_ref()) {
-        return Err(Error::InvalidProperty(record.property.clone()));
+        return Err(Error::InvalidProperty {
+            property: record.property,
+            record: position,
+        });
     }
 
     match index.get_property(&record.property) {
@@ -126,52 +180,117 @@ fn decode_ndjson_line(index: &mut Index, bytes: &[u8]) -> Result<()> {
             index.set_many(record.property.as_ref(), &record.values);
             Ok(())
         }
-        Some(_) => Err(Error::DuplicateProperty(record.property)),
+        Some(_) => Err(Error::DuplicateProperty {
+            property: record.property,
+            record: position,
+        }),
     }
 }
 
+fn decode_ndjson_line(
+    index: &mut Index,
+    bytes: &[u8],
+    line: usize,
+) -> Result<()> {
+    let record = serde_json::from_slice(bytes)
+        .map_err(|source| Error::InvalidRecord { line, source })?;
+    insert_record(index, record, line)
+}
+
 fn decode_ndjson<R: Read>(r: R) -> Result<Index> {
     let mut index = Index::default();
-    for x in BufReader::new(r).lines() {
+    for (i, x) in BufReader::new(r).lines().enumerate() {
         let ln = x?;
         if ln.is_empty() {
             continue;
         }
-        decode_ndjson_line(&mut index, ln.as_ref())?;
+        decode_ndjson_line(&mut index, ln.as_ref(), i + 1)?;
     }
     Ok(index)
 }
 
-fn encode_ndjson<W: Write>(mut w: W, index: &Index) -> Result<()> {
-    let mut sorted_pairs = index.inner().iter().collect::<Vec<_>>();
-    sorted_pairs.sort_by_key(|(k, _)| *k);
-    for (property, bm) in sorted_pairs {
-        let data = serde_json::to_vec(&JsonLineRecordOut {
-            property,
-            values: bm.to_vec(),
-        })?;
-        w.write_all(&data)?;
+/// Properties sorted by name, so that the output is deterministic.
+fn sorted_pairs(index: &Index) -> Vec<(&str, &Bitmap)> {
+    let mut sorted_pairs = index.iter().collect::<Vec<_>>();
+    sorted_pairs.sort_unstable_by_key(|(k, _)| *k);
+    sorted_pairs
+}
+
+fn encode_ndjson<W: Write>(w: W, index: &Index) -> Result<()> {
+    let mut w = BufWriter::new(w);
+    for (property, bm) in sorted_pairs(index) {
+        serde_json::to_writer(
+            &mut w,
+            &JsonLineRecordOut { property, values: Values(bm) },
+        )?;
         writeln!(&mut w)?;
     }
+    w.flush()?;
     Ok(())
 }
 
+/// Indices are serialized as a sequence of the same records as the `Json`
+/// encoding, sorted by property, which does not depend on the underlying
+/// Bitmap representation.
+impl Serialize for Index {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_seq(sorted_pairs(self).into_iter().map(
+            |(property, bm)| JsonLineRecordOut { property, values: Values(bm) },
+        ))
+    }
+}
+
+/// Same validation as decoding the `Json` encoding: property names must be
+/// valid and unique.
+impl<'de> Deserialize<'de> for Index {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let mut index = Index::default();
+        let records = Vec::<JsonLineRecordIn>::deserialize(deserializer)?;
+        for (i, record) in records.into_iter().enumerate() {
+            insert_record(&mut index, record, i + 1)
+                .map_err(<D::Error as serde::de::Error>::custom)?;
+        }
+        Ok(index)
+    }
+}
+
 type BincodeIntermediate = Vec<(String, Vec<u8>)>;
 
 fn decode_bincode_intermediate(data: BincodeIntermediate) -> Result<Index> {
+    // Deserializing dominates load time for large indices.
+    #[cfg(feature = "rayon")]
+    let bitmaps = {
+        use rayon::prelude::*;
+        data.into_par_iter()
+            .map(|(property, bytes)| {
+                (property, croaring::Bitmap::try_deserialize(&bytes))
+            })
+            .collect::<Vec<_>>()
+    };
+    #[cfg(not(feature = "rayon"))]
+    let bitmaps = data.into_iter().map(|(property, bytes)| {
+        (property, croaring::Bitmap::try_deserialize(&bytes))
+    });
+
     let mut index = Index::default();
-    for (property, bytes) in data {
+    for (i, (property, bm)) in bitmaps.into_iter().enumerate() {
+        let record = i + 1;
         match index.get_property(&property) {
-            None => match croaring::Bitmap::try_deserialize(&bytes) {
+            None => match bm {
                 None => {
-                    return Err(Error::InvalidBitmap(property));
+                    return Err(Error::InvalidBitmap { property, record });
                 }
                 Some(bm) => {
                     index.set_property(property.as_ref(), bm);
                 }
             },
             Some(_) => {
-                return Err(Error::DuplicateProperty(property));
+                return Err(Error::DuplicateProperty { property, record });
             }
         }
     }
@@ -183,18 +302,17 @@ fn decode_bincode<R: Read>(r: R) -> Result<Index> {
     decode_bincode_intermediate(data)
 }
 
-fn encode_bincode_intermediate(index: &Index) -> Result<Vec<u8>> {
-    let mut sorted_pairs: BincodeIntermediate = index
-        .inner()
-        .iter()
-        .map(|(k, bm)| (k.to_owned(), bm.serialize()))
-        .collect::<Vec<_>>();
-    sorted_pairs.sort_by_key(|(k, _)| k.clone());
-    Ok(bincode::serialize(&sorted_pairs)?)
-}
-
-fn encode_bincode<W: Write>(mut w: W, index: &Index) -> Result<()> {
-    w.write_all(&encode_bincode_intermediate(index)?)?;
+/// Write the same output as serializing a `BincodeIntermediate` one property
+/// at a time, so that only a single serialized bitmap is held in memory.
+fn encode_bincode<W: Write>(w: W, index: &Index) -> Result<()> {
+    let mut w = BufWriter::new(w);
+    let sorted_pairs = sorted_pairs(index);
+    // Sequences are prefixed with their length as a u64.
+    bincode::serialize_into(&mut w, &(sorted_pairs.len() as u64))?;
+    for (property, bm) in sorted_pairs {
+        bincode::serialize_into(&mut w, &(property, bm.serialize()))?;
+    }
+    w.flush()?;
     Ok(())
 }
 
@@ -202,7 +320,7 @@ fn encode_bincode<W: Write>(mut w: W, index: &Index) -> Result<()> {
 mod tests {
     use std::str;
 
-    use super::Encoder;
+    use super::{BincodeIntermediate, Encoder, Error};
     use crate::Index;
 
     macro_rules! test_index {
@@ -227,6 +345,25 @@ mod tests {
         assert!(index.is_empty());
     }
 
+    #[test]
+    fn test_ndjson_decode_errors() {
+        let err = Encoder::Json
+            .decode(&b"{\"property\":\"foo\",\"values\":[1]}\n\n{"[..])
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidRecord { line: 3, .. }));
+        assert_eq!(err.code(), "invalid_json");
+
+        let err = Encoder::Json
+            .decode(TEST_JSON_ENCODED.repeat(2).as_bytes())
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::DuplicateProperty { ref property, record: 4 }
+                if property == "bar"
+        ));
+        assert_eq!(err.to_string(), "duplicate property \"bar\" in record 4");
+    }
+
     #[test]
     fn test_ndjson_encode_empty() {
         let index = Index::default();
@@ -256,6 +393,42 @@ mod tests {
         assert_eq!(str::from_utf8(&out).unwrap(), TEST_JSON_ENCODED);
     }
 
+    #[test]
+    fn test_serde() {
+        let index = test_index!();
+        let json = serde_json::to_string(&index).unwrap();
+
+        assert_eq!(
+            json,
+            format!("[{}]", TEST_JSON_ENCODED.trim().replace('\n', ","))
+        );
+        assert_eq!(serde_json::from_str::<Index>(&json).unwrap(), index);
+        assert_eq!(
+            bincode::deserialize::<Index>(&bincode::serialize(&index).unwrap())
+                .unwrap(),
+            index
+        );
+    }
+
+    #[test]
+    fn test_serde_invalid() {
+        assert!(
+            serde_json::from_str::<Index>(
+                r#"[{"property":"foo","values":[1]},
+                    {"property":"foo","values":[2]}]"#
+            )
+            .unwrap_err()
+            .to_string()
+            .starts_with("duplicate property \"foo\"")
+        );
+        assert!(
+            serde_json::from_str::<Index>(
+                r#"[{"property":"foo and bar","values":[1]}]"#
+            )
+            .is_err()
+        );
+    }
+
     #[test]
     fn test_bincode_encode_decode_loop_empty() {
         let index = Index::default();
@@ -267,6 +440,21 @@ mod tests {
         assert_eq!(index, decoded);
     }
 
+    #[test]
+    fn test_bincode_encode_matches_intermediate() {
+        let index = test_index!();
+
+        let mut out: Vec<u8> = Vec::new();
+        Encoder::Bin.encode(&mut out, &index).unwrap();
+
+        let mut intermediate: BincodeIntermediate = index
+            .iter()
+            .map(|(k, bm)| (k.to_owned(), bm.serialize()))
+            .collect();
+        intermediate.sort();
+        assert_eq!(out, bincode::serialize(&intermediate).unwrap());
+    }
+
     #[test]
     fn test_bincode_encode_decode_loop() {
         let index = test_index!();
@@ -5,6 +5,7 @@
 // TODO: Better error handling?
 // TODO: Fuzzy precedence?
 
+use std::collections::BTreeSet;
 use std::ops::{BitAnd, BitOr, BitXor, Not, Sub};
 use std::str::FromStr;
 
@@ -33,6 +34,7 @@ const MAX_LENGTH: usize = 2048;
 //
 // <inverted> = "not" \s+ <expression>
 // <wrapped> = "(" \s* <expression> \s* ")"
+// <prefix> = { "any" | "all" } "(" \s* <property> \s* ")"
 //
 // <subexpression> = <and-operation>
 //                 | <or-operation>
@@ -40,7 +42,7 @@ const MAX_LENGTH: usize = 2048;
 //                 | <sub-operation>
 //                 | <term>
 //
-// <term> = <inverted> | <wrapped> | <property>
+// <term> = <inverted> | <wrapped> | <prefix> | <property>
 //
 // <root> = "*"
 //
@@ -75,7 +77,9 @@ fn parse_property(s: &str) -> IResult<&str, Expression> {
     )(s)
 }
 
-pub(crate) fn validate_property_name(s: &str) -> bool {
+/// Whether `s` can be used as a property name, i.e. could be referenced in a
+/// query.
+pub fn validate_property_name(s: &str) -> bool {
     parse_property(s).map_or(false, |(rest, _)| rest.is_empty())
 }
 
@@ -152,8 +156,34 @@ fn parse_wrapped(s: &str) -> ParseResult {
     )(s)
 }
 
+/// `any(prefix)` and `all(prefix)` match the union and intersection of every
+/// property starting with `prefix`. Prefixes use the same characters as
+/// properties, e.g. `any(color:)`.
+fn parse_prefix(s: &str) -> ParseResult {
+    let (rest, all) = terminated(
+        alt((
+            map(tag_no_case("any"), |_| false),
+            map(tag_no_case("all"), |_| true),
+        )),
+        tag("("),
+    )(s)?;
+    let (rest, prefix) = cut(terminated(
+        delimited(multispace0, recognize(parse_property), multispace0),
+        tag(")"),
+    ))(rest)?;
+    let prefix = prefix.to_owned();
+    Ok((
+        rest,
+        if all {
+            Expression::AllPrefix(prefix)
+        } else {
+            Expression::AnyPrefix(prefix)
+        },
+    ))
+}
+
 fn parse_term(s: &str) -> ParseResult {
-    alt((parse_inverted, parse_wrapped, parse_property))(s)
+    alt((parse_inverted, parse_wrapped, parse_prefix, parse_property))(s)
 }
 
 fn parse_subexpression(s: &str) -> ParseResult {
@@ -195,6 +225,17 @@ pub enum Error {
     InputStringToolLong,
 }
 
+impl Error {
+    /// Stable identifier of the kind of error, suitable for API responses.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Invalid(_) => "invalid_query",
+            Self::InvalidEndOfInput(_) => "invalid_end_of_input",
+            Self::InputStringToolLong => "query_too_long",
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 /// A boolean expression.
 pub enum Expression {
@@ -205,6 +246,12 @@ pub enum Expression {
     Xor(Vec<Expression>),
     Sub(Vec<Expression>),
     Not(Box<Expression>),
+    /// Union of every property starting with the prefix, empty if there
+    /// are none.
+    AnyPrefix(String),
+    /// Intersection of every property starting with the prefix, empty if
+    /// there are none.
+    AllPrefix(String),
 }
 
 #[inline]
@@ -251,12 +298,107 @@ impl Expression {
         Expression::Property(name.to_owned())
     }
 
+    /// All properties referenced by the expression.
+    ///
+    /// ```
+    /// # use crible_lib::expression::Expression;
+    ///
+    /// let expr = Expression::parse("(foo or bar) - (baz and foo)").unwrap();
+    /// assert_eq!(
+    ///     expr.properties().into_iter().collect::<Vec<_>>(),
+    ///     vec!["bar", "baz", "foo"],
+    /// );
+    /// ```
+    pub fn properties(&self) -> BTreeSet<&str> {
+        let mut properties = BTreeSet::new();
+        self.collect_properties(&mut properties);
+        properties
+    }
+
+    fn collect_properties<'a>(&'a self, properties: &mut BTreeSet<&'a str>) {
+        match self {
+            Self::Root | Self::AnyPrefix(_) | Self::AllPrefix(_) => {}
+            Self::Property(name) => {
+                properties.insert(name.as_str());
+            }
+            Self::Not(inner) => inner.collect_properties(properties),
+            Self::And(inner)
+            | Self::Or(inner)
+            | Self::Xor(inner)
+            | Self::Sub(inner) => {
+                for e in inner {
+                    e.collect_properties(properties);
+                }
+            }
+        }
+    }
+
+    /// All prefixes referenced by `any(...)` and `all(...)` terms, the
+    /// properties they match are not part of `properties`.
+    ///
+    /// ```
+    /// # use crible_lib::expression::Expression;
+    ///
+    /// let expr = Expression::parse("foo - (any(tag:) or all(s:))").unwrap();
+    /// assert_eq!(
+    ///     expr.prefixes().into_iter().collect::<Vec<_>>(),
+    ///     vec!["s:", "tag:"],
+    /// );
+    /// ```
+    pub fn prefixes(&self) -> BTreeSet<&str> {
+        let mut prefixes = BTreeSet::new();
+        self.collect_prefixes(&mut prefixes);
+        prefixes
+    }
+
+    fn collect_prefixes<'a>(&'a self, prefixes: &mut BTreeSet<&'a str>) {
+        match self {
+            Self::Root | Self::Property(_) => {}
+            Self::AnyPrefix(prefix) | Self::AllPrefix(prefix) => {
+                prefixes.insert(prefix.as_str());
+            }
+            Self::Not(inner) => inner.collect_prefixes(prefixes),
+            Self::And(inner)
+            | Self::Or(inner)
+            | Self::Xor(inner)
+            | Self::Sub(inner) => {
+                for e in inner {
+                    e.collect_prefixes(prefixes);
+                }
+            }
+        }
+    }
+
+    /// Whether evaluating the expression depends on the root bitmap, i.e. on
+    /// every property and not only those returned by `properties`.
+    ///
+    /// ```
+    /// # use crible_lib::expression::Expression;
+    ///
+    /// assert!(!Expression::parse("foo - bar").unwrap().uses_root());
+    /// assert!(Expression::parse("foo or not bar").unwrap().uses_root());
+    /// ```
+    pub fn uses_root(&self) -> bool {
+        match self {
+            Self::Root | Self::Not(_) => true,
+            Self::Property(_) | Self::AnyPrefix(_) | Self::AllPrefix(_) => {
+                false
+            }
+            Self::And(inner)
+            | Self::Or(inner)
+            | Self::Xor(inner)
+            | Self::Sub(inner) => inner.iter().any(|e| e.uses_root()),
+        }
+    }
+
     // This should provide a _canonical_ representation of a query ignoring
     // whitespace and parenthesis. Useful for caching / deduplication / etc.
     pub fn serialize(&self) -> String {
         match self {
             Self::Root => "*".to_owned(),
             Self::Property(name) => name.clone(),
+            Self::AnyPrefix(prefix) => format!("any({})", prefix),
+            Self::AllPrefix(prefix) => format!("all({})", prefix),
             Self::Not(inner) => format!("not ({})", inner.as_ref().serialize()),
             Self::And(inner) => join(" and ", inner),
             Self::Or(inner) => join(" or ", inner),
@@ -433,6 +575,14 @@ mod tests {
             ]
         )
     )]
+    #[case(
+        "foo and any(tag:)",
+        E::And(vec![p("foo"), E::AnyPrefix("tag:".to_owned())])
+    )]
+    #[case(
+        "ALL( tag:a ) or anything",
+        E::Or(vec![E::AllPrefix("tag:a".to_owned()), p("anything")])
+    )]
     fn parse_valid_expression(
         #[case] value: &str,
         #[case] expected: Expression,
@@ -452,6 +602,9 @@ mod tests {
     #[case("(and)")]
     #[case("foo and bar or baz")]
     #[case("foo and bar and baz and")]
+    #[case("any()")]
+    #[case("any(tag:")]
+    #[case("all(:tag)")]
     fn parse_invalid_expression(#[case] value: &str) {
         assert!(Expression::parse(value).is_err());
     }
@@ -475,8 +628,33 @@ mod tests {
     #[case("foo and (bar or baz)")]
     #[case("foo - (bar or baz) - (foo and bar and baz)")]
     #[case("foo - (bar or baz) - (foo and (bar and baz and bam))")]
+    #[case("foo and (any(tag:) - all(size:))")]
     fn parse_serialize_round_trip(#[case] input: &str) {
         let parsed = Expression::parse(input).unwrap();
         assert_eq!(parsed, Expression::parse(&parsed.serialize()).unwrap());
     }
+
+    #[rstest]
+    #[case("*", &[])]
+    #[case("foo", &["foo"])]
+    #[case("not (foo and bar)", &["bar", "foo"])]
+    #[case("foo - (bar or baz) - (foo and baz)", &["bar", "baz", "foo"])]
+    #[case("foo xor (bar and not foo)", &["bar", "foo"])]
+    fn properties(#[case] input: &str, #[case] expected: &[&str]) {
+        let parsed = Expression::parse(input).unwrap();
+        assert_eq!(
+            parsed.properties().into_iter().collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case("*", true)]
+    #[case("foo", false)]
+    #[case("(foo and bar) - baz", false)]
+    #[case("foo and not bar", true)]
+    #[case("foo xor (bar or *)", true)]
+    fn uses_root(#[case] input: &str, #[case] expected: bool) {
+        assert_eq!(Expression::parse(input).unwrap().uses_root(), expected);
+    }
 }
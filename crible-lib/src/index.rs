@@ -1,20 +1,84 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap};
 use std::convert::{From, Into};
+use std::ops::Range;
+use std::sync::Arc;
 
 use croaring::Bitmap;
 use serde_derive::Serialize;
 use thiserror::Error;
 
+use crate::cancellation::Cancellation;
 use crate::expression::Expression;
 
+/// Operands of `and`, `or` and `xor` expressions are executed in parallel
+/// when there are at least this many, below that the overhead of scheduling
+/// them on the rayon pool outweighs the gain.
+#[cfg(feature = "rayon")]
+static PARALLEL_EXECUTION_MIN_OPERANDS: usize = 16;
+
 #[derive(Error, Debug, PartialEq, Eq)]
 pub enum Error {
-    #[error("property {0:?} does not exist")]
-    PropertyDoesNotExist(String),
+    /// `operand` locates the reference to the property in the expression as
+    /// the positions of the operands leading to it, e.g. `[1, 0]` for `baz`
+    /// in `foo and (baz or bar)`.
+    #[error("property {property:?} does not exist")]
+    PropertyDoesNotExist { property: String, operand: Vec<usize> },
+    #[error("execution was cancelled")]
+    Cancelled,
+    #[error("bit {bit} is out of range, bits must be lower than {universe}")]
+    BitOutOfRange { bit: u32, universe: u32 },
+}
+
+impl Error {
+    pub(crate) fn property_does_not_exist(property: &str) -> Self {
+        Self::PropertyDoesNotExist {
+            property: property.to_owned(),
+            operand: vec![],
+        }
+    }
+
+    /// Record that the error happened while executing the operand at
+    /// `position` of an expression.
+    pub(crate) fn in_operand(mut self, position: usize) -> Self {
+        if let Self::PropertyDoesNotExist { operand, .. } = &mut self {
+            operand.insert(0, position);
+        }
+        self
+    }
+
+    /// Stable identifier of the kind of error, suitable for API responses.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::PropertyDoesNotExist { .. } => "property_does_not_exist",
+            Self::Cancelled => "cancelled",
+            Self::BitOutOfRange { .. } => "bit_out_of_range",
+        }
+    }
 }
 
+/// Bitmaps by property name. Property names are hashed with aHash which is
+/// significantly faster than the default SipHash for short keys while still
+/// resisting HashDoS, see the `index` benchmarks.
+pub type PropertyMap = HashMap<String, Bitmap, ahash::RandomState>;
+
+/// Bitmaps by property name as stored in an index. Bitmaps are shared
+/// between clones of an index and only copied when one of them modifies
+/// them, names are reference counted so cloning an index only copies the
+/// table itself rather than any name or bitmap.
+pub type SharedPropertyMap = HashMap<Arc<str>, Arc<Bitmap>, ahash::RandomState>;
+
 #[derive(Clone, Default, PartialEq)]
-pub struct Index(HashMap<String, Bitmap>);
+pub struct Index {
+    properties: SharedPropertyMap,
+    /// Sorted names of `properties`, kept in sync with it so that looking up
+    /// properties by prefix is a range scan rather than a full scan. Shared
+    /// between clones until a property is added or deleted, the names
+    /// themselves are shared with `properties`.
+    names: Arc<BTreeSet<Arc<str>>>,
+    /// Exclusive upper bound of the bits, see `Index::set_universe`.
+    universe: Option<u32>,
+}
 
 /// An Index is simply a very large bit-matrix where each row is an individual
 /// property and each column is unique element id represented by a bit on the
@@ -24,8 +88,13 @@ pub struct Index(HashMap<String, Bitmap>);
 /// All semantics must exist outside of the Index (meaning of the
 /// properties, of their combinations, etc.).
 impl Index {
-    pub fn new(data: HashMap<String, Bitmap>) -> Self {
-        Self(data)
+    pub fn new(data: PropertyMap) -> Self {
+        let properties: SharedPropertyMap = data
+            .into_iter()
+            .map(|(k, v)| (Arc::from(k), Arc::new(v)))
+            .collect();
+        let names = Arc::new(properties.keys().cloned().collect());
+        Self { properties, names, universe: None }
     }
 
     pub fn of<T, S>(value: T) -> Self
@@ -41,6 +110,49 @@ impl Index {
         )
     }
 
+    /// Create an empty index with room for at least `capacity` properties
+    /// before reallocating.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            properties: SharedPropertyMap::with_capacity_and_hasher(
+                capacity,
+                Default::default(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    /// Build an index from owned `(property, bits)` pairs. Unlike `of` this
+    /// consumes any iterator, bits of repeated properties are merged.
+    ///
+    /// ```
+    /// # use crible_lib::index::Index;
+    ///
+    /// let index = Index::from_pairs(["foo", "bar", "foo"].into_iter().zip([
+    ///     vec![1, 2],
+    ///     vec![3],
+    ///     vec![4],
+    /// ]));
+    ///
+    /// assert_eq!(index.len(), 2);
+    /// assert_eq!(index.get_property("foo").unwrap().to_vec(), vec![1, 2, 4]);
+    /// ```
+    pub fn from_pairs<I, S, V>(pairs: I) -> Self
+    where
+        I: IntoIterator<Item = (S, V)>,
+        S: Into<String>,
+        V: AsRef<[u32]>,
+    {
+        let mut index = Self::default();
+        index.extend(pairs);
+        index
+    }
+
+    /// Reserve room for at least `additional` more properties.
+    pub fn reserve(&mut self, additional: usize) {
+        self.properties.reserve(additional);
+    }
+
     /// Return the number of unique properties covered by the index.
     ///
     /// ```
@@ -57,7 +169,7 @@ impl Index {
     /// assert_eq!(index.len(), 3);
     /// ```
     pub fn len(&self) -> usize {
-        self.0.len()
+        self.properties.len()
     }
 
     /// Return the number of unique properties covered by the index.
@@ -76,7 +188,7 @@ impl Index {
     /// assert!(!index.is_empty());
     /// ```
     pub fn is_empty(&self) -> bool {
-        self.0.is_empty()
+        self.properties.is_empty()
     }
 
     /// Return a Bitmap containing all values in the index..
@@ -99,38 +211,223 @@ impl Index {
         // Just iterating is actually slightly faster at low property counts but
         // given the gain is relatively small it's better overall to use
         // fast_or.
-        Bitmap::fast_or(&self.0.values().collect::<Vec<&Bitmap>>())
+        Bitmap::fast_or(
+            &self.properties.values().map(Arc::as_ref).collect::<Vec<_>>(),
+        )
+    }
+
+    /// Properties and their bitmap, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Bitmap)> {
+        self.into_iter()
     }
 
     /// Access the inner hashmap.
-    pub fn inner(&self) -> &HashMap<String, Bitmap> {
-        &self.0
+    pub fn inner(&self) -> &SharedPropertyMap {
+        &self.properties
+    }
+
+    /// Consume the index and return the inner hashmap. Bitmaps still shared
+    /// with clones of the index are copied.
+    pub fn into_inner(self) -> PropertyMap {
+        self.properties
+            .into_iter()
+            .map(|(k, v)| {
+                let v = Arc::try_unwrap(v).unwrap_or_else(|v| (*v).clone());
+                (k.to_string(), v)
+            })
+            .collect()
+    }
+
+    /// Exclusive upper bound of the bits, if any.
+    pub fn universe(&self) -> Option<u32> {
+        self.universe
+    }
+
+    /// Restrict the bits of the index to `0..universe`, failing with
+    /// `Error::BitOutOfRange` if a bit outside of it is already set. Setting
+    /// bits afterwards is not prevented, callers are expected to reject bits
+    /// outside the universe with `check_bits` first. `not` is then computed
+    /// against the whole universe rather than the bits set in at least one
+    /// property.
+    ///
+    /// ```
+    /// # use crible_lib::index::Index;
+    ///
+    /// let mut index = Index::of([("foo", vec![1, 2]), ("bar", vec![3])]);
+    /// let query = "not foo".parse().unwrap();
+    /// assert_eq!(index.execute(&query).unwrap().to_vec(), vec![3]);
+    ///
+    /// index.set_universe(Some(5)).unwrap();
+    /// assert_eq!(index.execute(&query).unwrap().to_vec(), vec![0, 3, 4]);
+    /// assert!(index.check_bits(&[0, 4]).is_ok());
+    /// assert!(index.check_bits(&[5]).is_err());
+    /// assert!(index.set_universe(Some(3)).is_err());
+    /// ```
+    pub fn set_universe(&mut self, universe: Option<u32>) -> Result<(), Error> {
+        if let Some(universe) = universe {
+            if let Some(bit) = self
+                .properties
+                .values()
+                .filter_map(|bm| bm.maximum())
+                .max()
+                .filter(|&bit| bit >= universe)
+            {
+                return Err(Error::BitOutOfRange { bit, universe });
+            }
+        }
+        self.set_universe_unchecked(universe);
+        Ok(())
+    }
+
+    /// See `set_universe`, for callers already guaranteeing that no bit is
+    /// outside the universe.
+    pub(crate) fn set_universe_unchecked(&mut self, universe: Option<u32>) {
+        self.universe = universe;
+    }
+
+    /// Fail with `Error::BitOutOfRange` for the first of `bits` outside the
+    /// universe, if any.
+    pub fn check_bits(&self, bits: &[u32]) -> Result<(), Error> {
+        check_bits(self.universe, bits)
     }
 
     // Operate on rows.
 
     pub fn get_property(&self, property: &str) -> Option<&Bitmap> {
-        self.0.get(property)
+        self.properties.get(property).map(Arc::as_ref)
+    }
+
+    /// Number of bits set for `property` which are lower than or equal to
+    /// `bit`, i.e. the 1-based position of `bit` if it is set. `None` if the
+    /// property does not exist.
+    ///
+    /// ```
+    /// # use crible_lib::index::Index;
+    ///
+    /// let index = Index::of([("foo", vec![2, 4, 8])]);
+    ///
+    /// assert_eq!(index.rank("foo", 4), Some(2));
+    /// assert_eq!(index.rank("foo", 5), Some(2));
+    /// assert_eq!(index.rank("foo", 1), Some(0));
+    /// assert_eq!(index.rank("bar", 4), None);
+    /// ```
+    pub fn rank(&self, property: &str, bit: u32) -> Option<u64> {
+        self.properties.get(property).map(|bm| bm.rank(bit))
+    }
+
+    /// Bit at 0-based position `n` in `property`, `None` if the property
+    /// does not exist or has `n` or fewer bits set.
+    ///
+    /// ```
+    /// # use crible_lib::index::Index;
+    ///
+    /// let index = Index::of([("foo", vec![2, 4, 8])]);
+    ///
+    /// assert_eq!(index.select("foo", 0), Some(2));
+    /// assert_eq!(index.select("foo", 2), Some(8));
+    /// assert_eq!(index.select("foo", 3), None);
+    /// assert_eq!(index.select("bar", 0), None);
+    /// ```
+    pub fn select(&self, property: &str, n: u32) -> Option<u32> {
+        self.properties.get(property).and_then(|bm| bm.select(n))
+    }
+
+    /// Bitmap of `property`, created empty if it does not exist. The bitmap
+    /// is copied first if it is shared with a clone of the index so callers
+    /// should only use this when they are about to modify it.
+    fn property_mut(&mut self, property: &str) -> &mut Bitmap {
+        // Reuse the existing name rather than allocating a new one.
+        let name = match self.properties.get_key_value(property) {
+            Some((name, _)) => name.clone(),
+            None => {
+                let name: Arc<str> = Arc::from(property);
+                Arc::make_mut(&mut self.names).insert(name.clone());
+                name
+            }
+        };
+        Arc::make_mut(self.properties.entry(name).or_default())
+    }
+
+    /// Bitmap of `property` if it exists, copied first if it is shared, see
+    /// `property_mut`.
+    fn existing_property_mut(&mut self, property: &str) -> Option<&mut Bitmap> {
+        self.properties.get_mut(property).map(Arc::make_mut)
     }
 
     pub fn set_property(&mut self, property: &str, bm: Bitmap) {
-        self.0.insert(property.to_owned(), bm);
+        match self.properties.get_mut(property) {
+            Some(v) => *v = Arc::new(bm),
+            None => {
+                let name: Arc<str> = Arc::from(property);
+                Arc::make_mut(&mut self.names).insert(name.clone());
+                self.properties.insert(name, Arc::new(bm));
+            }
+        }
     }
 
     pub fn delete_property(&mut self, property: &str) -> bool {
-        self.0.remove(property).is_some()
+        let deleted = self.properties.remove(property).is_some();
+        if deleted {
+            Arc::make_mut(&mut self.names).remove(property);
+        }
+        deleted
     }
 
     pub fn clear(&mut self) {
-        self.0.clear();
+        self.properties.clear();
+        self.names = Default::default();
     }
 
+    /// Optimize the storage of every property, see `optimize_properties`.
     pub fn optimize(&mut self) {
-        for v in self.0.values_mut() {
-            v.run_optimize();
+        for bm in self.properties.values_mut() {
+            optimize_bitmap(bm);
+        }
+    }
+
+    /// Optimize the storage of a subset of properties, converting containers
+    /// to run-length encoding where it is smaller and releasing unused
+    /// memory. Bitmaps shared with a clone of the index are only replaced by
+    /// their optimized copy when it is smaller. Unknown properties are
+    /// ignored. This does not change the content of the index.
+    pub fn optimize_properties<T: AsRef<str>>(&mut self, properties: &[T]) {
+        for property in properties {
+            if let Some(bm) = self.properties.get_mut(property.as_ref()) {
+                optimize_bitmap(bm);
+            }
         }
     }
 
+    /// Release memory allocated but unused by the bitmaps and the property
+    /// map, which accumulates as properties are modified or deleted. Returns
+    /// the approximate number of bytes reclaimed. Bitmaps shared with a clone
+    /// of the index are left as they are as shrinking them would copy them
+    /// first. This does not change the content of the index.
+    ///
+    /// ```
+    /// # use crible_lib::index::Index;
+    ///
+    /// let mut index = Index::with_capacity(1000);
+    /// index.set("foo", 1);
+    ///
+    /// assert!(index.shrink_to_fit() > 0);
+    /// assert_eq!(index.shrink_to_fit(), 0);
+    /// assert_eq!(index.get_property("foo").unwrap().to_vec(), vec![1]);
+    /// ```
+    pub fn shrink_to_fit(&mut self) -> usize {
+        let bitmaps: usize = self
+            .properties
+            .values_mut()
+            .filter_map(Arc::get_mut)
+            .map(|bm| bm.shrink_to_fit())
+            .sum();
+        let capacity = self.properties.capacity();
+        self.properties.shrink_to_fit();
+        bitmaps
+            + (capacity - self.properties.capacity())
+                * std::mem::size_of::<(Arc<str>, Arc<Bitmap>)>()
+    }
+
     // Operate on individual bits.
 
     /// Set a bit for a single property. Returns whether the bit was not already
@@ -147,10 +444,10 @@ impl Index {
     /// assert_eq!(index.get_property("foo").unwrap().to_vec(), vec![1]);
     /// ```
     pub fn set(&mut self, property: &str, bit: u32) -> bool {
-        self.0
-            .entry(property.to_owned())
-            .or_insert_with(Bitmap::create)
-            .add_checked(bit)
+        if self.get_property(property).map_or(false, |bm| bm.contains(bit)) {
+            return false;
+        }
+        self.property_mut(property).add_checked(bit)
     }
 
     /// Set multiple bits for a single property.
@@ -165,10 +462,7 @@ impl Index {
     /// assert_eq!(index.get_property("foo").unwrap().to_vec(), vec![1, 2, 3, 4]);
     /// ```
     pub fn set_many(&mut self, property: &str, bits: &[u32]) {
-        self.0
-            .entry(property.to_owned())
-            .or_insert_with(Bitmap::create)
-            .add_many(bits);
+        self.property_mut(property).add_many(bits);
     }
 
     /// Set multiple bits from a all properties.
@@ -193,8 +487,10 @@ impl Index {
     /// ```
     pub fn set_all(&mut self, bits: &[u32]) {
         let mask = Bitmap::of(bits);
-        for bm in self.0.values_mut() {
-            bm.or_inplace(&mask);
+        for bm in self.properties.values_mut() {
+            if !mask.is_subset(bm) {
+                Arc::make_mut(bm).or_inplace(&mask);
+            }
         }
     }
 
@@ -212,7 +508,11 @@ impl Index {
     /// assert_eq!(index.get_property("foo").unwrap().to_vec(), vec![2, 3, 4]);
     /// ```
     pub fn unset(&mut self, property: &str, bit: u32) -> bool {
-        self.0.get_mut(property).map_or(false, |bm| bm.remove_checked(bit))
+        if !self.get_property(property).map_or(false, |bm| bm.contains(bit)) {
+            return false;
+        }
+        self.existing_property_mut(property)
+            .map_or(false, |bm| bm.remove_checked(bit))
     }
 
     /// Unset multiple bits from a single property.
@@ -228,8 +528,11 @@ impl Index {
     /// assert_eq!(index.get_property("foo").unwrap().to_vec(), vec![2, 3]);
     /// ```
     pub fn unset_many(&mut self, property: &str, bits: &[u32]) {
-        if let Some(bm) = self.0.get_mut(property) {
-            bm.andnot_inplace(&Bitmap::of(bits));
+        let mask = Bitmap::of(bits);
+        if let Some(bm) = self.properties.get_mut(property) {
+            if bm.intersect(&mask) {
+                Arc::make_mut(bm).andnot_inplace(&mask);
+            }
         }
     }
 
@@ -252,8 +555,59 @@ impl Index {
     /// ```
     pub fn unset_all(&mut self, bits: &[u32]) {
         let mask = Bitmap::of(bits);
-        for bm in self.0.values_mut() {
-            bm.andnot_inplace(&mask);
+        for bm in self.properties.values_mut() {
+            if bm.intersect(&mask) {
+                Arc::make_mut(bm).andnot_inplace(&mask);
+            }
+        }
+    }
+
+    /// Unset every bit in `range` from every property.
+    ///
+    /// ```
+    /// # use crible_lib::index::Index;
+    ///
+    /// let mut index =
+    ///     Index::of([("foo", vec![1, 2, 3, 4]), ("bar", vec![1, 5, 6, 7])]);
+    ///
+    /// index.unset_range(2..6);
+    ///
+    /// assert_eq!(index.get_property("foo").unwrap().to_vec(), vec![1]);
+    /// assert_eq!(index.get_property("bar").unwrap().to_vec(), vec![1, 6, 7]);
+    /// ```
+    pub fn unset_range(&mut self, range: Range<u32>) {
+        for bm in self.properties.values_mut() {
+            if intersects_range(bm, &range) {
+                Arc::make_mut(bm).remove_range(range.clone());
+            }
+        }
+    }
+
+    /// Unset every bit in `range` from a subset of properties. Unknown
+    /// properties are ignored.
+    ///
+    /// ```
+    /// # use crible_lib::index::Index;
+    ///
+    /// let mut index =
+    ///     Index::of([("foo", vec![1, 2, 3, 4]), ("bar", vec![1, 5, 6, 7])]);
+    ///
+    /// index.unset_range_properties(0..6, &["bar", "baz"]);
+    ///
+    /// assert_eq!(index.get_property("foo").unwrap().to_vec(), vec![1, 2, 3, 4]);
+    /// assert_eq!(index.get_property("bar").unwrap().to_vec(), vec![6, 7]);
+    /// ```
+    pub fn unset_range_properties<T: AsRef<str>>(
+        &mut self,
+        range: Range<u32>,
+        properties: &[T],
+    ) {
+        for property in properties {
+            if let Some(bm) = self.properties.get_mut(property.as_ref()) {
+                if intersects_range(bm, &range) {
+                    Arc::make_mut(bm).remove_range(range.clone());
+                }
+            }
         }
     }
 
@@ -277,14 +631,58 @@ impl Index {
     pub fn get_properties_with_bit(&self, bit: u32) -> Vec<String> {
         let mut vec: Vec<String> = self
             .into_iter()
-            .filter_map(
-                |(k, v)| if v.contains(bit) { Some(k.clone()) } else { None },
-            )
+            .filter(|(_, v)| v.contains(bit))
+            .map(|(k, _)| k.to_owned())
             .collect();
         vec.sort_unstable();
         vec
     }
 
+    /// List the properties where each of `bits` is set, going through the
+    /// index once. Bits which are not set in any property map to an empty
+    /// list.
+    ///
+    /// WARN: This can be slow as it iterates over the entire index.
+    ///
+    /// ```
+    /// # use std::collections::HashMap;
+    /// # use crible_lib::index::Index;
+    ///
+    /// let index = Index::of([
+    ///     ("foo", vec![1, 2, 3]),
+    ///     ("bar", vec![1, 3, 4]),
+    ///     ("baz", vec![2, 3, 4]),
+    /// ]);
+    ///
+    /// assert_eq!(
+    ///     index.get_properties_with_bits(&[2, 4, 5]),
+    ///     HashMap::from([
+    ///         (2, vec!["baz".to_owned(), "foo".to_owned()]),
+    ///         (4, vec!["bar".to_owned(), "baz".to_owned()]),
+    ///         (5, vec![]),
+    ///     ])
+    /// );
+    /// ```
+    pub fn get_properties_with_bits(
+        &self,
+        bits: &[u32],
+    ) -> HashMap<u32, Vec<String>> {
+        let requested = Bitmap::of(bits);
+        let mut result: HashMap<u32, Vec<String>> =
+            bits.iter().map(|bit| (*bit, vec![])).collect();
+        for (k, v) in self {
+            if v.intersect(&requested) {
+                for bit in v.and(&requested).iter() {
+                    result.entry(bit).or_default().push(k.to_owned());
+                }
+            }
+        }
+        for properties in result.values_mut() {
+            properties.sort_unstable();
+        }
+        result
+    }
+
     /// Set `bit` for all given properties and remove it from all others.
     ///
     /// WARN: This can be slow as it iterates over the entire index.
@@ -310,17 +708,204 @@ impl Index {
         properties: &[T],
     ) -> bool {
         let c: Vec<&str> = properties.iter().map(|x| x.as_ref()).collect();
-        self.0.iter_mut().fold(false, |changed, (k, v)| {
-            (if !c.contains(&k.as_ref()) {
-                v.remove_checked(bit)
+        self.properties.iter_mut().fold(false, |changed, (k, v)| {
+            let expected = c.contains(&k.as_ref());
+            if v.contains(bit) == expected {
+                return changed;
+            }
+            if expected {
+                Arc::make_mut(v).add(bit);
             } else {
-                v.add_checked(bit)
-            }) || changed
+                Arc::make_mut(v).remove(bit);
+            }
+            true
         })
     }
 
+    /// Same as `set_properties_with_bit` for each bit of `assignments`,
+    /// going through the index once. Returns whether any property changed.
+    ///
+    /// WARN: This can be slow as it iterates over the entire index.
+    ///
+    /// ```
+    /// # use std::collections::HashMap;
+    /// # use crible_lib::index::Index;
+    ///
+    /// let mut index = Index::of([
+    ///     ("foo", vec![1, 2, 3]),
+    ///     ("bar", vec![1, 3, 4]),
+    ///     ("baz", vec![2, 3, 4]),
+    /// ]);
+    ///
+    /// assert!(index.set_properties_with_bits(&HashMap::from([
+    ///     (3, vec!["foo"]),
+    ///     (8, vec!["bar", "baz"]),
+    /// ])));
+    /// assert_eq!(index.get_properties_with_bit(3), vec!["foo"]);
+    /// assert_eq!(index.get_properties_with_bit(8), vec!["bar", "baz"]);
+    /// ```
+    pub fn set_properties_with_bits<T: AsRef<str>>(
+        &mut self,
+        assignments: &HashMap<u32, Vec<T>>,
+    ) -> bool {
+        let bits = Bitmap::of(&assignments.keys().copied().collect::<Vec<_>>());
+        let mut masks: HashMap<&str, Bitmap> = HashMap::new();
+        for (bit, properties) in assignments {
+            for property in properties {
+                masks.entry(property.as_ref()).or_default().add(*bit);
+            }
+        }
+
+        let mut changed = false;
+        for (k, v) in self.properties.iter_mut() {
+            let mask = masks.get(&**k);
+            let kept = mask.map_or(0, |m| v.and_cardinality(m));
+            let added = mask.map_or(0, |m| m.cardinality()) - kept;
+            let removed = v.and_cardinality(&bits) - kept;
+            if added + removed > 0 {
+                let v = Arc::make_mut(v);
+                v.andnot_inplace(&bits);
+                if let Some(mask) = mask {
+                    v.or_inplace(mask);
+                }
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Properties from `properties` which are not in the index, in order.
+    ///
+    /// ```
+    /// # use crible_lib::index::Index;
+    /// let index = Index::of([("foo", vec![1]), ("bar", vec![2])]);
+    ///
+    /// assert_eq!(index.missing_properties(&["bar", "qux"]), vec!["qux"]);
+    /// ```
+    pub fn missing_properties<'a, T: AsRef<str>>(
+        &self,
+        properties: &'a [T],
+    ) -> Vec<&'a str> {
+        properties
+            .iter()
+            .map(|x| x.as_ref())
+            .filter(|x| !self.properties.contains_key(*x))
+            .collect()
+    }
+
     // Run queries.
 
+    /// Properties starting with `prefix` in lexicographic order, found
+    /// without going through the other properties.
+    ///
+    /// ```
+    /// # use crible_lib::index::Index;
+    ///
+    /// let index = Index::of([
+    ///     ("tag:b", vec![1]),
+    ///     ("size:m", vec![2]),
+    ///     ("tag:a", vec![3]),
+    /// ]);
+    ///
+    /// assert_eq!(
+    ///     index.iter_prefix("tag:").map(|(k, _)| k).collect::<Vec<_>>(),
+    ///     vec!["tag:a", "tag:b"]
+    /// );
+    /// assert_eq!(index.iter_prefix("").count(), 3);
+    /// ```
+    pub fn iter_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = (&'a str, &'a Bitmap)> + 'a {
+        self.names
+            .range::<str, _>(prefix..)
+            .take_while(move |k| k.starts_with(prefix))
+            .filter_map(|k| self.properties.get(k).map(|v| (&**k, &**v)))
+    }
+
+    /// Properties starting with `prefix` if any, in no particular order.
+    fn iter_matching<'a>(
+        &'a self,
+        prefix: Option<&'a str>,
+    ) -> Box<dyn Iterator<Item = (&'a str, &'a Bitmap)> + 'a> {
+        match prefix {
+            Some(p) => Box::new(self.iter_prefix(p)),
+            None => Box::new(self.into_iter()),
+        }
+    }
+
+    fn properties_with_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = &'a Bitmap> + 'a {
+        self.iter_prefix(prefix).map(|(_, bm)| bm)
+    }
+
+    /// Union of every property starting with `prefix`, empty if there are
+    /// none. This is the result of the `any(<prefix>)` expression.
+    pub fn union_prefix(&self, prefix: &str) -> Bitmap {
+        Bitmap::fast_or(
+            &self.properties_with_prefix(prefix).collect::<Vec<_>>(),
+        )
+    }
+
+    /// Intersection of every property starting with `prefix`, empty if there
+    /// are none. This is the result of the `all(<prefix>)` expression.
+    pub fn intersection_prefix(&self, prefix: &str) -> Bitmap {
+        intersection(self.properties_with_prefix(prefix))
+    }
+
+    /// Intersection of `source` with the union of every property starting
+    /// with `prefix`, without copying any of them. This is equivalent to
+    /// `source and (p1 or p2 or ...)` listing every property.
+    ///
+    /// ```
+    /// # use crible_lib::index::Index;
+    /// # use croaring::Bitmap;
+    ///
+    /// let index = Index::of([
+    ///     ("tag:a", vec![1, 2]),
+    ///     ("tag:b", vec![3, 4]),
+    ///     ("size:m", vec![2, 5]),
+    /// ]);
+    ///
+    /// let source = Bitmap::of(&[2, 4, 5]);
+    /// assert_eq!(index.and_prefix(&source, "tag:").to_vec(), vec![2, 4]);
+    /// assert!(index.and_prefix(&source, "color:").is_empty());
+    /// ```
+    pub fn and_prefix(&self, source: &Bitmap, prefix: &str) -> Bitmap {
+        let mut res = self.union_prefix(prefix);
+        res.and_inplace(source);
+        res
+    }
+
+    /// Intersection of `source` with every property starting with `prefix`,
+    /// empty if there are none.
+    ///
+    /// ```
+    /// # use crible_lib::index::Index;
+    /// # use croaring::Bitmap;
+    ///
+    /// let index = Index::of([("tag:a", vec![1, 2, 3]), ("tag:b", vec![2, 3])]);
+    ///
+    /// let source = Bitmap::of(&[1, 3, 5]);
+    /// assert_eq!(index.and_all_prefix(&source, "tag:").to_vec(), vec![3]);
+    /// ```
+    pub fn and_all_prefix(&self, source: &Bitmap, prefix: &str) -> Bitmap {
+        let mut properties = self.properties_with_prefix(prefix).peekable();
+        if properties.peek().is_none() {
+            return Bitmap::create();
+        }
+        let mut res = source.clone();
+        for bm in properties {
+            if res.is_empty() {
+                break;
+            }
+            res.and_inplace(bm);
+        }
+        res
+    }
+
     /// Execute a query against the index.
     ///
     /// ```
@@ -366,80 +951,248 @@ impl Index {
     /// );
     /// ```
     pub fn execute(&self, expression: &Expression) -> Result<Bitmap, Error> {
-        match expression {
-            Expression::Root => Ok(self.root()),
-            Expression::Property(name) => self
-                .get_property(name)
-                .ok_or_else(|| Error::PropertyDoesNotExist(name.clone()))
-                .cloned(),
-            Expression::And(inner) => {
-                let mut res: Bitmap = self.execute(&inner[0])?;
-                for e in &inner[1..] {
-                    // TODO: Would it be cheaper to break here if one is empty?
-                    res.and_inplace(&self.execute(e)?)
-                }
-                Ok(res)
-            }
-            Expression::Or(inner) => {
-                if inner.len() == 2 {
-                    Ok(self.execute(&inner[0])?.or(&self.execute(&inner[1])?))
-                } else {
-                    let mut inner_executed = Vec::with_capacity(inner.len());
-                    for x in inner {
-                        inner_executed.push(self.execute(x)?);
-                    }
-                    Ok(Bitmap::fast_or(
-                        &inner_executed.iter().collect::<Vec<_>>(),
-                    ))
-                }
-            }
-            Expression::Xor(inner) => {
-                if inner.len() == 2 {
-                    Ok(self.execute(&inner[0])?.xor(&self.execute(&inner[1])?))
-                } else {
-                    let mut inner_executed = Vec::with_capacity(inner.len());
-                    for x in inner {
-                        inner_executed.push(self.execute(x)?);
-                    }
-                    Ok(Bitmap::fast_xor(
-                        &inner_executed.iter().collect::<Vec<_>>(),
-                    ))
-                }
-            }
-            Expression::Sub(inner) => {
-                let mut res: Bitmap = self.execute(&inner[0])?;
-                for e in &inner[1..] {
-                    res.andnot_inplace(&self.execute(e)?)
-                }
-                Ok(res)
-            }
-            // TODO: Is there a version using `flip()` which is faster? As root
-            // can be slow on a large index.
-            Expression::Not(e) => Ok(self.root() - self.execute(e.as_ref())?),
-        }
+        self.execute_borrowed(expression).map(Cow::into_owned)
     }
 
-    /// Compute the cardinality of a given Bitmap with all other Bitmaps in the
-    /// index. This is mostly useful to filter which properties still have
-    /// result after executing a predicate.
+    /// Check that all properties referenced by the expression exist, listing
+    /// the ones which do not in order otherwise. Unlike `execute` this
+    /// reports every missing property instead of only the first one.
     ///
     /// ```
     /// # use crible_lib::index::Index;
-    /// # use std::str::FromStr;
-    /// # use std::collections::HashMap;
-    ///
-    /// let mut index = Index::of([
-    ///     ("foo", vec![1, 2, 3, 6]),
-    ///     ("bar", vec![1, 3, 4, 7]),
-    ///     ("baz", vec![3, 4, 5, 7]),
-    /// ]);
-    ///
-    /// let res = index.execute(&"foo and bar".parse().unwrap()).unwrap();
     ///
-    /// let unprefixed = index.cardinalities(&res, None);
-    /// assert_eq!(*unprefixed.get("foo").unwrap(), 2);
-    /// assert_eq!(*unprefixed.get("bar").unwrap(), 2);
-    /// assert_eq!(*unprefixed.get("baz").unwrap(), 1);
+    /// let index = Index::of([("foo", vec![1, 2]), ("bar", vec![2, 3])]);
+    ///
+    /// assert!(index.validate(&"foo and bar".parse().unwrap()).is_ok());
+    /// assert_eq!(
+    ///     index.validate(&"(foo or qux) - baz".parse().unwrap()),
+    ///     Err(vec!["baz".to_owned(), "qux".to_owned()]),
+    /// );
+    /// ```
+    pub fn validate(&self, expression: &Expression) -> Result<(), Vec<String>> {
+        let missing: Vec<String> = expression
+            .properties()
+            .into_iter()
+            .filter(|p| !self.properties.contains_key(*p))
+            .map(|p| p.to_owned())
+            .collect();
+        if missing.is_empty() { Ok(()) } else { Err(missing) }
+    }
+
+    /// Same as `execute` but returns bitmaps stored in the index as is
+    /// instead of copying them, which makes single property queries nearly
+    /// free when the result is only inspected.
+    ///
+    /// ```
+    /// # use crible_lib::index::Index;
+    /// # use std::borrow::Cow;
+    ///
+    /// let index =
+    ///     Index::of([("foo", vec![1, 2, 3, 6]), ("bar", vec![1, 3, 4, 7])]);
+    ///
+    /// let res = index.execute_borrowed(&"foo".parse().unwrap()).unwrap();
+    /// assert!(matches!(res, Cow::Borrowed(_)));
+    /// assert_eq!(res.cardinality(), 4);
+    ///
+    /// let res = index.execute_borrowed(&"foo and bar".parse().unwrap());
+    /// assert_eq!(res.unwrap().to_vec(), vec![1, 3]);
+    /// ```
+    pub fn execute_borrowed(
+        &self,
+        expression: &Expression,
+    ) -> Result<Cow<'_, Bitmap>, Error> {
+        self.execute_cancellable(expression, &Cancellation::default())
+    }
+
+    /// Same as `execute_borrowed` but returns a lazy iterator over the bits
+    /// of the result, so that large results can be consumed without copying
+    /// them into a vector. Skipping ahead is cheap, see `ResultIter`.
+    ///
+    /// ```
+    /// # use crible_lib::index::Index;
+    ///
+    /// let index =
+    ///     Index::of([("foo", vec![1, 2, 3, 6]), ("bar", vec![1, 3, 4, 7])]);
+    ///
+    /// let bits = index.execute_iter(&"foo or bar".parse().unwrap()).unwrap();
+    /// assert_eq!(bits.len(), 6);
+    /// assert_eq!(bits.skip(2).take(3).collect::<Vec<_>>(), vec![3, 4, 6]);
+    ///
+    /// let mut bits = index.execute_iter(&"foo".parse().unwrap()).unwrap();
+    /// bits.seek(3);
+    /// assert_eq!(bits.collect::<Vec<_>>(), vec![3, 6]);
+    /// ```
+    pub fn execute_iter(
+        &self,
+        expression: &Expression,
+    ) -> Result<ResultIter<'_>, Error> {
+        self.execute_borrowed(expression).map(ResultIter::new)
+    }
+
+    /// Same as `execute_borrowed` but gives up with `Error::Cancelled` once
+    /// `cancellation` is cancelled. This is checked between operands so a
+    /// single bitmap operation always runs to completion.
+    pub fn execute_cancellable(
+        &self,
+        expression: &Expression,
+        cancellation: &Cancellation,
+    ) -> Result<Cow<'_, Bitmap>, Error> {
+        cancellation.check()?;
+        match expression {
+            Expression::Root => Ok(Cow::Owned(self.root())),
+            Expression::Property(name) => self
+                .get_property(name)
+                .map(Cow::Borrowed)
+                .ok_or_else(|| Error::property_does_not_exist(name)),
+            // TODO: Would it be cheaper to break early if one is empty?
+            Expression::And(inner) => fold_operands(
+                self.execute_operands(inner, cancellation)?,
+                Bitmap::and,
+                Bitmap::and_inplace,
+                cancellation,
+            ),
+            Expression::Or(inner) => {
+                let inner_executed =
+                    self.execute_operands(inner, cancellation)?;
+                Ok(Cow::Owned(match &inner_executed[..] {
+                    [left, right] => left.or(right),
+                    _ => Bitmap::fast_or(
+                        &inner_executed
+                            .iter()
+                            .map(|x| &**x)
+                            .collect::<Vec<_>>(),
+                    ),
+                }))
+            }
+            Expression::Xor(inner) => {
+                let inner_executed =
+                    self.execute_operands(inner, cancellation)?;
+                Ok(Cow::Owned(match &inner_executed[..] {
+                    [left, right] => left.xor(right),
+                    _ => Bitmap::fast_xor(
+                        &inner_executed
+                            .iter()
+                            .map(|x| &**x)
+                            .collect::<Vec<_>>(),
+                    ),
+                }))
+            }
+            Expression::Sub(inner) => fold_operands(
+                self.execute_operands(inner, cancellation)?,
+                Bitmap::andnot,
+                Bitmap::andnot_inplace,
+                cancellation,
+            ),
+            // Without a universe, root can be slow on a large index.
+            Expression::Not(e) => {
+                let inner = self
+                    .execute_cancellable(e.as_ref(), cancellation)
+                    .map_err(|e| e.in_operand(0))?;
+                let mut res = match self.universe {
+                    Some(universe) => universe_bitmap(universe),
+                    None => self.root(),
+                };
+                res.andnot_inplace(&inner);
+                Ok(Cow::Owned(res))
+            }
+            Expression::AnyPrefix(prefix) => {
+                Ok(Cow::Owned(self.union_prefix(prefix)))
+            }
+            Expression::AllPrefix(prefix) => {
+                Ok(Cow::Owned(self.intersection_prefix(prefix)))
+            }
+        }
+    }
+
+    /// Execute all operands of an expression, in parallel on the current
+    /// rayon pool for wide expressions.
+    fn execute_operands(
+        &self,
+        inner: &[Expression],
+        cancellation: &Cancellation,
+    ) -> Result<Vec<Cow<'_, Bitmap>>, Error> {
+        let execute = |(i, e): (usize, &Expression)| {
+            self.execute_cancellable(e, cancellation)
+                .map_err(|err| err.in_operand(i))
+        };
+
+        #[cfg(feature = "rayon")]
+        if inner.len() >= PARALLEL_EXECUTION_MIN_OPERANDS {
+            use rayon::prelude::*;
+
+            return inner.par_iter().enumerate().map(execute).collect();
+        }
+        inner.iter().enumerate().map(execute).collect()
+    }
+
+    /// Estimate the cost of executing a query as the number of values it
+    /// goes through: the cardinality of every property it references, `*`
+    /// and `not` referencing all of them. Unknown properties cost nothing as
+    /// the query fails before doing any work.
+    ///
+    /// ```
+    /// # use crible_lib::index::Index;
+    ///
+    /// let index = Index::of([
+    ///     ("foo", vec![1, 2, 3, 6]),
+    ///     ("bar", vec![1, 3, 4, 7]),
+    ///     ("baz", vec![3, 4, 5]),
+    /// ]);
+    ///
+    /// assert_eq!(index.estimate_cost(&"foo".parse().unwrap()), 4);
+    /// assert_eq!(index.estimate_cost(&"foo and bar".parse().unwrap()), 8);
+    /// assert_eq!(index.estimate_cost(&"*".parse().unwrap()), 11);
+    /// assert_eq!(index.estimate_cost(&"not baz".parse().unwrap()), 14);
+    /// ```
+    pub fn estimate_cost(&self, expression: &Expression) -> u64 {
+        match expression {
+            Expression::Root => {
+                self.properties.values().map(|bm| bm.cardinality()).sum()
+            }
+            Expression::Property(name) => {
+                self.get_property(name).map_or(0, |bm| bm.cardinality())
+            }
+            Expression::And(inner)
+            | Expression::Or(inner)
+            | Expression::Xor(inner)
+            | Expression::Sub(inner) => {
+                inner.iter().map(|e| self.estimate_cost(e)).sum()
+            }
+            Expression::Not(e) if self.universe.is_some() => {
+                self.estimate_cost(e)
+            }
+            Expression::Not(e) => {
+                self.estimate_cost(&Expression::Root) + self.estimate_cost(e)
+            }
+            Expression::AnyPrefix(prefix) | Expression::AllPrefix(prefix) => {
+                self.properties_with_prefix(prefix)
+                    .map(|bm| bm.cardinality())
+                    .sum()
+            }
+        }
+    }
+
+    /// Compute the cardinality of a given Bitmap with all other Bitmaps in the
+    /// index. This is mostly useful to filter which properties still have
+    /// result after executing a predicate.
+    ///
+    /// ```
+    /// # use crible_lib::index::Index;
+    /// # use std::str::FromStr;
+    /// # use std::collections::HashMap;
+    ///
+    /// let mut index = Index::of([
+    ///     ("foo", vec![1, 2, 3, 6]),
+    ///     ("bar", vec![1, 3, 4, 7]),
+    ///     ("baz", vec![3, 4, 5, 7]),
+    /// ]);
+    ///
+    /// let res = index.execute(&"foo and bar".parse().unwrap()).unwrap();
+    ///
+    /// let unprefixed = index.cardinalities(&res, None);
+    /// assert_eq!(*unprefixed.get("foo").unwrap(), 2);
+    /// assert_eq!(*unprefixed.get("bar").unwrap(), 2);
+    /// assert_eq!(*unprefixed.get("baz").unwrap(), 1);
     ///
     /// let prefixed = index.cardinalities(&res, Some("ba"));
     /// assert!(prefixed.get("foo").is_none());
@@ -451,80 +1204,463 @@ impl Index {
         source: &Bitmap,
         prefix: Option<&str>,
     ) -> HashMap<String, u64> {
+        self.iter_matching(prefix)
+            .filter_map(|x| _filter_map_cardinality(source, x))
+            .collect()
+    }
+
+    /// Same as `cardinalities` but gives up with `Error::Cancelled` once
+    /// `cancellation` is cancelled, checked between properties.
+    pub fn cardinalities_cancellable(
+        &self,
+        source: &Bitmap,
+        prefix: Option<&str>,
+        cancellation: &Cancellation,
+    ) -> Result<HashMap<String, u64>, Error> {
+        self.iter_matching(prefix)
+            .map(|x| {
+                cancellation.check().map(|_| _filter_map_cardinality(source, x))
+            })
+            .filter_map(Result::transpose)
+            .collect()
+    }
+
+    #[cfg(feature = "rayon")]
+    pub fn par_cardinalities(
+        &self,
+        source: &Bitmap,
+        prefix: Option<&str>,
+    ) -> HashMap<String, u64> {
+        use rayon::prelude::*;
+
+        // TODO: Chunking may be more efficient.
         match prefix {
             None => self
-                .0
-                .iter()
+                .properties
+                .par_iter()
+                .map(|(k, v)| (&**k, &**v))
                 .filter_map(|x| _filter_map_cardinality(source, x))
                 .collect(),
             Some(p) => self
-                .0
-                .iter()
-                .filter_map(|(k, v)| {
-                    if k.starts_with(p) {
-                        _filter_map_cardinality(source, (k, v))
-                    } else {
-                        None
-                    }
-                })
+                .iter_prefix(p)
+                .par_bridge()
+                .filter_map(|x| _filter_map_cardinality(source, x))
                 .collect(),
         }
     }
 
-    pub fn par_cardinalities(
+    /// See `cardinalities_cancellable`.
+    #[cfg(feature = "rayon")]
+    pub fn par_cardinalities_cancellable(
         &self,
         source: &Bitmap,
         prefix: Option<&str>,
-    ) -> HashMap<String, u64> {
+        cancellation: &Cancellation,
+    ) -> Result<HashMap<String, u64>, Error> {
         use rayon::prelude::*;
 
-        // TODO: Chunking may be more efficient.
+        let f = |x| {
+            cancellation.check().map(|_| _filter_map_cardinality(source, x))
+        };
         match prefix {
             None => self
-                .0
+                .properties
                 .par_iter()
-                .filter_map(|x| _filter_map_cardinality(source, x))
+                .map(|(k, v)| (&**k, &**v))
+                .map(f)
+                .filter_map(Result::transpose)
                 .collect(),
             Some(p) => self
-                .0
-                .par_iter()
-                .filter_map(|(k, v)| {
-                    if k.starts_with(p) {
-                        _filter_map_cardinality(source, (k, v))
-                    } else {
-                        None
-                    }
+                .iter_prefix(p)
+                .par_bridge()
+                .map(f)
+                .filter_map(Result::transpose)
+                .collect(),
+        }
+    }
+
+    /// Distribution of the cardinalities of the properties, optionally
+    /// limited to those starting with `prefix`. `buckets` are the inclusive
+    /// upper bounds of the histogram buckets, in increasing order.
+    ///
+    /// ```
+    /// # use crible_lib::index::Index;
+    ///
+    /// let index = Index::of([
+    ///     ("foo", vec![1]),
+    ///     ("bar", vec![1, 2, 3]),
+    ///     ("baz", (0..50).collect()),
+    /// ]);
+    ///
+    /// let histogram = index.cardinality_histogram(&[1, 10], None);
+    /// assert_eq!(histogram.properties, 3);
+    /// assert_eq!(histogram.p50, 3);
+    /// assert_eq!(histogram.maximum, 50);
+    /// assert_eq!(
+    ///     histogram.buckets.iter().map(|b| b.count).collect::<Vec<_>>(),
+    ///     vec![1, 1, 1]
+    /// );
+    /// ```
+    pub fn cardinality_histogram(
+        &self,
+        buckets: &[u64],
+        prefix: Option<&str>,
+    ) -> CardinalityHistogram {
+        let mut cardinalities = self
+            .iter_matching(prefix)
+            .map(|(_, v)| v.cardinality())
+            .collect::<Vec<_>>();
+        cardinalities.sort_unstable();
+
+        let percentile = |p: usize| {
+            let rank = (p * cardinalities.len() + 99) / 100;
+            cardinalities.get(rank.max(1) - 1).copied().unwrap_or(0)
+        };
+
+        let mut counts = vec![0; buckets.len() + 1];
+        for cardinality in &cardinalities {
+            counts[buckets.partition_point(|le| le < cardinality)] += 1;
+        }
+
+        CardinalityHistogram {
+            properties: cardinalities.len() as u64,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            maximum: cardinalities.last().copied().unwrap_or(0),
+            buckets: counts
+                .into_iter()
+                .enumerate()
+                .map(|(i, count)| HistogramBucket {
+                    le: buckets.get(i).copied(),
+                    count,
                 })
                 .collect(),
         }
     }
 }
 
+/// Intersection of `bitmaps`, empty if there are none.
+pub(crate) fn intersection<'a, I>(bitmaps: I) -> Bitmap
+where
+    I: IntoIterator<Item = &'a Bitmap>,
+{
+    let mut bitmaps = bitmaps.into_iter();
+    let mut res = match bitmaps.next() {
+        Some(bm) => bm.clone(),
+        None => return Bitmap::create(),
+    };
+    for bm in bitmaps {
+        res.and_inplace(bm);
+    }
+    res
+}
+
+/// Combine operands left to right. The first operand is never modified in
+/// place so that it doesn't need to be copied when borrowed from the index.
+#[inline]
+fn fold_operands<'a>(
+    operands: Vec<Cow<'a, Bitmap>>,
+    op: fn(&Bitmap, &Bitmap) -> Bitmap,
+    op_inplace: fn(&mut Bitmap, &Bitmap),
+    cancellation: &Cancellation,
+) -> Result<Cow<'a, Bitmap>, Error> {
+    let mut operands = operands.into_iter();
+    let first = operands.next().unwrap_or_default();
+    match operands.next() {
+        None => Ok(first),
+        Some(second) => {
+            let mut res = op(&first, &second);
+            for bm in operands {
+                cancellation.check()?;
+                op_inplace(&mut res, &bm);
+            }
+            Ok(Cow::Owned(res))
+        }
+    }
+}
+
 #[inline]
 fn _filter_map_cardinality(
     source: &Bitmap,
-    (k, v): (&String, &Bitmap),
+    (k, v): (&str, &Bitmap),
 ) -> Option<(String, u64)> {
     let x = source.and_cardinality(v);
-    if x > 0 { Some((k.clone(), x)) } else { None }
+    if x > 0 { Some((k.to_owned(), x)) } else { None }
 }
 
 impl std::fmt::Debug for Index {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Index [{} properties]", self.0.len())
+        write!(f, "Index [{} properties]", self.properties.len())
     }
 }
 
+type SharedPropertyIter<'a> = std::iter::Map<
+    std::collections::hash_map::Iter<'a, Arc<str>, Arc<Bitmap>>,
+    fn((&'a Arc<str>, &'a Arc<Bitmap>)) -> (&'a str, &'a Bitmap),
+>;
+
 impl<'a> IntoIterator for &'a Index {
-    type Item = (&'a String, &'a Bitmap);
-    type IntoIter = std::collections::hash_map::Iter<'a, String, Bitmap>;
+    type Item = (&'a str, &'a Bitmap);
+    type IntoIter = SharedPropertyIter<'a>;
 
     #[inline]
     fn into_iter(self) -> Self::IntoIter {
-        self.0.iter()
+        let unshare: fn(_) -> _ =
+            |(k, v): (&'a Arc<str>, &'a Arc<Bitmap>)| (&**k, &**v);
+        self.properties.iter().map(unshare)
+    }
+}
+
+/// See `Index::check_bits`.
+pub(crate) fn check_bits(
+    universe: Option<u32>,
+    bits: &[u32],
+) -> Result<(), Error> {
+    match universe {
+        Some(universe) => match bits.iter().find(|&&bit| bit >= universe) {
+            Some(&bit) => Err(Error::BitOutOfRange { bit, universe }),
+            None => Ok(()),
+        },
+        None => Ok(()),
     }
 }
 
+/// Optimize `bm` in place if it is not shared with a clone of the index.
+/// Otherwise optimize a copy and only swap it in when it is smaller, copying
+/// every shared bitmap would double their memory until the clone is dropped.
+fn optimize_bitmap(bm: &mut Arc<Bitmap>) {
+    match Arc::get_mut(bm) {
+        Some(bm) => {
+            bm.run_optimize();
+            bm.shrink_to_fit();
+        }
+        None => {
+            let mut optimized = Bitmap::clone(bm);
+            optimized.run_optimize();
+            optimized.shrink_to_fit();
+            if optimized.get_serialized_size_in_bytes()
+                < bm.get_serialized_size_in_bytes()
+            {
+                *bm = Arc::new(optimized);
+            }
+        }
+    }
+}
+
+/// Bitmap of all the bits of the universe, i.e. `0..universe`. `not` is
+/// computed by removing the operand from it rather than flipping the operand
+/// so that operand bits outside the universe cannot end up in the result.
+pub(crate) fn universe_bitmap(universe: u32) -> Bitmap {
+    let mut bm = Bitmap::create();
+    bm.add_range(0..universe);
+    bm
+}
+
+/// Whether any bit of `range` is set in `bm`.
+fn intersects_range(bm: &Bitmap, range: &Range<u32>) -> bool {
+    match range.end.checked_sub(1) {
+        Some(last) if range.start <= last => {
+            let before = match range.start.checked_sub(1) {
+                Some(x) => bm.rank(x),
+                None => 0,
+            };
+            bm.rank(last) > before
+        }
+        _ => false,
+    }
+}
+
+/// Number of bits decoded at once by `ResultIter` from either end.
+static RESULT_ITER_BATCH_SIZE: u64 = 4096;
+
+/// Lazy iterator over the bits of a query result, see `Index::execute_iter`.
+/// Bits are decoded in batches as they are consumed, locating a batch by
+/// rank once and then reading it in a single pass, so skipping ahead through
+/// `nth`, `skip` or `seek` costs the same as reading a single batch.
+pub struct ResultIter<'a> {
+    bitmap: Cow<'a, Bitmap>,
+    /// Rank of the next bit to yield from the front.
+    front: u64,
+    /// Rank past the next bit to yield from the back.
+    back: u64,
+    /// Decoded bits starting at rank `front`, in reverse order so that the
+    /// next one is at the end.
+    front_batch: Vec<u32>,
+    /// Decoded bits ending at rank `back`, in order.
+    back_batch: Vec<u32>,
+}
+
+impl<'a> ResultIter<'a> {
+    pub(crate) fn new(bitmap: Cow<'a, Bitmap>) -> Self {
+        let back = bitmap.cardinality();
+        Self { bitmap, front: 0, back, front_batch: vec![], back_batch: vec![] }
+    }
+
+    /// The whole result, including bits which were already consumed.
+    pub fn bitmap(&self) -> &Bitmap {
+        &self.bitmap
+    }
+
+    /// Skip all bits lower than `bit`.
+    pub fn seek(&mut self, bit: u32) {
+        let rank = match bit.checked_sub(1) {
+            None => 0,
+            Some(x) => self.bitmap.rank(x),
+        };
+        if rank > self.front {
+            self.skip_front(rank - self.front);
+        }
+    }
+
+    fn remaining(&self) -> u64 {
+        self.back - self.front
+    }
+
+    fn select(&self, rank: u64) -> Option<u32> {
+        self.bitmap.select(u32::try_from(rank).ok()?)
+    }
+
+    /// Bits of ranks `start..end` in order, `start < end <= cardinality`.
+    fn decode(&self, start: u64, end: u64) -> Vec<u32> {
+        match (self.select(start), self.select(end - 1)) {
+            (Some(first), Some(last)) => {
+                let mut mask = Bitmap::create();
+                mask.add_range(first..last);
+                mask.add(last);
+                self.bitmap.and(&mask).to_vec()
+            }
+            _ => vec![],
+        }
+    }
+
+    fn skip_front(&mut self, n: u64) {
+        let n = n.min(self.remaining());
+        let kept = (self.front_batch.len() as u64).saturating_sub(n);
+        self.front_batch.truncate(kept as usize);
+        self.front += n;
+    }
+
+    fn skip_back(&mut self, n: u64) {
+        let n = n.min(self.remaining());
+        let kept = (self.back_batch.len() as u64).saturating_sub(n);
+        self.back_batch.truncate(kept as usize);
+        self.back -= n;
+    }
+}
+
+impl std::fmt::Debug for ResultIter<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ResultIter [{} remaining bits]", self.remaining())
+    }
+}
+
+impl Iterator for ResultIter<'_> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.front >= self.back {
+            return None;
+        }
+        if self.front_batch.is_empty() {
+            let end = self.back.min(self.front + RESULT_ITER_BATCH_SIZE);
+            self.front_batch = self.decode(self.front, end);
+            self.front_batch.reverse();
+        }
+        self.front += 1;
+        self.front_batch.pop()
+    }
+
+    fn nth(&mut self, n: usize) -> Option<u32> {
+        self.skip_front(n as u64);
+        self.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match usize::try_from(self.remaining()) {
+            Ok(n) => (n, Some(n)),
+            Err(_) => (usize::MAX, None),
+        }
+    }
+
+    fn count(self) -> usize {
+        self.remaining() as usize
+    }
+
+    fn last(mut self) -> Option<u32> {
+        self.next_back()
+    }
+}
+
+impl DoubleEndedIterator for ResultIter<'_> {
+    fn next_back(&mut self) -> Option<u32> {
+        if self.front >= self.back {
+            return None;
+        }
+        if self.back_batch.is_empty() {
+            let start = self
+                .front
+                .max(self.back.saturating_sub(RESULT_ITER_BATCH_SIZE));
+            self.back_batch = self.decode(start, self.back);
+        }
+        self.back -= 1;
+        self.back_batch.pop()
+    }
+
+    fn nth_back(&mut self, n: usize) -> Option<u32> {
+        self.skip_back(n as u64);
+        self.next_back()
+    }
+}
+
+impl ExactSizeIterator for ResultIter<'_> {}
+
+/// Set the bits of every `(property, bits)` pair, see `Index::from_pairs`.
+impl<S, V> Extend<(S, V)> for Index
+where
+    S: Into<String>,
+    V: AsRef<[u32]>,
+{
+    fn extend<I: IntoIterator<Item = (S, V)>>(&mut self, pairs: I) {
+        let pairs = pairs.into_iter();
+        self.reserve(pairs.size_hint().0);
+        for (property, bits) in pairs {
+            let property: String = property.into();
+            self.property_mut(&property).add_many(bits.as_ref());
+        }
+    }
+}
+
+impl<S, V> FromIterator<(S, V)> for Index
+where
+    S: Into<String>,
+    V: AsRef<[u32]>,
+{
+    fn from_iter<I: IntoIterator<Item = (S, V)>>(pairs: I) -> Self {
+        Self::from_pairs(pairs)
+    }
+}
+
+/// See `Index::cardinality_histogram`. Percentiles use the nearest-rank
+/// method and are 0 without any property.
+#[derive(Debug, Serialize, Default, PartialEq, Eq)]
+pub struct CardinalityHistogram {
+    pub properties: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub maximum: u64,
+    pub buckets: Vec<HistogramBucket>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct HistogramBucket {
+    /// Inclusive upper bound of the cardinalities counted in this bucket,
+    /// above the previous bucket's. `None` for the last bucket.
+    pub le: Option<u64>,
+    pub count: u64,
+}
+
 #[derive(Debug, Serialize, Default, PartialEq, Eq)]
 pub struct Stats {
     pub cardinality: u64,
@@ -560,6 +1696,80 @@ impl From<&Index> for Stats {
     }
 }
 
+/// Storage of a bitmap by container type, see `Bitmap::statistics`. Run
+/// containers are usually the most compact for contiguous bits, mostly array
+/// or bitset containers on dense ranges hint that `Index::optimize` would
+/// help.
+#[derive(Debug, Serialize, Default, PartialEq, Eq, Clone, Copy)]
+pub struct ContainerStats {
+    pub array_containers: u64,
+    pub run_containers: u64,
+    pub bitset_containers: u64,
+    pub array_bytes: u64,
+    pub run_bytes: u64,
+    pub bitset_bytes: u64,
+}
+
+impl ContainerStats {
+    pub fn containers(&self) -> u64 {
+        self.array_containers + self.run_containers + self.bitset_containers
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.array_bytes + self.run_bytes + self.bitset_bytes
+    }
+}
+
+impl From<&Bitmap> for ContainerStats {
+    fn from(bm: &Bitmap) -> Self {
+        let stats = bm.statistics();
+        Self {
+            array_containers: stats.n_array_containers.into(),
+            run_containers: stats.n_run_containers.into(),
+            bitset_containers: stats.n_bitset_containers.into(),
+            array_bytes: stats.n_bytes_array_containers.into(),
+            run_bytes: stats.n_bytes_run_containers.into(),
+            bitset_bytes: stats.n_bytes_bitset_containers.into(),
+        }
+    }
+}
+
+impl std::ops::AddAssign for ContainerStats {
+    fn add_assign(&mut self, other: Self) {
+        self.array_containers += other.array_containers;
+        self.run_containers += other.run_containers;
+        self.bitset_containers += other.bitset_containers;
+        self.array_bytes += other.array_bytes;
+        self.run_bytes += other.run_bytes;
+        self.bitset_bytes += other.bitset_bytes;
+    }
+}
+
+/// Nearest-rank percentile of the bits set in `bm`, i.e. the lowest bit
+/// such that at least `p` percent of the bits are lower or equal. `p` is
+/// capped at 100, `None` if `bm` is empty.
+///
+/// ```
+/// # use croaring::Bitmap;
+/// # use crible_lib::index::percentile;
+///
+/// let bm = Bitmap::of(&[10, 20, 30, 40]);
+///
+/// assert_eq!(percentile(&bm, 0.0), Some(10));
+/// assert_eq!(percentile(&bm, 50.0), Some(20));
+/// assert_eq!(percentile(&bm, 60.0), Some(30));
+/// assert_eq!(percentile(&bm, 100.0), Some(40));
+/// assert_eq!(percentile(&Bitmap::create(), 50.0), None);
+/// ```
+pub fn percentile(bm: &Bitmap, p: f64) -> Option<u32> {
+    let cardinality = bm.cardinality();
+    if cardinality == 0 {
+        return None;
+    }
+    let rank = (p.clamp(0.0, 100.0) / 100.0 * cardinality as f64).ceil() as u64;
+    bm.select(rank.clamp(1, cardinality) as u32 - 1)
+}
+
 // TODO: These are limited unit tests. Should write some more complete tests
 // over real-life data.
 #[cfg(test)]
@@ -595,6 +1805,10 @@ mod tests {
     #[case("(foo and bar) or baz", &[1, 3, 4, 6, 8, 9])]
     #[case("foo - (bar and baz) - (foo xor bar)", &[1, 3])]
     #[case("baz - foo - bar", &[8])]
+    #[case("any(ba)", &[1, 3, 4, 5, 6, 7, 8, 9])]
+    #[case("foo and any(ba)", &[1, 3, 4, 9])]
+    #[case("all(ba)", &[6])]
+    #[case("any(qux) or all(qux)", &[])]
     fn test_queries(#[case] input: &str, #[case] expected: &[u32]) {
         let index = Index::of([
             ("foo", vec![1, 2, 3, 4, 9]),
@@ -605,6 +1819,183 @@ mod tests {
         assert_eq!(&res.to_vec(), expected);
     }
 
+    #[rstest]
+    #[case(" or ", (0..=20).collect())]
+    #[case(" and ", vec![0, 1])]
+    #[case(" xor ", (2..=20).collect())]
+    fn test_wide_queries(#[case] operator: &str, #[case] expected: Vec<u32>) {
+        // Wide enough to be executed in parallel.
+        let index = Index::of(
+            (1..=20)
+                .map(|n| (format!("p{}", n), vec![0, 1, n]))
+                .collect::<Vec<_>>(),
+        );
+        let query = (1..=20)
+            .map(|n| format!("p{}", n))
+            .collect::<Vec<_>>()
+            .join(operator);
+        let res = index.execute(&query.parse().unwrap()).unwrap();
+        assert_eq!(res.to_vec(), expected);
+    }
+
+    #[rstest]
+    #[case("qux", vec![])]
+    #[case("foo and qux", vec![1])]
+    #[case("foo - (bar or (baz and qux))", vec![1, 1, 1])]
+    #[case("not (foo xor qux)", vec![0, 1])]
+    fn test_missing_property(#[case] input: &str, #[case] operand: Vec<usize>) {
+        let index = Index::of([
+            ("foo", vec![1, 2, 3, 4, 9]),
+            ("bar", vec![1, 3, 5, 6, 7]),
+            ("baz", vec![4, 6, 8, 9]),
+        ]);
+        let err = index.execute(&input.parse().unwrap()).unwrap_err();
+        assert_eq!(err.code(), "property_does_not_exist");
+        assert_eq!(
+            err,
+            Error::PropertyDoesNotExist { property: "qux".to_owned(), operand }
+        );
+    }
+
+    #[test]
+    fn test_not_stays_within_universe() {
+        let mut index = Index::of([("foo", vec![1, 2])]);
+        index.set_universe(Some(5)).unwrap();
+        // Setting bits is not checked, `not` must still ignore this one.
+        index.set("foo", 7);
+
+        let res = index.execute(&"not foo".parse().unwrap()).unwrap();
+        assert_eq!(res.to_vec(), vec![0, 3, 4]);
+    }
+
+    #[test]
+    fn test_set_universe_rejects_bits_out_of_range() {
+        let mut index = Index::of([("foo", vec![1, 9]), ("bar", vec![4])]);
+
+        assert_eq!(
+            index.set_universe(Some(9)),
+            Err(Error::BitOutOfRange { bit: 9, universe: 9 })
+        );
+        assert_eq!(index.universe(), None);
+        assert_eq!(index.set_universe(Some(10)), Ok(()));
+        assert_eq!(index.universe(), Some(10));
+    }
+
+    #[test]
+    fn test_expired_deadline_cancels() {
+        let index = Index::of([("foo", vec![1, 2]), ("bar", vec![2, 3])]);
+        let expired = Cancellation::with_deadline(std::time::Instant::now());
+        let source = index.root();
+
+        assert_eq!(
+            index
+                .execute_cancellable(&"foo or bar".parse().unwrap(), &expired)
+                .unwrap_err(),
+            Error::Cancelled
+        );
+        assert_eq!(
+            index.cardinalities_cancellable(&source, None, &expired),
+            Err(Error::Cancelled)
+        );
+        assert_eq!(
+            index.cardinalities_cancellable(
+                &source,
+                Some("ba"),
+                &Cancellation::default()
+            ),
+            Ok(index.cardinalities(&source, Some("ba")))
+        );
+    }
+
+    #[test]
+    fn test_from_pairs() {
+        let mut index: Index =
+            vec![("foo".to_owned(), vec![1, 2]), ("bar".to_owned(), vec![3])]
+                .into_iter()
+                .collect();
+        index.extend([("foo", [5]), ("baz", [6])]);
+
+        assert_eq!(
+            index,
+            Index::of([
+                ("foo", vec![1, 2, 5]),
+                ("bar", vec![3]),
+                ("baz", vec![6])
+            ])
+        );
+        assert!(Index::with_capacity(16).is_empty());
+    }
+
+    #[test]
+    fn test_execute_iter() {
+        let index = Index::of([
+            ("foo", vec![1, 2, 3, 4, 9, 100_000]),
+            ("bar", vec![1, 3, 5, 6, 7]),
+        ]);
+        let expr = "foo or bar".parse().unwrap();
+        let expected = index.execute(&expr).unwrap().to_vec();
+
+        let iter = || index.execute_iter(&expr).unwrap();
+        assert_eq!(iter().collect::<Vec<_>>(), expected);
+        assert_eq!(iter().rev().collect::<Vec<_>>(), {
+            let mut x = expected.clone();
+            x.reverse();
+            x
+        });
+        assert_eq!(iter().len(), 9);
+        assert_eq!(iter().last(), Some(100_000));
+        assert_eq!(iter().nth(8), Some(100_000));
+        assert_eq!(iter().nth(9), None);
+        assert_eq!(iter().skip(3).take(2).collect::<Vec<_>>(), vec![4, 5]);
+
+        let mut bits = iter();
+        assert_eq!(bits.nth_back(1), Some(9));
+        bits.seek(5);
+        assert_eq!(bits.len(), 3);
+        assert_eq!(bits.collect::<Vec<_>>(), vec![5, 6, 7]);
+
+        let mut bits = iter();
+        bits.nth(5);
+        bits.seek(0);
+        assert_eq!(bits.next(), Some(7));
+        bits.seek(u32::MAX);
+        assert_eq!(bits.next(), None);
+    }
+
+    #[test]
+    fn test_execute_iter_across_batches() {
+        let bits: Vec<u32> = (0..20_000)
+            .map(|x| x * 7)
+            .chain([u32::MAX - 1, u32::MAX])
+            .collect();
+        let index = Index::from_pairs([("foo", bits.clone())]);
+        let expr = "foo".parse().unwrap();
+        let iter = || index.execute_iter(&expr).unwrap();
+
+        assert_eq!(iter().collect::<Vec<_>>(), bits);
+        assert!(iter().rev().eq(bits.iter().rev().copied()));
+
+        let mut it = iter();
+        assert_eq!(it.nth(4095), Some(bits[4095]));
+        assert_eq!(it.next(), Some(bits[4096]));
+        assert_eq!(it.nth_back(1), Some(bits[20_000]));
+        it.seek(7 * 10_000 + 1);
+        assert_eq!(it.next(), Some(bits[10_001]));
+        assert_eq!(it.len(), 20_002 - 10_002 - 2);
+
+        // Both ends meet within the batches decoded from the other end.
+        let mut it = iter();
+        let mut collected = vec![];
+        while let Some(bit) = it.next() {
+            collected.push(bit);
+            if let Some(bit) = it.next_back() {
+                collected.push(bit);
+            }
+        }
+        collected.sort_unstable();
+        assert_eq!(collected, bits);
+    }
+
     #[test]
     fn test_stats() {
         assert_eq!(Stats::default(), Index::default().into());
@@ -626,4 +2017,181 @@ mod tests {
             &index.get_property("foo").unwrap().into(),
         );
     }
+
+    #[test]
+    fn test_container_stats() {
+        assert_eq!(ContainerStats::default(), (&Bitmap::create()).into());
+
+        let mut bm = Bitmap::of(&[1, 2, 3, 1 << 16]);
+        let stats = ContainerStats::from(&bm);
+        assert_eq!(stats.array_containers, 2);
+        assert_eq!(stats.containers(), 2);
+
+        bm.add_range(0..10_000);
+        bm.run_optimize();
+        let mut total = ContainerStats::from(&bm);
+        assert_eq!(total.run_containers, 1);
+        assert!(total.bytes() > 0);
+
+        total += stats;
+        assert_eq!(total.containers(), 4);
+    }
+
+    #[test]
+    fn test_iter_prefix_follows_changes() {
+        let prefixed = |index: &Index, prefix: &str| {
+            index
+                .iter_prefix(prefix)
+                .map(|(k, _)| k.to_owned())
+                .collect::<Vec<_>>()
+        };
+        let mut index =
+            Index::from_pairs([("tag:b", vec![1]), ("size", vec![2])]);
+        index.set("tag:a", 3);
+        index.set_many("tag:c", &[4]);
+        index.set_property("tag:aa", Bitmap::of(&[5]));
+        assert_eq!(
+            prefixed(&index, "tag:"),
+            ["tag:a", "tag:aa", "tag:b", "tag:c"]
+        );
+        assert_eq!(prefixed(&index, "tag:a"), ["tag:a", "tag:aa"]);
+        assert!(prefixed(&index, "tag:d").is_empty());
+
+        assert!(index.delete_property("tag:b"));
+        assert_eq!(prefixed(&index, "tag:"), ["tag:a", "tag:aa", "tag:c"]);
+        assert_eq!(index.union_prefix("tag:").to_vec(), vec![3, 4, 5]);
+
+        index.clear();
+        assert!(prefixed(&index, "").is_empty());
+    }
+
+    #[rstest]
+    #[case::set(|index: &mut Index| { index.set("foo", 4); }, &["foo"])]
+    #[case::set_unchanged(|index: &mut Index| { index.set("foo", 1); }, &[])]
+    #[case::unset(|index: &mut Index| { index.unset("bar", 3); }, &["bar"])]
+    #[case::unset_missing(|index: &mut Index| { index.unset("bar", 1); }, &[])]
+    #[case::unset_all(|index: &mut Index| index.unset_all(&[2]), &["foo"])]
+    #[case::unset_range(|index: &mut Index| index.unset_range(3..5), &["bar"])]
+    #[case::set_properties_with_bit(
+        |index: &mut Index| { index.set_properties_with_bit(3, &["foo"]); },
+        &["foo", "bar"]
+    )]
+    #[case::new_property(|index: &mut Index| { index.set("baz", 1); }, &[])]
+    fn test_clones_share_unmodified_bitmaps(
+        #[case] mutation: fn(&mut Index),
+        #[case] copied: &[&str],
+    ) {
+        let index = Index::of([("foo", vec![1, 2]), ("bar", vec![3])]);
+        let mut clone = index.clone();
+        mutation(&mut clone);
+        for (property, bm) in index.inner() {
+            let (name, cloned) = clone.inner().get_key_value(property).unwrap();
+            assert!(Arc::ptr_eq(property, name));
+            assert_eq!(
+                !Arc::ptr_eq(bm, cloned),
+                copied.contains(&&**property),
+                "{}",
+                property
+            );
+        }
+        assert!(index == Index::of([("foo", vec![1, 2]), ("bar", vec![3])]));
+    }
+
+    #[test]
+    fn test_optimize_only_copies_smaller_shared_bitmaps() {
+        let index = Index::of([
+            ("runs", (0..1000).collect::<Vec<_>>()),
+            ("sparse", vec![1, 5000]),
+        ]);
+        let mut clone = index.clone();
+
+        clone.optimize_properties(&["runs", "sparse", "missing"]);
+        clone.shrink_to_fit();
+
+        let shared = |property: &str| {
+            Arc::ptr_eq(&index.inner()[property], &clone.inner()[property])
+        };
+        assert!(!shared("runs"));
+        assert!(shared("sparse"));
+        assert!(clone == index);
+    }
+
+    #[test]
+    fn test_set_properties_with_bits() {
+        let mut index = Index::of([
+            ("foo", vec![1, 2, 3]),
+            ("bar", vec![1, 3, 4]),
+            ("baz", vec![2, 3, 4]),
+        ]);
+        let mut expected = index.clone();
+        let assignments = HashMap::from([
+            (1, vec!["baz"]),
+            (3, vec!["foo", "bar", "qux"]),
+            (4, vec![]),
+            (9, vec!["foo"]),
+        ]);
+        for (bit, properties) in &assignments {
+            expected.set_properties_with_bit(*bit, properties);
+        }
+
+        assert!(index.set_properties_with_bits(&assignments));
+        assert!(index == expected);
+        assert!(index.get_property("qux").is_none());
+        assert!(!index.set_properties_with_bits(&assignments));
+        assert!(
+            !index.set_properties_with_bits(&HashMap::<u32, Vec<&str>>::new())
+        );
+    }
+
+    #[rstest]
+    #[case(&[], vec![4])]
+    #[case(&[0], vec![1, 3])]
+    #[case(&[1, 10, 100], vec![2, 1, 1, 0])]
+    #[case(&[1000], vec![4, 0])]
+    fn test_cardinality_histogram(
+        #[case] buckets: &[u64],
+        #[case] expected: Vec<u64>,
+    ) {
+        let index = Index::of([
+            ("a:foo", vec![]),
+            ("a:bar", vec![1]),
+            ("a:baz", vec![1, 2, 3]),
+            ("b:qux", (0..100).collect()),
+        ]);
+        let histogram = index.cardinality_histogram(buckets, None);
+        assert_eq!(
+            histogram.buckets.iter().map(|b| b.count).collect::<Vec<_>>(),
+            expected
+        );
+        assert_eq!(histogram.buckets.last().unwrap().le, None);
+        assert_eq!(
+            (histogram.p50, histogram.p90, histogram.p99, histogram.maximum),
+            (1, 100, 100, 100)
+        );
+
+        let histogram = index.cardinality_histogram(buckets, Some("a:"));
+        assert_eq!(histogram.properties, 3);
+        assert_eq!(histogram.maximum, 3);
+
+        let histogram = Index::default().cardinality_histogram(buckets, None);
+        assert_eq!((histogram.properties, histogram.p50), (0, 0));
+        assert!(histogram.buckets.iter().all(|b| b.count == 0));
+    }
+
+    #[rstest]
+    #[case(vec![], 50.0, None)]
+    #[case(vec![7], 0.0, Some(7))]
+    #[case(vec![7], 100.0, Some(7))]
+    #[case((1..=100).collect(), 1.0, Some(1))]
+    #[case((1..=100).collect(), 99.0, Some(99))]
+    #[case((1..=100).collect(), 99.5, Some(100))]
+    #[case((1..=100).collect(), 150.0, Some(100))]
+    #[case((1..=100).collect(), -1.0, Some(1))]
+    fn test_percentile(
+        #[case] bits: Vec<u32>,
+        #[case] p: f64,
+        #[case] expected: Option<u32>,
+    ) {
+        assert_eq!(percentile(&Bitmap::of(&bits), p), expected);
+    }
 }
@@ -11,10 +11,28 @@
     unused_qualifications
 )]
 
+#[cfg(feature = "arrow")]
+pub mod arrow;
+#[cfg(feature = "index")]
+pub mod cancellation;
+#[cfg(feature = "index")]
+pub mod concurrent;
+#[cfg(feature = "index")]
 pub mod encoding;
 pub mod expression;
+#[cfg(feature = "index")]
 pub mod index;
+#[cfg(feature = "rayon")]
+pub mod sharded;
 
+#[cfg(feature = "index")]
+pub use cancellation::Cancellation;
+#[cfg(feature = "index")]
+pub use concurrent::ConcurrentIndex;
+#[cfg(feature = "index")]
 pub use encoding::Encoder;
 pub use expression::Expression;
+#[cfg(feature = "index")]
 pub use index::Index;
+#[cfg(feature = "rayon")]
+pub use sharded::ShardedIndex;
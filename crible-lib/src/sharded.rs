@@ -0,0 +1,545 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+
+use croaring::Bitmap;
+use rayon::prelude::*;
+
+use crate::cancellation::Cancellation;
+use crate::expression::Expression;
+use crate::index::{check_bits, universe_bitmap, Error, Index};
+
+/// An index where properties are partitioned across a fixed number of
+/// shards based on the hash of their name. Operations spanning many
+/// properties (`root()`, `cardinalities()`, expressions over multiple
+/// properties) run across shards in parallel on the current rayon pool while
+/// operations on a single property only touch its shard.
+///
+/// ```
+/// # use crible_lib::index::Index;
+/// # use crible_lib::sharded::ShardedIndex;
+///
+/// let index = ShardedIndex::from_index(
+///     Index::of([
+///         ("foo", vec![1, 2, 3, 6]),
+///         ("bar", vec![1, 3, 4, 7]),
+///         ("baz", vec![3, 4, 5, 7]),
+///     ]),
+///     4,
+/// );
+///
+/// assert_eq!(index.len(), 3);
+/// assert_eq!(index.root().to_vec(), vec![1, 2, 3, 4, 5, 6, 7]);
+/// assert_eq!(
+///     index.execute(&"foo and bar".parse().unwrap()).unwrap().to_vec(),
+///     vec![1, 3],
+/// );
+/// ```
+#[derive(Clone, PartialEq)]
+pub struct ShardedIndex {
+    shards: Vec<Index>,
+    /// See `Index::set_universe`, kept here rather than on the shards.
+    universe: Option<u32>,
+}
+
+impl ShardedIndex {
+    /// Create an empty index with `shard_count` shards.
+    ///
+    /// Panics if `shard_count` is 0.
+    pub fn new(shard_count: usize) -> Self {
+        assert!(shard_count > 0, "shard_count must be greater than 0");
+        Self { shards: vec![Index::default(); shard_count], universe: None }
+    }
+
+    /// Partition an existing index across `shard_count` shards.
+    ///
+    /// Panics if `shard_count` is 0.
+    pub fn from_index(index: Index, shard_count: usize) -> Self {
+        let mut sharded = Self::new(shard_count);
+        sharded.universe = index.universe();
+        for (property, bm) in index.into_inner() {
+            let shard = sharded.shard_of(&property);
+            sharded.shards[shard].set_property(&property, bm);
+        }
+        sharded
+    }
+
+    /// Merge all shards back into a single index.
+    pub fn into_index(self) -> Index {
+        let mut index = Index::new(
+            self.shards.into_iter().flat_map(|s| s.into_inner()).collect(),
+        );
+        index.set_universe_unchecked(self.universe);
+        index
+    }
+
+    #[inline]
+    fn shard_of(&self, property: &str) -> usize {
+        // `DefaultHasher::new()` uses fixed keys so the assignment of
+        // properties to shards is stable.
+        let mut hasher = DefaultHasher::new();
+        property.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    #[inline]
+    fn shard(&self, property: &str) -> &Index {
+        &self.shards[self.shard_of(property)]
+    }
+
+    #[inline]
+    fn shard_mut(&mut self, property: &str) -> &mut Index {
+        let shard = self.shard_of(property);
+        &mut self.shards[shard]
+    }
+
+    pub fn shards(&self) -> &[Index] {
+        &self.shards
+    }
+
+    /// Return the number of unique properties covered by the index.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.is_empty())
+    }
+
+    /// See `Index::universe`.
+    pub fn universe(&self) -> Option<u32> {
+        self.universe
+    }
+
+    /// See `Index::set_universe`.
+    pub fn set_universe(&mut self, universe: Option<u32>) -> Result<(), Error> {
+        if let Some(universe) = universe {
+            if let Some(bit) = self
+                .shards
+                .iter()
+                .flat_map(|s| s.iter().filter_map(|(_, bm)| bm.maximum()))
+                .max()
+                .filter(|&bit| bit >= universe)
+            {
+                return Err(Error::BitOutOfRange { bit, universe });
+            }
+        }
+        self.universe = universe;
+        Ok(())
+    }
+
+    /// See `Index::check_bits`.
+    pub fn check_bits(&self, bits: &[u32]) -> Result<(), Error> {
+        check_bits(self.universe, bits)
+    }
+
+    /// Return a Bitmap containing all values in the index.
+    pub fn root(&self) -> Bitmap {
+        let roots: Vec<Bitmap> =
+            self.shards.par_iter().map(|s| s.root()).collect();
+        Bitmap::fast_or(&roots.iter().collect::<Vec<_>>())
+    }
+
+    /// See `Index::union_prefix`.
+    pub fn union_prefix(&self, prefix: &str) -> Bitmap {
+        let unions: Vec<Bitmap> =
+            self.shards.par_iter().map(|s| s.union_prefix(prefix)).collect();
+        Bitmap::fast_or(&unions.iter().collect::<Vec<_>>())
+    }
+
+    /// See `Index::intersection_prefix`.
+    pub fn intersection_prefix(&self, prefix: &str) -> Bitmap {
+        crate::index::intersection(
+            self.shards
+                .iter()
+                .flat_map(|s| s.iter_prefix(prefix).map(|(_, bm)| bm)),
+        )
+    }
+
+    // Operate on rows.
+
+    pub fn get_property(&self, property: &str) -> Option<&Bitmap> {
+        self.shard(property).get_property(property)
+    }
+
+    pub fn set_property(&mut self, property: &str, bm: Bitmap) {
+        self.shard_mut(property).set_property(property, bm)
+    }
+
+    pub fn delete_property(&mut self, property: &str) -> bool {
+        self.shard_mut(property).delete_property(property)
+    }
+
+    pub fn clear(&mut self) {
+        for shard in &mut self.shards {
+            shard.clear();
+        }
+    }
+
+    pub fn optimize(&mut self) {
+        self.shards.par_iter_mut().for_each(|s| s.optimize());
+    }
+
+    // Operate on individual bits.
+
+    /// See `Index::set`.
+    pub fn set(&mut self, property: &str, bit: u32) -> bool {
+        self.shard_mut(property).set(property, bit)
+    }
+
+    /// See `Index::set_many`.
+    pub fn set_many(&mut self, property: &str, bits: &[u32]) {
+        self.shard_mut(property).set_many(property, bits)
+    }
+
+    /// See `Index::set_all`.
+    pub fn set_all(&mut self, bits: &[u32]) {
+        self.shards.par_iter_mut().for_each(|s| s.set_all(bits));
+    }
+
+    /// See `Index::unset`.
+    pub fn unset(&mut self, property: &str, bit: u32) -> bool {
+        self.shard_mut(property).unset(property, bit)
+    }
+
+    /// See `Index::unset_many`.
+    pub fn unset_many(&mut self, property: &str, bits: &[u32]) {
+        self.shard_mut(property).unset_many(property, bits)
+    }
+
+    /// See `Index::unset_all`.
+    pub fn unset_all(&mut self, bits: &[u32]) {
+        self.shards.par_iter_mut().for_each(|s| s.unset_all(bits));
+    }
+
+    /// See `Index::unset_range`.
+    pub fn unset_range(&mut self, range: Range<u32>) {
+        self.shards.par_iter_mut().for_each(|s| s.unset_range(range.clone()));
+    }
+
+    /// See `Index::unset_range_properties`.
+    pub fn unset_range_properties<T: AsRef<str>>(
+        &mut self,
+        range: Range<u32>,
+        properties: &[T],
+    ) {
+        for property in properties {
+            self.shard_mut(property.as_ref())
+                .unset_range_properties(range.clone(), &[property]);
+        }
+    }
+
+    // Operations on all properties for a given bit.
+
+    /// See `Index::get_properties_with_bit`.
+    pub fn get_properties_with_bit(&self, bit: u32) -> Vec<String> {
+        let mut vec: Vec<String> = self
+            .shards
+            .par_iter()
+            .flat_map_iter(|s| s.get_properties_with_bit(bit))
+            .collect();
+        vec.sort_unstable();
+        vec
+    }
+
+    /// See `Index::get_properties_with_bits`.
+    pub fn get_properties_with_bits(
+        &self,
+        bits: &[u32],
+    ) -> HashMap<u32, Vec<String>> {
+        let mut result = self
+            .shards
+            .par_iter()
+            .map(|s| s.get_properties_with_bits(bits))
+            .reduce(HashMap::new, |mut acc, shard| {
+                for (bit, properties) in shard {
+                    acc.entry(bit).or_default().extend(properties);
+                }
+                acc
+            });
+        for properties in result.values_mut() {
+            properties.sort_unstable();
+        }
+        result
+    }
+
+    /// See `Index::set_properties_with_bit`.
+    pub fn set_properties_with_bit<T: AsRef<str> + Sync>(
+        &mut self,
+        bit: u32,
+        properties: &[T],
+    ) -> bool {
+        self.shards
+            .par_iter_mut()
+            .map(|s| s.set_properties_with_bit(bit, properties))
+            .reduce(|| false, |a, b| a || b)
+    }
+
+    // Run queries.
+
+    /// Execute a query against the index, see `Index::execute`. Operands of
+    /// `and`, `or`, `xor` and `-` are evaluated in parallel.
+    pub fn execute(&self, expression: &Expression) -> Result<Bitmap, Error> {
+        self.execute_cancellable(expression, &Cancellation::default())
+    }
+
+    /// See `Index::execute_cancellable`.
+    pub fn execute_cancellable(
+        &self,
+        expression: &Expression,
+        cancellation: &Cancellation,
+    ) -> Result<Bitmap, Error> {
+        cancellation.check()?;
+        match expression {
+            Expression::Root => Ok(self.root()),
+            Expression::Property(name) => self
+                .get_property(name)
+                .ok_or_else(|| Error::property_does_not_exist(name))
+                .cloned(),
+            Expression::And(inner) => {
+                let mut operands = self.execute_all(inner, cancellation)?;
+                let mut res = operands.remove(0);
+                for bm in &operands {
+                    res.and_inplace(bm);
+                }
+                Ok(res)
+            }
+            Expression::Or(inner) => Ok(Bitmap::fast_or(
+                &self
+                    .execute_all(inner, cancellation)?
+                    .iter()
+                    .collect::<Vec<_>>(),
+            )),
+            Expression::Xor(inner) => Ok(Bitmap::fast_xor(
+                &self
+                    .execute_all(inner, cancellation)?
+                    .iter()
+                    .collect::<Vec<_>>(),
+            )),
+            Expression::Sub(inner) => {
+                let mut operands = self.execute_all(inner, cancellation)?;
+                let mut res = operands.remove(0);
+                for bm in &operands {
+                    res.andnot_inplace(bm);
+                }
+                Ok(res)
+            }
+            Expression::Not(e) => {
+                let (all, res) = rayon::join(
+                    || match self.universe {
+                        Some(universe) => universe_bitmap(universe),
+                        None => self.root(),
+                    },
+                    || self.execute_cancellable(e.as_ref(), cancellation),
+                );
+                Ok(all - res.map_err(|e| e.in_operand(0))?)
+            }
+            Expression::AnyPrefix(prefix) => Ok(self.union_prefix(prefix)),
+            Expression::AllPrefix(prefix) => {
+                Ok(self.intersection_prefix(prefix))
+            }
+        }
+    }
+
+    #[inline]
+    fn execute_all(
+        &self,
+        expressions: &[Expression],
+        cancellation: &Cancellation,
+    ) -> Result<Vec<Bitmap>, Error> {
+        expressions
+            .par_iter()
+            .enumerate()
+            .map(|(i, e)| {
+                self.execute_cancellable(e, cancellation)
+                    .map_err(|err| err.in_operand(i))
+            })
+            .collect()
+    }
+
+    /// See `Index::cardinalities`, shards are processed in parallel.
+    pub fn cardinalities(
+        &self,
+        source: &Bitmap,
+        prefix: Option<&str>,
+    ) -> HashMap<String, u64> {
+        self.shards.par_iter().map(|s| s.cardinalities(source, prefix)).reduce(
+            HashMap::new,
+            |mut acc, x| {
+                acc.extend(x);
+                acc
+            },
+        )
+    }
+}
+
+impl std::fmt::Debug for ShardedIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ShardedIndex [{} properties, {} shards]",
+            self.len(),
+            self.shards.len()
+        )
+    }
+}
+
+impl From<ShardedIndex> for Index {
+    fn from(index: ShardedIndex) -> Self {
+        index.into_index()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn index() -> Index {
+        Index::of([
+            ("foo", vec![1, 2, 3, 4, 9]),
+            ("bar", vec![1, 3, 5, 6, 7]),
+            ("baz", vec![4, 6, 8, 9]),
+            ("qux", vec![2, 10]),
+        ])
+    }
+
+    #[rstest]
+    #[case("*")]
+    #[case("foo")]
+    #[case("not foo")]
+    #[case("!!foo")]
+    #[case("foo and bar")]
+    #[case("foo and bar and qux")]
+    #[case("foo or bar")]
+    #[case("foo or bar or qux")]
+    #[case("foo xor bar")]
+    #[case("foo xor bar xor baz")]
+    #[case("not (foo and bar)")]
+    #[case("(foo and bar) or baz")]
+    #[case("foo - (bar and baz) - (foo xor bar)")]
+    #[case("foo and any(ba)")]
+    #[case("all(ba) or all(qux)")]
+    fn test_queries_match_index(
+        #[case] input: &str,
+        #[values(1, 2, 7)] shard_count: usize,
+    ) {
+        let index = index();
+        let sharded = ShardedIndex::from_index(index.clone(), shard_count);
+        let expr = input.parse().unwrap();
+        assert_eq!(
+            sharded.execute(&expr).unwrap().to_vec(),
+            index.execute(&expr).unwrap().to_vec()
+        );
+    }
+
+    #[rstest]
+    #[case("not foo")]
+    #[case("not (foo or bar)")]
+    fn test_universe_is_kept(#[case] input: &str) {
+        let mut index = index();
+        index.set_universe(Some(16)).unwrap();
+        let sharded = ShardedIndex::from_index(index.clone(), 3);
+        assert_eq!(sharded.universe(), Some(16));
+        let expr = input.parse().unwrap();
+        assert_eq!(
+            sharded.execute(&expr).unwrap().to_vec(),
+            index.execute(&expr).unwrap().to_vec()
+        );
+        assert_eq!(sharded.into_index().universe(), Some(16));
+    }
+
+    #[test]
+    fn test_set_universe() {
+        let mut sharded = ShardedIndex::from_index(index(), 3);
+        assert_eq!(
+            sharded.set_universe(Some(10)),
+            Err(Error::BitOutOfRange { bit: 10, universe: 10 })
+        );
+        assert_eq!(sharded.universe(), None);
+        sharded.set_universe(Some(11)).unwrap();
+        assert!(sharded.check_bits(&[10]).is_ok());
+        assert!(sharded.check_bits(&[11]).is_err());
+    }
+
+    #[test]
+    fn test_execute_cancelled() {
+        let sharded = ShardedIndex::from_index(index(), 3);
+        let cancellation = Cancellation::default();
+        cancellation.cancel();
+        assert_eq!(
+            sharded.execute_cancellable(
+                &"foo and bar".parse().unwrap(),
+                &cancellation
+            ),
+            Err(Error::Cancelled)
+        );
+    }
+
+    #[test]
+    fn test_unknown_property() {
+        let sharded = ShardedIndex::from_index(index(), 3);
+        assert_eq!(
+            sharded.execute(&"foo and unknown".parse().unwrap()),
+            Err(Error::PropertyDoesNotExist {
+                property: "unknown".to_owned(),
+                operand: vec![1]
+            })
+        );
+    }
+
+    #[rstest]
+    #[case(None)]
+    #[case(Some("ba"))]
+    fn test_cardinalities_match_index(#[case] prefix: Option<&str>) {
+        let index = index();
+        let sharded = ShardedIndex::from_index(index.clone(), 3);
+        let source = index.execute(&"foo or bar".parse().unwrap()).unwrap();
+        assert_eq!(
+            sharded.cardinalities(&source, prefix),
+            index.cardinalities(&source, prefix)
+        );
+    }
+
+    #[test]
+    fn test_get_properties_with_bits_match_index() {
+        let index = index();
+        let sharded = ShardedIndex::from_index(index.clone(), 3);
+        let bits = index.root().iter().chain([1000]).collect::<Vec<_>>();
+        assert_eq!(
+            sharded.get_properties_with_bits(&bits),
+            index.get_properties_with_bits(&bits)
+        );
+    }
+
+    #[test]
+    fn test_mutations() {
+        let mut sharded = ShardedIndex::new(3);
+        assert!(sharded.set("foo", 1));
+        sharded.set_many("bar", &[1, 2]);
+        sharded.set_all(&[5]);
+        assert_eq!(sharded.get_properties_with_bit(5), vec!["bar", "foo"]);
+
+        assert!(sharded.set_properties_with_bit(7, &["foo"]));
+        assert_eq!(sharded.get_properties_with_bit(7), vec!["foo"]);
+
+        sharded.unset_all(&[1]);
+        assert!(!sharded.unset("foo", 1));
+        assert_eq!(sharded.get_property("bar").unwrap().to_vec(), vec![2, 5]);
+
+        sharded.set_many("baz", &[3, 6]);
+        sharded.unset_range_properties(0..4, &["baz", "qux"]);
+        sharded.unset_range(6..7);
+
+        assert_eq!(
+            sharded.into_index(),
+            Index::of([
+                ("foo", vec![5, 7]),
+                ("bar", vec![2, 5]),
+                ("baz", vec![])
+            ])
+        );
+    }
+}
@@ -0,0 +1,362 @@
+//! Python bindings for crible-lib, built with maturin as the `crible`
+//! module, see `pyproject.toml`.
+//!
+//! Bits are exchanged as one dimensional `numpy.uint32` arrays. Methods
+//! accepting bits also take any sequence of integers but arrays with the
+//! right dtype avoid a copy.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crible_lib::expression::validate_property_name;
+use numpy::{IntoPyArray, PyArray1, PyReadonlyArray1};
+use pyo3::basic::CompareOp;
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+create_exception!(crible, CribleError, PyException);
+create_exception!(crible, InvalidQuery, CribleError);
+create_exception!(crible, PropertyDoesNotExist, CribleError);
+create_exception!(crible, EncodingError, CribleError);
+
+struct Failure(PyErr);
+
+impl From<crible_lib::expression::Error> for Failure {
+    fn from(e: crible_lib::expression::Error) -> Self {
+        Self(InvalidQuery::new_err(e.to_string()))
+    }
+}
+
+impl From<crible_lib::index::Error> for Failure {
+    fn from(e: crible_lib::index::Error) -> Self {
+        Self(PropertyDoesNotExist::new_err(e.to_string()))
+    }
+}
+
+impl From<crible_lib::encoding::Error> for Failure {
+    fn from(e: crible_lib::encoding::Error) -> Self {
+        let mut message = e.to_string();
+        let mut source = std::error::Error::source(&e);
+        while let Some(e) = source {
+            message.push_str(&format!(": {}", e));
+            source = e.source();
+        }
+        Self(EncodingError::new_err(message))
+    }
+}
+
+impl From<Failure> for PyErr {
+    fn from(failure: Failure) -> Self {
+        failure.0
+    }
+}
+
+fn check_property(property: &str) -> PyResult<()> {
+    if validate_property_name(property) {
+        Ok(())
+    } else {
+        Err(PyValueError::new_err(format!(
+            "invalid property name {:?}",
+            property
+        )))
+    }
+}
+
+/// Bits passed in from Python.
+#[derive(FromPyObject)]
+enum Bits<'py> {
+    Array(PyReadonlyArray1<'py, u32>),
+    Sequence(Vec<u32>),
+}
+
+impl Bits<'_> {
+    fn as_slice(&self) -> Cow<'_, [u32]> {
+        match self {
+            Self::Array(array) => match array.as_slice() {
+                Ok(bits) => Cow::Borrowed(bits),
+                // Strided views must be copied.
+                Err(_) => Cow::Owned(array.as_array().to_vec()),
+            },
+            Self::Sequence(bits) => Cow::Borrowed(bits),
+        }
+    }
+}
+
+/// Queries can be passed as strings or already parsed expressions.
+#[derive(FromPyObject)]
+enum Query {
+    Expression(PyExpression),
+    Text(String),
+}
+
+impl Query {
+    fn into_expression(self) -> Result<crible_lib::Expression, Failure> {
+        match self {
+            Self::Expression(e) => Ok(e.0),
+            Self::Text(text) => Ok(crible_lib::Expression::parse(&text)?),
+        }
+    }
+}
+
+/// A parsed query.
+#[pyclass(name = "Expression", module = "crible")]
+#[derive(Clone, PartialEq, Eq)]
+struct PyExpression(crible_lib::Expression);
+
+#[pymethods]
+impl PyExpression {
+    #[new]
+    fn new(query: &str) -> Result<Self, Failure> {
+        Ok(Self(crible_lib::Expression::parse(query)?))
+    }
+
+    /// Expression matching every bit set in the index.
+    #[staticmethod]
+    fn root() -> Self {
+        Self(crible_lib::Expression::Root)
+    }
+
+    #[staticmethod]
+    fn property(name: &str) -> PyResult<Self> {
+        check_property(name)?;
+        Ok(Self(crible_lib::Expression::property(name)))
+    }
+
+    /// Sorted properties referenced by the expression.
+    fn properties(&self) -> Vec<String> {
+        self.0.properties().into_iter().map(|p| p.to_owned()).collect()
+    }
+
+    fn __str__(&self) -> String {
+        self.0.serialize()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Expression({:?})", self.0.serialize())
+    }
+
+    fn __richcmp__(&self, other: &Self, op: CompareOp, py: Python) -> PyObject {
+        match op {
+            CompareOp::Eq => (self == other).into_py(py),
+            CompareOp::Ne => (self != other).into_py(py),
+            _ => py.NotImplemented(),
+        }
+    }
+
+    fn __or__(&self, other: &Self) -> Self {
+        Self(self.0.clone() | other.0.clone())
+    }
+
+    fn __and__(&self, other: &Self) -> Self {
+        Self(self.0.clone() & other.0.clone())
+    }
+
+    fn __xor__(&self, other: &Self) -> Self {
+        Self(self.0.clone() ^ other.0.clone())
+    }
+
+    fn __sub__(&self, other: &Self) -> Self {
+        Self(self.0.clone() - other.0.clone())
+    }
+
+    fn __invert__(&self) -> Self {
+        Self(!self.0.clone())
+    }
+}
+
+/// Encoding formats, see `crible_lib::Encoder`.
+#[pyclass(name = "Encoder", module = "crible")]
+#[derive(Clone, Copy)]
+enum PyEncoder {
+    Json,
+    Bin,
+}
+
+impl From<PyEncoder> for crible_lib::Encoder {
+    fn from(encoder: PyEncoder) -> Self {
+        match encoder {
+            PyEncoder::Json => Self::Json,
+            PyEncoder::Bin => Self::Bin,
+        }
+    }
+}
+
+#[pymethods]
+impl PyEncoder {
+    /// Parse an encoder name as accepted by the crible CLI, e.g. `json`.
+    #[staticmethod]
+    fn parse(value: &str) -> Result<Self, Failure> {
+        Ok(match crible_lib::Encoder::from_str(value)? {
+            crible_lib::Encoder::Json => Self::Json,
+            crible_lib::Encoder::Bin => Self::Bin,
+        })
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<PyIndex, Failure> {
+        Ok(PyIndex(crible_lib::Encoder::from(*self).decode(data)?))
+    }
+
+    fn encode<'py>(
+        &self,
+        py: Python<'py>,
+        index: &PyIndex,
+    ) -> Result<&'py PyBytes, Failure> {
+        let mut buffer = Vec::new();
+        crible_lib::Encoder::from(*self).encode(&mut buffer, &index.0)?;
+        Ok(PyBytes::new(py, &buffer))
+    }
+}
+
+/// A crible index mapping properties to sets of bits.
+#[pyclass(name = "Index", module = "crible")]
+#[derive(Default)]
+struct PyIndex(crible_lib::Index);
+
+#[pymethods]
+impl PyIndex {
+    #[new]
+    fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build an index from a mapping of properties to bits.
+    #[staticmethod]
+    fn from_dict(properties: HashMap<String, Bits>) -> PyResult<Self> {
+        let mut index = crible_lib::Index::with_capacity(properties.len());
+        for (property, bits) in &properties {
+            check_property(property)?;
+            index.set_many(property, &bits.as_slice());
+        }
+        Ok(Self(index))
+    }
+
+    fn to_dict<'py>(
+        &self,
+        py: Python<'py>,
+    ) -> HashMap<String, &'py PyArray1<u32>> {
+        self.0
+            .iter()
+            .map(|(k, v)| (k.to_owned(), v.to_vec().into_pyarray(py)))
+            .collect()
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (path, encoder = PyEncoder::Bin))]
+    fn load(path: &str, encoder: PyEncoder) -> Result<Self, Failure> {
+        let encoder = crible_lib::Encoder::from(encoder);
+        Ok(Self(encoder.load_index_from_file(path)?))
+    }
+
+    #[pyo3(signature = (path, encoder = PyEncoder::Bin))]
+    fn save(&self, path: &str, encoder: PyEncoder) -> Result<(), Failure> {
+        let encoder = crible_lib::Encoder::from(encoder);
+        Ok(encoder.save_index_from_file(path, &self.0)?)
+    }
+
+    fn __len__(&self) -> usize {
+        self.0.len()
+    }
+
+    fn __contains__(&self, property: &str) -> bool {
+        self.0.get_property(property).is_some()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<Index with {} properties>", self.0.len())
+    }
+
+    /// Sorted list of properties.
+    fn properties(&self) -> Vec<String> {
+        let mut properties: Vec<_> =
+            self.0.inner().keys().map(|k| k.to_string()).collect();
+        properties.sort();
+        properties
+    }
+
+    /// Bits set for `property`, `None` if it does not exist.
+    fn get<'py>(
+        &self,
+        py: Python<'py>,
+        property: &str,
+    ) -> Option<&'py PyArray1<u32>> {
+        self.0
+            .get_property(property)
+            .map(|bitmap| bitmap.to_vec().into_pyarray(py))
+    }
+
+    fn set(&mut self, property: &str, bit: u32) -> PyResult<bool> {
+        check_property(property)?;
+        Ok(self.0.set(property, bit))
+    }
+
+    fn set_many(&mut self, property: &str, bits: Bits) -> PyResult<()> {
+        check_property(property)?;
+        self.0.set_many(property, &bits.as_slice());
+        Ok(())
+    }
+
+    fn unset(&mut self, property: &str, bit: u32) -> bool {
+        self.0.unset(property, bit)
+    }
+
+    fn unset_many(&mut self, property: &str, bits: Bits) {
+        self.0.unset_many(property, &bits.as_slice());
+    }
+
+    fn delete(&mut self, property: &str) -> bool {
+        self.0.delete_property(property)
+    }
+
+    /// Bits matching `query`, in increasing order.
+    fn execute<'py>(
+        &self,
+        py: Python<'py>,
+        query: Query,
+    ) -> Result<&'py PyArray1<u32>, Failure> {
+        let expression = query.into_expression()?;
+        let bits = py.allow_threads(|| {
+            self.0.execute(&expression).map(|bitmap| bitmap.to_vec())
+        })?;
+        Ok(bits.into_pyarray(py))
+    }
+
+    fn count(&self, py: Python, query: Query) -> Result<u64, Failure> {
+        let expression = query.into_expression()?;
+        Ok(py.allow_threads(|| {
+            self.0.execute(&expression).map(|bitmap| bitmap.cardinality())
+        })?)
+    }
+
+    /// Number of bits matching `query` for every property starting with
+    /// `prefix`, properties without any match are omitted.
+    #[pyo3(signature = (query, prefix = None))]
+    fn cardinalities(
+        &self,
+        py: Python,
+        query: Query,
+        prefix: Option<&str>,
+    ) -> Result<HashMap<String, u64>, Failure> {
+        let expression = query.into_expression()?;
+        Ok(py.allow_threads(|| {
+            self.0
+                .execute(&expression)
+                .map(|bitmap| self.0.cardinalities(&bitmap, prefix))
+        })?)
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "crible")]
+fn module(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<PyIndex>()?;
+    m.add_class::<PyExpression>()?;
+    m.add_class::<PyEncoder>()?;
+    m.add("CribleError", py.get_type::<CribleError>())?;
+    m.add("InvalidQuery", py.get_type::<InvalidQuery>())?;
+    m.add("PropertyDoesNotExist", py.get_type::<PropertyDoesNotExist>())?;
+    m.add("EncodingError", py.get_type::<EncodingError>())?;
+    Ok(())
+}
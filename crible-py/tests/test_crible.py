@@ -0,0 +1,83 @@
+import numpy as np
+import pytest
+
+import crible
+
+
+@pytest.fixture
+def index():
+    return crible.Index.from_dict(
+        {
+            "foo": np.array([1, 2, 3], dtype=np.uint32),
+            "bar": [3, 4],
+        }
+    )
+
+
+def test_index(index):
+    assert len(index) == 2
+    assert "foo" in index
+    assert "baz" not in index
+    assert index.properties() == ["bar", "foo"]
+    assert index.get("baz") is None
+
+    bits = index.get("foo")
+    assert bits.dtype == np.uint32
+    assert bits.tolist() == [1, 2, 3]
+
+
+def test_set_unset(index):
+    assert index.set("baz", 1)
+    assert not index.set("baz", 1)
+    index.set_many("baz", np.arange(10, 20, 2, dtype=np.uint32))
+    index.unset_many("baz", np.array([10, 12], dtype=np.uint64))
+    assert index.unset("baz", 1)
+    assert index.get("baz").tolist() == [14, 16, 18]
+    assert index.delete("baz")
+    assert not index.delete("baz")
+
+    with pytest.raises(ValueError):
+        index.set("1baz", 1)
+
+
+def test_execute(index):
+    assert index.execute("foo - bar").tolist() == [1, 2]
+    assert index.execute(crible.Expression("foo and bar")).tolist() == [3]
+    assert index.count("foo or bar") == 4
+    assert index.cardinalities("foo") == {"foo": 3, "bar": 1}
+    assert index.cardinalities("foo", prefix="b") == {"bar": 1}
+
+    with pytest.raises(crible.InvalidQuery):
+        index.execute("foo and")
+
+    with pytest.raises(crible.PropertyDoesNotExist):
+        index.count("baz")
+
+
+def test_expression():
+    foo, bar = crible.Expression.property("foo"), crible.Expression("bar")
+    assert str(foo | bar) == "(foo or bar)"
+    assert str(foo - ~bar) == "(foo - not (bar))"
+    assert (foo & bar).properties() == ["bar", "foo"]
+    assert crible.Expression("foo or bar") == foo | bar
+    assert repr(foo) == 'Expression("foo")'
+
+
+@pytest.mark.parametrize("encoder", [crible.Encoder.Bin, crible.Encoder.Json])
+def test_encoder(index, encoder, tmp_path):
+    decoded = encoder.decode(encoder.encode(index))
+    assert decoded.to_dict().keys() == index.to_dict().keys()
+
+    path = str(tmp_path / "index")
+    index.save(path, encoder)
+    assert crible.Index.load(path, encoder).get("bar").tolist() == [3, 4]
+
+    with pytest.raises(crible.EncodingError):
+        crible.Index.load(str(tmp_path / "missing"), encoder)
+
+
+def test_parse_encoder():
+    assert crible.Encoder.parse("ndjson") == crible.Encoder.Json
+
+    with pytest.raises(crible.EncodingError):
+        crible.Encoder.parse("csv")
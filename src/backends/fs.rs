@@ -1,8 +1,9 @@
 use std::fs;
+use std::time::SystemTime;
 
 use crible_lib::{Encoder, Index};
 
-use super::Backend;
+use super::{Backend, Metadata};
 
 // TODO: Use buffered read and writes.
 
@@ -50,6 +51,14 @@ impl FSBackend {
 
         Ok(self.encoder.decode(f)?)
     }
+
+    /// Property metadata is stored as JSON next to the index, in
+    /// `<path>.meta.json`.
+    fn metadata_path(&self) -> std::path::PathBuf {
+        let mut path = self.path.clone();
+        crate::utils::add_extension(&mut path, "meta.json");
+        path
+    }
 }
 
 impl Backend for FSBackend {
@@ -62,10 +71,49 @@ impl Backend for FSBackend {
     }
 
     fn clear(&self) -> Result<(), eyre::Report> {
-        match fs::remove_file(&self.path) {
-            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
-            x => x,
-        }?;
+        for path in [&self.path, &self.metadata_path()] {
+            match fs::remove_file(path) {
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    Ok(())
+                }
+                x => x,
+            }?;
+        }
+        Ok(())
+    }
+
+    fn ping(&self) -> Result<(), eyre::Report> {
+        fs::metadata(&self.path)?;
+        Ok(())
+    }
+
+    /// Latest modification time of the index and metadata files.
+    fn last_modified(&self) -> Result<Option<SystemTime>, eyre::Report> {
+        let index = fs::metadata(&self.path)?.modified()?;
+        let metadata = match fs::metadata(self.metadata_path()) {
+            Ok(m) => Some(m.modified()?),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(metadata.map_or(index, |m| m.max(index))))
+    }
+
+    fn load_metadata(&self) -> Result<Metadata, eyre::Report> {
+        match fs::read(self.metadata_path()) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(Metadata::new())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn dump_metadata(&self, metadata: &Metadata) -> Result<(), eyre::Report> {
+        let path = self.metadata_path();
+        let tmp = crate::utils::tmp_path(&path);
+        fs::create_dir_all(self.path.parent().unwrap())?;
+        fs::write(&tmp, serde_json::to_vec(metadata)?)?;
+        fs::rename(&tmp, &path)?;
         Ok(())
     }
 }
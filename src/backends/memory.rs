@@ -2,10 +2,10 @@ use std::sync::RwLock;
 
 use crible_lib::index::Index;
 
-use super::Backend;
+use super::{Backend, Metadata};
 
 #[derive(Default, Debug)]
-pub struct Memory(RwLock<Index>);
+pub struct Memory(RwLock<Index>, RwLock<Metadata>);
 
 // TODO: Does this even need a copy?
 
@@ -22,6 +22,20 @@ impl Backend for Memory {
 
     fn clear(&self) -> Result<(), eyre::Report> {
         self.0.write().unwrap().clear();
+        self.1.write().unwrap().clear();
+        Ok(())
+    }
+
+    fn ping(&self) -> Result<(), eyre::Report> {
+        Ok(())
+    }
+
+    fn load_metadata(&self) -> Result<Metadata, eyre::Report> {
+        Ok(self.1.read().unwrap().clone())
+    }
+
+    fn dump_metadata(&self, metadata: &Metadata) -> Result<(), eyre::Report> {
+        *self.1.write().unwrap() = metadata.clone();
         Ok(())
     }
 }
@@ -1,6 +1,7 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::SystemTime;
 
 use crible_lib::{Encoder, Index};
 use url::{Host, Url};
@@ -16,6 +17,11 @@ pub use self::redis::Redis;
 static DEFAULT_FS_LOCATION: &str = "data.bin";
 static DEFAULT_REDIS_PREFIX: &str = "crible";
 
+/// Arbitrary JSON attached to properties by clients, e.g. labels or display
+/// order. It is stored next to the index but never interpreted, and may
+/// refer to properties which do not exist in the index.
+pub type Metadata = BTreeMap<String, serde_json::Value>;
+
 // Munge a url in a filesystem path.
 // This is not great and makes many, likely wrong assumptions about paths but it
 // allows a consistent and fairly ergonomic interface between backends.
@@ -46,7 +52,32 @@ fn single_path_from_url(url: &Url) -> Result<Option<PathBuf>, eyre::Report> {
 pub trait Backend: Send + Sync + std::fmt::Debug {
     fn load(&self) -> Result<Index, eyre::Report>;
     fn dump(&self, index: &Index) -> Result<(), eyre::Report>;
+    /// Persist only the given properties, removing the ones which no longer
+    /// exist in the index. Backends which cannot write individual properties
+    /// dump the whole index.
+    fn dump_partial(
+        &self,
+        index: &Index,
+        _properties: &HashSet<String>,
+    ) -> Result<(), eyre::Report> {
+        self.dump(index)
+    }
     fn clear(&self) -> Result<(), eyre::Report>;
+    /// Check that the backend is reachable without loading any data.
+    fn ping(&self) -> Result<(), eyre::Report>;
+    /// When the stored data was last modified, `None` when unknown. Used to
+    /// skip reloading unmodified data.
+    fn last_modified(&self) -> Result<Option<SystemTime>, eyre::Report> {
+        Ok(None)
+    }
+    /// Property metadata, empty when none was stored.
+    fn load_metadata(&self) -> Result<Metadata, eyre::Report> {
+        Ok(Metadata::new())
+    }
+    /// Replace the stored property metadata.
+    fn dump_metadata(&self, _metadata: &Metadata) -> Result<(), eyre::Report> {
+        Err(eyre::Report::msg("Backend does not support property metadata"))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
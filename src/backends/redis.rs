@@ -1,11 +1,13 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crible_lib::index::Index;
 use croaring::Bitmap;
 use eyre::Context;
+use rayon::prelude::*;
 use redis::Commands;
 
-use super::Backend;
+use super::{Backend, Metadata};
 
 #[derive(Debug)]
 pub struct Redis {
@@ -22,14 +24,54 @@ impl Redis {
             key,
         })
     }
+
+    /// Property metadata is stored as JSON strings in a separate hash.
+    fn metadata_key(&self) -> String {
+        format!("{}:meta", self.key)
+    }
+
+    /// Time of the last write, in milliseconds since the Unix epoch, updated
+    /// along with the data, see `Backend::last_modified`.
+    fn modified_key(&self) -> String {
+        format!("{}:modified", self.key)
+    }
+
+    fn mark_modified(&self, pipe: &mut redis::Pipeline) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        pipe.set(self.modified_key(), now);
+    }
 }
 
 impl Backend for Redis {
     fn dump<'a>(&self, index: &Index) -> Result<(), eyre::Report> {
         let mut pipe = redis::pipe();
-        for (k, v) in index.inner() {
+        for (k, v) in index {
             pipe.hset(&self.key, k, v.serialize());
         }
+        self.mark_modified(&mut pipe);
+        let mut con = self.client.get_connection()?;
+        pipe.query(&mut con)?;
+        Ok(())
+    }
+
+    fn dump_partial(
+        &self,
+        index: &Index,
+        properties: &HashSet<String>,
+    ) -> Result<(), eyre::Report> {
+        if properties.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        for property in properties {
+            match index.get_property(property) {
+                Some(bm) => pipe.hset(&self.key, property, bm.serialize()),
+                None => pipe.hdel(&self.key, property),
+            };
+        }
+        self.mark_modified(&mut pipe);
         let mut con = self.client.get_connection()?;
         pipe.query(&mut con)?;
         Ok(())
@@ -38,16 +80,65 @@ impl Backend for Redis {
     fn load(&self) -> Result<Index, eyre::Report> {
         let mut con = self.client.get_connection()?;
         let data: HashMap<String, Vec<u8>> = con.hgetall(&self.key)?;
+        // Deserializing dominates load time for large indices.
         Ok(Index::new(
-            data.iter()
-                .map(|(k, v)| (k.clone(), Bitmap::deserialize(v)))
-                .collect(),
+            data.into_par_iter()
+                .map(|(k, v)| match Bitmap::try_deserialize(&v) {
+                    Some(bm) => Ok((k, bm)),
+                    None => Err(eyre::Report::msg(format!(
+                        "Invalid bitmap for property {:?}",
+                        k
+                    ))),
+                })
+                .collect::<Result<_, _>>()?,
         ))
     }
 
     fn clear(&self) -> Result<(), eyre::Report> {
         let mut con = self.client.get_connection()?;
-        con.del(&self.key)?;
+        con.del(vec![
+            self.key.clone(),
+            self.metadata_key(),
+            self.modified_key(),
+        ])?;
+        Ok(())
+    }
+
+    fn ping(&self) -> Result<(), eyre::Report> {
+        let mut con = self.client.get_connection()?;
+        redis::cmd("PING").query::<()>(&mut con)?;
+        Ok(())
+    }
+
+    fn last_modified(&self) -> Result<Option<SystemTime>, eyre::Report> {
+        let mut con = self.client.get_connection()?;
+        let millis: Option<u64> = con.get(self.modified_key())?;
+        Ok(millis.map(|ms| UNIX_EPOCH + Duration::from_millis(ms)))
+    }
+
+    fn load_metadata(&self) -> Result<Metadata, eyre::Report> {
+        let mut con = self.client.get_connection()?;
+        let data: HashMap<String, String> = con.hgetall(self.metadata_key())?;
+        data.into_iter()
+            .map(|(k, v)| {
+                let value = serde_json::from_str(&v).wrap_err_with(|| {
+                    format!("Invalid metadata for property {:?}", k)
+                })?;
+                Ok((k, value))
+            })
+            .collect()
+    }
+
+    fn dump_metadata(&self, metadata: &Metadata) -> Result<(), eyre::Report> {
+        let key = self.metadata_key();
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(&key);
+        for (k, v) in metadata {
+            pipe.hset(&key, k, serde_json::to_string(v)?);
+        }
+        self.mark_modified(&mut pipe);
+        let mut con = self.client.get_connection()?;
+        pipe.query(&mut con)?;
         Ok(())
     }
 }
@@ -0,0 +1,217 @@
+use std::collections::{HashSet, VecDeque};
+use std::ops::Range;
+
+use serde_derive::Serialize;
+
+/// Modification of the index, published by the executor to subscribers after
+/// every write and reload.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Change {
+    Mutation {
+        /// Name of the operation which modified the index.
+        operation: &'static str,
+        /// Affected properties, `None` when the mutation may have affected
+        /// every property.
+        properties: Option<Vec<String>>,
+        /// Affected bits.
+        bits: Vec<u32>,
+        /// Affected range of bits, for mutations which are too large to list
+        /// individual bits.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        range: Option<Range<u32>>,
+    },
+    Reload,
+}
+
+impl Change {
+    pub fn mutation(
+        operation: &'static str,
+        properties: Option<Vec<String>>,
+        bits: Vec<u32>,
+    ) -> Self {
+        Change::Mutation { operation, properties, bits, range: None }
+    }
+
+    pub fn range_mutation(
+        operation: &'static str,
+        properties: Option<Vec<String>>,
+        range: Range<u32>,
+    ) -> Self {
+        Change::Mutation {
+            operation,
+            properties,
+            bits: vec![],
+            range: Some(range),
+        }
+    }
+
+    /// Whether this change may have affected any property starting with
+    /// `prefix`.
+    pub fn affects_prefix(&self, prefix: &str) -> bool {
+        match self {
+            Change::Reload => true,
+            Change::Mutation { properties: None, .. } => true,
+            Change::Mutation { properties: Some(properties), .. } => {
+                properties.iter().any(|p| p.starts_with(prefix))
+            }
+        }
+    }
+}
+
+/// Properties modified by `events`, `None` if any of them may have modified
+/// every property.
+pub fn modified_properties(events: &[Event]) -> Option<HashSet<String>> {
+    let mut modified = HashSet::new();
+    for event in events {
+        match &event.change {
+            Change::Mutation { properties: Some(properties), .. } => {
+                modified.extend(properties.iter().cloned());
+            }
+            _ => return None,
+        }
+    }
+    Some(modified)
+}
+
+/// Change along with its generation, as returned by `ChangeLog::since`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Event {
+    pub generation: u64,
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+/// Most recent changes, numbered in the order they were published starting
+/// from 1, so that consumers can resume from the last change they
+/// processed. Generations start over when the server restarts, `id` tells
+/// logs apart.
+#[derive(Debug)]
+pub struct ChangeLog {
+    id: String,
+    capacity: usize,
+    events: VecDeque<Event>,
+    generation: u64,
+}
+
+impl ChangeLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            id: ulid::Ulid::new().to_string(),
+            capacity,
+            events: VecDeque::with_capacity(capacity),
+            generation: 0,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Generation of the most recent change, 0 if there are none.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Record `change`, dropping the oldest change if the log is full.
+    /// Returns the generation of `change`.
+    pub fn push(&mut self, change: Change) -> u64 {
+        self.generation += 1;
+        if self.events.len() >= self.capacity {
+            self.events.pop_front();
+        }
+        if self.capacity > 0 {
+            self.events
+                .push_back(Event { generation: self.generation, change });
+        }
+        self.generation
+    }
+
+    /// Changes published after generation `since`, oldest first. `None`
+    /// when some of them were dropped from the log or `since` is unknown,
+    /// e.g. it comes from before a restart: the consumer must then start
+    /// over from the whole index.
+    pub fn since(&self, since: u64) -> Option<Vec<Event>> {
+        let oldest = self.generation + 1 - self.events.len() as u64;
+        if since > self.generation || since + 1 < oldest {
+            return None;
+        }
+        Some(
+            self.events
+                .iter()
+                .skip((since + 1 - oldest) as usize)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::{modified_properties, Change, ChangeLog, Event};
+
+    #[test]
+    fn test_affects_prefix() {
+        let change = Change::mutation(
+            "set",
+            Some(vec!["country:fr".to_owned(), "lang:fr".to_owned()]),
+            vec![1],
+        );
+        assert!(change.affects_prefix("country:"));
+        assert!(change.affects_prefix("lang:"));
+        assert!(!change.affects_prefix("device:"));
+
+        assert!(
+            Change::mutation("delete-bits", None, vec![1])
+                .affects_prefix("device:")
+        );
+        assert!(Change::Reload.affects_prefix("device:"));
+    }
+
+    #[rstest]
+    #[case(0, None)]
+    #[case(1, None)]
+    #[case(2, Some(vec![3, 4, 5]))]
+    #[case(4, Some(vec![5]))]
+    #[case(5, Some(vec![]))]
+    #[case(6, None)]
+    fn test_change_log(#[case] since: u64, #[case] expected: Option<Vec<u64>>) {
+        let mut log = ChangeLog::new(3);
+        for bit in 0..5 {
+            log.push(Change::mutation("set", None, vec![bit]));
+        }
+        assert_eq!(log.generation(), 5);
+        assert_eq!(
+            log.since(since)
+                .map(|events| events.iter().map(|e| e.generation).collect()),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_modified_properties() {
+        let event = |change| Event { generation: 1, change };
+        let set = |property: &str| {
+            event(Change::mutation(
+                "set",
+                Some(vec![property.to_owned()]),
+                vec![1],
+            ))
+        };
+        assert_eq!(
+            modified_properties(&[set("foo"), set("bar"), set("foo")]),
+            Some(["foo".to_owned(), "bar".to_owned()].into())
+        );
+        assert_eq!(modified_properties(&[]), Some(Default::default()));
+        assert_eq!(
+            modified_properties(&[
+                set("foo"),
+                event(Change::mutation("delete-bits", None, vec![1]))
+            ]),
+            None
+        );
+        assert_eq!(modified_properties(&[event(Change::Reload)]), None);
+    }
+}
@@ -0,0 +1,244 @@
+use std::str::FromStr;
+
+use crible_lib::expression::validate_property_name;
+use crible_lib::Index;
+use eyre::{Context, Report};
+
+use crate::backends::BackendOptions;
+
+/// Property name pattern where `*` matches any sequence of characters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PropertyPattern(String);
+
+impl PropertyPattern {
+    pub fn matches(&self, property: &str) -> bool {
+        let mut parts = self.0.split('*');
+        // There is always at least one part, possibly empty.
+        let first = parts.next().unwrap_or_default();
+        let mut rest = match property.strip_prefix(first) {
+            Some(rest) => rest,
+            None => return false,
+        };
+
+        let parts: Vec<&str> = parts.collect();
+        match parts.split_last() {
+            // No wildcard.
+            None => rest.is_empty(),
+            Some((last, middle)) => {
+                for part in middle {
+                    match rest.find(part) {
+                        Some(i) => rest = &rest[i + part.len()..],
+                        None => return false,
+                    }
+                }
+                rest.ends_with(last)
+            }
+        }
+    }
+}
+
+impl FromStr for PropertyPattern {
+    type Err = std::convert::Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(Self(value.to_owned()))
+    }
+}
+
+/// Replace the `from` prefix of matching properties with `to`, formatted as
+/// `<from>=<to>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rename {
+    pub from: String,
+    pub to: String,
+}
+
+impl FromStr for Rename {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once('=') {
+            Some((from, to)) => {
+                Ok(Self { from: from.to_owned(), to: to.to_owned() })
+            }
+            None => Err(eyre::Report::msg(format!(
+                "Invalid rename {:?}, expected <from>=<to>",
+                value
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Selection {
+    /// Only keep properties matching one of these, keep everything if empty.
+    pub include: Vec<PropertyPattern>,
+    /// Drop properties matching any of these, even if included.
+    pub exclude: Vec<PropertyPattern>,
+    /// Applied after filtering, the first matching rename wins.
+    pub rename: Vec<Rename>,
+}
+
+impl Selection {
+    /// Name of `property` in the converted index, if it is kept.
+    fn apply(&self, property: &str) -> Option<String> {
+        let included = self.include.is_empty()
+            || self.include.iter().any(|p| p.matches(property));
+        if !included || self.exclude.iter().any(|p| p.matches(property)) {
+            return None;
+        }
+        Some(
+            self.rename
+                .iter()
+                .find_map(|r| {
+                    property
+                        .strip_prefix(&r.from)
+                        .map(|rest| format!("{}{}", r.to, rest))
+                })
+                .unwrap_or_else(|| property.to_owned()),
+        )
+    }
+}
+
+/// Copy the selected properties from one backend to another, replacing the
+/// destination's content. With `dry_run` nothing is written and only the
+/// changes are printed.
+pub fn convert(
+    from: &BackendOptions,
+    to: &BackendOptions,
+    selection: &Selection,
+    dry_run: bool,
+) -> Result<(), Report> {
+    let from_backend = from.build().wrap_err("Invalid source backend")?;
+    let to_backend = to.build().wrap_err("Invalid destination backend")?;
+
+    let source = from_backend.load().wrap_err("Failed to load index")?;
+    let source_len = source.len();
+
+    let mut renames = vec![];
+    let mut index = select(source, selection, |from, to| {
+        if from != to {
+            renames.push(format!("{} -> {}", from, to));
+        }
+    })?;
+
+    eprintln!(
+        "Keeping {} of {} properties, renaming {}",
+        index.len(),
+        source_len,
+        renames.len()
+    );
+    if dry_run {
+        renames.sort_unstable();
+        for rename in renames {
+            eprintln!("  {}", rename);
+        }
+        return Ok(());
+    }
+
+    index.optimize();
+    to_backend.clear()?;
+    to_backend.dump(&index).wrap_err("Failed to dump index")?;
+    Ok(())
+}
+
+fn select(
+    source: Index,
+    selection: &Selection,
+    mut on_kept: impl FnMut(&str, &str),
+) -> Result<Index, Report> {
+    let mut index = Index::default();
+    for (property, bm) in source.into_inner() {
+        if let Some(name) = selection.apply(&property) {
+            if !validate_property_name(&name) {
+                return Err(eyre::Report::msg(format!(
+                    "Renaming {:?} results in invalid property {:?}",
+                    property, name
+                )));
+            }
+            if index.get_property(&name).is_some() {
+                return Err(eyre::Report::msg(format!(
+                    "Renaming {:?} results in duplicate property {:?}",
+                    property, name
+                )));
+            }
+            on_kept(&property, &name);
+            index.set_property(&name, bm);
+        }
+    }
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use crible_lib::Index;
+    use rstest::rstest;
+
+    use super::{select, PropertyPattern, Rename, Selection};
+
+    #[rstest]
+    #[case("foo", "foo", true)]
+    #[case("foo", "foobar", false)]
+    #[case("foo:*", "foo:bar", true)]
+    #[case("foo:*", "foo:", true)]
+    #[case("foo:*", "bar:foo", false)]
+    #[case("*:bar", "foo:bar", true)]
+    #[case("*:bar", "foo:baz", false)]
+    #[case("a*b*c", "axxbyyc", true)]
+    #[case("a*b*c", "acb", false)]
+    #[case("a*a", "a", false)]
+    #[case("*", "anything", true)]
+    fn test_pattern_matches(
+        #[case] pattern: &str,
+        #[case] property: &str,
+        #[case] expected: bool,
+    ) {
+        let pattern: PropertyPattern = pattern.parse().unwrap();
+        assert_eq!(pattern.matches(property), expected);
+    }
+
+    fn selection(include: &[&str], exclude: &[&str]) -> Selection {
+        Selection {
+            include: include.iter().map(|x| x.parse().unwrap()).collect(),
+            exclude: exclude.iter().map(|x| x.parse().unwrap()).collect(),
+            rename: vec![],
+        }
+    }
+
+    #[rstest]
+    #[case(selection(&[], &[]), vec!["a:foo", "a:bar", "b:foo"])]
+    #[case(selection(&["a:*"], &[]), vec!["a:foo", "a:bar"])]
+    #[case(selection(&["a:*"], &["*:bar"]), vec!["a:foo"])]
+    #[case(selection(&[], &["a:*"]), vec!["b:foo"])]
+    fn test_select(#[case] selection: Selection, #[case] expected: Vec<&str>) {
+        let source = Index::of([
+            ("a:foo", vec![1]),
+            ("a:bar", vec![2]),
+            ("b:foo", vec![3]),
+        ]);
+        let index = select(source, &selection, |_, _| ()).unwrap();
+        let mut properties: Vec<_> = index.iter().map(|(k, _)| k).collect();
+        properties.sort();
+        let mut expected = expected;
+        expected.sort();
+        assert_eq!(properties, expected);
+    }
+
+    #[test]
+    fn test_select_rename() {
+        let source = Index::of([("a:foo", vec![1]), ("b:foo", vec![3])]);
+        let mut selection = selection(&[], &[]);
+        selection.rename.push("a:=c:".parse::<Rename>().unwrap());
+
+        let index = select(source, &selection, |_, _| ()).unwrap();
+        assert_eq!(index, Index::of([("c:foo", vec![1]), ("b:foo", vec![3])]));
+    }
+
+    #[test]
+    fn test_select_rename_conflict() {
+        let source = Index::of([("a:foo", vec![1]), ("b:foo", vec![3])]);
+        let mut selection = selection(&[], &[]);
+        selection.rename.push("a:=b:".parse().unwrap());
+        assert!(select(source, &selection, |_, _| ()).is_err());
+    }
+}
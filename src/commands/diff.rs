@@ -0,0 +1,149 @@
+use std::io::Write;
+
+use crible_lib::Index;
+use eyre::{Context, Report};
+use serde_derive::Serialize;
+
+use crate::backends::BackendOptions;
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct PropertyDiff {
+    pub property: String,
+    /// Number of bits only set in the second index.
+    pub added: u64,
+    /// Number of bits only set in the first index.
+    pub removed: u64,
+}
+
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct IndexDiff {
+    /// Properties only present in the second index.
+    pub added: Vec<PropertyDiff>,
+    /// Properties only present in the first index.
+    pub removed: Vec<PropertyDiff>,
+    /// Properties present in both indices with different bits.
+    pub changed: Vec<PropertyDiff>,
+}
+
+impl IndexDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+    }
+}
+
+/// Compare the indices stored in 2 backends and print the differences.
+/// Returns whether there were any.
+pub fn diff(
+    a: &BackendOptions,
+    b: &BackendOptions,
+    json: bool,
+) -> Result<bool, Report> {
+    let left = a.build().wrap_err("Invalid first backend")?;
+    let right = b.build().wrap_err("Invalid second backend")?;
+    let left = left.load().wrap_err("Failed to load first index")?;
+    let right = right.load().wrap_err("Failed to load second index")?;
+
+    let diff = diff_indices(&left, &right);
+
+    let stdout = std::io::stdout();
+    let mut buffer = std::io::BufWriter::new(stdout.lock());
+    if json {
+        serde_json::to_writer(&mut buffer, &diff)?;
+        writeln!(buffer)?;
+    } else {
+        for d in &diff.added {
+            writeln!(buffer, "+ {} ({} bits)", d.property, d.added)?;
+        }
+        for d in &diff.removed {
+            writeln!(buffer, "- {} ({} bits)", d.property, d.removed)?;
+        }
+        for d in &diff.changed {
+            writeln!(
+                buffer,
+                "~ {} (+{} -{} bits)",
+                d.property, d.added, d.removed
+            )?;
+        }
+    }
+    buffer.flush()?;
+
+    Ok(!diff.is_empty())
+}
+
+pub(super) fn diff_indices(left: &Index, right: &Index) -> IndexDiff {
+    let mut diff = IndexDiff::default();
+
+    for (property, bm) in left {
+        match right.get_property(property) {
+            None => diff.removed.push(PropertyDiff {
+                property: property.to_owned(),
+                added: 0,
+                removed: bm.cardinality(),
+            }),
+            Some(other) if other != bm => diff.changed.push(PropertyDiff {
+                property: property.to_owned(),
+                added: other.andnot_cardinality(bm),
+                removed: bm.andnot_cardinality(other),
+            }),
+            Some(_) => {}
+        }
+    }
+
+    for (property, bm) in right {
+        if left.get_property(property).is_none() {
+            diff.added.push(PropertyDiff {
+                property: property.to_owned(),
+                added: bm.cardinality(),
+                removed: 0,
+            });
+        }
+    }
+
+    for x in [&mut diff.added, &mut diff.removed, &mut diff.changed] {
+        x.sort_unstable_by(|a, b| a.property.cmp(&b.property));
+    }
+
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use crible_lib::Index;
+
+    use super::{diff_indices, IndexDiff, PropertyDiff};
+
+    fn property_diff(property: &str, added: u64, removed: u64) -> PropertyDiff {
+        PropertyDiff { property: property.to_owned(), added, removed }
+    }
+
+    #[test]
+    fn test_diff_identical() {
+        let index = Index::of([("foo", vec![1, 2]), ("bar", vec![3])]);
+        assert!(diff_indices(&index, &index.clone()).is_empty());
+    }
+
+    #[test]
+    fn test_diff() {
+        let left = Index::of([
+            ("foo", vec![1, 2, 3]),
+            ("bar", vec![3]),
+            ("baz", vec![4]),
+        ]);
+        let right = Index::of([
+            ("foo", vec![2, 3, 4, 5]),
+            ("baz", vec![4]),
+            ("qux", vec![6, 7]),
+        ]);
+
+        assert_eq!(
+            diff_indices(&left, &right),
+            IndexDiff {
+                added: vec![property_diff("qux", 2, 0)],
+                removed: vec![property_diff("bar", 0, 1)],
+                changed: vec![property_diff("foo", 2, 1)],
+            }
+        );
+    }
+}
@@ -0,0 +1,303 @@
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crible_lib::Encoder;
+use eyre::Report;
+use url::Url;
+
+use crate::backends::BackendOptions;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Check {
+    status: Status,
+    message: String,
+    /// What to do about it.
+    hint: Option<String>,
+}
+
+impl Check {
+    fn ok<S: Into<String>>(message: S) -> Self {
+        Self { status: Status::Ok, message: message.into(), hint: None }
+    }
+
+    fn warning<S: Into<String>>(message: S, hint: Option<&str>) -> Self {
+        Self {
+            status: Status::Warning,
+            message: message.into(),
+            hint: hint.map(Into::into),
+        }
+    }
+
+    fn error<S: Into<String>>(message: S, hint: Option<&str>) -> Self {
+        Self {
+            status: Status::Error,
+            message: message.into(),
+            hint: hint.map(Into::into),
+        }
+    }
+}
+
+static URL_HINT: &str = "Backend urls look like `fs:///absolute/index.bin`, \
+                         `fs://relative/index.json`, \
+                         `redis://localhost:6379?prefix=crible` or `memory://`";
+
+/// Diagnose common configuration problems of a backend and optionally of a
+/// running server. Returns whether any problem was found.
+pub async fn doctor(
+    backend: &str,
+    server: Option<&Url>,
+) -> Result<bool, Report> {
+    let mut checks = vec![];
+
+    match backend.parse::<BackendOptions>() {
+        Err(e) => checks.push(Check::error(
+            format!("Invalid backend url {:?}: {}", backend, e),
+            Some(URL_HINT),
+        )),
+        Ok(options) => {
+            checks
+                .push(Check::ok(format!("Backend url {:?} is valid", backend)));
+            match &options {
+                BackendOptions::Memory => checks.push(Check::warning(
+                    "The memory backend starts empty and is lost on exit",
+                    None,
+                )),
+                BackendOptions::Fs { path, encoder } => {
+                    checks.extend(check_fs(path, *encoder))
+                }
+                BackendOptions::Redis { url, key } => {
+                    checks.extend(check_redis(url, key))
+                }
+            }
+        }
+    }
+
+    if let Some(server) = server {
+        checks.extend(check_server(server).await);
+    }
+
+    for check in &checks {
+        let label = match check.status {
+            Status::Ok => "ok",
+            Status::Warning => "warning",
+            Status::Error => "error",
+        };
+        println!("[{:>7}] {}", label, check.message);
+        if let Some(hint) = &check.hint {
+            println!("          {}", hint);
+        }
+    }
+
+    Ok(checks.iter().any(|c| c.status == Status::Error))
+}
+
+fn check_fs(path: &Path, encoder: Encoder) -> Vec<Check> {
+    let mut checks = vec![];
+    if path.is_relative() {
+        checks.push(Check::ok(format!(
+            "Relative path {:?} resolves to {:?}",
+            path,
+            std::env::current_dir().unwrap_or_default().join(path)
+        )));
+    }
+
+    let tmp = crate::utils::tmp_path(&path);
+    if tmp.exists() {
+        checks.push(Check::warning(
+            format!("Temporary file {:?} exists", tmp),
+            Some(
+                "Another process may be writing the index or a previous write \
+                 was interrupted. Delete it if no process is writing.",
+            ),
+        ));
+    }
+
+    match path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        Some(dir) if !dir.exists() => checks.push(Check::warning(
+            format!("Directory {:?} does not exist", dir),
+            Some("It is created on the first write."),
+        )),
+        parent => {
+            let dir = parent.unwrap_or_else(|| Path::new("."));
+            let probe = dir.join(".crible-doctor");
+            match fs::write(&probe, b"").and_then(|_| fs::remove_file(&probe)) {
+                Ok(()) => {
+                    checks.push(Check::ok(format!("{:?} is writable", dir)))
+                }
+                Err(e) => checks.push(Check::error(
+                    format!("{:?} is not writable: {}", dir, e),
+                    Some(
+                        "Writes and flushes will fail, use --read-only or fix \
+                         the permissions.",
+                    ),
+                )),
+            }
+        }
+    }
+
+    let file = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(e) => {
+            checks.push(Check::error(
+                format!("Cannot read {:?}: {}", path, e),
+                Some(
+                    "Create the index with `crible import`, `crible copy` or \
+                     `crible generate`.",
+                ),
+            ));
+            return checks;
+        }
+    };
+
+    let start = Instant::now();
+    match encoder.decode(file) {
+        Ok(index) => checks.push(Check::ok(format!(
+            "Decoded {} properties as {:?} in {:?}",
+            index.len(),
+            encoder,
+            start.elapsed()
+        ))),
+        Err(e) => {
+            let (other, format) = match encoder {
+                Encoder::Bin => (Encoder::Json, "json"),
+                Encoder::Json => (Encoder::Bin, "bin"),
+            };
+            let decodes_as_other =
+                fs::File::open(path).map_or(false, |f| other.decode(f).is_ok());
+            let hint = if decodes_as_other {
+                format!(
+                    "The file is valid {:?}, add `?format={}` to the url.",
+                    other, format
+                )
+            } else {
+                "Check that the file was written by crible.".to_owned()
+            };
+            checks.push(Check::error(
+                format!("Cannot decode {:?} as {:?}: {}", path, encoder, e),
+                Some(hint.as_str()),
+            ));
+        }
+    }
+    checks
+}
+
+fn check_redis(url: &Url, key: &str) -> Vec<Check> {
+    let mut checks = vec![];
+    let connection = redis::Client::open(url.to_string())
+        .and_then(|client| client.get_connection());
+    let mut con = match connection {
+        Ok(con) => con,
+        Err(e) => {
+            checks.push(Check::error(
+                format!("Cannot connect to {}: {}", url, e),
+                Some("Check that Redis is running and reachable from here."),
+            ));
+            return checks;
+        }
+    };
+
+    match redis::cmd("TYPE").arg(key).query::<String>(&mut con) {
+        Ok(t) if t == "hash" => {
+            let len: redis::RedisResult<usize> =
+                redis::cmd("HLEN").arg(key).query(&mut con);
+            checks.push(Check::ok(format!(
+                "Key {:?} holds {} properties",
+                key,
+                len.unwrap_or_default()
+            )));
+        }
+        Ok(t) if t == "none" => checks.push(Check::warning(
+            format!("Key {:?} does not exist", key),
+            Some("The index starts empty, check the `prefix` parameter."),
+        )),
+        Ok(t) => checks.push(Check::error(
+            format!("Key {:?} holds a {}, expected a hash", key, t),
+            Some("Use another key through the `prefix` parameter."),
+        )),
+        Err(e) => checks.push(Check::error(
+            format!("Cannot inspect key {:?}: {}", key, e),
+            Some("Check the permissions of the Redis user."),
+        )),
+    }
+    checks
+}
+
+async fn check_server(server: &Url) -> Vec<Check> {
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => return vec![Check::error(e.to_string(), None)],
+    };
+
+    let mut checks = vec![];
+    for route in ["healthz", "readyz"] {
+        let url = match server.join(route) {
+            Ok(url) => url,
+            Err(e) => {
+                checks.push(Check::error(
+                    format!("Invalid server url {}: {}", server, e),
+                    None,
+                ));
+                break;
+            }
+        };
+        match client.get(url.clone()).send().await {
+            Ok(res) if res.status().is_success() => checks
+                .push(Check::ok(format!("{} returned {}", url, res.status()))),
+            Ok(res) => {
+                let status = res.status();
+                let body = res.text().await.unwrap_or_default();
+                checks.push(Check::error(
+                    format!("{} returned {}: {}", url, status, body.trim()),
+                    None,
+                ));
+            }
+            Err(e) => checks.push(Check::error(
+                format!("Cannot reach {}: {}", url, e),
+                Some("Check the server address and that it is running."),
+            )),
+        }
+    }
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use crible_lib::{Encoder, Index};
+
+    use super::{check_fs, Status};
+
+    #[test]
+    fn test_check_fs() {
+        let dir = std::env::temp_dir().join(ulid::Ulid::new().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("index.json");
+
+        let checks = check_fs(&path, Encoder::Json);
+        assert_eq!(checks.last().unwrap().status, Status::Error);
+
+        Encoder::Json
+            .save_index_from_file(&path, &Index::of([("foo", vec![1])]))
+            .unwrap();
+        let checks = check_fs(&path, Encoder::Json);
+        assert!(checks.iter().all(|c| c.status == Status::Ok));
+
+        // Suggests the right format.
+        let checks = check_fs(&path, Encoder::Bin);
+        let last = checks.last().unwrap();
+        assert_eq!(last.status, Status::Error);
+        assert!(last.hint.as_ref().unwrap().contains("?format=json"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use crible_lib::{Encoder, Index};
+use eyre::{Context, Report};
+
+use crate::backends::Backend;
+
+/// Output formats of the export subcommand: the library encoders, plus CSV
+/// in the shape accepted by the import subcommand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Encoder(Encoder),
+    Csv,
+}
+
+impl FromStr for ExportFormat {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            x => Ok(Self::Encoder(x.parse()?)),
+        }
+    }
+}
+
+/// Load the index stored in `backend` and write it to `out`, `-` writes to
+/// stdout.
+pub fn export(
+    backend: &dyn Backend,
+    format: ExportFormat,
+    out: &Path,
+) -> Result<(), Report> {
+    let index = backend.load().wrap_err("Failed to load index")?;
+
+    let writer: Box<dyn Write> =
+        if out.as_os_str() == "-" {
+            Box::new(std::io::stdout().lock())
+        } else {
+            Box::new(File::create(out).wrap_err_with(|| {
+                format!("Failed to create {}", out.display())
+            })?)
+        };
+
+    write_index(&index, format, BufWriter::new(writer))
+        .wrap_err("Failed to export index")
+}
+
+fn write_index<W: Write>(
+    index: &Index,
+    format: ExportFormat,
+    mut w: W,
+) -> Result<(), Report> {
+    match format {
+        ExportFormat::Encoder(encoder) => encoder.encode(&mut w, index)?,
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(&mut w);
+            writer.write_record(["property", "id"])?;
+
+            let mut properties: Vec<_> = index.iter().collect();
+            properties.sort_unstable_by_key(|(k, _)| *k);
+
+            for (property, bm) in properties {
+                for bit in bm.iter() {
+                    writer
+                        .write_record([property, bit.to_string().as_str()])?;
+                }
+            }
+            writer.flush()?;
+        }
+    }
+    w.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crible_lib::{Encoder, Index};
+    use rstest::rstest;
+
+    use super::{write_index, ExportFormat};
+
+    #[rstest]
+    #[case("csv", ExportFormat::Csv)]
+    #[case("json", ExportFormat::Encoder(Encoder::Json))]
+    #[case("bin", ExportFormat::Encoder(Encoder::Bin))]
+    fn test_parse_format(#[case] value: &str, #[case] expected: ExportFormat) {
+        assert_eq!(value.parse::<ExportFormat>().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_parse_unknown_format() {
+        assert!("xml".parse::<ExportFormat>().is_err());
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let index = Index::of([("foo", vec![1, 2]), ("bar", vec![3])]);
+        let mut out = Vec::new();
+        write_index(&index, ExportFormat::Csv, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "property,id\nbar,3\nfoo,1\nfoo,2\n"
+        );
+    }
+
+    #[rstest]
+    #[case(Encoder::Json)]
+    #[case(Encoder::Bin)]
+    fn test_write_encoder_roundtrip(#[case] encoder: Encoder) {
+        let index = Index::of([("foo", vec![1, 2]), ("bar", vec![3])]);
+        let mut out = Vec::new();
+        write_index(&index, ExportFormat::Encoder(encoder), &mut out).unwrap();
+        assert_eq!(encoder.decode(out.as_slice()).unwrap(), index);
+    }
+}
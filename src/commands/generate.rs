@@ -0,0 +1,232 @@
+use std::str::FromStr;
+
+use crible_lib::expression::validate_property_name;
+use crible_lib::index::PropertyMap;
+use crible_lib::Index;
+use croaring::Bitmap;
+use eyre::{Context, Report};
+use rayon::prelude::*;
+
+use crate::backends::BackendOptions;
+
+/// How property sizes are distributed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Distribution {
+    /// All properties have the same size.
+    Uniform,
+    /// The size of the nth largest property is proportional to 1 / n^s, a
+    /// few large properties and a long tail of small ones as in most real
+    /// world facets.
+    #[default]
+    Zipf,
+}
+
+impl FromStr for Distribution {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "uniform" => Ok(Self::Uniform),
+            "zipf" => Ok(Self::Zipf),
+            _ => Err(eyre::Report::msg(format!(
+                "Invalid distribution {:?}, expected uniform or zipf",
+                value
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GenerateOptions {
+    pub properties: usize,
+    /// Bits are picked in `[0, bits)`.
+    pub bits: u32,
+    pub distribution: Distribution,
+    /// Share of the bits set in the largest property.
+    pub density: f64,
+    /// Exponent of the zipf distribution.
+    pub exponent: f64,
+    pub prefix: String,
+    pub seed: u64,
+}
+
+/// Small, fast and seedable generator (SplitMix64). Implemented here rather
+/// than depending on a random crate so that the same seed always produces
+/// the same index.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniformly distributed in `[0, n)`.
+    fn below(&mut self, n: u32) -> u32 {
+        (((self.next_u64() >> 32) * n as u64) >> 32) as u32
+    }
+}
+
+impl GenerateOptions {
+    fn validate(&self) -> Result<(), Report> {
+        if self.bits == 0 {
+            return Err(eyre::Report::msg(
+                "The number of bits must be positive",
+            ));
+        }
+        if !(self.density > 0.0 && self.density <= 1.0) {
+            return Err(eyre::Report::msg("The density must be in (0, 1]"));
+        }
+        if !validate_property_name(&format!("{}0", self.prefix)) {
+            return Err(eyre::Report::msg(format!(
+                "Invalid property prefix {:?}",
+                self.prefix
+            )));
+        }
+        Ok(())
+    }
+
+    /// Number of bits set for the property of rank `i`, starting at 0.
+    fn cardinality(&self, i: usize) -> u32 {
+        let largest = self.bits as f64 * self.density;
+        let size = match self.distribution {
+            Distribution::Uniform => largest,
+            Distribution::Zipf => {
+                largest / ((i + 1) as f64).powf(self.exponent)
+            }
+        };
+        (size.round() as u32).clamp(1, self.bits)
+    }
+
+    fn property(&self, i: usize) -> (String, Bitmap) {
+        let mut rng = Rng(self.seed ^ (i as u64).wrapping_mul(0x2545_F491));
+        let cardinality = self.cardinality(i) as u64;
+        let mut bm = Bitmap::create();
+        let mut batch = vec![];
+        while bm.cardinality() < cardinality {
+            batch.clear();
+            batch.extend(
+                (0..cardinality - bm.cardinality())
+                    .map(|_| rng.below(self.bits)),
+            );
+            bm.add_many(&batch);
+        }
+        (format!("{}{}", self.prefix, i), bm)
+    }
+
+    pub fn generate(&self) -> Result<Index, Report> {
+        self.validate()?;
+        let properties: PropertyMap = (0..self.properties)
+            .into_par_iter()
+            .map(|i| self.property(i))
+            .collect();
+        Ok(Index::new(properties))
+    }
+}
+
+/// Replace the index stored in a backend with generated data.
+pub fn generate(
+    backend_options: &BackendOptions,
+    options: &GenerateOptions,
+) -> Result<(), Report> {
+    let backend = backend_options.build().wrap_err("Invalid backend")?;
+    let mut index = options.generate()?;
+    index.optimize();
+
+    backend.clear()?;
+    backend.dump(&index).wrap_err("Failed to dump index")?;
+    eprintln!(
+        "Generated {} properties, {} bits set in total",
+        index.len(),
+        index.inner().values().map(|bm| bm.cardinality()).sum::<u64>()
+    );
+    Ok(())
+}
+
+/// Parse integers written as floats, e.g. `10e6`.
+pub fn parse_count(value: &str) -> Result<u32, Report> {
+    match value.parse::<u32>() {
+        Ok(n) => Ok(n),
+        Err(_) => {
+            let n: f64 = value
+                .parse()
+                .wrap_err_with(|| format!("Invalid number {:?}", value))?;
+            if n.fract() == 0.0 && (0.0..=u32::MAX as f64).contains(&n) {
+                Ok(n as u32)
+            } else {
+                Err(eyre::Report::msg(format!("Invalid number {:?}", value)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::{parse_count, Distribution, GenerateOptions};
+
+    fn options(distribution: Distribution, seed: u64) -> GenerateOptions {
+        GenerateOptions {
+            properties: 10,
+            bits: 1000,
+            distribution,
+            density: 0.5,
+            exponent: 1.0,
+            prefix: "p".to_owned(),
+            seed,
+        }
+    }
+
+    #[rstest]
+    #[case("1000", Some(1000))]
+    #[case("10e6", Some(10_000_000))]
+    #[case("1.5e3", Some(1500))]
+    #[case("1.5", None)]
+    #[case("-1", None)]
+    #[case("1e10", None)]
+    #[case("abc", None)]
+    fn test_parse_count(#[case] value: &str, #[case] expected: Option<u32>) {
+        assert_eq!(parse_count(value).ok(), expected);
+    }
+
+    #[test]
+    fn test_generate_zipf() {
+        let index = options(Distribution::Zipf, 0).generate().unwrap();
+        assert_eq!(index.len(), 10);
+        let cardinality = |i: usize| {
+            index.get_property(&format!("p{}", i)).unwrap().cardinality()
+        };
+        assert_eq!(cardinality(0), 500);
+        assert_eq!(cardinality(1), 250);
+        assert_eq!(cardinality(9), 50);
+        assert!(index.root().maximum().unwrap() < 1000);
+    }
+
+    #[test]
+    fn test_generate_uniform() {
+        let index = options(Distribution::Uniform, 0).generate().unwrap();
+        assert!(index.inner().values().all(|bm| bm.cardinality() == 500));
+    }
+
+    #[test]
+    fn test_generate_is_reproducible() {
+        let a = options(Distribution::Zipf, 42).generate().unwrap();
+        assert_eq!(a, options(Distribution::Zipf, 42).generate().unwrap());
+        assert_ne!(a, options(Distribution::Zipf, 43).generate().unwrap());
+    }
+
+    #[test]
+    fn test_generate_invalid() {
+        let mut options = options(Distribution::Zipf, 0);
+        options.density = 0.0;
+        assert!(options.generate().is_err());
+
+        options.density = 0.5;
+        options.prefix = "1".to_owned();
+        assert!(options.generate().is_err());
+    }
+}
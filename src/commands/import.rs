@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+use crible_lib::Index;
+use eyre::{Context, Report};
+
+use crate::backends::Backend;
+
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    /// CSV file to read, `-` reads from stdin.
+    pub from: PathBuf,
+    pub property_col: String,
+    pub id_col: String,
+    pub delimiter: u8,
+    /// Number of rows read before they are added to the index.
+    pub chunk_size: usize,
+    /// Start from an empty index instead of merging into the backend's.
+    pub replace: bool,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportStats {
+    pub rows: usize,
+    pub skipped: usize,
+}
+
+/// Add the rows of a CSV file, where every row pairs a property with a bit,
+/// to the index stored in `backend`.
+pub fn import(
+    backend: &dyn Backend,
+    options: &ImportOptions,
+) -> Result<(), Report> {
+    let mut index = if options.replace {
+        Index::default()
+    } else {
+        backend.load().wrap_err("Failed to load index")?
+    };
+
+    let reader: Box<dyn Read> = if options.from.as_os_str() == "-" {
+        Box::new(std::io::stdin().lock())
+    } else {
+        Box::new(File::open(&options.from).wrap_err_with(|| {
+            format!("Failed to open {}", options.from.display())
+        })?)
+    };
+
+    let stats = import_csv(&mut index, reader, options, |stats| {
+        eprintln!("Imported {} rows ({} skipped)", stats.rows, stats.skipped);
+    })?;
+
+    index.optimize();
+    backend.dump(&index).wrap_err("Failed to dump index")?;
+
+    eprintln!(
+        "Done: {} rows ({} skipped), {} properties",
+        stats.rows,
+        stats.skipped,
+        index.len()
+    );
+    Ok(())
+}
+
+/// Read `reader` in chunks of `options.chunk_size` rows, calling `progress`
+/// after every chunk. Rows with an empty property are skipped, rows with an
+/// invalid id are errors.
+fn import_csv<R: Read>(
+    index: &mut Index,
+    reader: R,
+    options: &ImportOptions,
+    mut progress: impl FnMut(&ImportStats),
+) -> Result<ImportStats, Report> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(options.delimiter)
+        .from_reader(reader);
+
+    let headers = reader.headers()?;
+    let column = |name: &str| {
+        headers.iter().position(|h| h == name).ok_or_else(|| {
+            eyre::eyre!("Column `{}` not found in {:?}", name, headers)
+        })
+    };
+    let property_idx = column(&options.property_col)?;
+    let id_idx = column(&options.id_col)?;
+
+    let mut stats = ImportStats::default();
+    let mut chunk: HashMap<String, Vec<u32>> = HashMap::new();
+    let mut chunk_rows = 0;
+
+    for (line, record) in reader.records().enumerate() {
+        let record = record?;
+        let (property, id) =
+            match (record.get(property_idx), record.get(id_idx)) {
+                (Some(p), Some(i)) if !p.is_empty() => (p, i),
+                _ => {
+                    stats.skipped += 1;
+                    continue;
+                }
+            };
+        let bit: u32 = id.trim().parse().wrap_err_with(|| {
+            format!("Invalid id `{}` on line {}", id, line + 2)
+        })?;
+
+        chunk.entry(property.to_owned()).or_default().push(bit);
+        chunk_rows += 1;
+
+        if chunk_rows >= options.chunk_size {
+            flush_chunk(index, &mut chunk);
+            stats.rows += chunk_rows;
+            chunk_rows = 0;
+            progress(&stats);
+        }
+    }
+
+    if chunk_rows > 0 {
+        flush_chunk(index, &mut chunk);
+        stats.rows += chunk_rows;
+        progress(&stats);
+    }
+
+    Ok(stats)
+}
+
+fn flush_chunk(index: &mut Index, chunk: &mut HashMap<String, Vec<u32>>) {
+    for (property, bits) in chunk.drain() {
+        index.set_many(&property, &bits);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crible_lib::Index;
+
+    use super::{import_csv, ImportOptions, ImportStats};
+
+    fn options(chunk_size: usize) -> ImportOptions {
+        ImportOptions {
+            from: PathBuf::from("-"),
+            property_col: "facet".to_owned(),
+            id_col: "id".to_owned(),
+            delimiter: b',',
+            chunk_size,
+            replace: false,
+        }
+    }
+
+    #[test]
+    fn test_import_csv() {
+        let data = "id,facet,other\n1,foo,x\n2,foo,x\n2,bar,x\n3,,x\n4,bar,x\n";
+        let mut index = Index::of([("foo", vec![10]), ("baz", vec![1])]);
+        let mut calls = 0;
+
+        let stats =
+            import_csv(&mut index, data.as_bytes(), &options(2), |_| {
+                calls += 1
+            })
+            .unwrap();
+
+        assert_eq!(stats, ImportStats { rows: 4, skipped: 1 });
+        assert_eq!(calls, 2);
+        assert_eq!(
+            index,
+            Index::of([
+                ("foo", vec![1, 2, 10]),
+                ("bar", vec![2, 4]),
+                ("baz", vec![1]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_import_csv_missing_column() {
+        let data = "id,property\n1,foo\n";
+        let mut index = Index::default();
+        assert!(
+            import_csv(&mut index, data.as_bytes(), &options(10), |_| ())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_import_csv_invalid_id() {
+        let data = "id,facet\n1,foo\nabc,foo\n";
+        let mut index = Index::default();
+        let err = import_csv(&mut index, data.as_bytes(), &options(10), |_| ())
+            .unwrap_err();
+        assert!(err.to_string().contains("line 3"));
+    }
+}
@@ -0,0 +1,223 @@
+use std::io::Write;
+
+use crible_lib::index::Stats;
+use crible_lib::Index;
+use croaring::Bitmap;
+use eyre::{Context, Report};
+use serde_derive::Serialize;
+
+use crate::backends::BackendOptions;
+
+/// Roaring bitmaps split values in containers of up to 2^16 values, each
+/// stored as a sorted array, a bitset or runs depending on its content.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct Containers {
+    pub array: u32,
+    pub bitset: u32,
+    pub run: u32,
+    /// Size of the serialized bitmap in bytes.
+    pub bytes: usize,
+}
+
+impl From<&Bitmap> for Containers {
+    fn from(bm: &Bitmap) -> Self {
+        let statistics = bm.statistics();
+        Self {
+            array: statistics.n_array_containers,
+            bitset: statistics.n_bitset_containers,
+            run: statistics.n_run_containers,
+            bytes: bm.get_serialized_size_in_bytes(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Overlap {
+    pub property: String,
+    /// Number of bits set in both properties.
+    pub count: u64,
+    /// Share of the inspected property's bits also set in this property.
+    pub ratio: f64,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Inspection {
+    pub property: String,
+    #[serde(flatten)]
+    pub stats: Stats,
+    pub containers: Containers,
+    /// Smallest bits.
+    pub head: Vec<u32>,
+    /// Largest bits.
+    pub tail: Vec<u32>,
+    /// Properties sharing the most bits with the inspected one.
+    pub overlaps: Vec<Overlap>,
+}
+
+#[derive(Debug, Clone)]
+pub struct InspectOptions {
+    /// Only compute overlaps with properties starting with this prefix.
+    pub prefix: Option<String>,
+    /// Number of bits in the head and tail samples.
+    pub sample: usize,
+    /// Maximum number of overlapping properties.
+    pub top: usize,
+}
+
+/// Describe a single property of the index stored in a backend.
+pub fn inspect(
+    backend_options: &BackendOptions,
+    property: &str,
+    options: &InspectOptions,
+    json: bool,
+) -> Result<(), Report> {
+    let backend = backend_options.build().wrap_err("Invalid backend")?;
+    let index = backend.load().wrap_err("Failed to load index")?;
+
+    let inspection = inspect_property(&index, property, options)
+        .ok_or_else(|| eyre::eyre!("Unknown property {:?}", property))?;
+
+    let stdout = std::io::stdout();
+    let mut buffer = std::io::BufWriter::new(stdout.lock());
+    if json {
+        serde_json::to_writer(&mut buffer, &inspection)?;
+        writeln!(buffer)?;
+    } else {
+        write_inspection(&mut buffer, &inspection)?;
+    }
+    buffer.flush()?;
+    Ok(())
+}
+
+fn inspect_property(
+    index: &Index,
+    property: &str,
+    options: &InspectOptions,
+) -> Option<Inspection> {
+    let bm = index.get_property(property)?;
+    let cardinality = bm.cardinality();
+
+    let mut overlaps: Vec<Overlap> = index
+        .iter()
+        .filter(|(k, _)| {
+            *k != property
+                && options.prefix.as_ref().map_or(true, |p| k.starts_with(p))
+        })
+        .filter_map(|(k, other)| {
+            let count = bm.and_cardinality(other);
+            (count > 0).then(|| Overlap {
+                property: k.to_owned(),
+                count,
+                ratio: count as f64 / cardinality as f64,
+            })
+        })
+        .collect();
+    overlaps.sort_unstable_by(|a, b| {
+        b.count.cmp(&a.count).then_with(|| a.property.cmp(&b.property))
+    });
+    overlaps.truncate(options.top);
+
+    let values = bm.to_vec();
+    let tail_start = values.len().saturating_sub(options.sample);
+
+    Some(Inspection {
+        property: property.to_owned(),
+        stats: bm.into(),
+        containers: bm.into(),
+        head: values.iter().take(options.sample).copied().collect(),
+        tail: values[tail_start..].to_vec(),
+        overlaps,
+    })
+}
+
+fn write_inspection<W: Write>(
+    w: &mut W,
+    inspection: &Inspection,
+) -> std::io::Result<()> {
+    let optional =
+        |x: Option<u32>| x.map(|x| x.to_string()).unwrap_or_default();
+    let join = |values: &[u32]| {
+        values.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(" ")
+    };
+
+    writeln!(w, "Property:    {}", inspection.property)?;
+    writeln!(w, "Cardinality: {}", inspection.stats.cardinality)?;
+    writeln!(
+        w,
+        "Range:       [{}, {}]",
+        optional(inspection.stats.minimum),
+        optional(inspection.stats.maximum)
+    )?;
+    writeln!(
+        w,
+        "Containers:  {} array, {} bitset, {} run ({} bytes)",
+        inspection.containers.array,
+        inspection.containers.bitset,
+        inspection.containers.run,
+        inspection.containers.bytes
+    )?;
+    writeln!(w, "Head:        {}", join(&inspection.head))?;
+    writeln!(w, "Tail:        {}", join(&inspection.tail))?;
+
+    if !inspection.overlaps.is_empty() {
+        writeln!(w, "\nOverlaps:")?;
+        for o in &inspection.overlaps {
+            writeln!(
+                w,
+                "  {:<30} {:>12} {:>7.2}%",
+                o.property,
+                o.count,
+                o.ratio * 100.0
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crible_lib::Index;
+
+    use super::{inspect_property, InspectOptions};
+
+    fn options(prefix: Option<&str>) -> InspectOptions {
+        InspectOptions { prefix: prefix.map(Into::into), sample: 2, top: 10 }
+    }
+
+    #[test]
+    fn test_inspect_property() {
+        let index = Index::of([
+            ("foo", vec![1, 2, 3, 4, 5]),
+            ("a:bar", vec![1, 2]),
+            ("a:baz", vec![2, 3, 4, 5, 6]),
+            ("b:qux", vec![5]),
+            ("b:none", vec![10]),
+        ]);
+
+        let inspection =
+            inspect_property(&index, "foo", &options(None)).unwrap();
+        assert_eq!(inspection.stats.cardinality, 5);
+        assert_eq!(inspection.head, vec![1, 2]);
+        assert_eq!(inspection.tail, vec![4, 5]);
+        assert_eq!(inspection.containers.array, 1);
+        assert_eq!(
+            inspection
+                .overlaps
+                .iter()
+                .map(|o| (o.property.as_str(), o.count))
+                .collect::<Vec<_>>(),
+            vec![("a:baz", 4), ("a:bar", 2), ("b:qux", 1)]
+        );
+        assert_eq!(inspection.overlaps[0].ratio, 0.8);
+
+        let inspection =
+            inspect_property(&index, "foo", &options(Some("b:"))).unwrap();
+        assert_eq!(inspection.overlaps.len(), 1);
+    }
+
+    #[test]
+    fn test_inspect_unknown_property() {
+        let index = Index::of([("foo", vec![1])]);
+        assert!(inspect_property(&index, "bar", &options(None)).is_none());
+    }
+}
@@ -0,0 +1,199 @@
+use std::str::FromStr;
+
+use crible_lib::expression::validate_property_name;
+use crible_lib::Index;
+use eyre::{Context, Report};
+
+use crate::backends::BackendOptions;
+
+/// How properties which exist in more than one index are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergeStrategy {
+    /// Keep the bits set in any of the indices.
+    #[default]
+    Union,
+    /// Keep the property from the last index it appears in.
+    Replace,
+}
+
+impl FromStr for MergeStrategy {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "union" => Ok(Self::Union),
+            "replace" => Ok(Self::Replace),
+            _ => Err(eyre::Report::msg(format!(
+                "Invalid merge strategy {:?}, expected union or replace",
+                value
+            ))),
+        }
+    }
+}
+
+/// Prefix added to all properties of a source, formatted as
+/// `<source>=<prefix>` where source is the 1-based position of the source on
+/// the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixMapping {
+    pub source: usize,
+    pub prefix: String,
+}
+
+impl FromStr for PrefixMapping {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once('=').map(|(n, p)| (n.parse::<usize>(), p)) {
+            Some((Ok(source), prefix)) if source > 0 => {
+                Ok(Self { source, prefix: prefix.to_owned() })
+            }
+            _ => Err(eyre::Report::msg(format!(
+                "Invalid prefix mapping {:?}, expected <source>=<prefix>",
+                value
+            ))),
+        }
+    }
+}
+
+/// Merge `sources` in order into the index stored in `into`.
+pub fn merge(
+    into: &BackendOptions,
+    sources: &[BackendOptions],
+    strategy: MergeStrategy,
+    prefix_map: &[PrefixMapping],
+) -> Result<(), Report> {
+    if let Some(m) = prefix_map.iter().find(|m| m.source > sources.len()) {
+        return Err(eyre::Report::msg(format!(
+            "Prefix mapping for source {} but only {} sources were given",
+            m.source,
+            sources.len()
+        )));
+    }
+
+    let target = into.build().wrap_err("Invalid destination backend")?;
+    let mut index =
+        target.load().wrap_err("Failed to load destination index")?;
+
+    for (i, options) in sources.iter().enumerate() {
+        let source = options.build().wrap_err("Invalid source backend")?;
+        let other = source
+            .load()
+            .wrap_err_with(|| format!("Failed to load source {}", i + 1))?;
+        let prefix = prefix_map
+            .iter()
+            .rev()
+            .find(|m| m.source == i + 1)
+            .map(|m| m.prefix.as_str());
+        eprintln!(
+            "Merging source {} ({} properties{})",
+            i + 1,
+            other.len(),
+            prefix.map(|p| format!(", prefix {:?}", p)).unwrap_or_default()
+        );
+        merge_index(&mut index, other, prefix, strategy)?;
+    }
+
+    index.optimize();
+    target.dump(&index).wrap_err("Failed to dump index")?;
+    eprintln!("Done: {} properties", index.len());
+    Ok(())
+}
+
+fn merge_index(
+    index: &mut Index,
+    other: Index,
+    prefix: Option<&str>,
+    strategy: MergeStrategy,
+) -> Result<(), Report> {
+    for (property, bm) in other.into_inner() {
+        let property = match prefix {
+            Some(p) => format!("{}{}", p, property),
+            None => property,
+        };
+        if !validate_property_name(&property) {
+            return Err(eyre::Report::msg(format!(
+                "Invalid property {:?}",
+                property
+            )));
+        }
+
+        match (strategy, index.get_property(&property)) {
+            (MergeStrategy::Union, Some(existing)) => {
+                let merged = existing.or(&bm);
+                index.set_property(&property, merged);
+            }
+            _ => index.set_property(&property, bm),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crible_lib::Index;
+    use rstest::rstest;
+
+    use super::{merge_index, MergeStrategy, PrefixMapping};
+
+    #[rstest]
+    #[case("1=a:", Some((1, "a:")))]
+    #[case("12=", Some((12, "")))]
+    #[case("0=a:", None)]
+    #[case("a:", None)]
+    #[case("x=a:", None)]
+    fn test_parse_prefix_mapping(
+        #[case] value: &str,
+        #[case] expected: Option<(usize, &str)>,
+    ) {
+        assert_eq!(
+            value.parse::<PrefixMapping>().ok(),
+            expected.map(|(source, prefix)| PrefixMapping {
+                source,
+                prefix: prefix.to_owned()
+            })
+        );
+    }
+
+    #[rstest]
+    #[case(
+        MergeStrategy::Union,
+        None,
+        Index::of([("foo", vec![1, 2, 3]), ("bar", vec![4]), ("baz", vec![5])])
+    )]
+    #[case(
+        MergeStrategy::Replace,
+        None,
+        Index::of([("foo", vec![2, 3]), ("bar", vec![4]), ("baz", vec![5])])
+    )]
+    #[case(
+        MergeStrategy::Union,
+        Some("job:"),
+        Index::of([
+            ("foo", vec![1, 2]),
+            ("bar", vec![4]),
+            ("job:foo", vec![2, 3]),
+            ("job:baz", vec![5]),
+        ])
+    )]
+    fn test_merge_index(
+        #[case] strategy: MergeStrategy,
+        #[case] prefix: Option<&str>,
+        #[case] expected: Index,
+    ) {
+        let mut index = Index::of([("foo", vec![1, 2]), ("bar", vec![4])]);
+        let other = Index::of([("foo", vec![2, 3]), ("baz", vec![5])]);
+        merge_index(&mut index, other, prefix, strategy).unwrap();
+        assert_eq!(index, expected);
+    }
+
+    #[test]
+    fn test_merge_index_invalid_prefix() {
+        let mut index = Index::default();
+        let other = Index::of([("foo", vec![1])]);
+        assert!(
+            merge_index(&mut index, other, Some("1"), MergeStrategy::Union)
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,38 @@
+//! Offline subcommands operating directly on a backend, outside of the
+//! server.
+
+mod convert;
+mod diff;
+mod doctor;
+mod export;
+mod generate;
+mod import;
+mod inspect;
+mod merge;
+mod optimize;
+mod query;
+mod repl;
+mod replicate;
+mod snapshot;
+mod stats;
+mod tail;
+mod validate;
+
+pub use self::convert::{convert, PropertyPattern, Rename, Selection};
+pub use self::diff::diff;
+pub use self::doctor::doctor;
+pub use self::export::{export, ExportFormat};
+pub use self::generate::{
+    generate, parse_count, Distribution, GenerateOptions,
+};
+pub use self::import::{import, ImportOptions};
+pub use self::inspect::{inspect, InspectOptions};
+pub use self::merge::{merge, MergeStrategy, PrefixMapping};
+pub use self::optimize::optimize;
+pub use self::query::{query, query_batch, OutputFormat, QueryOptions};
+pub use self::repl::repl;
+pub use self::replicate::{replicate, Source};
+pub use self::snapshot::{restore, snapshot};
+pub use self::stats::{stats, StatsSort};
+pub use self::tail::tail;
+pub use self::validate::validate;
@@ -0,0 +1,123 @@
+use std::io::Write;
+
+use crible_lib::Index;
+use eyre::{Context, Report};
+use serde_derive::Serialize;
+
+use crate::backends::BackendOptions;
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct PropertySizes {
+    pub property: String,
+    /// Serialized size in bytes before optimizing.
+    pub before: usize,
+    /// Serialized size in bytes after optimizing.
+    pub after: usize,
+}
+
+/// Optimize every property of the index stored in a backend and store it
+/// again, printing the size of every property before and after.
+pub fn optimize(
+    backend_options: &BackendOptions,
+    json: bool,
+) -> Result<(), Report> {
+    let backend = backend_options.build().wrap_err("Invalid backend")?;
+    let mut index = backend.load().wrap_err("Failed to load index")?;
+
+    let sizes = optimize_index(&mut index);
+    backend.dump(&index).wrap_err("Failed to dump index")?;
+
+    let stdout = std::io::stdout();
+    let mut buffer = std::io::BufWriter::new(stdout.lock());
+    if json {
+        serde_json::to_writer(&mut buffer, &sizes)?;
+        writeln!(buffer)?;
+    } else {
+        let width = sizes
+            .iter()
+            .map(|s| s.property.len())
+            .chain(std::iter::once("PROPERTY".len()))
+            .max()
+            .unwrap_or_default();
+        writeln!(
+            buffer,
+            "{:<width$}  {:>12}  {:>12}",
+            "PROPERTY",
+            "BEFORE",
+            "AFTER",
+            width = width
+        )?;
+        for s in &sizes {
+            writeln!(
+                buffer,
+                "{:<width$}  {:>12}  {:>12}",
+                s.property,
+                s.before,
+                s.after,
+                width = width
+            )?;
+        }
+        writeln!(
+            buffer,
+            "\nTotal: {} -> {} bytes",
+            sizes.iter().map(|s| s.before).sum::<usize>(),
+            sizes.iter().map(|s| s.after).sum::<usize>()
+        )?;
+    }
+    buffer.flush()?;
+    Ok(())
+}
+
+/// Largest savings first.
+fn optimize_index(index: &mut Index) -> Vec<PropertySizes> {
+    let properties: Vec<String> =
+        index.iter().map(|(k, _)| k.to_owned()).collect();
+    let size = |index: &Index, property: &str| {
+        index
+            .get_property(property)
+            .map_or(0, |bm| bm.get_serialized_size_in_bytes())
+    };
+
+    let mut sizes: Vec<PropertySizes> = properties
+        .into_iter()
+        .map(|property| {
+            let before = size(index, &property);
+            index.optimize_properties(&[&property]);
+            let after = size(index, &property);
+            PropertySizes { property, before, after }
+        })
+        .collect();
+
+    sizes.sort_unstable_by(|a, b| {
+        b.before
+            .saturating_sub(b.after)
+            .cmp(&a.before.saturating_sub(a.after))
+            .then_with(|| a.property.cmp(&b.property))
+    });
+    sizes
+}
+
+#[cfg(test)]
+mod tests {
+    use crible_lib::Index;
+
+    use super::optimize_index;
+
+    #[test]
+    fn test_optimize_index() {
+        let mut index = Index::of([
+            ("runs", (0..10_000).collect::<Vec<u32>>()),
+            ("sparse", vec![1, 1000, 100_000]),
+        ]);
+        let expected = index.clone();
+
+        let sizes = optimize_index(&mut index);
+
+        assert_eq!(index, expected);
+        assert_eq!(sizes.len(), 2);
+        assert_eq!(sizes[0].property, "runs");
+        assert!(sizes[0].after < sizes[0].before);
+        assert_eq!(sizes[1].property, "sparse");
+        assert_eq!(sizes[1].after, sizes[1].before);
+    }
+}
@@ -0,0 +1,376 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use crible_lib::{Expression, Index};
+use croaring::Bitmap;
+use eyre::{Context, Report};
+use serde_json::json;
+
+use crate::backends::BackendOptions;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// One value per line.
+    #[default]
+    Plain,
+    /// A single object with the values, their count and cardinalities.
+    Json,
+    /// One object per value and per property cardinality.
+    Ndjson,
+    /// A `value` column.
+    Csv,
+    /// Only the number of matching values.
+    Count,
+}
+
+impl FromStr for OutputFormat {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "plain" => Ok(Self::Plain),
+            "json" => Ok(Self::Json),
+            "ndjson" => Ok(Self::Ndjson),
+            "csv" => Ok(Self::Csv),
+            "count" => Ok(Self::Count),
+            _ => Err(eyre::Report::msg(format!(
+                "Invalid output format {:?}, expected one of plain, json, \
+                 ndjson, csv or count",
+                value
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct QueryOptions {
+    pub output: OutputFormat,
+    /// Include the number of matching values for every property.
+    pub include_cardinalities: bool,
+    /// Maximum number of values printed.
+    pub limit: Option<usize>,
+    /// Number of values skipped before printing.
+    pub offset: usize,
+}
+
+impl QueryOptions {
+    fn validate(&self) -> Result<(), Report> {
+        if self.include_cardinalities
+            && !matches!(self.output, OutputFormat::Json | OutputFormat::Ndjson)
+        {
+            return Err(eyre::Report::msg(
+                "Cardinalities can only be included in json and ndjson output",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Execute a single query against the index stored in a backend.
+pub fn query(
+    backend_options: &BackendOptions,
+    expr: &Expression,
+    options: &QueryOptions,
+) -> Result<(), Report> {
+    options.validate()?;
+
+    let backend = backend_options.build().wrap_err("Invalid backend")?;
+    let index = backend.load().wrap_err("Failed to load index")?;
+
+    let stdout = std::io::stdout();
+    let mut buffer = std::io::BufWriter::new(stdout.lock());
+    write_query(&mut buffer, &index, expr, options)?;
+    buffer.flush()?;
+    Ok(())
+}
+
+/// Execute every line read from `input`, or stdin, as a query against the
+/// index stored in a backend, which is only loaded once. Results are printed
+/// as one JSON object per query, tagged with their line number.
+pub fn query_batch(
+    backend_options: &BackendOptions,
+    input: Option<&Path>,
+    options: &QueryOptions,
+) -> Result<(), Report> {
+    let backend = backend_options.build().wrap_err("Invalid backend")?;
+    let index = backend.load().wrap_err("Failed to load index")?;
+
+    let reader: Box<dyn Read> =
+        match input {
+            None => Box::new(std::io::stdin().lock()),
+            Some(path) => Box::new(File::open(path).wrap_err_with(|| {
+                format!("Failed to open {}", path.display())
+            })?),
+        };
+
+    let stdout = std::io::stdout();
+    let mut buffer = std::io::BufWriter::new(stdout.lock());
+    let (total, failed) =
+        write_batch(&mut buffer, &index, BufReader::new(reader), options)?;
+    buffer.flush()?;
+
+    eprintln!("Executed {} queries, {} failed", total, failed);
+    Ok(())
+}
+
+/// Empty lines and lines starting with `#` are skipped. Returns the number of
+/// queries executed and how many of those failed.
+fn write_batch<W: Write, R: BufRead>(
+    w: &mut W,
+    index: &Index,
+    reader: R,
+    options: &QueryOptions,
+) -> Result<(usize, usize), Report> {
+    let (mut total, mut failed) = (0, 0);
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let query = line.trim();
+        if query.is_empty() || query.starts_with('#') {
+            continue;
+        }
+        total += 1;
+
+        let mut result = json!({ "line": i + 1, "query": query });
+        match query.parse::<Expression>() {
+            Err(e) => {
+                failed += 1;
+                result["error"] = json!(e.to_string());
+            }
+            Ok(expr) => match index.execute(&expr) {
+                Err(e) => {
+                    failed += 1;
+                    result["error"] = json!(e.to_string());
+                }
+                Ok(bm) => {
+                    result["count"] = json!(bm.cardinality());
+                    if options.output != OutputFormat::Count {
+                        result["values"] = json!(
+                            bm.iter()
+                                .skip(options.offset)
+                                .take(options.limit.unwrap_or(usize::MAX))
+                                .collect::<Vec<_>>()
+                        );
+                    }
+                    if options.include_cardinalities {
+                        result["cardinalities"] = json!(
+                            index
+                                .par_cardinalities(&bm, None)
+                                .into_iter()
+                                .collect::<BTreeMap<_, _>>()
+                        );
+                    }
+                }
+            },
+        }
+
+        serde_json::to_writer(&mut *w, &result)?;
+        writeln!(w)?;
+    }
+
+    Ok((total, failed))
+}
+
+fn write_query<W: Write>(
+    w: &mut W,
+    index: &Index,
+    expr: &Expression,
+    options: &QueryOptions,
+) -> Result<(), Report> {
+    let bm = index.execute(expr)?;
+    let cardinalities = if options.include_cardinalities {
+        Some(index.par_cardinalities(&bm, None))
+    } else {
+        None
+    };
+    write_result(w, &bm, cardinalities, options)
+}
+
+fn write_result<W: Write>(
+    w: &mut W,
+    bm: &Bitmap,
+    cardinalities: Option<HashMap<String, u64>>,
+    options: &QueryOptions,
+) -> Result<(), Report> {
+    let values = bm
+        .iter()
+        .skip(options.offset)
+        .take(options.limit.unwrap_or(usize::MAX));
+    let cardinalities =
+        cardinalities.map(|c| c.into_iter().collect::<BTreeMap<_, _>>());
+
+    match options.output {
+        OutputFormat::Plain => {
+            for x in values {
+                writeln!(w, "{}", x)?;
+            }
+        }
+        OutputFormat::Csv => {
+            writeln!(w, "value")?;
+            for x in values {
+                writeln!(w, "{}", x)?;
+            }
+        }
+        OutputFormat::Count => writeln!(w, "{}", bm.cardinality())?,
+        OutputFormat::Json => {
+            let mut result = json!({
+                "count": bm.cardinality(),
+                "values": values.collect::<Vec<_>>(),
+            });
+            if let Some(c) = cardinalities {
+                result["cardinalities"] = json!(c);
+            }
+            serde_json::to_writer(&mut *w, &result)?;
+            writeln!(w)?;
+        }
+        OutputFormat::Ndjson => {
+            for x in values {
+                serde_json::to_writer(&mut *w, &json!({ "value": x }))?;
+                writeln!(w)?;
+            }
+            for (property, n) in cardinalities.into_iter().flatten() {
+                serde_json::to_writer(
+                    &mut *w,
+                    &json!({ "property": property, "cardinality": n }),
+                )?;
+                writeln!(w)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crible_lib::Index;
+    use rstest::rstest;
+
+    use super::{write_batch, write_query, OutputFormat, QueryOptions};
+
+    fn run(options: QueryOptions) -> String {
+        let index = Index::of([("foo", vec![1, 2, 3, 4]), ("bar", vec![2, 5])]);
+        let mut out = Vec::new();
+        write_query(&mut out, &index, &"foo".parse().unwrap(), &options)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[rstest]
+    #[case(OutputFormat::Plain, "1\n2\n3\n4\n")]
+    #[case(OutputFormat::Csv, "value\n1\n2\n3\n4\n")]
+    #[case(OutputFormat::Count, "4\n")]
+    #[case(OutputFormat::Json, "{\"count\":4,\"values\":[1,2,3,4]}\n")]
+    #[case(
+        OutputFormat::Ndjson,
+        "{\"value\":1}\n{\"value\":2}\n{\"value\":3}\n{\"value\":4}\n"
+    )]
+    fn test_output_formats(
+        #[case] output: OutputFormat,
+        #[case] expected: &str,
+    ) {
+        assert_eq!(
+            run(QueryOptions { output, ..Default::default() }),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_limit_offset() {
+        let options = QueryOptions {
+            output: OutputFormat::Json,
+            limit: Some(2),
+            offset: 1,
+            ..Default::default()
+        };
+        assert_eq!(run(options), "{\"count\":4,\"values\":[2,3]}\n");
+    }
+
+    #[test]
+    fn test_include_cardinalities() {
+        let options = QueryOptions {
+            output: OutputFormat::Json,
+            include_cardinalities: true,
+            limit: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            run(options),
+            "{\"cardinalities\":{\"bar\":1,\"foo\":4},\"count\":4,\"values\":\
+             []}\n"
+        );
+    }
+
+    #[rstest]
+    #[case(OutputFormat::Plain, false)]
+    #[case(OutputFormat::Csv, false)]
+    #[case(OutputFormat::Count, false)]
+    #[case(OutputFormat::Json, true)]
+    #[case(OutputFormat::Ndjson, true)]
+    fn test_validate_cardinalities(
+        #[case] output: OutputFormat,
+        #[case] valid: bool,
+    ) {
+        let options = QueryOptions {
+            output,
+            include_cardinalities: true,
+            ..Default::default()
+        };
+        assert_eq!(options.validate().is_ok(), valid);
+    }
+
+    #[test]
+    fn test_batch() {
+        let index = Index::of([("foo", vec![1, 2, 3, 4]), ("bar", vec![2, 5])]);
+        let input = "foo\n\n# comment\nfoo and bar\nfoo and\n";
+        let mut out = Vec::new();
+
+        let (total, failed) = write_batch(
+            &mut out,
+            &index,
+            input.as_bytes(),
+            &QueryOptions { limit: Some(2), ..Default::default() },
+        )
+        .unwrap();
+
+        assert_eq!((total, failed), (3, 1));
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(
+            lines[0],
+            serde_json::json!({
+                "line": 1, "query": "foo", "count": 4, "values": [1, 2]
+            })
+        );
+        assert_eq!(
+            lines[1],
+            serde_json::json!({
+                "line": 4, "query": "foo and bar", "count": 1, "values": [2]
+            })
+        );
+        assert_eq!(lines[2]["line"], 5);
+        assert!(lines[2]["error"].is_string());
+    }
+
+    #[test]
+    fn test_batch_count() {
+        let index = Index::of([("foo", vec![1, 2, 3, 4])]);
+        let mut out = Vec::new();
+        write_batch(
+            &mut out,
+            &index,
+            "foo\n".as_bytes(),
+            &QueryOptions { output: OutputFormat::Count, ..Default::default() },
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"count\":4,\"line\":1,\"query\":\"foo\"}\n"
+        );
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::time::Instant;
+
+use crible_lib::{Expression, Index};
+use eyre::{Context, Report};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Editor, Helper};
+
+use crate::backends::{Backend, BackendOptions};
+
+/// Maximum number of bits printed for a query, the count is always printed.
+const MAX_DISPLAYED_BITS: usize = 100;
+
+static HELP: &str = "\
+<expression>                 Print the bits matching a query
+count <expression>           Print the number of bits matching a query
+properties [prefix]          List properties
+set <property> <bit>...      Set bits (requires --allow-writes)
+unset <property> <bit>...    Unset bits (requires --allow-writes)
+delete <property>            Delete a property (requires --allow-writes)
+save                         Persist changes to the backend
+help                         Print this message
+exit                         Leave, also works with Ctrl-D";
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Query(Expression),
+    Count(Expression),
+    Properties(Option<String>),
+    Set(String, Vec<u32>),
+    Unset(String, Vec<u32>),
+    Delete(String),
+    Save,
+    Help,
+    Exit,
+}
+
+impl Command {
+    fn parse(line: &str) -> Result<Self, Report> {
+        let (head, rest) = match line.split_once(char::is_whitespace) {
+            Some((head, rest)) => (head, rest.trim()),
+            None => (line, ""),
+        };
+
+        let bits = |rest: &str| -> Result<(String, Vec<u32>), Report> {
+            let mut parts = rest.split_whitespace();
+            let property = parts
+                .next()
+                .ok_or_else(|| eyre::eyre!("Missing property"))?
+                .to_owned();
+            let bits = parts
+                .map(|x| {
+                    x.parse().wrap_err_with(|| format!("Invalid bit {}", x))
+                })
+                .collect::<Result<Vec<u32>, _>>()?;
+            if bits.is_empty() {
+                return Err(eyre::eyre!("Missing bits"));
+            }
+            Ok((property, bits))
+        };
+
+        Ok(match head {
+            "count" => Self::Count(rest.parse()?),
+            "properties" if rest.is_empty() => Self::Properties(None),
+            "properties" => Self::Properties(Some(rest.to_owned())),
+            "set" => {
+                let (property, bits) = bits(rest)?;
+                Self::Set(property, bits)
+            }
+            "unset" => {
+                let (property, bits) = bits(rest)?;
+                Self::Unset(property, bits)
+            }
+            "delete" if !rest.is_empty() => Self::Delete(rest.to_owned()),
+            "save" => Self::Save,
+            "help" => Self::Help,
+            "exit" | "quit" => Self::Exit,
+            _ => Self::Query(line.parse()?),
+        })
+    }
+
+    fn mutates(&self) -> bool {
+        matches!(self, Self::Set(..) | Self::Unset(..) | Self::Delete(_))
+    }
+}
+
+struct Session {
+    index: Index,
+    backend: Box<dyn Backend>,
+    allow_writes: bool,
+    /// Whether there are changes which have not been saved to the backend.
+    dirty: bool,
+}
+
+impl Session {
+    fn run<W: Write>(
+        &mut self,
+        command: Command,
+        out: &mut W,
+    ) -> Result<(), Report> {
+        if command.mutates() && !self.allow_writes {
+            return Err(eyre::eyre!("Writes require --allow-writes"));
+        }
+
+        match command {
+            Command::Query(expr) => {
+                let bm = self.index.execute(&expr)?;
+                let mut bits = bm.iter();
+                for bit in bits.by_ref().take(MAX_DISPLAYED_BITS) {
+                    write!(out, "{} ", bit)?;
+                }
+                let remaining = bits.count();
+                if remaining > 0 {
+                    write!(out, "... ({} more)", remaining)?;
+                }
+                writeln!(out)?;
+                writeln!(out, "{} bits", bm.cardinality())?;
+            }
+            Command::Count(expr) => {
+                writeln!(out, "{}", self.index.execute(&expr)?.cardinality())?;
+            }
+            Command::Properties(prefix) => {
+                for property in self.properties() {
+                    if prefix.as_ref().map_or(true, |p| property.starts_with(p))
+                    {
+                        writeln!(out, "{}", property)?;
+                    }
+                }
+            }
+            Command::Set(property, bits) => {
+                self.index.set_many(&property, &bits);
+                self.dirty = true;
+            }
+            Command::Unset(property, bits) => {
+                self.index.unset_many(&property, &bits);
+                self.dirty = true;
+            }
+            Command::Delete(property) => {
+                self.dirty |= self.index.delete_property(&property);
+            }
+            Command::Save => {
+                self.backend.dump(&self.index).wrap_err("Failed to save")?;
+                self.dirty = false;
+            }
+            Command::Help => writeln!(out, "{}", HELP)?,
+            Command::Exit => {}
+        }
+        Ok(())
+    }
+
+    fn properties(&self) -> BTreeSet<String> {
+        self.index.iter().map(|(k, _)| k.to_owned()).collect()
+    }
+}
+
+/// Complete the word under the cursor with property names.
+struct PropertyCompleter {
+    properties: BTreeSet<String>,
+}
+
+impl Completer for PropertyCompleter {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(|c: char| c.is_whitespace() || c == '(')
+            .map_or(0, |i| i + 1);
+        let word = &line[start..pos];
+        Ok((
+            start,
+            self.properties
+                .range(word.to_owned()..)
+                .take_while(|p| p.starts_with(word))
+                .cloned()
+                .collect(),
+        ))
+    }
+}
+
+impl Hinter for PropertyCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for PropertyCompleter {}
+
+impl Validator for PropertyCompleter {}
+
+impl Helper for PropertyCompleter {}
+
+/// Run an interactive prompt over the index stored in a backend, which is
+/// only loaded once.
+pub fn repl(
+    backend_options: &BackendOptions,
+    allow_writes: bool,
+) -> Result<(), Report> {
+    let backend = backend_options.build().wrap_err("Invalid backend")?;
+    let index = backend.load().wrap_err("Failed to load index")?;
+    eprintln!("Loaded {} properties, type `help` for help", index.len());
+
+    let mut session = Session { index, backend, allow_writes, dirty: false };
+    let mut editor = Editor::<PropertyCompleter>::new()?;
+    editor.set_helper(Some(PropertyCompleter {
+        properties: session.properties(),
+    }));
+
+    let stdout = std::io::stdout();
+    loop {
+        let line = match editor.readline("crible> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line);
+
+        let command = match Command::parse(line) {
+            Ok(Command::Exit) => break,
+            Ok(command) => command,
+            Err(e) => {
+                eprintln!("Error: {:#}", e);
+                continue;
+            }
+        };
+        let mutates = command.mutates();
+
+        let start = Instant::now();
+        match session.run(command, &mut stdout.lock()) {
+            Ok(()) => eprintln!("({:?})", start.elapsed()),
+            Err(e) => eprintln!("Error: {:#}", e),
+        }
+
+        if mutates {
+            if let Some(helper) = editor.helper_mut() {
+                helper.properties = session.properties();
+            }
+        }
+    }
+
+    if session.dirty {
+        eprintln!("Warning: unsaved changes were discarded");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crible_lib::Index;
+    use rstest::rstest;
+
+    use super::{Command, Session};
+    use crate::backends::{Backend, Memory};
+
+    #[rstest]
+    #[case("foo and bar", Command::Query("foo and bar".parse().unwrap()))]
+    #[case("count foo", Command::Count("foo".parse().unwrap()))]
+    #[case("properties", Command::Properties(None))]
+    #[case("properties foo:", Command::Properties(Some("foo:".to_owned())))]
+    #[case("set foo 1 2", Command::Set("foo".to_owned(), vec![1, 2]))]
+    #[case("unset foo 3", Command::Unset("foo".to_owned(), vec![3]))]
+    #[case("delete foo", Command::Delete("foo".to_owned()))]
+    #[case("save", Command::Save)]
+    #[case("quit", Command::Exit)]
+    fn test_parse_command(#[case] line: &str, #[case] expected: Command) {
+        assert_eq!(Command::parse(line).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case("count")]
+    #[case("set foo")]
+    #[case("set foo a")]
+    #[case("foo and")]
+    fn test_parse_invalid_command(#[case] line: &str) {
+        assert!(Command::parse(line).is_err());
+    }
+
+    fn session(allow_writes: bool) -> Session {
+        Session {
+            index: Index::of([("foo", vec![1, 2, 3]), ("bar", vec![2])]),
+            backend: Box::new(Memory::default()),
+            allow_writes,
+            dirty: false,
+        }
+    }
+
+    fn run(session: &mut Session, line: &str) -> String {
+        let mut out = Vec::new();
+        session.run(Command::parse(line).unwrap(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_run_read_commands() {
+        let mut session = session(false);
+        assert_eq!(run(&mut session, "foo - bar"), "1 3 \n2 bits\n");
+        assert_eq!(run(&mut session, "count foo or bar"), "3\n");
+        assert_eq!(run(&mut session, "properties"), "bar\nfoo\n");
+        assert_eq!(run(&mut session, "properties f"), "foo\n");
+    }
+
+    #[test]
+    fn test_writes_require_allow_writes() {
+        let mut session = session(false);
+        let command = Command::parse("set foo 4").unwrap();
+        assert!(session.run(command, &mut Vec::new()).is_err());
+        assert!(!session.dirty);
+    }
+
+    #[test]
+    fn test_run_write_commands() {
+        let mut session = session(true);
+        run(&mut session, "set baz 4 5");
+        run(&mut session, "unset foo 1");
+        assert!(session.dirty);
+        assert_eq!(run(&mut session, "foo or baz"), "2 3 4 5 \n4 bits\n");
+
+        run(&mut session, "save");
+        assert!(!session.dirty);
+        assert_eq!(session.backend.load().unwrap(), session.index);
+    }
+}
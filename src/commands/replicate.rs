@@ -0,0 +1,307 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crible_api_types::{SyncRequest, SyncResult};
+use crible_lib::Index;
+use croaring::Bitmap;
+use eyre::{Context, Report};
+use serde_derive::Deserialize;
+use url::Url;
+
+use super::diff::{diff_indices, IndexDiff};
+use crate::backends::{Backend, BackendOptions};
+
+/// Where an index is replicated from, either a backend or the HTTP API of a
+/// running server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Backend(BackendOptions),
+    /// Base url of the server or tenant, e.g. `http://host:3000/tenant/`.
+    Server(Url),
+}
+
+impl FromStr for Source {
+    type Err = Report;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(value)?;
+        match url.scheme() {
+            "http" | "https" => Ok(Source::Server(url)),
+            _ => Ok(Source::Backend(value.parse()?)),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ServerStats {
+    properties: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ServerQueryResult {
+    values: Vec<u32>,
+}
+
+enum Upstream {
+    Backend(Box<dyn Backend>),
+    Server {
+        client: reqwest::Client,
+        url: Url,
+        token: Option<String>,
+        /// Change log and generation as of the last fetch, see `POST /sync`.
+        log: Option<String>,
+        generation: Option<u64>,
+    },
+}
+
+impl Upstream {
+    fn new(source: &Source, token: Option<&str>) -> Result<Self, Report> {
+        Ok(match source {
+            Source::Backend(options) => Self::Backend(
+                options.build().wrap_err("Invalid source backend")?,
+            ),
+            Source::Server(url) => Self::Server {
+                client: reqwest::Client::builder()
+                    .timeout(Duration::from_secs(30))
+                    .build()?,
+                url: url.clone(),
+                token: token.map(|t| t.to_owned()),
+                log: None,
+                generation: None,
+            },
+        })
+    }
+
+    /// Fetch the source index, `replica` being the result of the previous
+    /// call. Servers only send the properties which changed since the last
+    /// call and are read in full when they cannot tell, `None` is returned
+    /// when nothing changed. Backends do not track changes and are always
+    /// read in full.
+    async fn fetch(
+        &mut self,
+        replica: &Index,
+    ) -> Result<Option<Index>, Report> {
+        match self {
+            Self::Backend(backend) => Ok(Some(
+                backend.load().wrap_err("Failed to load source index")?,
+            )),
+            Self::Server { client, url, token, log, generation } => {
+                let request = |route: &str| -> Result<_, Report> {
+                    let request = client.post(url.join(route)?);
+                    Ok(match token.as_deref() {
+                        Some(token) => request.bearer_auth(token),
+                        None => request,
+                    })
+                };
+
+                let delta: SyncResult = request("sync")?
+                    .json(&SyncRequest {
+                        log: log.clone(),
+                        generation: *generation,
+                    })
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await
+                    .wrap_err("Failed to sync")?;
+                // Writes which happen after the sync carry a newer generation
+                // and are picked up on the next call.
+                *log = Some(delta.log);
+                *generation = Some(delta.generation);
+
+                if !delta.reload {
+                    if delta.bitmaps.is_empty() && delta.deleted.is_empty() {
+                        return Ok(None);
+                    }
+                    let mut index = replica.clone();
+                    for (property, encoded) in &delta.bitmaps {
+                        let bytes =
+                            base64::decode(encoded).wrap_err_with(|| {
+                                format!("Invalid bitmap for {:?}", property)
+                            })?;
+                        index.set_property(
+                            property,
+                            Bitmap::deserialize(&bytes),
+                        );
+                    }
+                    for property in &delta.deleted {
+                        index.delete_property(property);
+                    }
+                    return Ok(Some(index));
+                }
+
+                let stats: ServerStats = request("stats")?
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+
+                let mut index = Index::default();
+                for property in stats.properties.keys() {
+                    let result: ServerQueryResult = request("query")?
+                        .json(&serde_json::json!({ "query": property }))
+                        .send()
+                        .await?
+                        .error_for_status()?
+                        .json()
+                        .await
+                        .wrap_err_with(|| {
+                            format!("Failed to fetch property {:?}", property)
+                        })?;
+                    index.set_property(property, Bitmap::of(&result.values));
+                }
+                Ok(Some(index))
+            }
+        }
+    }
+}
+
+/// Properties which must be written for `replica` to match `source`.
+fn changed_properties(diff: &IndexDiff) -> HashSet<String> {
+    [&diff.added, &diff.removed, &diff.changed]
+        .into_iter()
+        .flatten()
+        .map(|d| d.property.clone())
+        .collect()
+}
+
+/// Copy the index from `from` into `to` and, when `watch` is provided, keep
+/// polling the source at that interval and only write the properties which
+/// changed since the last sync.
+pub async fn replicate(
+    from: &Source,
+    to: &BackendOptions,
+    token: Option<&str>,
+    watch: Option<Duration>,
+) -> Result<(), Report> {
+    let destination = to.build().wrap_err("Invalid destination backend")?;
+    let mut upstream = Upstream::new(from, token)?;
+
+    let mut replica =
+        upstream.fetch(&Index::default()).await?.unwrap_or_default();
+    destination.dump(&replica).wrap_err("Failed to write destination index")?;
+    eprintln!("Copied {} properties", replica.len());
+
+    let interval = match watch {
+        None => return Ok(()),
+        Some(interval) => interval,
+    };
+
+    loop {
+        tokio::select! {
+            _ = crate::utils::shutdown_signal("Replication") => break,
+            _ = tokio::time::sleep(interval) => {},
+        }
+
+        // Errors are reported and retried on the next tick so that a
+        // temporarily unavailable source does not stop the replication.
+        match sync(&mut upstream, &replica, destination.as_ref()).await {
+            Ok(None) => {}
+            Ok(Some((index, diff))) => {
+                eprintln!(
+                    "Synced {} added, {} removed and {} changed properties",
+                    diff.added.len(),
+                    diff.removed.len(),
+                    diff.changed.len()
+                );
+                replica = index;
+            }
+            Err(e) => eprintln!("Failed to sync: {:#}", e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the source changes since `replica` to `destination`, returning the
+/// new replica along with what changed or `None` when nothing did.
+async fn sync(
+    upstream: &mut Upstream,
+    replica: &Index,
+    destination: &dyn Backend,
+) -> Result<Option<(Index, IndexDiff)>, Report> {
+    let index = match upstream.fetch(replica).await? {
+        None => return Ok(None),
+        Some(index) => index,
+    };
+
+    let diff = diff_indices(replica, &index);
+    if diff.is_empty() {
+        return Ok(None);
+    }
+
+    destination
+        .dump_partial(&index, &changed_properties(&diff))
+        .wrap_err("Failed to write destination index")?;
+    Ok(Some((index, diff)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crible_lib::{Encoder, Index};
+
+    use super::{sync, Source, Upstream};
+    use crate::backends::BackendOptions;
+
+    #[test]
+    fn test_parse_source() {
+        assert_eq!(
+            "http://localhost:3000/".parse::<Source>().unwrap(),
+            Source::Server("http://localhost:3000/".parse().unwrap())
+        );
+        assert_eq!(
+            "memory://".parse::<Source>().unwrap(),
+            Source::Backend(BackendOptions::Memory)
+        );
+        assert!("ftp://localhost".parse::<Source>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        let dir = std::env::temp_dir().join(ulid::Ulid::new().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let options = |name: &str| BackendOptions::Fs {
+            path: dir.join(name),
+            encoder: Encoder::Json,
+        };
+        let source = options("source.json").build().unwrap();
+        let destination = options("destination.json").build().unwrap();
+
+        let replica = Index::of([("foo", vec![1, 2]), ("bar", vec![3])]);
+        let index = Index::of([("foo", vec![1, 2, 4]), ("baz", vec![5])]);
+        source.dump(&index).unwrap();
+        destination.dump(&replica).unwrap();
+
+        let mut upstream =
+            Upstream::new(&Source::Backend(options("source.json")), None)
+                .unwrap();
+        let (synced, diff) = sync(&mut upstream, &replica, &*destination)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(synced == index);
+        assert!(destination.load().unwrap() == index);
+        assert_eq!(
+            super::changed_properties(&diff),
+            HashSet::from([
+                "foo".to_owned(),
+                "bar".to_owned(),
+                "baz".to_owned()
+            ])
+        );
+        assert!(
+            sync(&mut upstream, &synced, &*destination)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
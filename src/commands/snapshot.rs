@@ -0,0 +1,65 @@
+use std::path::Path;
+use std::time::SystemTime;
+
+use crible_lib::Encoder;
+use eyre::{Context, Report};
+
+use crate::backends::BackendOptions;
+use crate::snapshots::{Snapshot, SnapshotStore};
+
+/// Write a timestamped snapshot of the index stored in a backend to `dir`,
+/// then delete all but the `keep` most recent snapshots if set.
+pub fn snapshot(
+    backend_options: &BackendOptions,
+    dir: &Path,
+    prefix: Option<&str>,
+    encoder: Encoder,
+    keep: Option<usize>,
+) -> Result<(), Report> {
+    let backend = backend_options.build().wrap_err("Invalid backend")?;
+    let index = backend.load().wrap_err("Failed to load index")?;
+
+    let store = SnapshotStore::new(dir, prefix);
+    let snapshot = store.write(&index, encoder, SystemTime::now())?;
+    println!("{}", snapshot.path.display());
+
+    if let Some(keep) = keep {
+        for pruned in store.prune(keep)? {
+            eprintln!("Deleted {}", pruned.path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Replace the index stored in a backend with a snapshot. `from` is either a
+/// snapshot file or a directory, in which case its most recent snapshot is
+/// used.
+pub fn restore(
+    backend_options: &BackendOptions,
+    from: &Path,
+    prefix: Option<&str>,
+) -> Result<(), Report> {
+    let snapshot = if from.is_dir() {
+        SnapshotStore::new(from, prefix).latest()?.ok_or_else(|| {
+            eyre::eyre!("No snapshot found in `{}`", from.display())
+        })?
+    } else {
+        let encoder = from
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| ext.parse().ok())
+            .unwrap_or(Encoder::Bin);
+        Snapshot { path: from.to_owned(), timestamp: String::new(), encoder }
+    };
+
+    let index = snapshot.load()?;
+    let backend = backend_options.build().wrap_err("Invalid backend")?;
+    backend.clear()?;
+    backend.dump(&index).wrap_err("Failed to dump index")?;
+    eprintln!(
+        "Restored {} properties from {}",
+        index.len(),
+        snapshot.path.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,203 @@
+use std::io::Write;
+use std::str::FromStr;
+
+use crible_lib::index::Stats;
+use crible_lib::Index;
+use eyre::{Context, Report};
+use serde_derive::Serialize;
+
+use crate::backends::BackendOptions;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StatsSort {
+    #[default]
+    Property,
+    /// Largest properties first.
+    Cardinality,
+}
+
+impl FromStr for StatsSort {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "property" | "name" => Ok(Self::Property),
+            "cardinality" => Ok(Self::Cardinality),
+            _ => Err(eyre::Report::msg(format!(
+                "Invalid sort {:?}, expected property or cardinality",
+                value
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct PropertyStats {
+    pub property: String,
+    #[serde(flatten)]
+    pub stats: Stats,
+    /// Size of the serialized bitmap, a good approximation of its size in
+    /// memory.
+    pub bytes: usize,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct IndexStats {
+    /// Stats of the union of all the selected properties.
+    pub root: Stats,
+    pub bytes: usize,
+    pub properties: Vec<PropertyStats>,
+}
+
+/// Print statistics about the properties of the index stored in a backend.
+pub fn stats(
+    backend_options: &BackendOptions,
+    prefix: Option<&str>,
+    sort: StatsSort,
+    json: bool,
+) -> Result<(), Report> {
+    let backend = backend_options.build().wrap_err("Invalid backend")?;
+    let index = backend.load().wrap_err("Failed to load index")?;
+
+    let stats = index_stats(&index, prefix, sort);
+
+    let stdout = std::io::stdout();
+    let mut buffer = std::io::BufWriter::new(stdout.lock());
+    if json {
+        serde_json::to_writer(&mut buffer, &stats)?;
+        writeln!(buffer)?;
+    } else {
+        write_table(&mut buffer, &stats)?;
+    }
+    buffer.flush()?;
+    Ok(())
+}
+
+fn index_stats(
+    index: &Index,
+    prefix: Option<&str>,
+    sort: StatsSort,
+) -> IndexStats {
+    let mut root = croaring::Bitmap::create();
+    let mut properties: Vec<PropertyStats> = index
+        .iter()
+        .filter(|(k, _)| prefix.map_or(true, |p| k.starts_with(p)))
+        .map(|(k, bm)| {
+            root.or_inplace(bm);
+            PropertyStats {
+                property: k.to_owned(),
+                stats: bm.into(),
+                bytes: bm.get_serialized_size_in_bytes(),
+            }
+        })
+        .collect();
+
+    match sort {
+        StatsSort::Property => {
+            properties.sort_unstable_by(|a, b| a.property.cmp(&b.property))
+        }
+        StatsSort::Cardinality => properties.sort_unstable_by(|a, b| {
+            b.stats
+                .cardinality
+                .cmp(&a.stats.cardinality)
+                .then_with(|| a.property.cmp(&b.property))
+        }),
+    }
+
+    IndexStats {
+        root: root.into(),
+        bytes: properties.iter().map(|p| p.bytes).sum(),
+        properties,
+    }
+}
+
+fn write_table<W: Write>(w: &mut W, stats: &IndexStats) -> std::io::Result<()> {
+    let width = stats
+        .properties
+        .iter()
+        .map(|p| p.property.len())
+        .chain(std::iter::once("PROPERTY".len()))
+        .max()
+        .unwrap_or_default();
+    let optional =
+        |x: Option<u32>| x.map(|x| x.to_string()).unwrap_or_default();
+
+    writeln!(
+        w,
+        "{:<width$}  {:>12}  {:>10}  {:>10}  {:>12}",
+        "PROPERTY",
+        "CARDINALITY",
+        "MIN",
+        "MAX",
+        "BYTES",
+        width = width
+    )?;
+    for p in &stats.properties {
+        writeln!(
+            w,
+            "{:<width$}  {:>12}  {:>10}  {:>10}  {:>12}",
+            p.property,
+            p.stats.cardinality,
+            optional(p.stats.minimum),
+            optional(p.stats.maximum),
+            p.bytes,
+            width = width
+        )?;
+    }
+    writeln!(
+        w,
+        "\n{} properties, {} distinct bits in [{}, {}], {} bytes",
+        stats.properties.len(),
+        stats.root.cardinality,
+        optional(stats.root.minimum),
+        optional(stats.root.maximum),
+        stats.bytes
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crible_lib::Index;
+    use rstest::rstest;
+
+    use super::{index_stats, StatsSort};
+
+    #[rstest]
+    #[case(None, StatsSort::Property, vec!["a:bar", "a:foo", "b:baz"])]
+    #[case(None, StatsSort::Cardinality, vec!["b:baz", "a:foo", "a:bar"])]
+    #[case(Some("a:"), StatsSort::Property, vec!["a:bar", "a:foo"])]
+    #[case(Some("c:"), StatsSort::Property, vec![])]
+    fn test_index_stats(
+        #[case] prefix: Option<&str>,
+        #[case] sort: StatsSort,
+        #[case] expected: Vec<&str>,
+    ) {
+        let index = Index::of([
+            ("a:foo", vec![1, 2]),
+            ("a:bar", vec![3]),
+            ("b:baz", vec![4, 5, 6]),
+        ]);
+        let stats = index_stats(&index, prefix, sort);
+        assert_eq!(
+            stats.properties.iter().map(|p| &p.property).collect::<Vec<_>>(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_index_stats_root() {
+        let index = Index::of([
+            ("a:foo", vec![1, 2]),
+            ("a:bar", vec![2, 3]),
+            ("b:baz", vec![10]),
+        ]);
+        let stats = index_stats(&index, Some("a:"), StatsSort::Property);
+        assert_eq!(stats.root.cardinality, 3);
+        assert_eq!(stats.root.minimum, Some(1));
+        assert_eq!(stats.root.maximum, Some(3));
+        assert_eq!(
+            stats.bytes,
+            stats.properties.iter().map(|p| p.bytes).sum::<usize>()
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use std::io::Write;
+use std::time::SystemTime;
+
+use eyre::{Context, Report};
+use futures_util::{SinkExt, StreamExt};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+use url::Url;
+
+/// Websocket url of the `/subscribe` route of the server or tenant at
+/// `server`.
+fn subscribe_url(server: &Url) -> Result<Url, Report> {
+    let mut url = server.join("subscribe")?;
+    let scheme = match url.scheme() {
+        "http" | "ws" => "ws",
+        "https" | "wss" => "wss",
+        x => {
+            return Err(eyre::eyre!(
+                "Invalid server scheme {:?}, expected http or https",
+                x
+            ));
+        }
+    };
+    url.set_scheme(scheme)
+        .map_err(|_| eyre::eyre!("Invalid server url {}", server))?;
+    Ok(url)
+}
+
+/// Add the time at which a notification was received.
+fn annotate(text: &str, at: SystemTime) -> Result<String, Report> {
+    let mut value: serde_json::Value = serde_json::from_str(text)?;
+    if let Some(object) = value.as_object_mut() {
+        object.insert(
+            "received_at".to_owned(),
+            humantime::format_rfc3339_millis(at).to_string().into(),
+        );
+    }
+    Ok(serde_json::to_string(&value)?)
+}
+
+/// Subscribe to the changes of a running server and print them as NDJSON
+/// until interrupted or the server closes the connection.
+pub async fn tail(
+    server: &Url,
+    prefixes: &[String],
+    token: Option<&str>,
+) -> Result<(), Report> {
+    let url = subscribe_url(server)?;
+    let mut request = url.into_client_request()?;
+    if let Some(token) = token {
+        request.headers_mut().insert(
+            "authorization",
+            HeaderValue::from_str(&format!("Bearer {}", token))?,
+        );
+    }
+
+    let (mut socket, _) = tokio_tungstenite::connect_async(request)
+        .await
+        .wrap_err_with(|| format!("Failed to subscribe to {}", server))?;
+
+    // The empty prefix matches every property.
+    let prefixes = if prefixes.is_empty() {
+        vec![String::new()]
+    } else {
+        prefixes.to_vec()
+    };
+    socket
+        .send(Message::Text(
+            serde_json::json!({ "prefixes": prefixes }).to_string(),
+        ))
+        .await?;
+
+    let stdout = std::io::stdout();
+    loop {
+        let message = tokio::select! {
+            _ = crate::utils::shutdown_signal("Tail") => {
+                socket.close(None).await?;
+                break;
+            },
+            message = socket.next() => message,
+        };
+
+        let text = match message {
+            None => break,
+            Some(message) => match message? {
+                Message::Text(text) => text,
+                Message::Close(_) => break,
+                _ => continue,
+            },
+        };
+
+        let mut out = stdout.lock();
+        writeln!(out, "{}", annotate(&text, SystemTime::now())?)?;
+        out.flush()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use rstest::*;
+    use url::Url;
+
+    use super::{annotate, subscribe_url};
+
+    #[rstest]
+    #[case("http://localhost:3000", Some("ws://localhost:3000/subscribe"))]
+    #[case("https://host/tenant/", Some("wss://host/tenant/subscribe"))]
+    #[case("ws://localhost:3000/", Some("ws://localhost:3000/subscribe"))]
+    #[case("ftp://localhost", None)]
+    fn test_subscribe_url(
+        #[case] server: &str,
+        #[case] expected: Option<&str>,
+    ) {
+        let server: Url = server.parse().unwrap();
+        assert_eq!(
+            subscribe_url(&server).ok().map(|u| u.to_string()),
+            expected.map(|x| x.to_owned())
+        );
+    }
+
+    #[test]
+    fn test_annotate() {
+        let at = SystemTime::UNIX_EPOCH + Duration::from_secs(60);
+        assert_eq!(
+            annotate(r#"{"type":"index_reloaded"}"#, at).unwrap(),
+            concat!(
+                r#"{"received_at":"1970-01-01T00:01:00.000Z","#,
+                r#""type":"index_reloaded"}"#
+            )
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use crible_lib::expression::validate_property_name;
+use crible_lib::Index;
+use eyre::Report;
+use serde_derive::Serialize;
+
+use crate::backends::BackendOptions;
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Problem {
+    /// The index could not be loaded at all, e.g. because a bitmap cannot be
+    /// deserialized or a property is duplicated.
+    Load {
+        error: String,
+    },
+    InvalidProperty {
+        property: String,
+    },
+    EmptyProperty {
+        property: String,
+    },
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Load { error } => {
+                write!(f, "failed to load index: {}", error)
+            }
+            Self::InvalidProperty { property } => {
+                write!(f, "invalid property name {:?}", property)
+            }
+            Self::EmptyProperty { property } => {
+                write!(f, "property {:?} has no bits set", property)
+            }
+        }
+    }
+}
+
+/// Check that the index stored in a backend can be served, printing any
+/// problem found. Returns whether there were any.
+pub fn validate(
+    backend_options: &BackendOptions,
+    json: bool,
+) -> Result<bool, Report> {
+    let backend = backend_options.build()?;
+
+    let problems = match backend.load() {
+        Ok(index) => check_index(&index),
+        Err(e) => vec![Problem::Load { error: format!("{:#}", e) }],
+    };
+
+    if json {
+        println!("{}", serde_json::to_string(&problems)?);
+    } else if problems.is_empty() {
+        eprintln!("No problem found");
+    } else {
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        eprintln!("Found {} problems", problems.len());
+    }
+
+    Ok(!problems.is_empty())
+}
+
+fn check_index(index: &Index) -> Vec<Problem> {
+    let mut properties: Vec<_> = index.iter().collect();
+    properties.sort_unstable_by_key(|(k, _)| *k);
+
+    let mut problems = vec![];
+    for (property, bm) in properties {
+        if !validate_property_name(property) {
+            problems.push(Problem::InvalidProperty {
+                property: property.to_owned(),
+            });
+        }
+        if bm.is_empty() {
+            problems
+                .push(Problem::EmptyProperty { property: property.to_owned() });
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use crible_lib::Index;
+
+    use super::{check_index, Problem};
+
+    #[test]
+    fn test_check_valid_index() {
+        let index = Index::of([("foo", vec![1, 2]), ("bar:baz", vec![3])]);
+        assert_eq!(check_index(&index), vec![]);
+    }
+
+    #[test]
+    fn test_check_index() {
+        let index = Index::of([
+            ("foo", vec![1]),
+            ("1foo", vec![2]),
+            ("and", vec![]),
+            ("bar", vec![]),
+        ]);
+        assert_eq!(
+            check_index(&index),
+            vec![
+                Problem::InvalidProperty { property: "1foo".to_owned() },
+                Problem::InvalidProperty { property: "and".to_owned() },
+                Problem::EmptyProperty { property: "and".to_owned() },
+                Problem::EmptyProperty { property: "bar".to_owned() },
+            ]
+        );
+    }
+}
@@ -1,33 +1,437 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::atomic::{
+    AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering,
+};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use crible_lib::Index;
-use parking_lot::{Mutex, RwLock};
+use arc_swap::ArcSwap;
+pub use crible_api_types::FlushStatus;
+use crible_lib::{index, Index};
+use eyre::Context;
+use parking_lot::Mutex;
+use serde_derive::Serialize;
 use thiserror::Error;
-use tokio::sync::{oneshot, Semaphore, TryAcquireError};
+use tokio::sync::{broadcast, oneshot, Notify, Semaphore, TryAcquireError};
 
-use crate::backends::Backend;
+use crate::backends::{Backend, Metadata};
+use crate::changes::{Change, ChangeLog, Event};
+use crate::metrics::{Histogram, HistogramSnapshot};
+use crate::statsd::Statsd;
 
 static DEFAULT_QUEUE_SIZE_TO_POOL_SIZE_RATIO: usize = 10;
 
+/// Writes are serialized anyway, the second thread lets the next write be
+/// picked up while waiting for the current one.
+static DEFAULT_WRITE_POOL_SIZE: usize = 2;
+
+/// Number of changes buffered for slow subscribers before they start missing
+/// events.
+static CHANGES_CHANNEL_CAPACITY: usize = 1024;
+
+/// Default number of changes kept for consumers resuming from a past
+/// generation, see `ExecutorBuilder::change_log_size`.
+static DEFAULT_CHANGE_LOG_SIZE: usize = 10_000;
+
+static DEFAULT_FLUSH_RETRIES: u32 = 3;
+
+/// Delay before the first flush retry, doubled on every subsequent attempt.
+static FLUSH_RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Too many requests")]
     TooManyRequests,
+    #[error("Index version is {actual}, expected {expected}")]
+    VersionMismatch { expected: u64, actual: u64 },
+    #[error("Shutting down")]
+    ShuttingDown,
+    #[error("Backend unavailable")]
+    BackendUnavailable,
     #[error("Unknown {0}")]
     Unknown(eyre::Report),
 }
 
+/// When writes are persisted to the backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    /// Flush after every write, before responding.
+    #[default]
+    OnWrite,
+    /// Flush pending writes in the background on a fixed interval.
+    Interval(Duration),
+    /// Flush in the background once this many writes are pending.
+    AfterWrites(usize),
+    /// Only flush when explicitly requested.
+    Manual,
+}
+
+impl FromStr for FlushPolicy {
+    type Err = eyre::Report;
+
+    /// Parse one of `on-write`, `interval(<ms>)`, `after-n-writes(<n>)` or
+    /// `manual`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || eyre::Report::msg(format!("Invalid flush policy {:?}", value));
+
+        let (name, arg) = match value.split_once('(') {
+            None => (value, None),
+            Some((name, rest)) => {
+                (name, Some(rest.strip_suffix(')').ok_or_else(invalid)?))
+            }
+        };
+
+        match (name, arg) {
+            ("on-write", None) => Ok(FlushPolicy::OnWrite),
+            ("manual", None) => Ok(FlushPolicy::Manual),
+            // A zero interval would make `tokio::time::interval` panic.
+            ("interval", Some(ms)) => match ms.parse() {
+                Ok(ms) if ms > 0 => {
+                    Ok(FlushPolicy::Interval(Duration::from_millis(ms)))
+                }
+                _ => Err(invalid()),
+            },
+            ("after-n-writes", Some(n)) => match n.parse() {
+                Ok(n) if n > 0 => Ok(FlushPolicy::AfterWrites(n)),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// What happens to tasks submitted while a lane's queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueuePolicy {
+    /// Reject them immediately.
+    #[default]
+    Reject,
+    /// Wait for a spot in the queue, rejecting them if none frees up in
+    /// time.
+    Wait(Duration),
+}
+
+impl FromStr for QueuePolicy {
+    type Err = eyre::Report;
+
+    /// Parse one of `reject` or `wait:<ms>`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || eyre::Report::msg(format!("Invalid queue policy {:?}", value));
+
+        match value.split_once(':') {
+            None if value == "reject" => Ok(QueuePolicy::Reject),
+            Some(("wait", ms)) => Ok(QueuePolicy::Wait(Duration::from_millis(
+                ms.parse().map_err(|_| invalid())?,
+            ))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// When the index and its properties were last modified.
+#[derive(Debug)]
+struct Modified {
+    index: SystemTime,
+    /// Last change which may have affected every property.
+    all: SystemTime,
+    /// Properties modified after `all`.
+    properties: HashMap<String, SystemTime>,
+}
+
+impl Modified {
+    fn new(at: SystemTime) -> Self {
+        Self { index: at, all: at, properties: HashMap::new() }
+    }
+
+    fn mark(&mut self, change: &Change, at: SystemTime) {
+        self.index = at;
+        match change {
+            Change::Mutation { properties: Some(properties), .. } => {
+                for property in properties {
+                    self.properties.insert(property.clone(), at);
+                }
+            }
+            _ => {
+                self.all = at;
+                self.properties.clear();
+            }
+        }
+    }
+
+    fn property(&self, property: &str) -> SystemTime {
+        self.properties.get(property).copied().unwrap_or(self.all)
+    }
+}
+
+/// Properties modified since the last successful flush.
+#[derive(Debug, PartialEq, Eq)]
+enum Dirty {
+    Properties(HashSet<String>),
+    /// The modified properties are unknown.
+    All,
+}
+
+impl Default for Dirty {
+    fn default() -> Self {
+        Dirty::Properties(HashSet::new())
+    }
+}
+
+impl Dirty {
+    fn mark(&mut self, change: &Change) {
+        match (&mut *self, change) {
+            (Dirty::All, _) => {}
+            (
+                Dirty::Properties(dirty),
+                Change::Mutation { properties: Some(properties), .. },
+            ) => {
+                dirty.extend(properties.iter().cloned());
+            }
+            (Dirty::Properties(_), _) => *self = Dirty::All,
+        }
+    }
+
+    fn merge(&mut self, other: Dirty) {
+        match (&mut *self, other) {
+            (Dirty::All, _) => {}
+            (_, Dirty::All) => *self = Dirty::All,
+            (Dirty::Properties(dirty), Dirty::Properties(other)) => {
+                dirty.extend(other);
+            }
+        }
+    }
+}
+
+/// Index shared by the executor tasks as immutable snapshots. Readers use
+/// the snapshot current when they start and never block, writers apply their
+/// changes to a copy of the current snapshot which then replaces it for
+/// subsequent readers. Copies share the property names and bitmaps of the
+/// snapshot, only the bitmaps a writer modifies are copied, see
+/// `index::SharedPropertyMap`.
+///
+/// Writers must be serialized by the caller (see `Executor::spawn_write`) or
+/// concurrent updates would be lost.
+pub struct SharedIndex(ArcSwap<Index>);
+
+impl SharedIndex {
+    pub fn new(index: Index) -> Self {
+        Self(ArcSwap::from_pointee(index))
+    }
+
+    /// Current snapshot. Holding on to it does not prevent writes, it only
+    /// keeps the snapshot alive.
+    pub fn read(&self) -> Arc<Index> {
+        self.0.load_full()
+    }
+
+    /// Replace the current snapshot with freshly loaded data. The universe
+    /// of the current snapshot is kept as it is configuration rather than
+    /// data, this fails if `index` has bits outside of it, see
+    /// `Index::set_universe`.
+    pub fn replace(&self, mut index: Index) -> Result<(), index::Error> {
+        let universe = self.0.load().universe();
+        if index.universe() != universe {
+            index.set_universe(universe)?;
+        }
+        self.store(index);
+        Ok(())
+    }
+
+    /// Publish a modified copy of the current snapshot as is.
+    pub fn store(&self, index: Index) {
+        self.0.store(Arc::new(index));
+    }
+
+    /// Apply `func` to a copy of the current snapshot and publish the result.
+    pub fn update<F, T>(&self, func: F) -> T
+    where
+        F: FnOnce(&mut Index) -> T,
+    {
+        let mut index = Index::clone(&self.0.load());
+        let output = func(&mut index);
+        self.store(index);
+        output
+    }
+}
+
+/// Executor tasks are split in lanes, each with its own thread pool and
+/// queue, so that a backlog of heavy queries cannot delay writes and vice
+/// versa.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lane {
+    /// Queries and other read only tasks.
+    Read,
+    /// Writes and reloads. Flushes run outside of the lanes so that a slow
+    /// backend never holds back writes, see `Executor::flush`.
+    Write,
+}
+
+tokio::task_local! {
+    static HANDOFF: Handoff;
+}
+
+/// Tracks whether the writes of a caller which may give up on them, e.g.
+/// after a timeout, were handed off to a thread. A write which has started
+/// cannot be abandoned: the caller must wait for it to complete so that it
+/// is reported and published.
+#[derive(Debug, Clone, Default)]
+pub struct Handoff(Arc<AtomicU8>);
+
+impl Handoff {
+    const PENDING: u8 = 0;
+    const STARTED: u8 = 1;
+    const ABANDONED: u8 = 2;
+
+    /// Run `future`, tracking the writes it submits on the write lane.
+    pub async fn scope<F: Future>(&self, future: F) -> F::Output {
+        HANDOFF.scope(self.clone(), future).await
+    }
+
+    /// Prevent writes which have not started yet from running. Returns
+    /// `false` when one already started, it must then be waited for.
+    pub fn abandon(&self) -> bool {
+        self.0
+            .compare_exchange(
+                Self::PENDING,
+                Self::ABANDONED,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .map_or_else(|state| state != Self::STARTED, |_| true)
+    }
+
+    /// Mark a write as started, unless the caller abandoned it.
+    fn start(&self) -> bool {
+        self.0
+            .compare_exchange(
+                Self::PENDING,
+                Self::STARTED,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .map_or_else(|state| state == Self::STARTED, |_| true)
+    }
+}
+
+struct LanePool {
+    queue: Semaphore,
+    queue_size: usize,
+    pool_size: usize,
+    /// Possibly shared with other executors, see
+    /// `ExecutorBuilder::share_threads`.
+    thread_pool: Arc<rayon::ThreadPool>,
+    executed: AtomicU64,
+    rejected: AtomicU64,
+    abandoned: Arc<AtomicU64>,
+    /// Time between submitting a task and a thread picking it up.
+    wait_time: Histogram,
+    run_time: Histogram,
+}
+
+impl LanePool {
+    fn new(
+        name: &'static str,
+        pool_size: Option<usize>,
+        queue_size: Option<usize>,
+        default_pool_size: usize,
+        shared: Option<Arc<rayon::ThreadPool>>,
+    ) -> eyre::Result<Self> {
+        let thread_pool = match (pool_size, shared) {
+            (None, Some(shared)) => shared,
+            (pool_size, _) => Arc::new(
+                rayon::ThreadPoolBuilder::new()
+                    .thread_name(move |n| {
+                        format!("crible-executor-{}-thread-{}", name, n)
+                    })
+                    .num_threads(pool_size.unwrap_or(default_pool_size))
+                    .build()?,
+            ),
+        };
+        let pool_size = thread_pool.current_num_threads();
+        let queue_size = queue_size
+            .unwrap_or(pool_size * DEFAULT_QUEUE_SIZE_TO_POOL_SIZE_RATIO);
+
+        Ok(Self {
+            queue: Semaphore::new(queue_size),
+            queue_size,
+            pool_size,
+            thread_pool,
+            executed: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            abandoned: Arc::new(AtomicU64::new(0)),
+            wait_time: Histogram::default(),
+            run_time: Histogram::default(),
+        })
+    }
+
+    fn stats(&self) -> LaneStats {
+        let available = self.queue.available_permits();
+        LaneStats {
+            pool_size: self.pool_size,
+            queue_size: self.queue_size,
+            queued: self.queue_size - available,
+            available,
+            executed: self.executed.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            abandoned: self.abandoned.load(Ordering::Relaxed),
+            wait_time: self.wait_time.snapshot(),
+            run_time: self.run_time.snapshot(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LaneStats {
+    pub pool_size: usize,
+    pub queue_size: usize,
+    /// Tasks currently running or waiting for a thread.
+    pub queued: usize,
+    /// Remaining spots in the queue.
+    pub available: usize,
+    /// Tasks run to completion since startup.
+    pub executed: u64,
+    /// Tasks rejected because the queue was full since startup.
+    pub rejected: u64,
+    /// Tasks skipped because their caller went away before they started
+    /// since startup.
+    pub abandoned: u64,
+    /// Time spent by tasks between being submitted and starting to run,
+    /// including waiting for a spot in the queue.
+    pub wait_time: HistogramSnapshot,
+    pub run_time: HistogramSnapshot,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExecutorStats {
+    pub read: LaneStats,
+    pub write: LaneStats,
+}
+
 pub struct ExecutorBuilder {
-    index: Arc<RwLock<Index>>,
+    index: Arc<SharedIndex>,
     backend: Arc<Mutex<Box<dyn Backend>>>,
     read_only: bool,
     pool_size: Option<usize>,
     queue_size: Option<usize>,
+    write_pool_size: Option<usize>,
+    write_queue_size: Option<usize>,
+    shared_thread_pools:
+        Option<(Arc<rayon::ThreadPool>, Arc<rayon::ThreadPool>)>,
+    queue_policy: QueuePolicy,
+    flush_policy: FlushPolicy,
+    flush_retries: u32,
+    reject_writes_when_unflushed: bool,
+    change_log_size: usize,
+    statsd: Option<Arc<Statsd>>,
 }
 
 impl ExecutorBuilder {
     pub fn new(
-        index: Arc<RwLock<Index>>,
+        index: Arc<SharedIndex>,
         backend: Arc<Mutex<Box<dyn Backend>>>,
     ) -> Self {
         Self {
@@ -36,6 +440,15 @@ impl ExecutorBuilder {
             read_only: false,
             pool_size: None,
             queue_size: None,
+            write_pool_size: None,
+            write_queue_size: None,
+            shared_thread_pools: None,
+            queue_policy: QueuePolicy::default(),
+            flush_policy: FlushPolicy::default(),
+            flush_retries: DEFAULT_FLUSH_RETRIES,
+            reject_writes_when_unflushed: false,
+            change_log_size: DEFAULT_CHANGE_LOG_SIZE,
+            statsd: None,
         }
     }
 
@@ -44,6 +457,7 @@ impl ExecutorBuilder {
         self
     }
 
+    /// Number of threads of the read lane.
     pub fn pool_size(mut self, pool_size: usize) -> Self {
         self.pool_size = Some(pool_size);
         if self.queue_size.is_none() {
@@ -57,84 +471,1260 @@ impl ExecutorBuilder {
         self
     }
 
-    pub fn build(self) -> eyre::Result<Executor> {
-        let pool_size = self.pool_size.unwrap_or_else(num_cpus::get);
-        let queue_size = self
-            .queue_size
-            .unwrap_or(pool_size * DEFAULT_QUEUE_SIZE_TO_POOL_SIZE_RATIO);
+    /// Number of threads of the write lane.
+    pub fn write_pool_size(mut self, pool_size: usize) -> Self {
+        self.write_pool_size = Some(pool_size);
+        if self.write_queue_size.is_none() {
+            self.write_queue_size = self.write_pool_size;
+        }
+        self
+    }
+
+    pub fn write_queue_size(mut self, queue_size: usize) -> Self {
+        self.write_queue_size = Some(queue_size);
+        self
+    }
+
+    /// Run tasks on the threads of `other` instead of starting new ones,
+    /// for lanes whose pool size is not set explicitly. This is meant for
+    /// serving multiple indices without multiplying the number of threads:
+    /// each executor keeps its own index, backend, queues and flush state.
+    pub fn share_threads(mut self, other: &Executor) -> Self {
+        self.shared_thread_pools = Some((
+            other.read_lane.thread_pool.clone(),
+            other.write_lane.thread_pool.clone(),
+        ));
+        self
+    }
+
+    pub fn queue_policy(mut self, queue_policy: QueuePolicy) -> Self {
+        self.queue_policy = queue_policy;
+        self
+    }
+
+    pub fn flush_policy(mut self, flush_policy: FlushPolicy) -> Self {
+        self.flush_policy = flush_policy;
+        self
+    }
+
+    /// Number of times a failed flush is retried, with exponential backoff,
+    /// before giving up.
+    pub fn flush_retries(mut self, flush_retries: u32) -> Self {
+        self.flush_retries = flush_retries;
+        self
+    }
 
+    /// Reject writes with `Error::BackendUnavailable` while pending writes
+    /// cannot be flushed instead of accepting writes which may be lost.
+    pub fn reject_writes_when_unflushed(mut self, reject: bool) -> Self {
+        self.reject_writes_when_unflushed = reject;
+        self
+    }
+
+    /// Number of most recent changes kept in memory for consumers resuming
+    /// from a past generation, see `Executor::changes_since`.
+    pub fn change_log_size(mut self, size: usize) -> Self {
+        self.change_log_size = size;
+        self
+    }
+
+    /// Report flush outcomes and durations.
+    pub fn statsd(mut self, statsd: Arc<Statsd>) -> Self {
+        self.statsd = Some(statsd);
+        self
+    }
+
+    pub fn build(self) -> eyre::Result<Executor> {
+        let (shared_read, shared_write) = match self.shared_thread_pools {
+            Some((read, write)) => (Some(read), Some(write)),
+            None => (None, None),
+        };
+        let metadata = self
+            .backend
+            .lock()
+            .load_metadata()
+            .wrap_err("Failed to load property metadata")?;
+        // The index was loaded by the caller, at best this is the time of
+        // the next modification.
+        let loaded = self.backend.lock().last_modified().unwrap_or(None);
         Ok(Executor {
+            read_lane: LanePool::new(
+                "read",
+                self.pool_size,
+                self.queue_size,
+                num_cpus::get(),
+                shared_read,
+            )?,
+            write_lane: LanePool::new(
+                "write",
+                self.write_pool_size,
+                self.write_queue_size,
+                DEFAULT_WRITE_POOL_SIZE,
+                shared_write,
+            )?,
+            queue_policy: self.queue_policy,
+            stopped: Arc::new(AtomicBool::new(false)),
             index: self.index,
             backend: self.backend,
+            metadata: Arc::new(ArcSwap::from_pointee(metadata)),
+            modified: Mutex::new(Modified::new(
+                loaded.unwrap_or_else(SystemTime::now),
+            )),
+            loaded: Arc::new(Mutex::new(loaded)),
             read_only: self.read_only,
-            queue: Semaphore::new(queue_size),
-            thread_pool: rayon::ThreadPoolBuilder::new()
-                .thread_name(|n| format!("crible-executor-thread-{}", n))
-                .num_threads(pool_size)
-                .build()?,
+            changes: broadcast::channel(CHANGES_CHANNEL_CAPACITY).0,
+            change_log: Mutex::new(ChangeLog::new(self.change_log_size)),
+            flush_policy: Mutex::new(self.flush_policy),
+            pending_writes: Arc::new(AtomicUsize::new(0)),
+            dirty: Arc::new(Mutex::new(Dirty::default())),
+            version: Arc::new(AtomicU64::new(0)),
+            writer: Arc::new(Mutex::new(())),
+            flush_requested: Notify::new(),
+            flush_retries: self.flush_retries,
+            flush_failing: AtomicBool::new(false),
+            reject_writes_when_unflushed: self.reject_writes_when_unflushed,
+            statsd: self.statsd,
         })
     }
 }
 
+#[inline]
+fn check_version(
+    version: &AtomicU64,
+    expected_version: Option<u64>,
+) -> Result<(), Error> {
+    let actual = version.load(Ordering::SeqCst);
+    match expected_version {
+        Some(expected) if expected != actual => {
+            Err(Error::VersionMismatch { expected, actual })
+        }
+        _ => Ok(()),
+    }
+}
+
+#[inline]
+fn check_stopped(stopped: &AtomicBool) -> Result<(), Error> {
+    if stopped.load(Ordering::SeqCst) {
+        Err(Error::ShuttingDown)
+    } else {
+        Ok(())
+    }
+}
+
 pub struct Executor {
-    queue: Semaphore,
-    thread_pool: rayon::ThreadPool,
-    index: Arc<RwLock<Index>>,
+    read_lane: LanePool,
+    write_lane: LanePool,
+    queue_policy: QueuePolicy,
+    /// Set on shutdown, writes are rejected from then on.
+    stopped: Arc<AtomicBool>,
+    index: Arc<SharedIndex>,
     backend: Arc<Mutex<Box<dyn Backend>>>,
+    /// Property metadata, persisted as soon as it is modified.
+    metadata: Arc<ArcSwap<Metadata>>,
+    modified: Mutex<Modified>,
+    /// Modification time reported by the backend for the data last loaded,
+    /// see `Backend::last_modified`.
+    loaded: Arc<Mutex<Option<SystemTime>>>,
+    changes: broadcast::Sender<Change>,
+    change_log: Mutex<ChangeLog>,
+    /// Number of writes applied since the last successful flush.
+    pending_writes: Arc<AtomicUsize>,
+    dirty: Arc<Mutex<Dirty>>,
+    /// Incremented after every write, only modified while holding `writer`.
+    version: Arc<AtomicU64>,
+    /// Serializes writes so that reading and bumping the version is atomic
+    /// with the write itself.
+    writer: Arc<Mutex<()>>,
+    flush_requested: Notify,
+    flush_policy: Mutex<FlushPolicy>,
+    flush_retries: u32,
+    /// Set when a flush failed after exhausting its retries, cleared on the
+    /// next successful one.
+    flush_failing: AtomicBool,
+    reject_writes_when_unflushed: bool,
+    statsd: Option<Arc<Statsd>>,
     pub read_only: bool,
 }
 
 impl Executor {
+    fn lane(&self, lane: Lane) -> &LanePool {
+        match lane {
+            Lane::Read => &self.read_lane,
+            Lane::Write => &self.write_lane,
+        }
+    }
+
+    /// Run a read only task on the read lane.
+    ///
+    /// Tasks which have not started yet are skipped when the returned future
+    /// is dropped. Tasks which already started run to completion unless they
+    /// check for cancellation themselves, see `crible_lib::Cancellation`.
     pub async fn spawn<F, T>(&self, func: F) -> Result<T, Error>
     where
-        F: FnOnce(Arc<RwLock<Index>>) -> T + Send + 'static,
+        F: FnOnce(Arc<SharedIndex>) -> T + Send + 'static,
         T: Sync + Send + 'static,
     {
-        // TODO: Can we support both queued and unlimited queue?
-        let maybe_permit = self.queue.try_acquire();
-        match maybe_permit {
+        self.spawn_on(Lane::Read, func).await
+    }
+
+    pub async fn spawn_on<F, T>(&self, lane: Lane, func: F) -> Result<T, Error>
+    where
+        F: FnOnce(Arc<SharedIndex>) -> T + Send + 'static,
+        T: Sync + Send + 'static,
+    {
+        let handoff = match lane {
+            Lane::Write => HANDOFF.try_with(|h| h.clone()).ok(),
+            Lane::Read => None,
+        };
+        let lane = self.lane(lane);
+        let submitted = Instant::now();
+
+        let maybe_permit = match self.queue_policy {
+            QueuePolicy::Reject => lane.queue.try_acquire(),
+            QueuePolicy::Wait(timeout) => {
+                let acquire = lane.queue.acquire();
+                match tokio::time::timeout(timeout, acquire).await {
+                    Ok(Ok(permit)) => Ok(permit),
+                    Ok(Err(e)) => {
+                        return Err(Error::Unknown(eyre::Report::new(e)));
+                    }
+                    Err(_) => Err(TryAcquireError::NoPermits),
+                }
+            }
+        };
+
+        let _permit = match maybe_permit {
+            Ok(permit) => permit,
             Err(TryAcquireError::NoPermits) => {
+                lane.rejected.fetch_add(1, Ordering::Relaxed);
                 return Err(Error::TooManyRequests);
             }
             Err(e) => {
                 return Err(Error::Unknown(eyre::Report::new(e)));
             }
-            _ => {}
         };
 
         let index = self.index.clone();
 
         let (tx, rx) = oneshot::channel();
 
-        self.thread_pool.spawn(move || {
+        let abandoned = lane.abandoned.clone();
+        lane.thread_pool.spawn(move || {
+            // The receiver is dropped along with the request when the client
+            // disconnects, there is no point in running the task then.
+            if tx.is_closed() || !handoff.map_or(true, |h| h.start()) {
+                abandoned.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            let started = Instant::now();
             let result = func(index);
             // TODO: Handle error?
-            let _ = tx.send(result);
+            let _ = tx.send((result, started, started.elapsed()));
         });
 
-        rx.await.map_err(|e| Error::Unknown(eyre::Report::new(e)))
+        let (result, started, run_time) =
+            rx.await.map_err(|e| Error::Unknown(eyre::Report::new(e)))?;
+        lane.executed.fetch_add(1, Ordering::Relaxed);
+        lane.wait_time.observe(started - submitted);
+        lane.run_time.observe(run_time);
+        Ok(result)
+    }
+
+    /// Run a task modifying the index, incrementing the index version. When
+    /// `expected_version` is provided the task is only run if it matches the
+    /// current version.
+    ///
+    /// Returns the output of the task along with the new index version.
+    pub async fn spawn_write<F, T>(
+        &self,
+        expected_version: Option<u64>,
+        func: F,
+    ) -> Result<(T, u64), Error>
+    where
+        F: FnOnce(Arc<SharedIndex>) -> T + Send + 'static,
+        T: Sync + Send + 'static,
+    {
+        if self.reject_writes_when_unflushed && self.is_flush_failing() {
+            return Err(Error::BackendUnavailable);
+        }
+
+        let writer = self.writer.clone();
+        let version = self.version.clone();
+        let stopped = self.stopped.clone();
+        self.spawn_on(Lane::Write, move |index| {
+            let _writer = writer.lock();
+            check_stopped(&stopped)?;
+            check_version(&version, expected_version)?;
+            let output = func(index);
+            Ok((output, version.fetch_add(1, Ordering::SeqCst) + 1))
+        })
+        .await?
+    }
+
+    /// Apply `apply` atomically: the properties returned by `touched` are
+    /// persisted before the result is visible to readers and nothing is
+    /// applied if persisting fails. `touched` must return every property
+    /// `apply` may modify. Nothing is applied either if `apply` fails.
+    ///
+    /// Returns the output of `apply` along with the new index version.
+    pub async fn transaction<P, A, T, E>(
+        &self,
+        expected_version: Option<u64>,
+        touched: P,
+        apply: A,
+    ) -> Result<(T, u64), E>
+    where
+        P: FnOnce(&Index) -> HashSet<String> + Send + 'static,
+        A: FnOnce(&mut Index) -> Result<T, E> + Send + 'static,
+        T: Sync + Send + 'static,
+        E: From<Error> + Sync + Send + 'static,
+    {
+        let writer = self.writer.clone();
+        let version = self.version.clone();
+        let stopped = self.stopped.clone();
+        let backend = self.backend.clone();
+        let read_only = self.read_only;
+        self.spawn_on(Lane::Write, move |index| {
+            let _writer = writer.lock();
+
+            check_stopped(&stopped)?;
+            check_version(&version, expected_version)?;
+
+            // Changes are applied to a copy which is only published once
+            // persisted, so nothing needs restoring if persisting fails.
+            let mut idx = Index::clone(&index.read());
+            let properties = touched(&idx);
+            let output = apply(&mut idx)?;
+
+            if !read_only {
+                if let Err(e) = backend.lock().dump_partial(&idx, &properties) {
+                    return Err(Error::Unknown(
+                        e.wrap_err("Failed to persist transaction"),
+                    )
+                    .into());
+                }
+            }
+
+            index.store(idx);
+            Ok((output, version.fetch_add(1, Ordering::SeqCst) + 1))
+        })
+        .await?
+    }
+
+    /// Stop accepting writes, wait for running tasks to complete and flush
+    /// pending writes a last time so that they are not lost on exit. Writes
+    /// still queued are rejected as they check for shutdown once running.
+    pub async fn shutdown(&self) -> eyre::Result<()> {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Running and queued tasks each hold a permit.
+        for lane in [&self.read_lane, &self.write_lane] {
+            drop(lane.queue.acquire_many(lane.queue_size as u32).await?);
+        }
+        self.flush_pending().await
+    }
+
+    /// Current index version. The version is incremented after publishing
+    /// the snapshot, reading it before loading the snapshot guarantees it is
+    /// not newer than the data being read.
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    /// Whether the executor can currently accept new tasks on both lanes
+    /// without rejecting them.
+    pub fn is_accepting_work(&self) -> bool {
+        self.read_lane.queue.available_permits() > 0
+            && self.write_lane.queue.available_permits() > 0
+    }
+
+    pub fn stats(&self) -> ExecutorStats {
+        ExecutorStats {
+            read: self.read_lane.stats(),
+            write: self.write_lane.stats(),
+        }
+    }
+
+    /// Check the backend connectivity. This does not go through the executor
+    /// queue so that it doesn't compete with actual work.
+    pub async fn ping_backend(&self) -> eyre::Result<()> {
+        let backend = self.backend.clone();
+        tokio::task::spawn_blocking(move || backend.lock().ping()).await?
     }
 
     pub async fn reload(&self) -> eyre::Result<()> {
         let backend = self.backend.clone();
-        self.spawn(move |index| {
-            *index.as_ref().write() = backend.lock().load()?;
+        let metadata = self.metadata.clone();
+        let loaded = self.loaded.clone();
+        let dirty = self.dirty.clone();
+        let pending_writes = self.pending_writes.clone();
+        self.spawn_write(None, move |index| -> eyre::Result<()> {
+            let backend = backend.lock();
+            // Read first so that modifications made while loading are picked
+            // up by the next refresh.
+            let modified = backend.last_modified()?;
+            index.replace(backend.load()?)?;
+            // Unflushed writes were discarded along with the previous index.
+            // Reset while still holding the writer lock so that writes
+            // applied after the reload are staged on top of it.
+            *dirty.lock() = Dirty::default();
+            pending_writes.store(0, Ordering::SeqCst);
+            metadata.store(Arc::new(backend.load_metadata()?));
+            *loaded.lock() = modified;
             Ok(())
         })
         .await?
+        .0?;
+        self.publish(Change::Reload);
+        Ok(())
     }
 
-    // TODO: Expose partial writes.
+    /// Reload the index unless the backend reports that it was not modified
+    /// since it was last loaded. Returns whether the index was reloaded.
+    pub async fn reload_if_modified(&self) -> eyre::Result<bool> {
+        let backend = self.backend.clone();
+        let modified =
+            tokio::task::spawn_blocking(move || backend.lock().last_modified())
+                .await??;
+        if modified.is_some() && modified == *self.loaded.lock() {
+            return Ok(false);
+        }
+        self.reload().await?;
+        Ok(true)
+    }
+
+    /// Optimize the storage of `properties`, see
+    /// `Index::optimize_properties`. This holds the write lock while running
+    /// but doesn't change the content of the index so neither the version
+    /// nor the pending writes are incremented.
+    pub async fn optimize(&self, properties: Vec<String>) -> Result<(), Error> {
+        let writer = self.writer.clone();
+        let stopped = self.stopped.clone();
+        self.spawn_on(Lane::Write, move |index| {
+            let _writer = writer.lock();
+            check_stopped(&stopped)?;
+            index.update(|idx| idx.optimize_properties(&properties));
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Release memory unused by the index, see `Index::shrink_to_fit`. Same
+    /// as `optimize`, neither the version nor the pending writes change.
+    /// Returns the number of bytes reclaimed.
+    pub async fn shrink(&self) -> Result<usize, Error> {
+        let writer = self.writer.clone();
+        let stopped = self.stopped.clone();
+        self.spawn_on(Lane::Write, move |index| {
+            let _writer = writer.lock();
+            check_stopped(&stopped)?;
+            Ok(index.update(|idx| idx.shrink_to_fit()))
+        })
+        .await?
+    }
+
+    /// Current property metadata.
+    pub fn metadata(&self) -> Arc<Metadata> {
+        self.metadata.load_full()
+    }
+
+    /// Apply `func` to the property metadata and persist the result before
+    /// making it visible, regardless of the flush policy. Metadata changes
+    /// do not affect the index version.
+    pub async fn update_metadata<F, T>(&self, func: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Metadata) -> T + Send + 'static,
+        T: Sync + Send + 'static,
+    {
+        let writer = self.writer.clone();
+        let stopped = self.stopped.clone();
+        let backend = self.backend.clone();
+        let metadata = self.metadata.clone();
+        let read_only = self.read_only;
+        self.spawn_on(Lane::Write, move |_| {
+            let _writer = writer.lock();
+            check_stopped(&stopped)?;
+
+            let mut updated = Metadata::clone(&metadata.load());
+            let output = func(&mut updated);
+            if !read_only {
+                if let Err(e) = backend.lock().dump_metadata(&updated) {
+                    return Err(Error::Unknown(
+                        e.wrap_err("Failed to persist property metadata"),
+                    ));
+                }
+            }
+            metadata.store(Arc::new(updated));
+            Ok(output)
+        })
+        .await?
+    }
+
+    /// Subscribe to changes applied to the index from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Change> {
+        self.changes.subscribe()
+    }
+
+    pub fn publish(&self, change: Change) {
+        self.modified.lock().mark(&change, SystemTime::now());
+        // Logged first so that subscribers woken up by the change find it
+        // in the log.
+        self.change_log.lock().push(change.clone());
+        // Sending only fails when there are no subscribers.
+        let _ = self.changes.send(change);
+    }
+
+    /// Generation of the most recently published change.
+    pub fn generation(&self) -> u64 {
+        self.change_log.lock().generation()
+    }
+
+    /// Identifies the change log, generations of different executors or
+    /// processes are unrelated.
+    pub fn change_log_id(&self) -> String {
+        self.change_log.lock().id().to_owned()
+    }
+
+    /// Changes published after generation `since`, `None` if some of them
+    /// are not kept anymore, see `ChangeLog::since`.
+    pub fn changes_since(&self, since: u64) -> Option<Vec<Event>> {
+        self.change_log.lock().since(since)
+    }
+
+    /// Notify subscribers of a successful mutation and record it as pending
+    /// without flushing, returning the number of pending writes. Callers
+    /// applying many writes in a row can use this and flush once at the end.
+    pub fn stage(&self, change: Change) -> usize {
+        self.dirty.lock().mark(&change);
+        self.publish(change);
+        self.pending_writes.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Notify subscribers of a successful mutation and persist it according
+    /// to the flush policy.
+    pub async fn commit(&self, change: Change) -> eyre::Result<()> {
+        let pending = self.stage(change);
+        match self.flush_policy() {
+            FlushPolicy::OnWrite => self.flush().await,
+            FlushPolicy::AfterWrites(n) if pending >= n => {
+                self.flush_requested.notify_one();
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn flush_policy(&self) -> FlushPolicy {
+        *self.flush_policy.lock()
+    }
+
+    /// Change the flush policy, waking up the flush task so that it picks up
+    /// the new policy.
+    pub fn set_flush_policy(&self, policy: FlushPolicy) {
+        *self.flush_policy.lock() = policy;
+        self.flush_requested.notify_one();
+    }
+
+    /// Number of writes not yet persisted to the backend.
+    pub fn pending_writes(&self) -> usize {
+        self.pending_writes.load(Ordering::SeqCst)
+    }
+
+    /// Wait until enough writes are pending for the `AfterWrites` policy.
+    /// Multiple requests made while no one is waiting are coalesced.
+    pub async fn flush_requested(&self) {
+        self.flush_requested.notified().await
+    }
+
+    /// Flush if there are any pending writes.
+    pub async fn flush_pending(&self) -> eyre::Result<()> {
+        if self.pending_writes() > 0 { self.flush().await } else { Ok(()) }
+    }
+
+    pub fn is_flush_failing(&self) -> bool {
+        self.flush_failing.load(Ordering::SeqCst)
+    }
+
+    /// When the index was last modified, by a write or a reload.
+    pub fn last_modified(&self) -> SystemTime {
+        self.modified.lock().index
+    }
+
+    /// When each of `properties` was last modified. Properties which were
+    /// never modified individually report the last change affecting every
+    /// property.
+    pub fn properties_last_modified<'a, I>(
+        &self,
+        properties: I,
+    ) -> HashMap<String, SystemTime>
+    where
+        I: IntoIterator<Item = &'a String>,
+    {
+        let modified = self.modified.lock();
+        properties
+            .into_iter()
+            .map(|p| (p.clone(), modified.property(p)))
+            .collect()
+    }
+
+    pub fn flush_status(&self) -> FlushStatus {
+        FlushStatus {
+            pending_writes: self.pending_writes(),
+            failing: self.is_flush_failing(),
+        }
+    }
+
+    /// Persist the properties modified since the last flush, or the whole
+    /// index if they are unknown. Failures are retried with exponential
+    /// backoff before giving up, see `ExecutorBuilder::flush_retries`.
+    ///
+    /// The backend is given a snapshot of the index, writes applied while it
+    /// is being persisted are not held back and are not observed.
     pub async fn flush(&self) -> eyre::Result<()> {
+        let mut delay = FLUSH_RETRY_BASE_DELAY;
+        let mut attempt = 0;
+        loop {
+            let start = Instant::now();
+            let result = self.flush_once().await;
+            if let Some(statsd) = &self.statsd {
+                let outcome =
+                    if result.is_ok() { "success" } else { "failure" };
+                statsd.count("flush", 1, &[("outcome", outcome)]);
+                statsd.timing("flush.duration", start.elapsed(), &[]);
+            }
+            match result {
+                Ok(()) => {
+                    if self.flush_failing.swap(false, Ordering::SeqCst) {
+                        tracing::info!("Flushing succeeded again.");
+                    }
+                    return Ok(());
+                }
+                Err(e) if attempt < self.flush_retries => {
+                    attempt += 1;
+                    tracing::warn!(
+                        "Failed to flush index (attempt {}), retrying in \
+                         {:?}: {:?}",
+                        attempt,
+                        delay,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => {
+                    self.flush_failing.store(true, Ordering::SeqCst);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    async fn flush_once(&self) -> eyre::Result<()> {
         if !self.read_only {
+            let pending = self.pending_writes.swap(0, Ordering::SeqCst);
+            let dirty = std::mem::take(&mut *self.dirty.lock());
             let backend = self.backend.clone();
-            self.spawn(move |index| {
-                backend.lock().dump(&index.read())?;
-                Ok(())
+            let index = self.index.clone();
+            let spawned = tokio::task::spawn_blocking(move || {
+                let backend = backend.lock();
+                // Taken once the backend is locked so that the snapshot is
+                // never older than what the previous holder persisted, e.g.
+                // by a transaction.
+                let idx = index.read();
+                let result = match &dirty {
+                    Dirty::All => backend.dump(&idx),
+                    Dirty::Properties(properties) => {
+                        backend.dump_partial(&idx, properties)
+                    }
+                };
+                (dirty, result)
             })
-            .await?
+            .await;
+            let (dirty, result) = match spawned {
+                Ok(x) => x,
+                Err(e) => {
+                    // The modified properties were dropped along with the
+                    // task.
+                    self.pending_writes.fetch_add(pending, Ordering::SeqCst);
+                    self.dirty.lock().merge(Dirty::All);
+                    return Err(e.into());
+                }
+            };
+            if result.is_err() {
+                // Keep track of the writes which were not persisted so the
+                // next flush picks them up.
+                self.pending_writes.fetch_add(pending, Ordering::SeqCst);
+                self.dirty.lock().merge(dirty);
+            }
+            result
         } else {
             Ok(())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use crible_lib::Index;
+    use parking_lot::Mutex;
+    use rstest::*;
+
+    use super::{
+        Dirty, Error, ExecutorBuilder, FlushPolicy, Handoff, Lane, Modified,
+        QueuePolicy, SharedIndex,
+    };
+    use crate::backends::{Backend, Memory};
+    use crate::changes::Change;
+
+    #[rstest]
+    #[case("on-write", Some(FlushPolicy::OnWrite))]
+    #[case("manual", Some(FlushPolicy::Manual))]
+    #[case(
+        "interval(500)",
+        Some(FlushPolicy::Interval(Duration::from_millis(500)))
+    )]
+    #[case("after-n-writes(100)", Some(FlushPolicy::AfterWrites(100)))]
+    #[case("after-n-writes(0)", None)]
+    #[case("interval", None)]
+    #[case("interval(0)", None)]
+    #[case("interval(500", None)]
+    #[case("manual(1)", None)]
+    #[case("foo", None)]
+    fn test_parse_flush_policy(
+        #[case] value: &str,
+        #[case] expected: Option<FlushPolicy>,
+    ) {
+        assert_eq!(value.parse::<FlushPolicy>().ok(), expected);
+    }
+
+    #[rstest]
+    #[case("reject", Some(QueuePolicy::Reject))]
+    #[case("wait:250", Some(QueuePolicy::Wait(Duration::from_millis(250))))]
+    #[case("wait", None)]
+    #[case("wait:", None)]
+    #[case("reject:1", None)]
+    #[case("foo", None)]
+    fn test_parse_queue_policy(
+        #[case] value: &str,
+        #[case] expected: Option<QueuePolicy>,
+    ) {
+        assert_eq!(value.parse::<QueuePolicy>().ok(), expected);
+    }
+
+    #[test]
+    fn test_dirty() {
+        let mut dirty = Dirty::default();
+        dirty.mark(&Change::mutation(
+            "set",
+            Some(vec!["a".to_owned()]),
+            vec![1],
+        ));
+        dirty.mark(&Change::mutation(
+            "set",
+            Some(vec!["b".to_owned()]),
+            vec![1],
+        ));
+        assert_eq!(
+            dirty,
+            Dirty::Properties(["a".to_owned(), "b".to_owned()].into())
+        );
+
+        let mut other = Dirty::default();
+        other.mark(&Change::mutation("delete-bits", None, vec![1]));
+        assert_eq!(other, Dirty::All);
+
+        dirty.merge(other);
+        assert_eq!(dirty, Dirty::All);
+    }
+
+    #[test]
+    fn test_modified() {
+        let start = SystemTime::UNIX_EPOCH;
+        let (t1, t2) =
+            (start + Duration::from_secs(1), start + Duration::from_secs(2));
+        let mut modified = Modified::new(start);
+
+        modified.mark(
+            &Change::mutation("set", Some(vec!["a".to_owned()]), vec![1]),
+            t1,
+        );
+        assert_eq!(modified.index, t1);
+        assert_eq!(modified.property("a"), t1);
+        assert_eq!(modified.property("b"), start);
+
+        modified.mark(&Change::mutation("delete-bits", None, vec![1]), t2);
+        assert_eq!(modified.index, t2);
+        assert_eq!(modified.property("a"), t2);
+        assert_eq!(modified.property("b"), t2);
+    }
+
+    #[test]
+    fn test_shared_index_snapshots() {
+        let index = SharedIndex::new(Index::of([("foo", vec![1])]));
+        let snapshot = index.read();
+
+        index.update(|idx| idx.set("foo", 2));
+
+        assert_eq!(snapshot.get_property("foo").unwrap().to_vec(), vec![1]);
+        assert_eq!(
+            index.read().get_property("foo").unwrap().to_vec(),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn test_shared_index_replace_keeps_universe() {
+        let mut initial = Index::default();
+        initial.set_universe(Some(10)).unwrap();
+        let index = SharedIndex::new(initial);
+
+        index.replace(Index::of([("foo", vec![1])])).unwrap();
+
+        assert_eq!(index.read().universe(), Some(10));
+        assert!(index.read().get_property("foo").is_some());
+
+        assert!(index.replace(Index::of([("bar", vec![10])])).is_err());
+        assert!(index.read().get_property("bar").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lanes_are_independent() {
+        let backend: Box<dyn Backend> = Box::new(Memory::default());
+        let executor = Arc::new(
+            ExecutorBuilder::new(
+                Arc::new(SharedIndex::new(Index::default())),
+                Arc::new(Mutex::new(backend)),
+            )
+            .flush_policy(FlushPolicy::Manual)
+            .pool_size(1)
+            .write_pool_size(1)
+            .build()
+            .unwrap(),
+        );
+
+        // Fill the read lane with a task which only completes once the write
+        // went through.
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let blocked = tokio::spawn({
+            let executor = executor.clone();
+            async move { executor.spawn(move |_| rx.recv().unwrap()).await }
+        });
+        while executor.stats().read.queued == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(matches!(
+            executor.spawn(|_| ()).await,
+            Err(Error::TooManyRequests)
+        ));
+        executor
+            .spawn_write(None, |index| index.update(|idx| idx.set("foo", 1)))
+            .await
+            .unwrap();
+        assert!(matches!(executor.spawn_on(Lane::Write, |_| ()).await, Ok(())));
+
+        tx.send(()).unwrap();
+        blocked.await.unwrap().unwrap();
+
+        let stats = executor.stats();
+        assert_eq!(stats.read.executed, 1);
+        assert_eq!(stats.read.rejected, 1);
+        assert_eq!(stats.write.executed, 2);
+        assert_eq!(stats.write.rejected, 0);
+    }
+
+    #[tokio::test]
+    async fn test_wait_queue_policy() {
+        let backend: Box<dyn Backend> = Box::new(Memory::default());
+        let executor = Arc::new(
+            ExecutorBuilder::new(
+                Arc::new(SharedIndex::new(Index::default())),
+                Arc::new(Mutex::new(backend)),
+            )
+            .queue_policy(QueuePolicy::Wait(Duration::from_millis(50)))
+            .pool_size(1)
+            .build()
+            .unwrap(),
+        );
+
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let blocked = tokio::spawn({
+            let executor = executor.clone();
+            async move { executor.spawn(move |_| rx.recv().unwrap()).await }
+        });
+        while executor.stats().read.queued == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // Nothing frees up within the timeout.
+        assert!(matches!(
+            executor.spawn(|_| ()).await,
+            Err(Error::TooManyRequests)
+        ));
+
+        // The task waits until the running one completes.
+        let waiting = tokio::spawn({
+            let executor = executor.clone();
+            async move { executor.spawn(|_| 1).await }
+        });
+        tx.send(()).unwrap();
+        blocked.await.unwrap().unwrap();
+        assert_eq!(waiting.await.unwrap().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_tasks_are_skipped() {
+        let backend: Box<dyn Backend> = Box::new(Memory::default());
+        let executor = Arc::new(
+            ExecutorBuilder::new(
+                Arc::new(SharedIndex::new(Index::default())),
+                Arc::new(Mutex::new(backend)),
+            )
+            .pool_size(1)
+            .queue_size(2)
+            .build()
+            .unwrap(),
+        );
+
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let blocked = tokio::spawn({
+            let executor = executor.clone();
+            async move { executor.spawn(move |_| rx.recv().unwrap()).await }
+        });
+        while executor.stats().read.queued == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let ran = Arc::new(AtomicBool::new(false));
+        let abandoned = tokio::spawn({
+            let executor = executor.clone();
+            let ran = ran.clone();
+            async move {
+                executor.spawn(move |_| ran.store(true, Ordering::SeqCst)).await
+            }
+        });
+        while executor.stats().read.queued == 1 {
+            tokio::task::yield_now().await;
+        }
+        abandoned.abort();
+        assert!(abandoned.await.unwrap_err().is_cancelled());
+
+        tx.send(()).unwrap();
+        blocked.await.unwrap().unwrap();
+        // Wait for the thread to pick up the abandoned task.
+        executor.spawn(|_| ()).await.unwrap();
+
+        assert!(!ran.load(Ordering::SeqCst));
+        assert_eq!(executor.stats().read.abandoned, 1);
+    }
+
+    #[tokio::test]
+    async fn test_handoff() {
+        let backend: Box<dyn Backend> = Box::new(Memory::default());
+        let index = Arc::new(SharedIndex::new(Index::default()));
+        let executor = Arc::new(
+            ExecutorBuilder::new(index.clone(), Arc::new(Mutex::new(backend)))
+                .pool_size(1)
+                .write_pool_size(1)
+                .build()
+                .unwrap(),
+        );
+
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let blocked = tokio::spawn({
+            let executor = executor.clone();
+            async move {
+                executor.spawn_write(None, move |_| rx.recv().unwrap()).await
+            }
+        });
+        while executor.stats().write.queued == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // Writes which did not start yet are skipped once abandoned.
+        let handoff = Handoff::default();
+        let abandoned = tokio::spawn({
+            let executor = executor.clone();
+            let handoff = handoff.clone();
+            async move {
+                handoff
+                    .scope(executor.spawn_write(None, |index| {
+                        index.update(|idx| idx.set("foo", 1))
+                    }))
+                    .await
+            }
+        });
+        while executor.stats().write.queued == 1 {
+            tokio::task::yield_now().await;
+        }
+        assert!(handoff.abandon());
+        tx.send(()).unwrap();
+        blocked.await.unwrap().unwrap();
+        assert!(abandoned.await.unwrap().is_err());
+        assert!(index.read().get_property("foo").is_none());
+
+        // Writes which started cannot be abandoned.
+        let handoff = Handoff::default();
+        handoff
+            .scope(executor.spawn_write(None, |index| {
+                index.update(|idx| idx.set("foo", 1))
+            }))
+            .await
+            .unwrap();
+        assert!(!handoff.abandon());
+        assert!(index.read().get_property("foo").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_share_threads() {
+        let new = || {
+            let backend: Box<dyn Backend> = Box::new(Memory::default());
+            ExecutorBuilder::new(
+                Arc::new(SharedIndex::new(Index::default())),
+                Arc::new(Mutex::new(backend)),
+            )
+        };
+
+        let first = new().pool_size(3).queue_size(5).build().unwrap();
+        let second =
+            new().share_threads(&first).write_pool_size(1).build().unwrap();
+
+        assert!(Arc::ptr_eq(
+            &first.read_lane.thread_pool,
+            &second.read_lane.thread_pool
+        ));
+        assert!(!Arc::ptr_eq(
+            &first.write_lane.thread_pool,
+            &second.write_lane.thread_pool
+        ));
+        // Queues are not shared.
+        assert_eq!(second.stats().read.pool_size, 3);
+        assert_eq!(second.stats().read.queue_size, 30);
+
+        second
+            .spawn_write(None, |index| index.update(|idx| idx.set("foo", 1)))
+            .await
+            .unwrap();
+        assert!(first.index.read().is_empty());
+        assert!(!second.index.read().is_empty());
+    }
+
+    /// Memory backend whose writes fail while `down` is set.
+    #[derive(Debug, Default)]
+    struct Flaky {
+        inner: Memory,
+        down: Arc<AtomicBool>,
+    }
+
+    impl Backend for Flaky {
+        fn load(&self) -> eyre::Result<Index> {
+            self.inner.load()
+        }
+
+        fn dump(&self, index: &Index) -> eyre::Result<()> {
+            if self.down.load(Ordering::SeqCst) {
+                Err(eyre::Report::msg("Backend is down"))
+            } else {
+                self.inner.dump(index)
+            }
+        }
+
+        fn clear(&self) -> eyre::Result<()> {
+            self.inner.clear()
+        }
+
+        fn ping(&self) -> eyre::Result<()> {
+            self.inner.ping()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush_failures() {
+        let backend = Flaky::default();
+        let down = backend.down.clone();
+        let backend: Box<dyn Backend> = Box::new(backend);
+        let executor = ExecutorBuilder::new(
+            Arc::new(SharedIndex::new(Index::default())),
+            Arc::new(Mutex::new(backend)),
+        )
+        .pool_size(1)
+        .flush_retries(1)
+        .reject_writes_when_unflushed(true)
+        .build()
+        .unwrap();
+        let set = |bit| {
+            move |index: Arc<SharedIndex>| {
+                index.update(|idx| idx.set("foo", bit));
+            }
+        };
+        let change =
+            || Change::mutation("set", Some(vec!["foo".to_owned()]), vec![1]);
+
+        down.store(true, Ordering::SeqCst);
+        executor.spawn_write(None, set(1)).await.unwrap();
+        assert!(executor.commit(change()).await.is_err());
+        assert!(executor.flush_status().failing);
+        assert_eq!(executor.flush_status().pending_writes, 1);
+        assert!(matches!(
+            executor.spawn_write(None, set(2)).await,
+            Err(Error::BackendUnavailable)
+        ));
+
+        down.store(false, Ordering::SeqCst);
+        executor.flush_pending().await.unwrap();
+        assert!(!executor.flush_status().failing);
+        assert_eq!(executor.flush_status().pending_writes, 0);
+        executor.spawn_write(None, set(2)).await.unwrap();
+    }
+
+    /// Memory backend whose dumps wait for `release`.
+    #[derive(Debug)]
+    struct Slow {
+        inner: Memory,
+        release: Mutex<std::sync::mpsc::Receiver<()>>,
+    }
+
+    impl Backend for Slow {
+        fn load(&self) -> eyre::Result<Index> {
+            self.inner.load()
+        }
+
+        fn dump(&self, index: &Index) -> eyre::Result<()> {
+            self.release.lock().recv()?;
+            self.inner.dump(index)
+        }
+
+        fn clear(&self) -> eyre::Result<()> {
+            self.inner.clear()
+        }
+
+        fn ping(&self) -> eyre::Result<()> {
+            self.inner.ping()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush_does_not_block_writes() {
+        let (release, rx) = std::sync::mpsc::channel();
+        let backend: Box<dyn Backend> = Box::new(Slow {
+            inner: Memory::default(),
+            release: Mutex::new(rx),
+        });
+        let backend = Arc::new(Mutex::new(backend));
+        let executor = Arc::new(
+            ExecutorBuilder::new(
+                Arc::new(SharedIndex::new(Index::of([("foo", vec![1])]))),
+                backend.clone(),
+            )
+            .flush_policy(FlushPolicy::Manual)
+            .pool_size(1)
+            .write_pool_size(1)
+            .build()
+            .unwrap(),
+        );
+
+        let flush = tokio::spawn({
+            let executor = executor.clone();
+            async move { executor.flush().await }
+        });
+        // Wait for the flush to hold the backend.
+        while backend.try_lock().is_some() {
+            tokio::task::yield_now().await;
+        }
+
+        executor
+            .spawn_write(None, |index| index.update(|idx| idx.set("foo", 2)))
+            .await
+            .unwrap();
+
+        release.send(()).unwrap();
+        flush.await.unwrap().unwrap();
+        assert_eq!(
+            backend.lock().load().unwrap(),
+            Index::of([("foo", vec![1])])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reload_discards_pending_writes() {
+        let backend: Box<dyn Backend> = Box::new(Memory::default());
+        let backend = Arc::new(Mutex::new(backend));
+        let executor = ExecutorBuilder::new(
+            Arc::new(SharedIndex::new(Index::default())),
+            backend.clone(),
+        )
+        .flush_policy(FlushPolicy::Manual)
+        .pool_size(1)
+        .build()
+        .unwrap();
+
+        let set = |property: &'static str| {
+            let executor = &executor;
+            async move {
+                executor
+                    .spawn_write(None, move |index| {
+                        index.update(|idx| idx.set(property, 1))
+                    })
+                    .await
+                    .unwrap();
+                executor.stage(Change::mutation(
+                    "set",
+                    Some(vec![property.to_owned()]),
+                    vec![1],
+                ));
+            }
+        };
+
+        set("foo").await;
+        executor.reload().await.unwrap();
+        assert_eq!(executor.pending_writes(), 0);
+        set("bar").await;
+        assert_eq!(executor.pending_writes(), 1);
+
+        executor.flush().await.unwrap();
+        assert_eq!(
+            backend.lock().load().unwrap(),
+            Index::of([("bar", vec![1])])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let backend: Box<dyn Backend> = Box::new(Memory::default());
+        let backend = Arc::new(Mutex::new(backend));
+        let executor = ExecutorBuilder::new(
+            Arc::new(SharedIndex::new(Index::default())),
+            backend.clone(),
+        )
+        .flush_policy(FlushPolicy::Manual)
+        .pool_size(1)
+        .build()
+        .unwrap();
+
+        executor
+            .spawn_write(None, |index| index.update(|idx| idx.set("foo", 1)))
+            .await
+            .unwrap();
+        executor
+            .commit(Change::mutation(
+                "set",
+                Some(vec!["foo".to_owned()]),
+                vec![1],
+            ))
+            .await
+            .unwrap();
+        assert_eq!(executor.pending_writes(), 1);
+
+        executor.shutdown().await.unwrap();
+
+        assert_eq!(executor.pending_writes(), 0);
+        assert!(backend.lock().load().unwrap().get_property("foo").is_some());
+        assert!(matches!(
+            executor.spawn_write(None, |_| ()).await,
+            Err(Error::ShuttingDown)
+        ));
+    }
+}
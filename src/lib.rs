@@ -0,0 +1,25 @@
+#![deny(unstable_features)]
+#![forbid(unsafe_code)]
+#![warn(
+    clippy::mut_mut,
+    clippy::large_types_passed_by_value,
+    trivial_casts,
+    trivial_numeric_casts,
+    unused_extern_crates,
+    unused_import_braces,
+    unused_qualifications
+)]
+
+pub mod backends;
+pub mod changes;
+pub mod commands;
+pub mod executor;
+pub mod metrics;
+pub mod operations;
+pub mod server;
+pub mod snapshots;
+pub mod statsd;
+pub mod systemd;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub mod utils;
@@ -10,26 +10,24 @@
     unused_qualifications
 )]
 
-mod backends;
-mod executor;
-mod operations;
-mod server;
-mod utils;
-
-use std::io::Write;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 use color_eyre::Report;
+use crible::backends::BackendOptions;
+use crible::executor::{
+    ExecutorBuilder, FlushPolicy, QueuePolicy, SharedIndex,
+};
+use crible::snapshots::{Retention, SnapshotStore};
+use crible::statsd::Statsd;
+use crible::{commands, server, systemd};
 use crible_lib::expression::Expression;
 use eyre::Context;
-use parking_lot::{Mutex, RwLock};
+use parking_lot::Mutex;
 use shadow_rs::shadow;
 
-use crate::backends::BackendOptions;
-use crate::executor::ExecutorBuilder;
-
 shadow!(build);
 
 #[cfg(not(debug_assertions))]
@@ -46,6 +44,8 @@ enum Command {
         #[clap(long = "backend", required = true, env = "CRIBLE_BACKEND")]
         backend_options: BackendOptions,
 
+        /// Address to listen on, ignored when a socket is passed through
+        /// systemd socket activation.
         #[clap(
             short = 'l',
             long = "listen",
@@ -62,8 +62,9 @@ enum Command {
         #[clap(long = "refresh", env = "CRIBLE_REFRESH_TIMEOUT")]
         refresh_timeout: Option<u64>,
 
-        /// Number of execuotor threads. Defaults to the number of CPU cores
-        /// available if unspecified.
+        /// Number of executor threads used for queries. Defaults to the
+        /// number of CPU cores available if unspecified. Tenants share these
+        /// threads unless they configure their own.
         #[clap(short = 't', long = "threads", env = "CRIBLE_THREAD_COUNT")]
         thread_count: Option<usize>,
 
@@ -76,6 +77,86 @@ enum Command {
         )]
         queue_size: Option<usize>,
 
+        /// Number of executor threads used for writes, kept separate from
+        /// query threads so that slow queries do not delay writes.
+        #[clap(long = "write-threads", env = "CRIBLE_WRITE_THREAD_COUNT")]
+        write_thread_count: Option<usize>,
+
+        /// The maximum number of writes that can be put in the queue. Writes
+        /// that exceed this limit are rejected with 429 HTTP status.
+        #[clap(long, env = "CRIBLE_WRITE_QUEUE_SIZE")]
+        write_queue_size: Option<usize>,
+
+        /// What happens to requests when the queue is full: `reject` them
+        /// immediately or `wait:<ms>` for a spot in the queue before
+        /// rejecting them.
+        #[clap(long, env = "CRIBLE_QUEUE_POLICY", default_value = "reject")]
+        queue_policy: QueuePolicy,
+
+        /// When writes are persisted to the backend: `on-write`,
+        /// `interval(<ms>)`, `after-n-writes(<n>)` or `manual`. With `manual`
+        /// writes are only persisted through the `/flush` route.
+        #[clap(long, env = "CRIBLE_FLUSH_POLICY", default_value = "on-write")]
+        flush_policy: FlushPolicy,
+
+        /// Number of times a failed flush is retried, with exponential
+        /// backoff, before giving up until the next flush.
+        #[clap(long, env = "CRIBLE_FLUSH_RETRIES", default_value = "3")]
+        flush_retries: u32,
+
+        /// Reject writes with 503 HTTP status while pending writes cannot be
+        /// persisted to the backend, instead of accepting writes which would
+        /// be lost if the server stopped.
+        #[clap(long, env = "CRIBLE_REJECT_WRITES_WHEN_UNFLUSHED")]
+        reject_writes_when_unflushed: bool,
+
+        /// Optimize the storage of the index in the background every this
+        /// many seconds. Disabled if unspecified.
+        #[clap(long, env = "CRIBLE_OPTIMIZE_INTERVAL")]
+        optimize_interval: Option<u64>,
+
+        /// Number of properties optimized at once by the background
+        /// optimization, writes are held back while a batch is optimized.
+        #[clap(
+            long,
+            env = "CRIBLE_OPTIMIZE_BATCH_SIZE",
+            default_value = "100"
+        )]
+        optimize_batch_size: usize,
+
+        /// Skip background optimization when more than this many writes per
+        /// second were applied since the previous one.
+        #[clap(long, env = "CRIBLE_OPTIMIZE_MAX_WRITE_RATE")]
+        optimize_max_write_rate: Option<f64>,
+
+        /// Write a timestamped snapshot of the index to this directory in the
+        /// background, independently of the backend. Snapshots of tenants
+        /// are prefixed with their name. Disabled if unspecified.
+        #[clap(long, env = "CRIBLE_SNAPSHOT_DIR")]
+        snapshot_dir: Option<PathBuf>,
+
+        /// Seconds between two background snapshots.
+        #[clap(long, env = "CRIBLE_SNAPSHOT_INTERVAL", default_value = "3600")]
+        snapshot_interval: u64,
+
+        /// Prefix of the background snapshot file names, defaults to
+        /// `crible`.
+        #[clap(long, env = "CRIBLE_SNAPSHOT_PREFIX")]
+        snapshot_prefix: Option<String>,
+
+        /// Background snapshot format: `bin` or `json`.
+        #[clap(long, env = "CRIBLE_SNAPSHOT_FORMAT", default_value = "bin")]
+        snapshot_format: crible_lib::Encoder,
+
+        /// Number of most recent background snapshots kept.
+        #[clap(long, env = "CRIBLE_SNAPSHOT_KEEP", default_value = "24")]
+        snapshot_keep: usize,
+
+        /// Also keep the most recent background snapshot of each of the last
+        /// this many days.
+        #[clap(long, env = "CRIBLE_SNAPSHOT_KEEP_DAILY", default_value = "0")]
+        snapshot_keep_daily: usize,
+
         /// TCP keep-alive setting in seconds. If unspecified keep alive is
         /// disabled.
         #[clap(
@@ -84,6 +165,260 @@ enum Command {
             env = "CRIBLE_TCP_KEEP_ALIVE"
         )]
         keep_alive: Option<u64>,
+
+        /// Path to a PEM encoded TLS certificate (chain). Enables HTTPS when
+        /// provided along with `--tls-key`.
+        #[clap(long, env = "CRIBLE_TLS_CERT", requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+
+        /// Path to the PEM encoded private key for `--tls-cert`.
+        #[clap(long, env = "CRIBLE_TLS_KEY", requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+
+        /// Interval in seconds at which the TLS certificate and key are
+        /// reloaded from disk. If unspecified they are only loaded on startup.
+        #[clap(
+            long,
+            env = "CRIBLE_TLS_RELOAD_INTERVAL",
+            requires = "tls_cert"
+        )]
+        tls_reload_interval: Option<u64>,
+
+        /// Shared secret used to validate HMAC signed JWT bearer tokens.
+        /// Enables authentication on all data routes.
+        #[clap(
+            long,
+            env = "CRIBLE_JWT_SECRET",
+            conflicts_with = "jwt_jwks_url"
+        )]
+        jwt_secret: Option<String>,
+
+        /// Url of a JWKS document used to validate asymmetrically signed JWT
+        /// bearer tokens. Enables authentication on all data routes.
+        #[clap(long, env = "CRIBLE_JWT_JWKS_URL")]
+        jwt_jwks_url: Option<url::Url>,
+
+        /// Expected `aud` claim of JWT bearer tokens.
+        #[clap(long, env = "CRIBLE_JWT_AUDIENCE")]
+        jwt_audience: Option<String>,
+
+        /// Expected `iss` claim of JWT bearer tokens.
+        #[clap(long, env = "CRIBLE_JWT_ISSUER")]
+        jwt_issuer: Option<String>,
+
+        /// Origins allowed to make cross-origin requests, `*` allows any
+        /// origin. CORS is disabled if unspecified.
+        #[clap(
+            long = "cors-allow-origin",
+            env = "CRIBLE_CORS_ALLOW_ORIGINS",
+            value_delimiter = ','
+        )]
+        cors_allow_origins: Vec<String>,
+
+        /// Methods allowed in cross-origin requests.
+        #[clap(
+            long = "cors-allow-method",
+            env = "CRIBLE_CORS_ALLOW_METHODS",
+            value_delimiter = ',',
+            default_value = "GET,POST"
+        )]
+        cors_allow_methods: Vec<String>,
+
+        /// Headers allowed in cross-origin requests.
+        #[clap(
+            long = "cors-allow-header",
+            env = "CRIBLE_CORS_ALLOW_HEADERS",
+            value_delimiter = ',',
+            default_value = "authorization,content-type,x-request-id"
+        )]
+        cors_allow_headers: Vec<String>,
+
+        /// How long in seconds browsers can cache preflight responses.
+        #[clap(long, env = "CRIBLE_CORS_MAX_AGE")]
+        cors_max_age: Option<u64>,
+
+        /// Maximum request body size in bytes, accepts K, M and G suffixes.
+        /// Larger requests are rejected with 413 HTTP status. Streamed
+        /// `/ingest` bodies are only limited by a route override, Parquet
+        /// files sent to `/ingest` are buffered and limited by the override
+        /// or this size.
+        #[clap(
+            long,
+            env = "CRIBLE_MAX_BODY_SIZE",
+            default_value = "2M",
+            value_parser = server::parse_byte_size
+        )]
+        max_body_size: usize,
+
+        /// Per route maximum request body size override formatted as
+        /// `<path>=<size>`, e.g. `/set-many=64M`. It also applies to the
+        /// route under every tenant unless overridden with the tenant's
+        /// path, e.g. `/acme/set-many=128M`.
+        #[clap(
+            long = "route-max-body-size",
+            env = "CRIBLE_ROUTE_MAX_BODY_SIZES",
+            value_delimiter = ','
+        )]
+        route_max_body_sizes: Vec<server::RouteBodyLimit>,
+
+        /// Maximum time in milliseconds spent handling a request, including
+        /// time spent queued. Requests exceeding it fail with 503 HTTP status.
+        /// Clients can shorten it through the `X-Request-Timeout` header.
+        /// It does not apply to `/ingest`, nor to writes which started before
+        /// it expired: they complete normally.
+        #[clap(long, env = "CRIBLE_REQUEST_TIMEOUT")]
+        request_timeout: Option<u64>,
+
+        /// Reject queries whose estimated cost, roughly the number of values
+        /// they go through, exceeds this with 422 HTTP status. Tokens can
+        /// override it with a `max_query_cost` claim.
+        #[clap(long, env = "CRIBLE_MAX_QUERY_COST")]
+        max_query_cost: Option<u64>,
+
+        /// Reject writes setting bits greater than or equal to this value
+        /// with 400 HTTP status. `not` queries then match every bit below it
+        /// rather than only the bits set in at least one property.
+        #[clap(long, env = "CRIBLE_UNIVERSE")]
+        universe: Option<u32>,
+
+        /// Separator between the field and the value of property names, used
+        /// to group cardinalities into facets when `/query` requests ask for
+        /// `facets`, e.g. `color` and `red` for `color:red`.
+        #[clap(
+            long,
+            env = "CRIBLE_FACET_DELIMITER",
+            default_value = ":",
+            value_parser = clap::builder::NonEmptyStringValueParser::new()
+        )]
+        facet_delimiter: String,
+
+        /// Address to serve the gRPC API on. The gRPC API is disabled if
+        /// unspecified.
+        #[clap(long = "grpc-listen", env = "CRIBLE_GRPC_BIND")]
+        grpc_bind: Option<String>,
+
+        /// Expose a GraphQL endpoint on `/graphql`.
+        #[clap(long, env = "CRIBLE_GRAPHQL")]
+        graphql: bool,
+
+        /// Urls notified with a JSON event after every successful write.
+        #[clap(
+            long = "webhook-url",
+            env = "CRIBLE_WEBHOOK_URLS",
+            value_delimiter = ','
+        )]
+        webhook_urls: Vec<url::Url>,
+
+        /// Secret used to sign webhook payloads. The signature is sent in the
+        /// `X-Crible-Signature` header.
+        #[clap(long, env = "CRIBLE_WEBHOOK_SECRET")]
+        webhook_secret: Option<String>,
+
+        /// Accumulate changes for this many milliseconds and deliver them as
+        /// a single webhook event. If unspecified, changes are sent as soon as
+        /// they happen.
+        #[clap(long, env = "CRIBLE_WEBHOOK_DEBOUNCE")]
+        webhook_debounce: Option<u64>,
+
+        /// Number of retries for failed webhook deliveries.
+        #[clap(long, env = "CRIBLE_WEBHOOK_MAX_RETRIES", default_value = "3")]
+        webhook_max_retries: u32,
+
+        /// Address of a StatsD or DogStatsD agent, e.g. `127.0.0.1:8125`, to
+        /// push request durations, executor stats and flush outcomes to.
+        #[clap(long, env = "CRIBLE_STATSD_ADDR")]
+        statsd_addr: Option<String>,
+
+        /// Prefix of the metric names sent to StatsD.
+        #[clap(long, env = "CRIBLE_STATSD_PREFIX", default_value = "crible")]
+        statsd_prefix: String,
+
+        /// DogStatsD tags added to every metric formatted as `<key>:<value>`.
+        #[clap(
+            long = "statsd-tag",
+            env = "CRIBLE_STATSD_TAGS",
+            value_delimiter = ','
+        )]
+        statsd_tags: Vec<crible::statsd::Tag>,
+
+        /// How often in seconds executor stats are sent to StatsD.
+        #[clap(long, env = "CRIBLE_STATSD_INTERVAL", default_value = "10")]
+        statsd_interval: u64,
+
+        /// Sentry DSN to report panics, unexpected errors and background
+        /// task failures to. Errors are only logged if unspecified.
+        #[clap(long, env = "CRIBLE_SENTRY_DSN")]
+        sentry_dsn: Option<String>,
+
+        /// Environment attached to the errors reported to Sentry, e.g.
+        /// `production`.
+        #[clap(long, env = "CRIBLE_SENTRY_ENVIRONMENT")]
+        sentry_environment: Option<String>,
+
+        /// Maximum number of responses kept to replay write requests with an
+        /// already seen `Idempotency-Key` header. Set to 0 to disable.
+        #[clap(
+            long,
+            env = "CRIBLE_IDEMPOTENCY_CACHE_SIZE",
+            default_value = "10000"
+        )]
+        idempotency_cache_size: usize,
+
+        /// How long in seconds responses to write requests with an
+        /// `Idempotency-Key` header are kept.
+        #[clap(long, env = "CRIBLE_IDEMPOTENCY_TTL", default_value = "86400")]
+        idempotency_ttl: u64,
+
+        /// Path to a TOML file declaring additional tenants, each served
+        /// under `/<tenant>/...` with its own backend and settings.
+        #[clap(long, env = "CRIBLE_TENANTS")]
+        tenants: Option<PathBuf>,
+
+        /// Append a JSON line recording who changed what to this file for
+        /// every mutation. Recent entries are exposed under `/admin/audit`.
+        #[clap(long, env = "CRIBLE_AUDIT_LOG")]
+        audit_log: Option<PathBuf>,
+
+        /// Append a JSON line recording the canonical query, duration, result
+        /// cardinality and caller of a sample of `/query` and `/count`
+        /// requests to this file.
+        #[clap(long, env = "CRIBLE_ANALYTICS_LOG")]
+        analytics_log: Option<PathBuf>,
+
+        /// Fraction of queries recorded to the analytics log.
+        #[clap(
+            long,
+            env = "CRIBLE_ANALYTICS_SAMPLE_RATE",
+            default_value = "1"
+        )]
+        analytics_sample_rate: f64,
+
+        /// Size after which the analytics log is moved to `<path>.1`,
+        /// replacing the previous one.
+        #[clap(
+            long,
+            env = "CRIBLE_ANALYTICS_MAX_SIZE",
+            default_value = "100M",
+            value_parser = server::parse_byte_size
+        )]
+        analytics_max_size: usize,
+
+        /// Path to a TOML configuration file setting any of the options
+        /// above by their long name with underscores, e.g. `queue_size = 10`,
+        /// and declaring tenants inline. Command line flags and environment
+        /// variables take precedence over the file, except for the log level,
+        /// flush policy, refresh interval and authentication keys: those
+        /// are read again on SIGHUP or `POST /admin/reload` and applied
+        /// without restarting.
+        #[clap(long, env = "CRIBLE_CONFIG")]
+        config: Option<PathBuf>,
+    },
+    /// Validate a configuration file for the `serve` command without starting
+    /// the server or loading any index.
+    CheckConfig {
+        /// Path to the TOML configuration file.
+        #[clap(long, env = "CRIBLE_CONFIG")]
+        config: PathBuf,
     },
     /// Execute a single query against the index.
     Query {
@@ -91,8 +426,41 @@ enum Command {
         #[clap(long = "backend", required = true, env = "CRIBLE_BACKEND")]
         backend_options: BackendOptions,
 
+        #[clap(
+            long,
+            required_unless_present_any = ["stdin", "file"],
+            conflicts_with_all = ["stdin", "file"]
+        )]
+        query: Option<Expression>,
+
+        /// Execute every line read from stdin as a query, printing one JSON
+        /// object per query.
+        #[clap(long, conflicts_with = "file")]
+        stdin: bool,
+
+        /// Execute every line of this file as a query, see `--stdin`.
         #[clap(long)]
-        query: Expression,
+        file: Option<PathBuf>,
+
+        /// Output format: `plain` for one value per line, `json`, `ndjson`,
+        /// `csv` or `count` to only print the number of values. Queries read
+        /// from stdin or a file always print JSON, only including the count
+        /// with `count`.
+        #[clap(long, default_value = "plain")]
+        output: commands::OutputFormat,
+
+        /// Include the number of matching values for every property, only
+        /// supported with the `json` and `ndjson` outputs.
+        #[clap(long)]
+        include_cardinalities: bool,
+
+        /// Maximum number of values printed.
+        #[clap(long)]
+        limit: Option<usize>,
+
+        /// Number of values skipped before printing.
+        #[clap(long, default_value = "0")]
+        offset: usize,
     },
     /// Copy data from one backend to another.
     Copy {
@@ -104,6 +472,333 @@ enum Command {
         #[clap(long)]
         to: BackendOptions,
     },
+    /// Copy a subset of the properties from one backend to another.
+    Convert {
+        /// Source backend configuration url.
+        #[clap(long)]
+        from: BackendOptions,
+
+        /// Destination backend configuration url.
+        #[clap(long)]
+        to: BackendOptions,
+
+        /// Only copy properties matching one of these patterns, where `*`
+        /// matches any sequence of characters. Copy all properties if
+        /// unspecified.
+        #[clap(long, value_delimiter = ',')]
+        include: Vec<commands::PropertyPattern>,
+
+        /// Do not copy properties matching any of these patterns.
+        #[clap(long, value_delimiter = ',')]
+        exclude: Vec<commands::PropertyPattern>,
+
+        /// Replace a property prefix, formatted as `<from>=<to>`, e.g.
+        /// `facet:=color:`. The first matching rename applies.
+        #[clap(long, value_delimiter = ',')]
+        rename: Vec<commands::Rename>,
+
+        /// Print what would be copied without writing anything.
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Build or extend an index from a CSV file with one row per property
+    /// and bit pair.
+    Import {
+        /// Backend configuration url.
+        #[clap(long = "backend", required = true, env = "CRIBLE_BACKEND")]
+        backend_options: BackendOptions,
+
+        /// CSV file to import, use `-` to read from stdin.
+        #[clap(long)]
+        from: PathBuf,
+
+        /// Name of the column holding the property.
+        #[clap(long, default_value = "property")]
+        property_col: String,
+
+        /// Name of the column holding the bit, which must be a positive
+        /// integer.
+        #[clap(long, default_value = "id")]
+        id_col: String,
+
+        /// Field delimiter.
+        #[clap(long, default_value = ",")]
+        delimiter: char,
+
+        /// Number of rows read between progress updates.
+        #[clap(long, default_value = "100000")]
+        chunk_size: usize,
+
+        /// Replace the existing index instead of merging into it.
+        #[clap(long)]
+        replace: bool,
+    },
+    /// Write the index to a file or stdout.
+    Export {
+        /// Backend configuration url.
+        #[clap(long = "backend", required = true, env = "CRIBLE_BACKEND")]
+        backend_options: BackendOptions,
+
+        /// Output format: `json`, `bin` or `csv`.
+        #[clap(long, default_value = "json")]
+        format: commands::ExportFormat,
+
+        /// Output file, use `-` to write to stdout.
+        #[clap(long, default_value = "-")]
+        out: PathBuf,
+    },
+    /// Merge the indices of multiple backends into another one.
+    Merge {
+        /// Destination backend configuration url, its current content is
+        /// merged with the sources.
+        #[clap(long)]
+        into: BackendOptions,
+
+        /// Source backend configuration urls, merged in order.
+        #[clap(required = true)]
+        sources: Vec<BackendOptions>,
+
+        /// How properties present in multiple indices are combined: `union`
+        /// or `replace`, where the last source wins.
+        #[clap(long, default_value = "union")]
+        strategy: commands::MergeStrategy,
+
+        /// Prefix the properties of a source, formatted as
+        /// `<source>=<prefix>` where source is the 1-based position of the
+        /// source, e.g. `2=job-b:`.
+        #[clap(long, value_delimiter = ',')]
+        prefix_map: Vec<commands::PrefixMapping>,
+    },
+    /// Compare the indices of 2 backends, exiting with a nonzero status when
+    /// they differ.
+    Diff {
+        /// First backend configuration url.
+        a: BackendOptions,
+
+        /// Second backend configuration url.
+        b: BackendOptions,
+
+        /// Print the differences as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+    /// Check that an index can be loaded and served, exiting with a nonzero
+    /// status when problems are found.
+    Validate {
+        /// Backend configuration url.
+        #[clap(long = "backend", required = true, env = "CRIBLE_BACKEND")]
+        backend_options: BackendOptions,
+
+        /// Print the problems as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+    /// Print cardinality and size statistics for the properties of an index.
+    Stats {
+        /// Backend configuration url.
+        #[clap(long = "backend", required = true, env = "CRIBLE_BACKEND")]
+        backend_options: BackendOptions,
+
+        /// Only include properties starting with this prefix.
+        #[clap(long)]
+        prefix: Option<String>,
+
+        /// Order properties by `property` or by `cardinality`, largest
+        /// first.
+        #[clap(long, default_value = "property")]
+        sort: commands::StatsSort,
+
+        /// Print the statistics as JSON instead of a table.
+        #[clap(long)]
+        json: bool,
+    },
+    /// Describe a single property: its stats, storage, a sample of its bits
+    /// and the properties it overlaps with.
+    Inspect {
+        /// Backend configuration url.
+        #[clap(long = "backend", required = true, env = "CRIBLE_BACKEND")]
+        backend_options: BackendOptions,
+
+        property: String,
+
+        /// Only look for overlaps with properties starting with this prefix.
+        #[clap(long)]
+        prefix: Option<String>,
+
+        /// Number of bits printed from the start and end of the property.
+        #[clap(long, default_value = "10")]
+        sample: usize,
+
+        /// Maximum number of overlapping properties printed.
+        #[clap(long, default_value = "20")]
+        top: usize,
+
+        /// Print the result as JSON.
+        #[clap(long)]
+        json: bool,
+    },
+    /// Write a timestamped snapshot of an index to a directory.
+    Snapshot {
+        /// Backend configuration url.
+        #[clap(long = "backend", required = true, env = "CRIBLE_BACKEND")]
+        backend_options: BackendOptions,
+
+        /// Directory in which snapshots are stored.
+        #[clap(long)]
+        to: PathBuf,
+
+        /// Prefix of the snapshot file names, defaults to `crible`.
+        #[clap(long)]
+        prefix: Option<String>,
+
+        /// Snapshot format: `bin` or `json`.
+        #[clap(long, default_value = "bin")]
+        format: crible_lib::Encoder,
+
+        /// Only keep this many snapshots, deleting the oldest ones.
+        #[clap(long)]
+        keep: Option<usize>,
+    },
+    /// Replace the index stored in a backend with a snapshot.
+    Restore {
+        /// Snapshot file, or directory to restore the most recent snapshot
+        /// from.
+        #[clap(long)]
+        from: PathBuf,
+
+        /// Prefix of the snapshot file names when restoring from a
+        /// directory, defaults to `crible`.
+        #[clap(long)]
+        prefix: Option<String>,
+
+        /// Backend configuration url.
+        #[clap(long = "backend", required = true, env = "CRIBLE_BACKEND")]
+        backend_options: BackendOptions,
+    },
+    /// Replace the index of a backend with generated data, for benchmarks and
+    /// reproducible examples.
+    Generate {
+        /// Backend configuration url.
+        #[clap(long = "backend", required = true, env = "CRIBLE_BACKEND")]
+        backend_options: BackendOptions,
+
+        /// Number of properties.
+        #[clap(
+            long,
+            default_value = "1000",
+            value_parser = commands::parse_count
+        )]
+        properties: u32,
+
+        /// Size of the range bits are picked from, starting at 0. Accepts
+        /// scientific notation, e.g. `10e6`.
+        #[clap(
+            long,
+            default_value = "1e6",
+            value_parser = commands::parse_count
+        )]
+        bits: u32,
+
+        /// Distribution of property sizes: `uniform` or `zipf`.
+        #[clap(long, default_value = "zipf")]
+        distribution: commands::Distribution,
+
+        /// Share of the bits set in the largest property.
+        #[clap(long, default_value = "0.1")]
+        density: f64,
+
+        /// Exponent of the zipf distribution, higher values produce more
+        /// skewed property sizes.
+        #[clap(long, default_value = "1.0")]
+        exponent: f64,
+
+        /// Prefix of the generated property names.
+        #[clap(long, default_value = "property-")]
+        prefix: String,
+
+        /// The same seed and options always generate the same index.
+        #[clap(long, default_value = "0")]
+        seed: u64,
+    },
+    /// Diagnose common configuration problems of a backend, exiting with a
+    /// nonzero status when any is found.
+    Doctor {
+        /// Backend configuration url.
+        #[clap(long = "backend", required = true, env = "CRIBLE_BACKEND")]
+        backend: String,
+
+        /// Url of a running server whose health checks are verified.
+        #[clap(long)]
+        server: Option<url::Url>,
+    },
+    /// Start an interactive prompt to explore an index.
+    Repl {
+        /// Backend configuration url.
+        #[clap(long = "backend", required = true, env = "CRIBLE_BACKEND")]
+        backend_options: BackendOptions,
+
+        /// Enable the `set`, `unset` and `delete` commands. Changes are only
+        /// persisted through the `save` command.
+        #[clap(long)]
+        allow_writes: bool,
+    },
+    /// Compact the storage of every property of an index, printing their
+    /// size before and after.
+    Optimize {
+        /// Backend configuration url.
+        #[clap(long = "backend", required = true, env = "CRIBLE_BACKEND")]
+        backend_options: BackendOptions,
+
+        /// Print the sizes as JSON instead of a table.
+        #[clap(long)]
+        json: bool,
+    },
+    /// Copy an index into another backend and optionally keep it in sync,
+    /// e.g. to maintain a standby environment.
+    Replicate {
+        /// Backend configuration url or base url of a running server to copy
+        /// the index from.
+        #[clap(long)]
+        from: commands::Source,
+
+        /// Backend configuration url to copy the index into.
+        #[clap(long)]
+        to: BackendOptions,
+
+        /// Bearer token used when replicating from a server.
+        #[clap(long, env = "CRIBLE_TOKEN")]
+        token: Option<String>,
+
+        /// Keep polling the source after the initial copy and only write the
+        /// properties which changed. Servers only send the properties which
+        /// changed since the last poll, see `POST /sync`.
+        #[clap(long)]
+        watch: bool,
+
+        /// Delay between two polls of the source when watching.
+        #[clap(
+            long,
+            default_value = "30s",
+            value_parser = humantime::parse_duration
+        )]
+        interval: std::time::Duration,
+    },
+    /// Print the changes made to the index of a running server as NDJSON,
+    /// as they happen.
+    Tail {
+        /// Base url of the server or tenant.
+        #[clap(long)]
+        server: url::Url,
+
+        /// Only print changes affecting properties starting with any of these
+        /// prefixes, all changes are printed by default.
+        #[clap(long = "prefix")]
+        prefixes: Vec<String>,
+
+        /// Bearer token used to authenticate with the server.
+        #[clap(long, env = "CRIBLE_TOKEN")]
+        token: Option<String>,
+    },
 }
 
 
@@ -120,8 +815,18 @@ pub struct App {
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Report> {
-    let app = App::parse();
-    crate::utils::setup_logging(app.debug.unwrap_or(_DEFAULT_DEBUG));
+    let command = App::command();
+    let mut from_file = vec![];
+    if let Some(path) = server::config_path(std::env::args_os()) {
+        if let Some(serve) = command.find_subcommand("serve") {
+            from_file = server::load_config_into_env(&path, serve)?;
+        }
+    }
+
+    let matches = command.clone().get_matches();
+    let app = App::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let log_filter =
+        crible::utils::setup_logging(app.debug.unwrap_or(_DEFAULT_DEBUG));
     match &app.command {
         Command::Serve {
             bind,
@@ -130,8 +835,68 @@ async fn main() -> Result<(), Report> {
             refresh_timeout,
             thread_count,
             queue_size,
+            write_thread_count,
+            write_queue_size,
+            queue_policy,
+            flush_policy,
+            flush_retries,
+            reject_writes_when_unflushed,
+            optimize_interval,
+            optimize_batch_size,
+            optimize_max_write_rate,
+            snapshot_dir,
+            snapshot_interval,
+            snapshot_prefix,
+            snapshot_format,
+            snapshot_keep,
+            snapshot_keep_daily,
             keep_alive,
+            tls_cert,
+            tls_key,
+            tls_reload_interval,
+            jwt_secret,
+            jwt_jwks_url,
+            jwt_audience,
+            jwt_issuer,
+            cors_allow_origins,
+            cors_allow_methods,
+            cors_allow_headers,
+            cors_max_age,
+            max_body_size,
+            route_max_body_sizes,
+            request_timeout,
+            max_query_cost,
+            universe,
+            facet_delimiter,
+            grpc_bind,
+            graphql,
+            webhook_urls,
+            webhook_secret,
+            webhook_debounce,
+            webhook_max_retries,
+            statsd_addr,
+            statsd_prefix,
+            statsd_tags,
+            statsd_interval,
+            sentry_dsn,
+            sentry_environment,
+            idempotency_cache_size,
+            idempotency_ttl,
+            tenants,
+            audit_log,
+            analytics_log,
+            analytics_sample_rate,
+            analytics_max_size,
+            config,
         } => {
+            // Kept until exit so that pending events are sent.
+            let _sentry = sentry_dsn
+                .as_deref()
+                .map(|dsn| {
+                    server::init_reporting(dsn, sentry_environment.as_deref())
+                })
+                .transpose()?;
+
             let addr: SocketAddr = bind
                 .parse()
                 .wrap_err_with(|| format!("Invalid bind `{}`", &bind))?;
@@ -139,14 +904,50 @@ async fn main() -> Result<(), Report> {
             let backend =
                 backend_options.build().wrap_err("Invalid backend")?;
 
-            let index = backend.load().wrap_err("Failed to load index")?;
+            let mut index = backend.load().wrap_err("Failed to load index")?;
+            index
+                .set_universe(*universe)
+                .wrap_err("Index does not fit in the universe")?;
+
+            let defaults = server::Defaults {
+                flush_policy: *flush_policy,
+                refresh: refresh_timeout.map(std::time::Duration::from_millis),
+                auth: server::AuthOptions {
+                    secret: jwt_secret.clone(),
+                    jwks_url: jwt_jwks_url.clone(),
+                    audience: jwt_audience.clone(),
+                    issuer: jwt_issuer.clone(),
+                },
+            };
+
+            let settings = match config {
+                Some(path) => {
+                    server::Config::load(path).await?.resolve(&defaults)?
+                }
+                None => server::Settings::from(defaults.clone()),
+            };
+            settings.apply_log_level(&log_filter)?;
+
+            let statsd = match statsd_addr {
+                Some(addr) => Some(Arc::new(
+                    Statsd::new(addr, statsd_prefix, statsd_tags.clone())
+                        .wrap_err_with(|| {
+                            format!("Invalid StatsD address `{}`", addr)
+                        })?,
+                )),
+                None => None,
+            };
 
             let executor = {
                 let mut executor_builder = ExecutorBuilder::new(
-                    Arc::new(RwLock::new(index)),
+                    Arc::new(SharedIndex::new(index)),
                     Arc::new(Mutex::new(backend)),
                 )
-                .read_only(*read_only);
+                .read_only(*read_only)
+                .queue_policy(*queue_policy)
+                .flush_policy(settings.flush_policy)
+                .flush_retries(*flush_retries)
+                .reject_writes_when_unflushed(*reject_writes_when_unflushed);
 
                 if let Some(c) = thread_count {
                     executor_builder = executor_builder.pool_size(*c);
@@ -156,52 +957,365 @@ async fn main() -> Result<(), Report> {
                     executor_builder = executor_builder.queue_size(*c);
                 }
 
+                if let Some(c) = write_thread_count {
+                    executor_builder = executor_builder.write_pool_size(*c);
+                }
+
+                if let Some(c) = write_queue_size {
+                    executor_builder = executor_builder.write_queue_size(*c);
+                }
+
+                if let Some(statsd) = &statsd {
+                    executor_builder = executor_builder.statsd(statsd.clone());
+                }
+
                 // TODO: Unwrap
                 executor_builder.build().unwrap()
             };
 
             let state = server::State::new(executor);
 
-            if let Some(interval) = refresh_timeout {
-                if !read_only {
-                    tracing::warn!(
-                        "Background refresh enabled in write mode. This is \
-                            generally unsafe as backends are not guaranteed to \
-                            be transactional."
-                    );
-                }
-                tokio::spawn(server::run_refresh_task(
+            tokio::spawn(server::run_flush_task(state.clone()));
+
+            let (refresh, refresh_interval) =
+                tokio::sync::watch::channel(settings.refresh);
+            tokio::spawn(server::run_refresh_task(
+                state.clone(),
+                refresh_interval,
+            ));
+
+            if let Some(statsd) = &statsd {
+                tokio::spawn(server::run_statsd_task(
+                    state.clone(),
+                    statsd.clone(),
+                    std::time::Duration::from_secs(*statsd_interval),
+                ));
+            }
+
+            if !webhook_urls.is_empty() {
+                tokio::spawn(server::run_webhooks_task(
                     state.clone(),
-                    std::time::Duration::from_millis(*interval),
+                    server::WebhookOptions {
+                        urls: webhook_urls.clone(),
+                        secret: webhook_secret.clone(),
+                        debounce: webhook_debounce
+                            .map(std::time::Duration::from_millis),
+                        max_retries: *webhook_max_retries,
+                    },
                 ));
             }
 
-            tracing::info!("Starting server on port {:?}", addr);
+            let tenants = match tenants {
+                Some(path) => server::load_tenants(path, &state).await?,
+                None => vec![],
+            };
+
+            if let Some(every) = optimize_interval {
+                let options = server::OptimizeOptions {
+                    interval: std::time::Duration::from_secs(*every),
+                    batch_size: *optimize_batch_size,
+                    max_write_rate: *optimize_max_write_rate,
+                };
+                for state in std::iter::once(&state)
+                    .chain(tenants.iter().map(|t| &t.state))
+                {
+                    tokio::spawn(server::run_optimize_task(
+                        state.clone(),
+                        options.clone(),
+                    ));
+                }
+            }
+
+            if let Some(dir) = snapshot_dir {
+                let prefix = snapshot_prefix.as_deref().unwrap_or("crible");
+                let options = server::SnapshotOptions {
+                    store: SnapshotStore::new(dir, Some(prefix)),
+                    encoder: *snapshot_format,
+                    interval: std::time::Duration::from_secs(
+                        *snapshot_interval,
+                    ),
+                    retention: Retention {
+                        keep_last: *snapshot_keep,
+                        keep_daily: *snapshot_keep_daily,
+                    },
+                };
+                for tenant in &tenants {
+                    tokio::spawn(server::run_snapshot_task(
+                        tenant.state.clone(),
+                        server::SnapshotOptions {
+                            store: SnapshotStore::new(
+                                dir,
+                                Some(&format!("{}-{}", prefix, tenant.name)),
+                            ),
+                            ..options.clone()
+                        },
+                    ));
+                }
+                tokio::spawn(server::run_snapshot_task(state.clone(), options));
+            }
+
+            for tenant in &tenants {
+                tokio::spawn(server::run_flush_task(tenant.state.clone()));
+                if let Some(interval) = tenant.refresh {
+                    tokio::spawn(server::run_refresh_task(
+                        tenant.state.clone(),
+                        tokio::sync::watch::channel(Some(interval)).1,
+                    ));
+                }
+            }
+
+            let tls = match (tls_cert, tls_key) {
+                (Some(cert), Some(key)) => Some(server::TlsOptions {
+                    cert: cert.clone(),
+                    key: key.clone(),
+                    reload_interval: tls_reload_interval
+                        .map(std::time::Duration::from_secs),
+                }),
+                _ => None,
+            };
+
+            let auth = server::Auth::new(&settings.auth)
+                .await
+                .wrap_err("Invalid authentication configuration")?
+                .map(Arc::new);
+
+            let reloader = config.as_ref().map(|path| {
+                Arc::new(server::Reloader {
+                    path: path.clone(),
+                    defaults,
+                    state: state.clone(),
+                    auth: auth.clone(),
+                    refresh,
+                    log_filter,
+                })
+            });
+
+            if let Some(reloader) = &reloader {
+                let reloader = reloader.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = server::run_reload_task(reloader).await {
+                        tracing::error!("Reload task failed: {:?}", e);
+                        server::capture_task_failure("reload", &e);
+                    }
+                });
+            }
 
-            server::run(
-                &addr,
-                keep_alive.map(std::time::Duration::from_secs),
+            let audit = audit_log
+                .as_deref()
+                .map(server::AuditLog::open)
+                .transpose()?
+                .map(Arc::new);
+
+            let analytics = analytics_log
+                .as_deref()
+                .map(|path| {
+                    server::AnalyticsLog::open(
+                        path,
+                        *analytics_max_size as u64,
+                        *analytics_sample_rate,
+                    )
+                })
+                .transpose()?
+                .map(Arc::new);
+
+            let mut grpc_task = None;
+            if let Some(grpc_bind) = grpc_bind {
+                let grpc_addr: SocketAddr =
+                    grpc_bind.parse().wrap_err_with(|| {
+                        format!("Invalid gRPC bind `{}`", &grpc_bind)
+                    })?;
+                tracing::info!("Starting gRPC server on port {:?}", grpc_addr);
+                let state = state.clone();
+                let auth = auth.clone();
+                let audit = audit.clone();
+                let max_query_cost = *max_query_cost;
+                grpc_task = Some(tokio::spawn(async move {
+                    if let Err(e) = server::run_grpc(
+                        &grpc_addr,
+                        state,
+                        auth,
+                        audit,
+                        max_query_cost,
+                    )
+                    .await
+                    {
+                        tracing::error!("gRPC server failed: {:?}", e);
+                        server::capture_task_failure("grpc", &e);
+                    }
+                }));
+            }
+
+            let indices: Vec<(String, server::State)> =
+                std::iter::once(("default".to_owned(), state.clone()))
+                    .chain(
+                        tenants
+                            .iter()
+                            .map(|t| (t.name.clone(), t.state.clone())),
+                    )
+                    .collect();
+
+            let listener = match systemd::listener()
+                .wrap_err("Invalid socket passed by systemd")?
+            {
+                Some(listener) => listener,
+                None => std::net::TcpListener::bind(addr)
+                    .wrap_err_with(|| format!("Failed to bind `{}`", addr))?,
+            };
+            listener.set_nonblocking(true)?;
+            tracing::info!("Starting server on {:?}", listener.local_addr()?);
+
+            systemd::notify_ready();
+            if let Some(timeout) = systemd::watchdog_interval() {
+                tokio::spawn(systemd::run_watchdog_task(timeout));
+            }
+
+            let served = server::run(
+                listener,
+                server::Options {
+                    keep_alive: keep_alive.map(std::time::Duration::from_secs),
+                    tls,
+                    auth,
+                    cors: if cors_allow_origins.is_empty() {
+                        None
+                    } else {
+                        Some(server::CorsOptions {
+                            allow_origins: cors_allow_origins.clone(),
+                            allow_methods: cors_allow_methods.clone(),
+                            allow_headers: cors_allow_headers.clone(),
+                            max_age: cors_max_age
+                                .map(std::time::Duration::from_secs),
+                        })
+                    },
+                    body_limits: server::BodyLimits::new(
+                        *max_body_size,
+                        route_max_body_sizes,
+                    ),
+                    request_timeout: request_timeout
+                        .map(std::time::Duration::from_millis),
+                    graphql: *graphql,
+                    tenants,
+                    idempotency: if *idempotency_cache_size > 0 {
+                        Some(server::IdempotencyOptions {
+                            capacity: *idempotency_cache_size,
+                            ttl: std::time::Duration::from_secs(
+                                *idempotency_ttl,
+                            ),
+                        })
+                    } else {
+                        None
+                    },
+                    audit,
+                    analytics,
+                    reloader,
+                    max_query_cost: *max_query_cost,
+                    facet_delimiter: crible::operations::FacetDelimiter(
+                        facet_delimiter.clone(),
+                    ),
+                    statsd,
+                    resolved_options: matches
+                        .subcommand_matches("serve")
+                        .zip(command.find_subcommand("serve"))
+                        .map(|(matches, serve)| {
+                            Arc::new(server::resolve_options(
+                                serve, matches, &from_file,
+                            ))
+                        }),
+                    sentry: sentry_dsn.is_some(),
+                },
                 state,
             )
-            .await?;
+            .await;
 
-            Ok(())
-        }
-        Command::Query { backend_options, query } => {
-            let backend =
-                backend_options.build().wrap_err("Invalid backend")?;
-            let index = backend.load().wrap_err("Failed to load index")?;
+            systemd::notify_stopping();
 
-            let res = index.execute(query)?;
+            if let Some(task) = grpc_task {
+                task.await?;
+            }
 
-            let stdout = std::io::stdout();
-            let mut buffer = std::io::BufWriter::new(stdout.lock());
+            // No more requests are being handled, persist the writes made
+            // since the last flush before exiting.
+            let mut flushed = Ok(());
+            for (name, state) in indices {
+                match state.shutdown().await {
+                    Ok(()) => {
+                        tracing::info!("Flushed index {:?} before exit", name)
+                    }
+                    Err(e) => {
+                        tracing::error!(
+                            "Failed to flush index {:?} before exit: {:?}",
+                            name,
+                            e
+                        );
+                        flushed = Err(e);
+                    }
+                }
+            }
 
-            for x in res.iter() {
-                writeln!(buffer, "{}", x)?;
+            served?;
+            flushed
+        }
+        Command::CheckConfig { config } => {
+            // The file was loaded into the environment before parsing the
+            // command line, so parsing `serve` validates its options.
+            let serve = App::try_parse_from(["crible", "serve"])?;
+            if let Command::Serve {
+                flush_policy,
+                refresh_timeout,
+                jwt_secret,
+                jwt_jwks_url,
+                jwt_audience,
+                jwt_issuer,
+                tenants,
+                ..
+            } = serve.command
+            {
+                let defaults = server::Defaults {
+                    flush_policy,
+                    refresh: refresh_timeout
+                        .map(std::time::Duration::from_millis),
+                    auth: server::AuthOptions {
+                        secret: jwt_secret,
+                        jwks_url: jwt_jwks_url,
+                        audience: jwt_audience,
+                        issuer: jwt_issuer,
+                    },
+                };
+                server::Config::load(config).await?.resolve(&defaults)?;
+                if let Some(path) = tenants {
+                    let count = server::check_tenants(&path).await?;
+                    println!("{} tenants", count);
+                }
             }
+            println!("Configuration is valid");
             Ok(())
         }
+        Command::Query {
+            backend_options,
+            query,
+            stdin: _,
+            file,
+            output,
+            include_cardinalities,
+            limit,
+            offset,
+        } => {
+            let options = commands::QueryOptions {
+                output: *output,
+                include_cardinalities: *include_cardinalities,
+                limit: *limit,
+                offset: *offset,
+            };
+            match query {
+                Some(query) => {
+                    commands::query(backend_options, query, &options)
+                }
+                None => commands::query_batch(
+                    backend_options,
+                    file.as_deref(),
+                    &options,
+                ),
+            }
+        }
         Command::Copy { from, to } => {
             let from_backend =
                 from.build().wrap_err("Invalid source backend")?;
@@ -217,5 +1331,142 @@ async fn main() -> Result<(), Report> {
             to_backend.dump(&index).wrap_err("Failed to dump index")?;
             Ok(())
         }
+        Command::Convert { from, to, include, exclude, rename, dry_run } => {
+            commands::convert(
+                from,
+                to,
+                &commands::Selection {
+                    include: include.clone(),
+                    exclude: exclude.clone(),
+                    rename: rename.clone(),
+                },
+                *dry_run,
+            )
+        }
+        Command::Import {
+            backend_options,
+            from,
+            property_col,
+            id_col,
+            delimiter,
+            chunk_size,
+            replace,
+        } => {
+            if !delimiter.is_ascii() {
+                return Err(eyre::eyre!(
+                    "Delimiter must be an ascii character"
+                ));
+            }
+            let backend =
+                backend_options.build().wrap_err("Invalid backend")?;
+            commands::import(
+                backend.as_ref(),
+                &commands::ImportOptions {
+                    from: from.clone(),
+                    property_col: property_col.clone(),
+                    id_col: id_col.clone(),
+                    delimiter: *delimiter as u8,
+                    chunk_size: (*chunk_size).max(1),
+                    replace: *replace,
+                },
+            )
+        }
+        Command::Export { backend_options, format, out } => {
+            let backend =
+                backend_options.build().wrap_err("Invalid backend")?;
+            commands::export(backend.as_ref(), *format, out)
+        }
+        Command::Merge { into, sources, strategy, prefix_map } => {
+            commands::merge(into, sources, *strategy, prefix_map)
+        }
+        Command::Diff { a, b, json } => {
+            if commands::diff(a, b, *json)? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Validate { backend_options, json } => {
+            if commands::validate(backend_options, *json)? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Stats { backend_options, prefix, sort, json } => {
+            commands::stats(backend_options, prefix.as_deref(), *sort, *json)
+        }
+        Command::Inspect {
+            backend_options,
+            property,
+            prefix,
+            sample,
+            top,
+            json,
+        } => commands::inspect(
+            backend_options,
+            property,
+            &commands::InspectOptions {
+                prefix: prefix.clone(),
+                sample: *sample,
+                top: *top,
+            },
+            *json,
+        ),
+        Command::Snapshot { backend_options, to, prefix, format, keep } => {
+            commands::snapshot(
+                backend_options,
+                to,
+                prefix.as_deref(),
+                *format,
+                *keep,
+            )
+        }
+        Command::Restore { from, prefix, backend_options } => {
+            commands::restore(backend_options, from, prefix.as_deref())
+        }
+        Command::Generate {
+            backend_options,
+            properties,
+            bits,
+            distribution,
+            density,
+            exponent,
+            prefix,
+            seed,
+        } => commands::generate(
+            backend_options,
+            &commands::GenerateOptions {
+                properties: *properties as usize,
+                bits: *bits,
+                distribution: *distribution,
+                density: *density,
+                exponent: *exponent,
+                prefix: prefix.clone(),
+                seed: *seed,
+            },
+        ),
+        Command::Doctor { backend, server } => {
+            if commands::doctor(backend, server.as_ref()).await? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::Repl { backend_options, allow_writes } => {
+            commands::repl(backend_options, *allow_writes)
+        }
+        Command::Optimize { backend_options, json } => {
+            commands::optimize(backend_options, *json)
+        }
+        Command::Replicate { from, to, token, watch, interval } => {
+            commands::replicate(
+                from,
+                to,
+                token.as_deref(),
+                watch.then_some(*interval),
+            )
+            .await
+        }
+        Command::Tail { server, prefixes, token } => {
+            commands::tail(server, prefixes, token.as_deref()).await
+        }
     }
 }
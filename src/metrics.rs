@@ -0,0 +1,89 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use serde_derive::Serialize;
+
+/// Upper bounds of the histogram buckets in seconds, a last implicit bucket
+/// catches everything above.
+static BUCKETS: [f64; 12] =
+    [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/// Lock free histogram of durations over fixed buckets.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; 12],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Bucket {
+    /// Upper bound of the bucket in seconds.
+    pub le: f64,
+    /// Number of observations less than or equal to `le`.
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct HistogramSnapshot {
+    /// Cumulative buckets, in the same format as Prometheus histograms.
+    pub buckets: Vec<Bucket>,
+    pub count: u64,
+    /// Sum of all observations in seconds.
+    pub sum: f64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|le| seconds <= *le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Current values, concurrent observations may be partially included.
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        HistogramSnapshot {
+            buckets: BUCKETS
+                .iter()
+                .zip(&self.buckets)
+                .map(|(le, count)| {
+                    cumulative += count.load(Ordering::Relaxed);
+                    Bucket { le: *le, count: cumulative }
+                })
+                .collect(),
+            count: self.count.load(Ordering::Relaxed),
+            sum: self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Histogram;
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::default();
+        for ms in [0, 3, 3, 40, 10_000] {
+            histogram.observe(Duration::from_millis(ms));
+        }
+
+        let snapshot = histogram.snapshot();
+        let counts: Vec<(f64, u64)> =
+            snapshot.buckets.iter().map(|b| (b.le, b.count)).collect();
+        assert_eq!(counts[0], (0.0005, 1));
+        assert_eq!(counts[2], (0.0025, 1));
+        assert_eq!(counts[3], (0.005, 3));
+        assert_eq!(counts[6], (0.05, 4));
+        assert_eq!(counts[11], (2.5, 4));
+        assert_eq!(snapshot.count, 5);
+        assert_eq!(snapshot.sum, 10.046);
+    }
+}
@@ -1,16 +1,23 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::From;
 
+use crible_api_types as api;
 use crible_lib::expression::Expression;
-use crible_lib::Index;
-use parking_lot::RwLock;
-use serde_derive::{Deserialize, Serialize};
+use crible_lib::index::{percentile, ContainerStats};
+use crible_lib::{Cancellation, Index};
+use croaring::Bitmap;
+use serde_derive::Deserialize;
+
+use crate::changes::Change;
+use crate::executor::SharedIndex;
+use crate::utils::glob_match;
 
 #[derive(Debug)]
 pub enum OperationError {
     ReadOnly,
     Expression(crible_lib::expression::Error),
     Index(crible_lib::index::Error),
+    TooExpensive { cost: u64, max_cost: u64 },
 }
 
 impl From<crible_lib::expression::Error> for OperationError {
@@ -27,190 +34,1047 @@ impl From<crible_lib::index::Error> for OperationError {
 
 type OperationResult<T> = Result<T, OperationError>;
 
+/// Reject queries whose combined estimated cost, see `Index::estimate_cost`,
+/// exceeds `max_cost`.
+pub fn check_cost(
+    index: &Index,
+    expressions: &[&Expression],
+    max_cost: Option<u64>,
+) -> OperationResult<()> {
+    if let Some(max_cost) = max_cost {
+        let cost = expressions.iter().map(|e| index.estimate_cost(e)).sum();
+        if cost > max_cost {
+            return Err(OperationError::TooExpensive { cost, max_cost });
+        }
+    }
+    Ok(())
+}
+
 pub trait Operation {
     type Output;
 
-    fn run(self, index: &RwLock<Index>) -> Self::Output;
+    /// Whether the operation modifies the index. Mutating operations are
+    /// rejected when the server is read-only.
+    const MUTATES: bool;
+
+    fn run(self, index: &SharedIndex) -> Self::Output;
 }
 
-/// Run a query against the index. The result will include all unique elements
-/// matching the query and optionally (if `include_cardinalities` is provided
-/// and true) a map containing the cardinality of the intersection of the query
-/// and every property included in the index.
-#[derive(Deserialize, Debug)]
-pub struct Query {
-    query: String,
-    include_cardinalities: Option<bool>,
+/// Separator between the field and the value of property names, e.g. `:`
+/// for `color:red`, used to group cardinalities into facets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FacetDelimiter(pub String);
+
+impl Default for FacetDelimiter {
+    fn default() -> Self {
+        Self(":".to_owned())
+    }
+}
+
+/// Group `cardinalities` into facets by splitting property names on the first
+/// occurrence of `delimiter`, see `api::Facet`. Facets are sorted by field.
+pub fn facets(
+    cardinalities: &HashMap<String, u64>,
+    delimiter: &FacetDelimiter,
+) -> Vec<api::Facet> {
+    let mut fields = BTreeMap::<&str, Vec<api::FacetBucket>>::new();
+    for (property, count) in cardinalities {
+        if let Some((field, value)) = property.split_once(&delimiter.0) {
+            fields.entry(field).or_default().push(api::FacetBucket {
+                value: value.to_owned(),
+                count: *count,
+            });
+        }
+    }
+    fields
+        .into_iter()
+        .map(|(field, mut buckets)| {
+            buckets.sort_unstable_by(|a, b| {
+                b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value))
+            });
+            api::Facet { field: field.to_owned(), buckets }
+        })
+        .collect()
 }
 
-#[derive(Serialize, Debug)]
-pub struct QueryResult {
-    values: Vec<u32>,
-    cardinalities: Option<HashMap<String, u64>>,
+/// Server side execution of `api::Query`.
+#[derive(Debug)]
+pub struct Query {
+    pub request: api::Query,
+    /// Set by the server from the caller's limits, see `check_cost`.
+    pub max_cost: Option<u64>,
+    /// Set by the server, stops the query once the request is abandoned.
+    pub cancellation: Cancellation,
+    /// Set by the server, used when the request asks for facets.
+    pub facet_delimiter: FacetDelimiter,
 }
 
 impl Operation for Query {
-    type Output = OperationResult<QueryResult>;
+    type Output = OperationResult<api::QueryResult>;
+
+    const MUTATES: bool = false;
 
     #[inline]
-    fn run(self, index: &RwLock<Index>) -> OperationResult<QueryResult> {
-        let expr = Expression::parse(&self.query)?;
+    fn run(self, index: &SharedIndex) -> OperationResult<api::QueryResult> {
+        let expr = Expression::parse(&self.request.query)?;
         let idx = index.read();
-        let bm = idx.execute(&expr)?;
-        let cardinalities = match self.include_cardinalities {
-            Some(true) => Some(idx.par_cardinalities(&bm, None)),
+        let include_cardinalities =
+            self.request.include_cardinalities == Some(true);
+        let include_facets = self.request.facets == Some(true);
+        if include_cardinalities || include_facets {
+            // Computing cardinalities goes through every property.
+            check_cost(&idx, &[&expr, &Expression::Root], self.max_cost)?;
+        } else {
+            check_cost(&idx, &[&expr], self.max_cost)?;
+        }
+        let bm = idx.execute_cancellable(&expr, &self.cancellation)?;
+        let cardinalities = if include_cardinalities || include_facets {
+            Some(idx.par_cardinalities_cancellable(
+                &bm,
+                None,
+                &self.cancellation,
+            )?)
+        } else {
+            None
+        };
+        let facets = match &cardinalities {
+            Some(c) if include_facets => Some(facets(c, &self.facet_delimiter)),
             _ => None,
         };
-        Ok(QueryResult { values: bm.to_vec(), cardinalities })
+        Ok(api::QueryResult {
+            values: if self.request.include_values == Some(false) {
+                vec![]
+            } else {
+                bm.to_vec()
+            },
+            cardinalities: cardinalities.filter(|_| include_cardinalities),
+            facets,
+            stats: (self.request.include_stats == Some(true))
+                .then(|| stats(bm.as_ref().into())),
+            ranks: self.request.ranks.map(|bits| {
+                bits.into_iter().map(|bit| (bit, bm.rank(bit))).collect()
+            }),
+            percentiles: self.request.percentiles.map(|ps| {
+                ps.into_iter()
+                    .filter_map(|p| {
+                        percentile(&bm, p.into()).map(|v| (p.min(100), v))
+                    })
+                    .collect()
+            }),
+        })
     }
 }
 
-#[derive(Deserialize, Debug)]
+/// Server side execution of `api::Count`.
+#[derive(Debug)]
 pub struct Count {
-    query: String,
+    pub request: api::Count,
+    /// Set by the server from the caller's limits, see `check_cost`.
+    pub max_cost: Option<u64>,
+    /// See `Query::cancellation`.
+    pub cancellation: Cancellation,
 }
 
 impl Operation for Count {
     type Output = OperationResult<u64>;
 
+    const MUTATES: bool = false;
+
     #[inline]
-    fn run(self, index: &RwLock<Index>) -> OperationResult<u64> {
-        let expr = Expression::parse(&self.query)?;
+    fn run(self, index: &SharedIndex) -> OperationResult<u64> {
+        let expr = Expression::parse(&self.request.query)?;
         let idx = index.read();
-        let bm = idx.execute(&expr)?;
+        check_cost(&idx, &[&expr], self.max_cost)?;
+        let bm = idx.execute_cancellable(&expr, &self.cancellation)?;
         Ok(bm.cardinality())
     }
 }
 
-#[derive(Deserialize, Debug)]
-pub struct Stats;
+/// Maximum number of bits returned by `GetProperties`, regardless of the
+/// caller's cost limit.
+pub static MAX_GET_PROPERTIES_BITS: u64 = 10_000_000;
 
-#[derive(Serialize, Debug)]
-pub struct StatsResult {
-    root: crible_lib::index::Stats,
-    properties: HashMap<String, crible_lib::index::Stats>,
+/// Server side execution of `api::GetProperties`.
+#[derive(Debug)]
+pub struct GetProperties {
+    pub request: api::GetProperties,
+    /// Set by the server from the caller's limits, the cost is the total
+    /// number of bits returned and is capped to `MAX_GET_PROPERTIES_BITS`.
+    pub max_cost: Option<u64>,
 }
 
-impl Operation for Stats {
-    type Output = StatsResult;
+impl Operation for GetProperties {
+    type Output = OperationResult<api::GetPropertiesResult>;
+
+    const MUTATES: bool = false;
 
     #[inline]
-    fn run(self, index: &RwLock<Index>) -> StatsResult {
+    fn run(self, index: &SharedIndex) -> Self::Output {
         let idx = index.read();
-        StatsResult {
-            root: (&*idx).into(),
-            properties: idx
-                .into_iter()
-                .map(|(k, v)| (k.clone(), v.into()))
-                .collect(),
+        let bitmaps = self
+            .request
+            .properties
+            .into_iter()
+            .filter_map(|p| {
+                let bm = idx.get_property(&p)?;
+                Some((p, bm))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let cost = bitmaps.values().map(|bm| bm.cardinality()).sum();
+        let max_cost = self.max_cost.map_or(MAX_GET_PROPERTIES_BITS, |m| {
+            m.min(MAX_GET_PROPERTIES_BITS)
+        });
+        if cost > max_cost {
+            return Err(OperationError::TooExpensive { cost, max_cost });
         }
+
+        Ok(if self.request.serialized == Some(true) {
+            api::GetPropertiesResult {
+                bitmaps: bitmaps
+                    .into_iter()
+                    .map(|(p, bm)| (p, base64::encode(bm.serialize())))
+                    .collect(),
+                ..Default::default()
+            }
+        } else {
+            api::GetPropertiesResult {
+                values: bitmaps
+                    .into_iter()
+                    .map(|(p, bm)| (p, bm.to_vec()))
+                    .collect(),
+                ..Default::default()
+            }
+        })
     }
 }
 
-#[derive(Deserialize, Debug)]
-pub struct Set {
-    property: String,
-    bit: u32,
+/// Server side execution of `api::CountMany`.
+#[derive(Debug)]
+pub struct CountMany {
+    pub request: api::CountMany,
+    /// Set by the server from the caller's limits, applies to the combined
+    /// cost of all the queries, see `check_cost`.
+    pub max_cost: Option<u64>,
+    /// See `Query::cancellation`.
+    pub cancellation: Cancellation,
 }
 
-impl Operation for Set {
-    type Output = bool;
+impl Operation for CountMany {
+    type Output = OperationResult<api::Batch<OperationResult<u64>>>;
+
+    const MUTATES: bool = false;
 
     #[inline]
-    fn run(self, index: &RwLock<Index>) -> bool {
-        index.write().set(&self.property, self.bit)
+    fn run(self, index: &SharedIndex) -> Self::Output {
+        let expressions = self.request.queries.map(|q| Expression::parse(&q));
+        let idx = index.read();
+        let valid = expressions
+            .values()
+            .into_iter()
+            .filter_map(|e| e.as_ref().ok())
+            .collect::<Vec<_>>();
+        check_cost(&idx, &valid, self.max_cost)?;
+        Ok(expressions.map(|expr| {
+            let bm = idx.execute_cancellable(&expr?, &self.cancellation)?;
+            Ok(bm.cardinality())
+        }))
     }
 }
 
-#[derive(Deserialize, Debug)]
-pub struct SetMany {
-    values: HashMap<String, Vec<u32>>,
+/// Server side execution of `api::Compare`.
+#[derive(Debug)]
+pub struct Compare {
+    pub request: api::Compare,
+    /// Set by the server from the caller's limits, see `check_cost`.
+    pub max_cost: Option<u64>,
+    /// See `Query::cancellation`.
+    pub cancellation: Cancellation,
 }
 
-impl Operation for SetMany {
-    type Output = ();
+impl Operation for Compare {
+    type Output = OperationResult<api::CompareResult>;
+
+    const MUTATES: bool = false;
 
     #[inline]
-    fn run(self, index: &RwLock<Index>) {
-        let mut idx = index.write();
-        for (property, bits) in &self.values {
-            idx.set_many(property, bits);
+    fn run(self, index: &SharedIndex) -> OperationResult<api::CompareResult> {
+        let left = Expression::parse(&self.request.left)?;
+        let right = Expression::parse(&self.request.right)?;
+        let idx = index.read();
+        check_cost(&idx, &[&left, &right], self.max_cost)?;
+        let lbm = idx.execute_cancellable(&left, &self.cancellation)?;
+        let rbm = idx.execute_cancellable(&right, &self.cancellation)?;
+
+        let both = lbm.and_cardinality(&rbm);
+
+        let (sample_both, sample_left, sample_right) = match self.request.sample
+        {
+            Some(n) => (
+                Some(lbm.and(&rbm).iter().take(n).collect()),
+                Some(lbm.andnot(&rbm).iter().take(n).collect()),
+                Some(rbm.andnot(&lbm).iter().take(n).collect()),
+            ),
+            None => (None, None, None),
+        };
+
+        Ok(api::CompareResult {
+            intersection: api::CompareBucket {
+                count: both,
+                sample: sample_both,
+            },
+            only_left: api::CompareBucket {
+                count: lbm.cardinality() - both,
+                sample: sample_left,
+            },
+            only_right: api::CompareBucket {
+                count: rbm.cardinality() - both,
+                sample: sample_right,
+            },
+        })
+    }
+}
+
+/// Current bitmaps of the properties modified since a replica's generation,
+/// see `api::SyncResult`.
+#[derive(Debug)]
+pub struct Sync {
+    pub log: String,
+    pub generation: u64,
+    /// `None` when the replica must reload the whole index.
+    pub modified: Option<HashSet<String>>,
+}
+
+impl Operation for Sync {
+    type Output = api::SyncResult;
+
+    const MUTATES: bool = false;
+
+    #[inline]
+    fn run(self, index: &SharedIndex) -> api::SyncResult {
+        let mut result = api::SyncResult {
+            log: self.log,
+            generation: self.generation,
+            reload: self.modified.is_none(),
+            bitmaps: HashMap::new(),
+            deleted: vec![],
+        };
+        let idx = index.read();
+        for property in self.modified.into_iter().flatten() {
+            match idx.get_property(&property) {
+                Some(bm) => {
+                    result
+                        .bitmaps
+                        .insert(property, base64::encode(bm.serialize()));
+                }
+                None => result.deleted.push(property),
+            }
         }
+        result.deleted.sort_unstable();
+        result
     }
 }
 
-#[derive(Deserialize, Debug)]
-pub struct Unset {
-    property: String,
-    bit: u32,
+/// `numerator / denominator`, 0 when `denominator` is 0.
+fn ratio(numerator: u64, denominator: u64) -> f64 {
+    if denominator == 0 { 0.0 } else { numerator as f64 / denominator as f64 }
 }
 
-impl Operation for Unset {
-    type Output = bool;
+/// Only the cardinalities of the results are computed, the values are never
+/// materialized.
+#[derive(Debug)]
+pub struct Similarity {
+    pub request: api::Similarity,
+    /// Set by the server from the caller's limits, see `check_cost`.
+    pub max_cost: Option<u64>,
+    /// See `Query::cancellation`.
+    pub cancellation: Cancellation,
+}
+
+impl Operation for Similarity {
+    type Output = OperationResult<api::SimilarityResult>;
+
+    const MUTATES: bool = false;
 
     #[inline]
-    fn run(self, index: &RwLock<Index>) -> bool {
-        index.write().unset(&self.property, self.bit)
+    fn run(
+        self,
+        index: &SharedIndex,
+    ) -> OperationResult<api::SimilarityResult> {
+        let left = Expression::parse(&self.request.left)?;
+        let right = Expression::parse(&self.request.right)?;
+        let idx = index.read();
+        check_cost(&idx, &[&left, &right], self.max_cost)?;
+        let lbm = idx.execute_cancellable(&left, &self.cancellation)?;
+        let rbm = idx.execute_cancellable(&right, &self.cancellation)?;
+
+        let (l, r) = (lbm.cardinality(), rbm.cardinality());
+        let intersection = lbm.and_cardinality(&rbm);
+        let union = l + r - intersection;
+        Ok(api::SimilarityResult {
+            left: l,
+            right: r,
+            intersection,
+            union,
+            jaccard: ratio(intersection, union),
+            overlap: ratio(intersection, l.min(r)),
+            containment_left: ratio(intersection, l),
+            containment_right: ratio(intersection, r),
+        })
     }
 }
 
-#[derive(Deserialize, Debug)]
-pub struct UnsetMany {
-    values: HashMap<String, Vec<u32>>,
+/// Convert bitmap statistics to their API representation.
+pub fn stats(stats: crible_lib::index::Stats) -> api::Stats {
+    api::Stats {
+        cardinality: stats.cardinality,
+        minimum: stats.minimum,
+        maximum: stats.maximum,
+        containers: None,
+    }
 }
 
-impl Operation for UnsetMany {
-    type Output = ();
+/// Convert container statistics to their API representation.
+pub fn container_stats(stats: ContainerStats) -> api::ContainerStats {
+    api::ContainerStats {
+        array_containers: stats.array_containers,
+        run_containers: stats.run_containers,
+        bitset_containers: stats.bitset_containers,
+        array_bytes: stats.array_bytes,
+        run_bytes: stats.run_bytes,
+        bitset_bytes: stats.bitset_bytes,
+    }
+}
+
+/// Stats of a single property, including its containers if requested.
+fn property_stats(bm: &Bitmap, include_containers: bool) -> api::Stats {
+    api::Stats {
+        containers: include_containers.then(|| container_stats(bm.into())),
+        ..stats(bm.into())
+    }
+}
+
+#[derive(Debug)]
+pub struct Stats {
+    pub request: api::StatsRequest,
+}
+
+impl Operation for Stats {
+    type Output = api::StatsResult;
+
+    const MUTATES: bool = false;
 
     #[inline]
-    fn run(self, index: &RwLock<Index>) {
-        let mut idx = index.write();
-        for (property, bits) in &self.values {
-            idx.unset_many(property, bits);
+    fn run(self, index: &SharedIndex) -> api::StatsResult {
+        let api::StatsRequest {
+            prefix,
+            properties,
+            include_root,
+            include_properties,
+            include_containers,
+        } = self.request;
+        let prefix = prefix.as_deref().unwrap_or("");
+        let include_containers = include_containers == Some(true);
+
+        let idx = index.read();
+        api::StatsResult {
+            root: include_root.unwrap_or(true).then(|| stats((&*idx).into())),
+            properties: match (include_properties.unwrap_or(true), properties) {
+                (false, _) => HashMap::new(),
+                (true, Some(properties)) => properties
+                    .into_iter()
+                    .filter(|k| k.starts_with(prefix))
+                    .filter_map(|k| {
+                        let bm = idx.get_property(&k)?;
+                        Some((k, property_stats(bm, include_containers)))
+                    })
+                    .collect(),
+                (true, None) => idx
+                    .iter_prefix(prefix)
+                    .map(|(k, v)| {
+                        (k.to_owned(), property_stats(v, include_containers))
+                    })
+                    .collect(),
+            },
+            flush: None,
+            last_modified: None,
         }
     }
 }
 
-#[derive(Deserialize, Debug)]
-pub struct GetBit {
-    bit: u32,
+/// Default of `api::DistributionRequest::buckets`.
+static DEFAULT_DISTRIBUTION_BUCKETS: [u64; 10] = [
+    1,
+    10,
+    100,
+    1_000,
+    10_000,
+    100_000,
+    1_000_000,
+    10_000_000,
+    100_000_000,
+    1_000_000_000,
+];
+
+impl Operation for api::DistributionRequest {
+    type Output = api::DistributionResult;
+
+    const MUTATES: bool = false;
+
+    #[inline]
+    fn run(self, index: &SharedIndex) -> api::DistributionResult {
+        let mut buckets = self
+            .buckets
+            .unwrap_or_else(|| DEFAULT_DISTRIBUTION_BUCKETS.to_vec());
+        buckets.sort_unstable();
+        buckets.dedup();
+        let histogram = index
+            .read()
+            .cardinality_histogram(&buckets, self.prefix.as_deref());
+        api::DistributionResult {
+            properties: histogram.properties,
+            p50: histogram.p50,
+            p90: histogram.p90,
+            p99: histogram.p99,
+            maximum: histogram.maximum,
+            buckets: histogram
+                .buckets
+                .into_iter()
+                .map(|b| api::DistributionBucket { le: b.le, count: b.count })
+                .collect(),
+        }
+    }
+}
+
+impl Operation for api::BitmapStatsRequest {
+    type Output = api::BitmapStatsResult;
+
+    const MUTATES: bool = false;
+
+    #[inline]
+    fn run(self, index: &SharedIndex) -> api::BitmapStatsResult {
+        let prefix = self.prefix.as_deref().unwrap_or("");
+        let idx = index.read();
+        let mut total = ContainerStats::default();
+        let properties = idx
+            .iter_prefix(prefix)
+            .map(|(k, v)| {
+                let stats = ContainerStats::from(v);
+                total += stats;
+                (k.to_owned(), container_stats(stats))
+            })
+            .collect();
+        api::BitmapStatsResult { total: container_stats(total), properties }
+    }
 }
 
-impl Operation for GetBit {
+impl Operation for api::ListProperties {
+    type Output = api::PropertiesResult;
+
+    const MUTATES: bool = false;
+
+    #[inline]
+    fn run(self, index: &SharedIndex) -> api::PropertiesResult {
+        let prefix = self.prefix.as_deref().unwrap_or("");
+        let pattern = self.pattern.as_deref().unwrap_or("*");
+
+        let idx = index.read();
+        let matching: Vec<_> = idx
+            .iter_prefix(prefix)
+            .filter(|(k, _)| glob_match(pattern, k))
+            .collect();
+
+        api::PropertiesResult {
+            stats: self.include_stats.unwrap_or(false).then(|| {
+                matching
+                    .iter()
+                    .map(|&(k, v)| (k.to_owned(), stats(v.into())))
+                    .collect()
+            }),
+            properties: matching
+                .into_iter()
+                .map(|(k, _)| k.to_owned())
+                .collect(),
+            metadata: None,
+        }
+    }
+}
+
+impl Operation for api::GetBit {
     type Output = Vec<String>;
 
+    const MUTATES: bool = false;
+
     #[inline]
-    fn run(self, index: &RwLock<Index>) -> Self::Output {
+    fn run(self, index: &SharedIndex) -> Self::Output {
         index.read().get_properties_with_bit(self.bit)
     }
 }
 
-#[derive(Deserialize, Debug)]
-pub struct SetBit {
-    bit: u32,
-    properties: Vec<String>,
+impl Operation for api::GetBits {
+    type Output = HashMap<u32, Vec<String>>;
+
+    const MUTATES: bool = false;
+
+    #[inline]
+    fn run(self, index: &SharedIndex) -> Self::Output {
+        index.read().get_properties_with_bits(&self.bits)
+    }
+}
+
+/// Server side behaviour of the mutations defined in `crible_api_types`.
+pub trait Mutate {
+    /// Apply the mutation, returns whether the index may have been modified.
+    fn apply(&self, index: &mut Index) -> bool;
+
+    /// Change published once the mutation is applied.
+    fn change(&self) -> Change;
+
+    /// Fail if the mutation cannot be applied to `index`, in which case it
+    /// must not be applied at all.
+    fn check(&self, _index: &Index) -> OperationResult<()> {
+        Ok(())
+    }
+}
+
+/// Change for setting or unsetting `values`, affecting the union of their
+/// bits.
+pub fn many_change(
+    operation: &'static str,
+    values: &HashMap<String, Vec<u32>>,
+) -> Change {
+    let mut bits: Vec<u32> = values.values().flatten().copied().collect();
+    bits.sort_unstable();
+    bits.dedup();
+    Change::mutation(operation, Some(values.keys().cloned().collect()), bits)
+}
+
+impl Mutate for api::Set {
+    fn apply(&self, index: &mut Index) -> bool {
+        index.set(&self.property, self.bit)
+    }
+
+    fn change(&self) -> Change {
+        Change::mutation(
+            "set",
+            Some(vec![self.property.clone()]),
+            vec![self.bit],
+        )
+    }
+
+    fn check(&self, index: &Index) -> OperationResult<()> {
+        Ok(index.check_bits(&[self.bit])?)
+    }
+}
+
+impl Mutate for api::SetMany {
+    fn apply(&self, index: &mut Index) -> bool {
+        for (property, bits) in &self.values {
+            index.set_many(property, bits);
+        }
+        true
+    }
+
+    fn change(&self) -> Change {
+        many_change("set-many", &self.values)
+    }
+
+    fn check(&self, index: &Index) -> OperationResult<()> {
+        for bits in self.values.values() {
+            index.check_bits(bits)?;
+        }
+        Ok(())
+    }
+}
+
+impl Mutate for api::Unset {
+    fn apply(&self, index: &mut Index) -> bool {
+        index.unset(&self.property, self.bit)
+    }
+
+    fn change(&self) -> Change {
+        Change::mutation(
+            "unset",
+            Some(vec![self.property.clone()]),
+            vec![self.bit],
+        )
+    }
+}
+
+impl Mutate for api::UnsetMany {
+    fn apply(&self, index: &mut Index) -> bool {
+        for (property, bits) in &self.values {
+            index.unset_many(property, bits);
+        }
+        true
+    }
+
+    fn change(&self) -> Change {
+        many_change("unset-many", &self.values)
+    }
+}
+
+impl Mutate for api::SetBit {
+    fn apply(&self, index: &mut Index) -> bool {
+        let mut created = false;
+        if self.missing == Some(api::MissingProperties::Create) {
+            for property in index.missing_properties(&self.properties) {
+                created |= index.set(property, self.bit);
+            }
+        }
+        index.set_properties_with_bit(self.bit, &self.properties) || created
+    }
+
+    fn change(&self) -> Change {
+        // Properties which previously had the bit are unset as well.
+        Change::mutation("set-bit", None, vec![self.bit])
+    }
+
+    fn check(&self, index: &Index) -> OperationResult<()> {
+        index.check_bits(&[self.bit])?;
+        if self.missing == Some(api::MissingProperties::Reject) {
+            if let Some(property) =
+                index.missing_properties(&self.properties).first()
+            {
+                return Err(crible_lib::index::Error::property_does_not_exist(
+                    property,
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Mutate for api::SetBits {
+    fn apply(&self, index: &mut Index) -> bool {
+        let mut created = false;
+        if self.missing == Some(api::MissingProperties::Create) {
+            for (bit, properties) in &self.bits {
+                for property in index.missing_properties(properties) {
+                    created |= index.set(property, *bit);
+                }
+            }
+        }
+        index.set_properties_with_bits(&self.bits) || created
+    }
+
+    fn change(&self) -> Change {
+        let mut bits: Vec<u32> = self.bits.keys().copied().collect();
+        bits.sort_unstable();
+        Change::mutation("set-bits", None, bits)
+    }
+
+    fn check(&self, index: &Index) -> OperationResult<()> {
+        index.check_bits(&self.bits.keys().copied().collect::<Vec<_>>())?;
+        if self.missing == Some(api::MissingProperties::Reject) {
+            if let Some(property) = self
+                .bits
+                .values()
+                .find_map(|p| index.missing_properties(p).first().copied())
+            {
+                return Err(crible_lib::index::Error::property_does_not_exist(
+                    property,
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Mutate for api::DeleteBits {
+    fn apply(&self, index: &mut Index) -> bool {
+        index.unset_all(&self.bits);
+        true
+    }
+
+    fn change(&self) -> Change {
+        Change::mutation("delete-bits", None, self.bits.clone())
+    }
+}
+
+impl Mutate for api::DeleteRange {
+    fn apply(&self, index: &mut Index) -> bool {
+        let range = self.start..self.end;
+        match &self.properties {
+            Some(properties) => index.unset_range_properties(range, properties),
+            None => index.unset_range(range),
+        }
+        true
+    }
+
+    fn change(&self) -> Change {
+        Change::range_mutation(
+            "delete-range",
+            self.properties.clone(),
+            self.start..self.end,
+        )
+    }
+}
+
+impl Mutate for api::Mutation {
+    fn apply(&self, index: &mut Index) -> bool {
+        match self {
+            api::Mutation::Set(op) => op.apply(index),
+            api::Mutation::SetMany(op) => op.apply(index),
+            api::Mutation::Unset(op) => op.apply(index),
+            api::Mutation::UnsetMany(op) => op.apply(index),
+            api::Mutation::SetBit(op) => op.apply(index),
+            api::Mutation::SetBits(op) => op.apply(index),
+            api::Mutation::DeleteBits(op) => op.apply(index),
+        }
+    }
+
+    fn change(&self) -> Change {
+        match self {
+            api::Mutation::Set(op) => op.change(),
+            api::Mutation::SetMany(op) => op.change(),
+            api::Mutation::Unset(op) => op.change(),
+            api::Mutation::UnsetMany(op) => op.change(),
+            api::Mutation::SetBit(op) => op.change(),
+            api::Mutation::SetBits(op) => op.change(),
+            api::Mutation::DeleteBits(op) => op.change(),
+        }
+    }
+
+    fn check(&self, index: &Index) -> OperationResult<()> {
+        match self {
+            api::Mutation::Set(op) => op.check(index),
+            api::Mutation::SetMany(op) => op.check(index),
+            api::Mutation::SetBit(op) => op.check(index),
+            api::Mutation::SetBits(op) => op.check(index),
+            _ => Ok(()),
+        }
+    }
 }
 
-impl Operation for SetBit {
+impl Operation for api::Set {
+    type Output = OperationResult<bool>;
+
+    const MUTATES: bool = true;
+
+    /// See `api::SetBit::run`.
+    #[inline]
+    fn run(self, index: &SharedIndex) -> OperationResult<bool> {
+        self.check(&index.read())?;
+        Ok(index.update(|idx| self.apply(idx)))
+    }
+}
+
+impl Operation for api::SetMany {
+    type Output = OperationResult<()>;
+
+    const MUTATES: bool = true;
+
+    /// See `api::SetBit::run`.
+    #[inline]
+    fn run(self, index: &SharedIndex) -> OperationResult<()> {
+        self.check(&index.read())?;
+        index.update(|idx| {
+            self.apply(idx);
+        });
+        Ok(())
+    }
+}
+
+/// Chunk of records applied by `POST /ingest`, same as `api::SetMany` but
+/// recorded as an ingestion in the change log.
+#[derive(Debug, Default)]
+pub struct Ingest(pub HashMap<String, Vec<u32>>);
+
+impl Mutate for Ingest {
+    fn apply(&self, index: &mut Index) -> bool {
+        for (property, bits) in &self.0 {
+            index.set_many(property, bits);
+        }
+        true
+    }
+
+    fn change(&self) -> Change {
+        many_change("ingest", &self.0)
+    }
+
+    fn check(&self, index: &Index) -> OperationResult<()> {
+        for bits in self.0.values() {
+            index.check_bits(bits)?;
+        }
+        Ok(())
+    }
+}
+
+impl Operation for Ingest {
+    type Output = OperationResult<()>;
+
+    const MUTATES: bool = true;
+
+    /// The whole chunk is rejected if any of its bits is out of range.
+    #[inline]
+    fn run(self, index: &SharedIndex) -> OperationResult<()> {
+        self.check(&index.read())?;
+        index.update(|idx| {
+            self.apply(idx);
+        });
+        Ok(())
+    }
+}
+
+impl Operation for api::Unset {
     type Output = bool;
 
+    const MUTATES: bool = true;
+
     #[inline]
-    fn run(self, index: &RwLock<Index>) -> Self::Output {
-        index.write().set_properties_with_bit(self.bit, &self.properties)
+    fn run(self, index: &SharedIndex) -> bool {
+        index.update(|idx| self.apply(idx))
     }
 }
 
-#[derive(Deserialize, Debug)]
-pub struct DeleteBits {
-    bits: Vec<u32>,
+impl Operation for api::UnsetMany {
+    type Output = ();
+
+    const MUTATES: bool = true;
+
+    #[inline]
+    fn run(self, index: &SharedIndex) {
+        index.update(|idx| {
+            self.apply(idx);
+        })
+    }
+}
+
+impl Operation for api::SetBit {
+    type Output = OperationResult<bool>;
+
+    const MUTATES: bool = true;
+
+    /// Writers are serialized so nothing can change the index between the
+    /// check and applying the mutation.
+    #[inline]
+    fn run(self, index: &SharedIndex) -> OperationResult<bool> {
+        self.check(&index.read())?;
+        Ok(index.update(|idx| self.apply(idx)))
+    }
+}
+
+impl Operation for api::SetBits {
+    type Output = OperationResult<bool>;
+
+    const MUTATES: bool = true;
+
+    /// See `api::SetBit::run`.
+    #[inline]
+    fn run(self, index: &SharedIndex) -> OperationResult<bool> {
+        self.check(&index.read())?;
+        Ok(index.update(|idx| self.apply(idx)))
+    }
 }
 
-impl Operation for DeleteBits {
+impl Operation for api::DeleteBits {
     type Output = ();
 
+    const MUTATES: bool = true;
+
+    #[inline]
+    fn run(self, index: &SharedIndex) {
+        index.update(|idx| {
+            self.apply(idx);
+        })
+    }
+}
+
+impl Operation for api::DeleteRange {
+    type Output = ();
+
+    const MUTATES: bool = true;
+
+    #[inline]
+    fn run(self, index: &SharedIndex) {
+        index.update(|idx| {
+            self.apply(idx);
+        })
+    }
+}
+
+/// Add the properties which applying `mutation` to `index` may modify to
+/// `properties`.
+fn touched(
+    mutation: &api::Mutation,
+    index: &Index,
+    properties: &mut HashSet<String>,
+) {
+    match mutation {
+        api::Mutation::Set(api::Set { property, .. })
+        | api::Mutation::Unset(api::Unset { property, .. }) => {
+            properties.insert(property.clone());
+        }
+        api::Mutation::SetMany(api::SetMany { values })
+        | api::Mutation::UnsetMany(api::UnsetMany { values }) => {
+            properties.extend(values.keys().cloned());
+        }
+        api::Mutation::SetBit(op) => {
+            properties.extend(index.get_properties_with_bit(op.bit));
+            properties.extend(op.properties.iter().cloned());
+        }
+        api::Mutation::SetBits(op) => {
+            let bits: Vec<u32> = op.bits.keys().copied().collect();
+            properties.extend(
+                index.get_properties_with_bits(&bits).into_values().flatten(),
+            );
+            properties.extend(op.bits.values().flatten().cloned());
+        }
+        api::Mutation::DeleteBits(op) => {
+            for bit in &op.bits {
+                properties.extend(index.get_properties_with_bit(*bit));
+            }
+        }
+    }
+}
+
+/// Server side execution of `api::Transaction`.
+#[derive(Deserialize, Debug)]
+#[serde(transparent)]
+pub struct Transaction(pub api::Transaction);
+
+impl Operation for Transaction {
+    type Output = OperationResult<bool>;
+
+    const MUTATES: bool = true;
+
+    /// Apply the transaction in memory only, see `Executor::transaction` to
+    /// also persist it atomically.
     #[inline]
-    fn run(self, index: &RwLock<Index>) {
-        index.write().unset_all(&self.bits);
+    fn run(self, index: &SharedIndex) -> OperationResult<bool> {
+        let mut idx = Index::clone(&index.read());
+        let changed = self.apply(&mut idx)?;
+        index.store(idx);
+        Ok(changed)
+    }
+}
+
+impl Transaction {
+    /// Properties which applying the transaction to `index` may modify.
+    /// Properties gaining bits during the transaction are always covered by
+    /// an earlier mutation so this only needs the initial state.
+    pub fn touched(&self, index: &Index) -> HashSet<String> {
+        let mut properties = HashSet::new();
+        for mutation in &self.0.operations {
+            touched(mutation, index, &mut properties);
+        }
+        properties
+    }
+
+    /// Mutations are checked against the state left by the previous ones,
+    /// `index` may be partially modified if one of them fails.
+    pub fn apply(&self, index: &mut Index) -> OperationResult<bool> {
+        self.0.operations.iter().try_fold(false, |changed, mutation| {
+            mutation.check(index)?;
+            Ok(mutation.apply(index) || changed)
+        })
+    }
+
+    pub fn changes(&self) -> Vec<Change> {
+        self.0.operations.iter().map(|mutation| mutation.change()).collect()
     }
 }
 
@@ -228,3 +1092,597 @@ impl Operation for DeleteBits {
 //     SetBit(SetBit),
 //     DeleteBits(DeleteBits),
 // }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap, HashSet};
+
+    use crible_api_types::{
+        self as api, BitmapStatsRequest, DistributionBucket,
+        DistributionRequest, Facet, FacetBucket, MissingProperties,
+        StatsRequest,
+    };
+    use crible_lib::Index;
+    use croaring::Bitmap;
+    use rstest::*;
+
+    use super::{
+        facets, Compare, FacetDelimiter, GetProperties, Operation,
+        OperationError, Query, Similarity, Stats, Sync, Transaction,
+    };
+    use crate::executor::SharedIndex;
+
+    #[test]
+    fn test_facets() {
+        let cardinalities = HashMap::from(
+            [("color:red", 3), ("color:blue", 5), ("size:m", 1), ("new", 2)]
+                .map(|(k, v)| (k.to_owned(), v)),
+        );
+        let bucket =
+            |value: &str, count| FacetBucket { value: value.to_owned(), count };
+        assert_eq!(
+            facets(&cardinalities, &FacetDelimiter::default()),
+            vec![
+                Facet {
+                    field: "color".to_owned(),
+                    buckets: vec![bucket("blue", 5), bucket("red", 3)],
+                },
+                Facet {
+                    field: "size".to_owned(),
+                    buckets: vec![bucket("m", 1)],
+                },
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case(None, None, vec![3, 4], None)]
+    #[case(Some(true), None, vec![3, 4], Some((2, Some(3), Some(4))))]
+    #[case(Some(true), Some(false), vec![], Some((2, Some(3), Some(4))))]
+    #[case(None, Some(false), vec![], None)]
+    fn test_query_stats(
+        #[case] include_stats: Option<bool>,
+        #[case] include_values: Option<bool>,
+        #[case] values: Vec<u32>,
+        #[case] expected: Option<(u64, Option<u32>, Option<u32>)>,
+    ) {
+        let index = SharedIndex::new(Index::of([
+            ("foo", vec![1, 2, 3]),
+            ("bar", vec![3, 4]),
+        ]));
+        let result = Query {
+            request: api::Query {
+                query: "bar".to_owned(),
+                include_cardinalities: None,
+                facets: None,
+                include_stats,
+                include_values,
+                ranks: None,
+                percentiles: None,
+            },
+            max_cost: None,
+            cancellation: Default::default(),
+            facet_delimiter: FacetDelimiter::default(),
+        }
+        .run(&index)
+        .unwrap();
+        assert_eq!(result.values, values);
+        assert_eq!(
+            result.stats.map(|s| (s.cardinality, s.minimum, s.maximum)),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_query_ranks_and_percentiles() {
+        let index = SharedIndex::new(Index::of([
+            ("foo", vec![10, 20, 30, 40]),
+            ("bar", vec![]),
+        ]));
+        let query = |query: &str| Query {
+            request: api::Query {
+                query: query.to_owned(),
+                include_cardinalities: None,
+                facets: None,
+                include_stats: None,
+                include_values: Some(false),
+                ranks: Some(vec![5, 20, 25]),
+                percentiles: Some(vec![50, 200]),
+            },
+            max_cost: None,
+            cancellation: Default::default(),
+            facet_delimiter: FacetDelimiter::default(),
+        };
+
+        let result = query("foo").run(&index).unwrap();
+        assert_eq!(
+            result.ranks,
+            Some(BTreeMap::from([(5, 0), (20, 2), (25, 2)]))
+        );
+        assert_eq!(
+            result.percentiles,
+            Some(BTreeMap::from([(50, 20), (100, 40)]))
+        );
+
+        let result = query("bar").run(&index).unwrap();
+        assert_eq!(
+            result.ranks,
+            Some(BTreeMap::from([(5, 0), (20, 0), (25, 0)]))
+        );
+        assert_eq!(result.percentiles, Some(BTreeMap::new()));
+    }
+
+    #[rstest]
+    #[case(None, Some(vec![("foo", vec![1, 2, 4]), ("bar", vec![3])]))]
+    #[case(
+        Some(MissingProperties::Create),
+        Some(vec![("foo", vec![1, 2, 4]), ("bar", vec![3]), ("qux", vec![4])])
+    )]
+    #[case(Some(MissingProperties::Reject), None)]
+    fn test_set_bits(
+        #[case] missing: Option<MissingProperties>,
+        #[case] expected: Option<Vec<(&str, Vec<u32>)>>,
+    ) {
+        let initial = Index::of([("foo", vec![1, 2, 3]), ("bar", vec![3, 4])]);
+        let index = SharedIndex::new(initial.clone());
+        let op = api::SetBits {
+            bits: HashMap::from([
+                (3, vec!["bar".to_owned()]),
+                (4, vec!["foo".to_owned(), "qux".to_owned()]),
+            ]),
+            missing,
+        };
+        assert_eq!(
+            Transaction(api::Transaction {
+                expected_version: None,
+                operations: vec![api::Mutation::SetBits(op.clone())],
+            })
+            .touched(&initial),
+            HashSet::from(["foo", "bar", "qux"].map(|x| x.to_owned()))
+        );
+
+        let result = op.run(&index);
+        match expected {
+            Some(expected) => {
+                assert!(result.unwrap());
+                assert!(*index.read() == Index::of(expected));
+            }
+            None => {
+                assert!(result.is_err());
+                assert!(*index.read() == initial);
+            }
+        }
+    }
+
+    #[test]
+    fn test_get_properties() {
+        let index = SharedIndex::new(Index::of([
+            ("foo", vec![1, 2, 3]),
+            ("bar", vec![3, 4]),
+        ]));
+        let request = |serialized, max_cost| GetProperties {
+            request: api::GetProperties {
+                properties: vec!["foo".to_owned(), "qux".to_owned()],
+                serialized,
+            },
+            max_cost,
+        };
+
+        let result = request(None, None).run(&index).unwrap();
+        assert_eq!(
+            result.values,
+            HashMap::from([("foo".to_owned(), vec![1, 2, 3])])
+        );
+        assert!(result.bitmaps.is_empty());
+
+        let result = request(Some(true), None).run(&index).unwrap();
+        assert!(result.values.is_empty());
+        let bytes = base64::decode(&result.bitmaps["foo"]).unwrap();
+        assert_eq!(Bitmap::deserialize(&bytes).to_vec(), vec![1, 2, 3]);
+
+        assert!(matches!(
+            request(None, Some(2)).run(&index),
+            Err(OperationError::TooExpensive { cost: 3, max_cost: 2 })
+        ));
+    }
+
+    #[test]
+    fn test_universe() {
+        let mut idx = Index::of([("foo", vec![1])]);
+        idx.set_universe(Some(10)).unwrap();
+        let index = SharedIndex::new(idx);
+
+        assert!(
+            api::Set { property: "foo".to_owned(), bit: 9 }
+                .run(&index)
+                .unwrap()
+        );
+        assert!(matches!(
+            api::Set { property: "foo".to_owned(), bit: 10 }.run(&index),
+            Err(OperationError::Index(
+                crible_lib::index::Error::BitOutOfRange {
+                    bit: 10,
+                    universe: 10
+                }
+            ))
+        ));
+        assert!(
+            api::SetMany {
+                values: HashMap::from([("bar".to_owned(), vec![2, 3, 12])]),
+            }
+            .run(&index)
+            .is_err()
+        );
+        assert!(index.read().get_property("bar").is_none());
+        assert_eq!(
+            index.read().get_property("foo").unwrap().to_vec(),
+            vec![1, 9]
+        );
+    }
+
+    #[test]
+    fn test_transaction() {
+        let mut index = Index::of([
+            ("foo", vec![1, 2, 3]),
+            ("bar", vec![3, 4]),
+            ("baz", vec![5]),
+        ]);
+
+        let transaction: Transaction = serde_json::from_str(
+            r#"{
+                "expected_version": 3,
+                "operations": [
+                    {"type": "set", "property": "qux", "bit": 1},
+                    {"type": "set-bit", "bit": 4, "properties": ["baz"]},
+                    {"type": "delete-bits", "bits": [3]}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(transaction.0.expected_version, Some(3));
+        assert_eq!(
+            transaction.touched(&index),
+            HashSet::from(["foo", "bar", "baz", "qux"].map(|x| x.to_owned()))
+        );
+
+        assert!(transaction.apply(&mut index).unwrap());
+        assert_eq!(index.get_property("foo").unwrap().to_vec(), vec![1, 2]);
+        assert!(index.get_property("bar").unwrap().is_empty());
+        assert_eq!(index.get_property("baz").unwrap().to_vec(), vec![4]);
+        assert_eq!(index.get_property("qux").unwrap().to_vec(), vec![1]);
+    }
+
+    #[rstest]
+    #[case(None, Some(vec![("foo", vec![1, 2, 3, 4]), ("bar", vec![3])]))]
+    #[case(
+        Some(MissingProperties::Ignore),
+        Some(vec![("foo", vec![1, 2, 3, 4]), ("bar", vec![3])])
+    )]
+    #[case(
+        Some(MissingProperties::Create),
+        Some(vec![
+            ("foo", vec![1, 2, 3, 4]),
+            ("bar", vec![3]),
+            ("qux", vec![4]),
+        ])
+    )]
+    #[case(Some(MissingProperties::Reject), None)]
+    fn test_set_bit_missing_properties(
+        #[case] missing: Option<MissingProperties>,
+        #[case] expected: Option<Vec<(&str, Vec<u32>)>>,
+    ) {
+        let initial = Index::of([("foo", vec![1, 2, 3]), ("bar", vec![3, 4])]);
+        let index = SharedIndex::new(initial.clone());
+        let result = api::SetBit {
+            bit: 4,
+            properties: vec!["foo".to_owned(), "qux".to_owned()],
+            missing,
+        }
+        .run(&index);
+
+        match expected {
+            Some(expected) => {
+                assert!(result.unwrap());
+                assert!(*index.read() == Index::of(expected));
+            }
+            None => {
+                assert!(matches!(
+                    result,
+                    Err(OperationError::Index(
+                        crible_lib::index::Error::PropertyDoesNotExist { .. }
+                    ))
+                ));
+                assert!(*index.read() == initial);
+            }
+        }
+    }
+
+    #[rstest]
+    #[case(None, None, vec!["bar", "baz", "foo"])]
+    #[case(Some("ba"), None, vec!["bar", "baz"])]
+    #[case(None, Some("*a*"), vec!["bar", "baz"])]
+    #[case(None, Some("*z"), vec!["baz"])]
+    #[case(Some("f"), Some("*z"), vec![])]
+    fn test_list_properties(
+        #[case] prefix: Option<&str>,
+        #[case] pattern: Option<&str>,
+        #[case] expected: Vec<&str>,
+        #[values(false, true)] include_stats: bool,
+    ) {
+        let index = SharedIndex::new(Index::of([
+            ("foo", vec![1, 2, 3]),
+            ("bar", vec![3, 4]),
+            ("baz", vec![5]),
+        ]));
+        let result = api::ListProperties {
+            prefix: prefix.map(|p| p.to_owned()),
+            pattern: pattern.map(|p| p.to_owned()),
+            include_stats: Some(include_stats),
+            include_metadata: None,
+        }
+        .run(&index);
+
+        assert_eq!(result.properties, expected);
+        match result.stats {
+            Some(stats) => {
+                assert!(include_stats);
+                let mut keys: Vec<_> = stats.keys().collect();
+                keys.sort();
+                assert_eq!(keys, expected.iter().collect::<Vec<_>>());
+            }
+            None => assert!(!include_stats),
+        }
+    }
+
+    #[rstest]
+    #[case(StatsRequest::default(), true, vec!["bar", "baz", "foo"])]
+    #[case(
+        StatsRequest { prefix: Some("ba".to_owned()), ..Default::default() },
+        true,
+        vec!["bar", "baz"]
+    )]
+    #[case(
+        StatsRequest {
+            prefix: Some("ba".to_owned()),
+            properties: Some(vec!["foo".to_owned(), "bar".to_owned()]),
+            ..Default::default()
+        },
+        true,
+        vec!["bar"]
+    )]
+    #[case(
+        StatsRequest {
+            properties: Some(vec!["foo".to_owned(), "qux".to_owned()]),
+            include_root: Some(false),
+            ..Default::default()
+        },
+        false,
+        vec!["foo"]
+    )]
+    #[case(
+        StatsRequest { include_properties: Some(false), ..Default::default() },
+        true,
+        vec![]
+    )]
+    fn test_stats(
+        #[case] request: StatsRequest,
+        #[case] root: bool,
+        #[case] expected: Vec<&str>,
+    ) {
+        let index = SharedIndex::new(Index::of([
+            ("foo", vec![1, 2, 3]),
+            ("bar", vec![3, 4]),
+            ("baz", vec![5]),
+        ]));
+        let result = Stats { request }.run(&index);
+        assert_eq!(result.root.map(|s| s.cardinality), root.then_some(5));
+        let mut properties =
+            result.properties.keys().map(|k| k.as_str()).collect::<Vec<_>>();
+        properties.sort_unstable();
+        assert_eq!(properties, expected);
+    }
+
+    #[test]
+    fn test_container_stats() {
+        let index = SharedIndex::new(Index::of([
+            ("foo", vec![1, 2, 3]),
+            ("bar", vec![3, 1 << 16]),
+            ("baz", vec![5]),
+        ]));
+
+        let result = Stats {
+            request: StatsRequest {
+                include_containers: Some(true),
+                ..Default::default()
+            },
+        }
+        .run(&index);
+        assert!(result.root.unwrap().containers.is_none());
+        assert_eq!(
+            result.properties["bar"]
+                .containers
+                .as_ref()
+                .unwrap()
+                .array_containers,
+            2
+        );
+        let result = Stats { request: StatsRequest::default() }.run(&index);
+        assert!(result.properties["bar"].containers.is_none());
+
+        let result =
+            BitmapStatsRequest { prefix: Some("ba".to_owned()) }.run(&index);
+        let mut properties =
+            result.properties.keys().map(|k| k.as_str()).collect::<Vec<_>>();
+        properties.sort_unstable();
+        assert_eq!(properties, vec!["bar", "baz"]);
+        assert_eq!(result.total.array_containers, 3);
+        assert_eq!(
+            result.total.array_bytes,
+            result.properties["bar"].array_bytes
+                + result.properties["baz"].array_bytes
+        );
+    }
+
+    fn compare(
+        left: &str,
+        right: &str,
+        sample: Option<usize>,
+    ) -> Result<api::CompareResult, OperationError> {
+        let index = SharedIndex::new(Index::of([
+            ("foo", vec![1, 2, 3]),
+            ("bar", vec![2, 3, 4, 5]),
+            ("baz", vec![6, 7]),
+        ]));
+        Compare {
+            request: api::Compare {
+                left: left.to_owned(),
+                right: right.to_owned(),
+                sample,
+            },
+            max_cost: None,
+            cancellation: Default::default(),
+        }
+        .run(&index)
+    }
+
+    #[rstest]
+    #[case("foo", "bar", (2, 1, 2))]
+    #[case("foo", "baz", (0, 3, 2))]
+    #[case("foo", "foo or baz", (3, 0, 2))]
+    #[case("foo", "bar - bar", (0, 3, 0))]
+    fn test_compare(
+        #[case] left: &str,
+        #[case] right: &str,
+        #[case] expected: (u64, u64, u64),
+    ) {
+        let result = compare(left, right, None).unwrap();
+        assert_eq!(
+            (
+                result.intersection.count,
+                result.only_left.count,
+                result.only_right.count
+            ),
+            expected
+        );
+        assert_eq!(result.intersection.sample, None);
+    }
+
+    #[test]
+    fn test_compare_sample() {
+        let result = compare("foo", "bar", Some(1)).unwrap();
+        assert_eq!(result.intersection.sample, Some(vec![2]));
+        assert_eq!(result.only_left.sample, Some(vec![1]));
+        assert_eq!(result.only_right.sample, Some(vec![4]));
+    }
+
+    #[rstest]
+    #[case("foo", "missing")]
+    #[case("missing", "foo")]
+    fn test_compare_missing_property(#[case] left: &str, #[case] right: &str) {
+        assert!(matches!(
+            compare(left, right, None),
+            Err(OperationError::Index(
+                crible_lib::index::Error::PropertyDoesNotExist { .. }
+            ))
+        ));
+    }
+
+    #[rstest]
+    #[case("foo", "bar", 2, 0.4, 2.0 / 3.0, 2.0 / 3.0, 0.5)]
+    #[case("foo", "foo", 3, 1.0, 1.0, 1.0, 1.0)]
+    #[case("foo", "baz", 0, 0.0, 0.0, 0.0, 0.0)]
+    #[case("baz", "baz - baz", 0, 0.0, 0.0, 0.0, 0.0)]
+    fn test_similarity(
+        #[case] left: &str,
+        #[case] right: &str,
+        #[case] intersection: u64,
+        #[case] jaccard: f64,
+        #[case] overlap: f64,
+        #[case] containment_left: f64,
+        #[case] containment_right: f64,
+    ) {
+        let index = SharedIndex::new(Index::of([
+            ("foo", vec![1, 2, 3]),
+            ("bar", vec![2, 3, 4, 5]),
+            ("baz", vec![]),
+        ]));
+        let result = Similarity {
+            request: api::Similarity {
+                left: left.to_owned(),
+                right: right.to_owned(),
+            },
+            max_cost: None,
+            cancellation: Default::default(),
+        }
+        .run(&index)
+        .unwrap();
+        assert_eq!(result.intersection, intersection);
+        assert_eq!(result.jaccard, jaccard);
+        assert_eq!(result.overlap, overlap);
+        assert_eq!(result.containment_left, containment_left);
+        assert_eq!(result.containment_right, containment_right);
+    }
+
+    #[test]
+    fn test_sync() {
+        let index = SharedIndex::new(Index::of([
+            ("foo", vec![1, 2, 3]),
+            ("bar", vec![2, 3, 4, 5]),
+        ]));
+        let result = Sync {
+            log: "log".to_owned(),
+            generation: 3,
+            modified: Some(HashSet::from(["foo".to_owned(), "baz".to_owned()])),
+        }
+        .run(&index);
+        assert!(!result.reload);
+        assert_eq!(result.generation, 3);
+        assert_eq!(result.deleted, vec!["baz".to_owned()]);
+        assert_eq!(
+            Bitmap::deserialize(
+                &base64::decode(&result.bitmaps["foo"]).unwrap()
+            ),
+            Bitmap::of(&[1, 2, 3])
+        );
+        assert_eq!(result.bitmaps.len(), 1);
+
+        let result =
+            Sync { log: "log".to_owned(), generation: 4, modified: None }
+                .run(&index);
+        assert!(result.reload);
+        assert!(result.bitmaps.is_empty() && result.deleted.is_empty());
+    }
+
+    #[test]
+    fn test_distribution() {
+        let index = SharedIndex::new(Index::of([
+            ("foo", vec![1, 2, 3]),
+            ("bar", vec![3, 1 << 16]),
+            ("baz", vec![5]),
+        ]));
+
+        let result =
+            DistributionRequest { prefix: None, buckets: Some(vec![2, 1]) }
+                .run(&index);
+        assert_eq!(result.properties, 3);
+        assert_eq!(result.maximum, 3);
+        assert_eq!(
+            result.buckets,
+            vec![
+                DistributionBucket { le: Some(1), count: 1 },
+                DistributionBucket { le: Some(2), count: 1 },
+                DistributionBucket { le: None, count: 1 },
+            ]
+        );
+
+        let result = DistributionRequest {
+            prefix: Some("ba".to_owned()),
+            ..Default::default()
+        }
+        .run(&index);
+        assert_eq!(result.properties, 2);
+        assert_eq!(result.buckets.len(), 11);
+        assert_eq!(result.buckets[1].count, 2);
+    }
+}
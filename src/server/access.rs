@@ -0,0 +1,245 @@
+use std::convert::Infallible;
+
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use crible_lib::Expression;
+
+use super::auth::Identity;
+use super::errors::APIError;
+use crate::changes::Change;
+
+/// Properties the caller can read and write, based on the prefix rules of
+/// their token, see `Identity::can_access`. Every property is accessible
+/// when authentication is disabled or the token has no rules.
+///
+/// Operations which depend on every property, e.g. queries using `*` or
+/// `not` or deleting bits from every property, are rejected for restricted
+/// callers. Results listing properties are filtered instead.
+#[derive(Debug, Clone, Default)]
+pub struct PropertyAccess(Option<Identity>);
+
+impl PropertyAccess {
+    pub fn new(identity: Option<&Identity>) -> Self {
+        Self(identity.filter(|i| i.is_restricted()).cloned())
+    }
+
+    pub fn is_restricted(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub fn can_access(&self, property: &str) -> bool {
+        self.0.as_ref().map_or(true, |i| i.can_access(property))
+    }
+
+    /// Whether every property starting with `prefix` is accessible.
+    pub fn can_access_prefix(&self, prefix: &str) -> bool {
+        self.0.as_ref().map_or(true, |i| i.can_access_prefix(prefix))
+    }
+
+    pub fn check<'a, I>(&self, properties: I) -> Result<(), APIError>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        match properties.into_iter().find(|p| !self.can_access(p)) {
+            Some(p) => Err(APIError::PropertyForbidden(Some(p.to_owned()))),
+            None => Ok(()),
+        }
+    }
+
+    /// Check an operation affecting every property.
+    pub fn check_all(&self) -> Result<(), APIError> {
+        if self.is_restricted() {
+            Err(APIError::PropertyForbidden(None))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check the properties a query depends on. Invalid queries are let
+    /// through so that they fail the same way as for other callers when
+    /// run.
+    pub fn check_query(&self, query: &str) -> Result<(), APIError> {
+        if !self.is_restricted() {
+            return Ok(());
+        }
+        match Expression::parse(query) {
+            Ok(expr) if expr.uses_root() => self.check_all(),
+            Ok(expr) => {
+                self.check(expr.properties())?;
+                match expr
+                    .prefixes()
+                    .into_iter()
+                    .find(|p| !self.can_access_prefix(p))
+                {
+                    Some(p) => {
+                        Err(APIError::PropertyForbidden(Some(p.to_owned())))
+                    }
+                    None => Ok(()),
+                }
+            }
+            Err(_) => Ok(()),
+        }
+    }
+
+    pub fn check_change(&self, change: &Change) -> Result<(), APIError> {
+        match change {
+            Change::Mutation { properties: Some(properties), .. } => {
+                self.check(properties.iter().map(|p| p.as_str()))
+            }
+            _ => self.check_all(),
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for PropertyAccess
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self::new(parts.extensions.get::<Identity>()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use crible_lib::Index;
+    use parking_lot::Mutex;
+    use rstest::*;
+    use tower::ServiceExt;
+
+    use super::PropertyAccess;
+    use crate::backends::{Backend, Memory};
+    use crate::changes::Change;
+    use crate::executor::{ExecutorBuilder, SharedIndex};
+    use crate::server::auth::{Auth, AuthOptions, Identity, Permission};
+    use crate::server::{router, Options, State};
+
+    fn access() -> PropertyAccess {
+        PropertyAccess::new(Some(&Identity {
+            subject: None,
+            permission: Permission::Write,
+            prefixes: Some(vec!["tenantA:".to_owned()]),
+            denied_prefixes: vec!["tenantA:secret".to_owned()],
+            max_query_cost: None,
+        }))
+    }
+
+    #[rstest]
+    #[case("tenantA:foo or tenantA:bar", true)]
+    #[case("tenantA:foo - tenantB:foo", false)]
+    #[case("tenantA:foo and tenantA:secret", false)]
+    #[case("*", false)]
+    #[case("tenantA:foo and not tenantA:bar", false)]
+    #[case("tenantA:foo and (", true)]
+    #[case("tenantA:foo and any(tenantA:tag:)", true)]
+    #[case("tenantA:foo and any(tenantA:)", false)]
+    #[case("tenantA:foo and all(tenant)", false)]
+    fn test_check_query(#[case] query: &str, #[case] allowed: bool) {
+        assert_eq!(access().check_query(query).is_ok(), allowed);
+        assert!(PropertyAccess::default().check_query(query).is_ok());
+    }
+
+    #[rstest]
+    #[case(
+        Change::mutation("set", Some(vec!["tenantA:foo".to_owned()]), vec![1]),
+        true
+    )]
+    #[case(
+        Change::mutation("set", Some(vec!["tenantB:foo".to_owned()]), vec![1]),
+        false
+    )]
+    #[case(Change::mutation("delete-bits", None, vec![1]), false)]
+    #[case(Change::Reload, false)]
+    fn test_check_change(#[case] change: Change, #[case] allowed: bool) {
+        assert_eq!(access().check_change(&change).is_ok(), allowed);
+        assert!(PropertyAccess::default().check_change(&change).is_ok());
+    }
+
+    #[rstest]
+    #[case("/count", r#"{"query": "tenantA:foo"}"#, StatusCode::OK)]
+    #[case("/count", r#"{"query": "tenantB:foo"}"#, StatusCode::FORBIDDEN)]
+    #[case("/count", r#"{"query": "not tenantA:foo"}"#, StatusCode::FORBIDDEN)]
+    #[case(
+        "/query",
+        r#"{"query": "tenantA:foo", "include_cardinalities": true}"#,
+        StatusCode::OK
+    )]
+    #[case("/set", r#"{"property": "tenantA:foo", "bit": 9}"#, StatusCode::OK)]
+    #[case(
+        "/set",
+        r#"{"property": "tenantB:foo", "bit": 9}"#,
+        StatusCode::FORBIDDEN
+    )]
+    #[case("/delete-bits", r#"{"bits": [1]}"#, StatusCode::FORBIDDEN)]
+    #[case("/stats/distribution", r#"{"prefix": "tenantA:"}"#, StatusCode::OK)]
+    #[case("/stats/distribution", "{}", StatusCode::FORBIDDEN)]
+    #[tokio::test]
+    async fn test_restricted_token(
+        #[case] path: &str,
+        #[case] body: &'static str,
+        #[case] expected: StatusCode,
+    ) {
+        let backend: Box<dyn Backend> = Box::new(Memory::default());
+        let state = State::new(
+            ExecutorBuilder::new(
+                Arc::new(SharedIndex::new(Index::of([
+                    ("tenantA:foo", vec![1, 2]),
+                    ("tenantB:foo", vec![1, 3]),
+                ]))),
+                Arc::new(Mutex::new(backend)),
+            )
+            .pool_size(1)
+            .build()
+            .unwrap(),
+        );
+        let auth = Auth::new(&AuthOptions {
+            secret: Some("secret".to_owned()),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        let options =
+            Options { auth: Some(Arc::new(auth)), ..Default::default() };
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &serde_json::json!({
+                "sub": "tenantA",
+                "scope": "write",
+                "prefixes": ["tenantA:"],
+                "exp": 4_102_444_800u64,
+            }),
+            &jsonwebtoken::EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+
+        let response = router(state, &options)
+            .oneshot(
+                Request::post(path)
+                    .header("content-type", "application/json")
+                    .header("authorization", format!("Bearer {}", token))
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), expected);
+        if expected == StatusCode::OK {
+            let body =
+                hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert!(!String::from_utf8_lossy(&body).contains("tenantB"));
+        }
+    }
+}
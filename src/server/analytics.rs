@@ -0,0 +1,263 @@
+use std::convert::Infallible;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use crible_lib::expression::Expression;
+use eyre::Context;
+use parking_lot::Mutex;
+use serde_derive::Serialize;
+
+use super::auth::Identity;
+
+#[derive(Serialize, Debug)]
+struct Entry<'a> {
+    timestamp: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<&'a str>,
+    request_id: Option<&'a str>,
+    /// Subject of the authenticated identity, if any.
+    subject: Option<&'a str>,
+    operation: &'a str,
+    /// Canonical representation of the query so that equivalent queries can
+    /// be grouped, see `Expression::serialize`.
+    query: String,
+    /// Time spent handling the query, including time spent queued.
+    duration_us: u64,
+    /// Number of matching elements.
+    cardinality: u64,
+}
+
+struct Current {
+    file: File,
+    size: u64,
+}
+
+/// Log of a sample of the queries run against the server, stored as JSON
+/// lines, to find out which queries are worth optimizing. Once the file
+/// reaches `max_size` it is moved to `<path>.1`, replacing the previous one.
+pub struct AnalyticsLog {
+    path: PathBuf,
+    max_size: u64,
+    sample_rate: f64,
+    seen: AtomicU64,
+    current: Mutex<Current>,
+}
+
+impl std::fmt::Debug for AnalyticsLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AnalyticsLog")
+            .field("path", &self.path)
+            .field("max_size", &self.max_size)
+            .field("sample_rate", &self.sample_rate)
+            .finish()
+    }
+}
+
+fn open(path: &Path) -> eyre::Result<Current> {
+    let file =
+        OpenOptions::new().create(true).append(true).open(path).wrap_err_with(
+            || format!("Failed to open analytics log `{}`", path.display()),
+        )?;
+    let size = file.metadata()?.len();
+    Ok(Current { file, size })
+}
+
+impl AnalyticsLog {
+    /// `sample_rate` is the fraction of queries recorded, between 0 and 1.
+    pub fn open(
+        path: &Path,
+        max_size: u64,
+        sample_rate: f64,
+    ) -> eyre::Result<Self> {
+        if !(sample_rate > 0.0 && sample_rate <= 1.0) {
+            return Err(eyre::Report::msg(format!(
+                "Invalid sample rate {}, expected a value in (0, 1]",
+                sample_rate
+            )));
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            max_size,
+            sample_rate,
+            seen: AtomicU64::new(0),
+            current: Mutex::new(open(path)?),
+        })
+    }
+
+    /// Whether the next query should be recorded. Queries are sampled at
+    /// regular intervals rather than randomly, e.g. one in four for 0.25.
+    fn sample(&self) -> bool {
+        let n = self.seen.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.sample_rate).floor() > (n * self.sample_rate).floor()
+    }
+
+    fn rotated_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".1");
+        path.into()
+    }
+
+    fn append(&self, entry: &Entry) -> eyre::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let mut current = self.current.lock();
+        if current.size > 0 && current.size + line.len() as u64 > self.max_size
+        {
+            std::fs::rename(&self.path, self.rotated_path())?;
+            *current = open(&self.path)?;
+        }
+        // Single write so that lines are never interleaved.
+        current.file.write_all(&line)?;
+        current.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Analytics log of the index served by a set of routes.
+#[derive(Clone)]
+pub struct Analyst {
+    pub log: Arc<AnalyticsLog>,
+    pub tenant: Option<String>,
+}
+
+/// Records the query run while handling a request if it was sampled. Does
+/// nothing when the analytics log is disabled.
+pub struct Analytics {
+    analyst: Option<Analyst>,
+    request_id: Option<String>,
+    subject: Option<String>,
+}
+
+impl Analytics {
+    pub fn record(
+        &self,
+        operation: &str,
+        query: &str,
+        duration: Duration,
+        cardinality: u64,
+    ) {
+        let analyst = match &self.analyst {
+            Some(analyst) => analyst,
+            None => return,
+        };
+
+        let entry = Entry {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now())
+                .to_string(),
+            tenant: analyst.tenant.as_deref(),
+            request_id: self.request_id.as_deref(),
+            subject: self.subject.as_deref(),
+            operation,
+            query: Expression::parse(query)
+                .map_or_else(|_| query.to_owned(), |e| e.serialize()),
+            duration_us: duration.as_micros() as u64,
+            cardinality,
+        };
+        if let Err(e) = analyst.log.append(&entry) {
+            tracing::warn!("Failed to write analytics log entry: {:?}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Analytics
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self {
+            analyst: parts
+                .extensions
+                .get::<Analyst>()
+                .filter(|analyst| analyst.log.sample())
+                .cloned(),
+            request_id: parts
+                .headers
+                .get("x-request-id")
+                .and_then(|hv| hv.to_str().ok())
+                .map(|hv| hv.to_owned()),
+            subject: parts
+                .extensions
+                .get::<Identity>()
+                .and_then(|i| i.subject.clone()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rstest::*;
+
+    use super::{Analyst, Analytics, AnalyticsLog};
+
+    fn log(max_size: u64, sample_rate: f64) -> AnalyticsLog {
+        let dir = std::env::temp_dir().join(ulid::Ulid::new().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        AnalyticsLog::open(&dir.join("analytics.log"), max_size, sample_rate)
+            .unwrap()
+    }
+
+    #[rstest]
+    #[case(1.0, 8)]
+    #[case(0.5, 4)]
+    #[case(0.25, 2)]
+    #[case(0.1, 0)]
+    fn test_sample(#[case] sample_rate: f64, #[case] expected: usize) {
+        let log = log(1024, sample_rate);
+        assert_eq!((0..8).filter(|_| log.sample()).count(), expected);
+    }
+
+    #[rstest]
+    #[case(0.0)]
+    #[case(1.5)]
+    #[case(f64::NAN)]
+    fn test_invalid_sample_rate(#[case] sample_rate: f64) {
+        let path = std::env::temp_dir().join(ulid::Ulid::new().to_string());
+        assert!(AnalyticsLog::open(&path, 1024, sample_rate).is_err());
+    }
+
+    #[test]
+    fn test_record_and_rotate() {
+        let analytics = Analytics {
+            analyst: Some(Analyst {
+                log: std::sync::Arc::new(log(200, 1.0)),
+                tenant: None,
+            }),
+            request_id: Some("1".to_owned()),
+            subject: None,
+        };
+        let log = &analytics.analyst.as_ref().unwrap().log;
+
+        analytics.record("query", "(foo)  or bar", Duration::from_millis(2), 3);
+        let content = std::fs::read_to_string(&log.path).unwrap();
+        let entry: serde_json::Value = serde_json::from_str(&content).unwrap();
+        assert_eq!(entry["query"], "(foo or bar)");
+        assert_eq!(entry["duration_us"], 2000);
+        assert_eq!(entry["cardinality"], 3);
+
+        analytics.record("count", "foo", Duration::from_millis(1), 1);
+        assert_eq!(
+            std::fs::read_to_string(log.rotated_path()).unwrap(),
+            content
+        );
+        assert_eq!(
+            std::fs::read_to_string(&log.path).unwrap().lines().count(),
+            1
+        );
+    }
+}
@@ -1,16 +1,64 @@
-use axum::extract::State as ExtractState;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+
+use axum::extract::{Path, Query, State as ExtractState};
 use axum::http::StatusCode;
-use axum::response::IntoResponse;
-use axum::Json;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use crible_api_types as api;
+use serde_json::json;
 
+use super::access::PropertyAccess;
+use super::analytics::Analytics;
+#[cfg(feature = "arrow")]
+use super::arrow::AcceptArrow;
+use super::audit::Audit;
+use super::cost::QueryCostLimit;
 use super::errors::APIError;
+use super::timeout::RequestCancellation;
+use super::version::{IfIndexVersion, Versioned};
 use super::State;
-use crate::operations::{self, Operation};
+use crate::changes;
+use crate::operations::{self, FacetDelimiter, Mutate, Operation};
 
 pub async fn handler_home() -> impl IntoResponse {
     format!("Crible Server {}", env!("CARGO_PKG_VERSION"))
 }
 
+/// Liveness probe, only checks that the process is up and serving requests.
+pub async fn handler_healthz() -> impl IntoResponse {
+    (StatusCode::OK, "OK")
+}
+
+/// Readiness probe. The server only starts listening once the initial index
+/// load has completed, so this checks that the backend is reachable and that
+/// the executor is still accepting work on all of its lanes and that writes
+/// are being persisted.
+pub async fn handler_readyz(
+    ExtractState(state): ExtractState<State>,
+) -> impl IntoResponse {
+    let backend = match state.0.ping_backend().await {
+        Ok(()) => None,
+        Err(e) => {
+            tracing::warn!("Backend readiness check failed: {:?}", e);
+            Some(e.to_string())
+        }
+    };
+    let executor = state.0.is_accepting_work();
+    let flushed = !state.0.is_flush_failing();
+    let ready = backend.is_none() && executor && flushed;
+    let backend = backend.map_or(json!(true), |e| json!({ "error": e }));
+
+    (
+        if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE },
+        Json(api::Readiness {
+            ready,
+            checks: api::ReadinessChecks { backend, executor, flushed },
+        }),
+    )
+}
+
 pub async fn handler_not_found() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, "Not found.")
 }
@@ -18,131 +66,587 @@ pub async fn handler_not_found() -> impl IntoResponse {
 pub type APIResult<T> = Result<(StatusCode, T), APIError>;
 pub type JSONAPIResult<T> = Result<(StatusCode, Json<T>), APIError>;
 pub type StaticAPIResult = APIResult<&'static str>;
+pub type VersionedAPIResult<T> = Result<Versioned<(StatusCode, T)>, APIError>;
 
+/// Run a query. With the `arrow` feature, results are returned in the Arrow
+/// IPC streaming format when requested through the `Accept` header.
 pub async fn handler_query(
     ExtractState(state): ExtractState<State>,
-    Json(payload): Json<operations::Query>,
-) -> JSONAPIResult<operations::QueryResult> {
+    QueryCostLimit(max_cost): QueryCostLimit,
+    RequestCancellation(cancellation): RequestCancellation,
+    Extension(facet_delimiter): Extension<FacetDelimiter>,
+    access: PropertyAccess,
+    analytics: Analytics,
+    #[cfg(feature = "arrow")] AcceptArrow(arrow): AcceptArrow,
+    Json(request): Json<api::Query>,
+) -> Result<Response, APIError> {
+    access.check_query(&request.query)?;
+    let start = Instant::now();
+    let query = request.query.clone();
+    // Only the values are part of Arrow responses.
+    #[cfg(feature = "arrow")]
+    let request = if arrow {
+        api::Query {
+            include_cardinalities: None,
+            facets: None,
+            include_stats: None,
+            include_values: None,
+            ranks: None,
+            percentiles: None,
+            ..request
+        }
+    } else {
+        request
+    };
+    let delimiter = facet_delimiter.0.clone();
+    let payload =
+        operations::Query { request, max_cost, cancellation, facet_delimiter };
+    let mut result =
+        state.0.spawn(move |index| payload.run(index.as_ref())).await??;
+    if access.is_restricted() {
+        filter_query_result(&mut result, &access, &delimiter);
+    }
+    analytics.record(
+        "query",
+        &query,
+        start.elapsed(),
+        result.values.len() as u64,
+    );
+    #[cfg(feature = "arrow")]
+    if arrow {
+        return super::arrow::query_response(result);
+    }
+    Ok((StatusCode::OK, Json(result)).into_response())
+}
+
+/// Only keep the cardinalities and facet buckets of properties the caller
+/// can access.
+fn filter_query_result(
+    result: &mut api::QueryResult,
+    access: &PropertyAccess,
+    delimiter: &str,
+) {
+    if let Some(cardinalities) = &mut result.cardinalities {
+        cardinalities.retain(|k, _| access.can_access(k));
+    }
+    if let Some(facets) = &mut result.facets {
+        for facet in facets.iter_mut() {
+            let field = format!("{}{}", facet.field, delimiter);
+            facet.buckets.retain(|b| {
+                access.can_access(&format!("{}{}", field, b.value))
+            });
+        }
+        facets.retain(|f| !f.buckets.is_empty());
+    }
+}
+
+/// Count elements matching a query.
+pub async fn handler_count(
+    ExtractState(state): ExtractState<State>,
+    QueryCostLimit(max_cost): QueryCostLimit,
+    RequestCancellation(cancellation): RequestCancellation,
+    access: PropertyAccess,
+    analytics: Analytics,
+    Json(request): Json<api::Count>,
+) -> JSONAPIResult<u64> {
+    access.check_query(&request.query)?;
+    let start = Instant::now();
+    let query = request.query.clone();
+    let payload = operations::Count { request, max_cost, cancellation };
+    let count =
+        state.0.spawn(move |index| payload.run(index.as_ref())).await??;
+    analytics.record("count", &query, start.elapsed(), count);
+    Ok((StatusCode::OK, Json(count)))
+}
+
+/// Count elements matching several queries against the same version of the
+/// index, see `api::CountMany`.
+pub async fn handler_count_many(
+    ExtractState(state): ExtractState<State>,
+    QueryCostLimit(max_cost): QueryCostLimit,
+    RequestCancellation(cancellation): RequestCancellation,
+    access: PropertyAccess,
+    Json(request): Json<api::CountMany>,
+) -> JSONAPIResult<api::CountManyResult> {
+    for query in request.queries.values() {
+        access.check_query(query)?;
+    }
+    let payload = operations::CountMany { request, max_cost, cancellation };
+    let counts =
+        state.0.spawn(move |index| payload.run(index.as_ref())).await??;
+    let counts = counts.map(|count| match count {
+        Ok(count) => api::CountOutcome::Count(count),
+        Err(e) => {
+            api::CountOutcome::Error(APIError::from(e).status_and_body().1)
+        }
+    });
+    Ok((StatusCode::OK, Json(api::CountManyResult { counts })))
+}
+
+/// Compare the results of two queries.
+pub async fn handler_compare(
+    ExtractState(state): ExtractState<State>,
+    QueryCostLimit(max_cost): QueryCostLimit,
+    RequestCancellation(cancellation): RequestCancellation,
+    access: PropertyAccess,
+    Json(request): Json<api::Compare>,
+) -> JSONAPIResult<api::CompareResult> {
+    access.check_query(&request.left)?;
+    access.check_query(&request.right)?;
+    let payload = operations::Compare { request, max_cost, cancellation };
     Ok((
         StatusCode::OK,
         Json(state.0.spawn(move |index| payload.run(index.as_ref())).await??),
     ))
 }
 
-/// Count elements matching a query.
-pub async fn handler_count(
+/// Similarity coefficients of the results of two queries.
+pub async fn handler_similarity(
     ExtractState(state): ExtractState<State>,
-    Json(payload): Json<operations::Count>,
-) -> JSONAPIResult<u64> {
+    QueryCostLimit(max_cost): QueryCostLimit,
+    RequestCancellation(cancellation): RequestCancellation,
+    access: PropertyAccess,
+    Json(request): Json<api::Similarity>,
+) -> JSONAPIResult<api::SimilarityResult> {
+    access.check_query(&request.left)?;
+    access.check_query(&request.right)?;
+    let payload = operations::Similarity { request, max_cost, cancellation };
     Ok((
         StatusCode::OK,
         Json(state.0.spawn(move |index| payload.run(index.as_ref())).await??),
     ))
 }
 
+/// Catch a replica up with the index, see `api::SyncResult`. Changes are
+/// read from the log before the index snapshot is taken so that the returned
+/// bitmaps are never older than the returned generation.
+pub async fn handler_sync(
+    ExtractState(state): ExtractState<State>,
+    access: PropertyAccess,
+    Json(request): Json<api::SyncRequest>,
+) -> JSONAPIResult<api::SyncResult> {
+    access.check_all()?;
+    let log = state.0.change_log_id();
+    let changes = match request.generation {
+        Some(since) if request.log.as_deref() == Some(log.as_str()) => {
+            state.0.changes_since(since).map(|events| {
+                (events.last().map_or(since, |e| e.generation), events)
+            })
+        }
+        _ => None,
+    };
+    let payload = match changes {
+        Some((generation, events)) => operations::Sync {
+            log,
+            generation,
+            modified: changes::modified_properties(&events),
+        },
+        None => operations::Sync {
+            log,
+            generation: state.0.generation(),
+            modified: None,
+        },
+    };
+    Ok((
+        StatusCode::OK,
+        Json(state.0.spawn(move |index| payload.run(index.as_ref())).await??),
+    ))
+}
+
+fn format_timestamp(at: SystemTime) -> String {
+    humantime::format_rfc3339_millis(at).to_string()
+}
+
+/// Stats of the index, the request body is optional and only needed to
+/// filter the stats, see `api::StatsRequest`.
 pub async fn handler_stats(
     ExtractState(state): ExtractState<State>,
-) -> JSONAPIResult<operations::StatsResult> {
+    access: PropertyAccess,
+    request: Option<Json<api::StatsRequest>>,
+) -> JSONAPIResult<api::StatsResult> {
+    let payload = operations::Stats {
+        request: request.map(|Json(r)| r).unwrap_or_default(),
+    };
+    let mut stats =
+        state.0.spawn(move |index| payload.run(index.as_ref())).await?;
+    if access.is_restricted() {
+        // The root covers every property.
+        stats.root = None;
+        stats.properties.retain(|k, _| access.can_access(k));
+    }
+    stats.flush = Some(state.0.flush_status());
+    stats.last_modified = Some(api::LastModified {
+        index: format_timestamp(state.0.last_modified()),
+        properties: state
+            .0
+            .properties_last_modified(stats.properties.keys())
+            .into_iter()
+            .map(|(k, v)| (k, format_timestamp(v)))
+            .collect(),
+    });
+    Ok((StatusCode::OK, Json(stats)))
+}
+
+/// Distribution of the cardinalities of the properties, the request body is
+/// optional, see `api::DistributionRequest`. Restricted callers must provide
+/// a prefix they can access.
+pub async fn handler_stats_distribution(
+    ExtractState(state): ExtractState<State>,
+    access: PropertyAccess,
+    request: Option<Json<api::DistributionRequest>>,
+) -> JSONAPIResult<api::DistributionResult> {
+    let payload = request.map(|Json(r)| r).unwrap_or_default();
+    match &payload.prefix {
+        Some(prefix) if !access.can_access_prefix(prefix) => {
+            return Err(APIError::PropertyForbidden(Some(prefix.clone())));
+        }
+        None => access.check_all()?,
+        _ => {}
+    }
+    Ok((
+        StatusCode::OK,
+        Json(state.0.spawn(move |index| payload.run(index.as_ref())).await?),
+    ))
+}
+
+/// List properties, optionally filtered by prefix or glob pattern, without
+/// computing the stats of the whole index.
+pub async fn handler_properties(
+    ExtractState(state): ExtractState<State>,
+    access: PropertyAccess,
+    Query(payload): Query<api::ListProperties>,
+) -> JSONAPIResult<api::PropertiesResult> {
+    let include_metadata = payload.include_metadata.unwrap_or(false);
+    let mut result =
+        state.0.spawn(move |index| payload.run(index.as_ref())).await?;
+    if access.is_restricted() {
+        result.properties.retain(|k| access.can_access(k));
+        if let Some(stats) = &mut result.stats {
+            stats.retain(|k, _| access.can_access(k));
+        }
+    }
+    if include_metadata {
+        let metadata = state.0.metadata();
+        result.metadata = Some(
+            result
+                .properties
+                .iter()
+                .filter_map(|k| Some((k.clone(), metadata.get(k)?.clone())))
+                .collect(),
+        );
+    }
+    Ok((StatusCode::OK, Json(result)))
+}
+
+/// Metadata of every property which has some, see `backends::Metadata`.
+pub async fn handler_list_metadata(
+    ExtractState(state): ExtractState<State>,
+    access: PropertyAccess,
+    Query(params): Query<api::ListMetadata>,
+) -> JSONAPIResult<HashMap<String, serde_json::Value>> {
+    let prefix = params.prefix.as_deref().unwrap_or("");
     Ok((
         StatusCode::OK,
         Json(
             state
                 .0
-                .spawn(move |index| (operations::Stats {}).run(index.as_ref()))
-                .await?,
+                .metadata()
+                .iter()
+                .filter(|(k, _)| k.starts_with(prefix) && access.can_access(k))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
         ),
     ))
 }
 
-pub async fn handler_set(
+pub async fn handler_get_metadata(
     ExtractState(state): ExtractState<State>,
-    Json(payload): Json<operations::Set>,
-) -> StaticAPIResult {
-    if state.0.read_only {
-        return Err(operations::OperationError::ReadOnly.into());
+    access: PropertyAccess,
+    Path(property): Path<String>,
+) -> JSONAPIResult<serde_json::Value> {
+    access.check([property.as_str()])?;
+    match state.0.metadata().get(&property) {
+        Some(value) => Ok((StatusCode::OK, Json(value.clone()))),
+        None => Err(APIError::MetadataNotFound(property)),
     }
+}
 
-    if state.0.spawn(move |index| payload.run(index.as_ref())).await? {
-        state.0.flush().await?;
+/// Replace the metadata of a property, which does not need to exist in the
+/// index.
+pub async fn handler_put_metadata(
+    ExtractState(state): ExtractState<State>,
+    access: PropertyAccess,
+    Path(property): Path<String>,
+    Json(value): Json<serde_json::Value>,
+) -> StaticAPIResult {
+    access.check([property.as_str()])?;
+    state
+        .0
+        .update_metadata(move |metadata| metadata.insert(property, value))
+        .await?;
+    Ok((StatusCode::OK, ""))
+}
+
+pub async fn handler_delete_metadata(
+    ExtractState(state): ExtractState<State>,
+    access: PropertyAccess,
+    Path(property): Path<String>,
+) -> StaticAPIResult {
+    access.check([property.as_str()])?;
+    let removed = state
+        .0
+        .update_metadata(move |metadata| metadata.remove(&property).is_some())
+        .await?;
+    if removed {
         Ok((StatusCode::OK, ""))
     } else {
         Ok((StatusCode::NO_CONTENT, ""))
     }
 }
 
-pub async fn handler_set_many(
+pub async fn handler_set(
     ExtractState(state): ExtractState<State>,
-    Json(payload): Json<operations::SetMany>,
-) -> StaticAPIResult {
-    if state.0.read_only {
-        return Err(operations::OperationError::ReadOnly.into());
+    IfIndexVersion(expected_version): IfIndexVersion,
+    access: PropertyAccess,
+    audit: Audit,
+    Json(payload): Json<api::Set>,
+) -> VersionedAPIResult<&'static str> {
+    let change = payload.change();
+    access.check_change(&change)?;
+    let (changed, version) = state
+        .0
+        .spawn_write(expected_version, move |index| payload.run(index.as_ref()))
+        .await?;
+    if changed? {
+        audit.record(&change);
+        state.0.commit(change).await?;
+        Ok(Versioned(version, (StatusCode::OK, "")))
+    } else {
+        Ok(Versioned(version, (StatusCode::NO_CONTENT, "")))
     }
+}
 
-    state.0.spawn(move |index| payload.run(index.as_ref())).await?;
-    state.0.flush().await?;
-    Ok((StatusCode::OK, ""))
+pub async fn handler_set_many(
+    ExtractState(state): ExtractState<State>,
+    IfIndexVersion(expected_version): IfIndexVersion,
+    access: PropertyAccess,
+    audit: Audit,
+    Json(payload): Json<api::SetMany>,
+) -> VersionedAPIResult<&'static str> {
+    let change = payload.change();
+    access.check_change(&change)?;
+    let (result, version) = state
+        .0
+        .spawn_write(expected_version, move |index| payload.run(index.as_ref()))
+        .await?;
+    result?;
+    audit.record(&change);
+    state.0.commit(change).await?;
+    Ok(Versioned(version, (StatusCode::OK, "")))
 }
 
 pub async fn handler_unset(
     ExtractState(state): ExtractState<State>,
-    Json(payload): Json<operations::Unset>,
-) -> StaticAPIResult {
-    if state.0.read_only {
-        return Err(operations::OperationError::ReadOnly.into());
-    }
-
-    if state.0.spawn(move |index| payload.run(index.as_ref())).await? {
-        state.0.flush().await?;
-        Ok((StatusCode::OK, ""))
+    IfIndexVersion(expected_version): IfIndexVersion,
+    access: PropertyAccess,
+    audit: Audit,
+    Json(payload): Json<api::Unset>,
+) -> VersionedAPIResult<&'static str> {
+    let change = payload.change();
+    access.check_change(&change)?;
+    let (changed, version) = state
+        .0
+        .spawn_write(expected_version, move |index| payload.run(index.as_ref()))
+        .await?;
+    if changed {
+        audit.record(&change);
+        state.0.commit(change).await?;
+        Ok(Versioned(version, (StatusCode::OK, "")))
     } else {
-        Ok((StatusCode::NO_CONTENT, ""))
+        Ok(Versioned(version, (StatusCode::NO_CONTENT, "")))
     }
 }
 
 pub async fn handler_unset_many(
     ExtractState(state): ExtractState<State>,
-    Json(payload): Json<operations::UnsetMany>,
-) -> StaticAPIResult {
-    if state.0.read_only {
-        return Err(operations::OperationError::ReadOnly.into());
-    }
-
-    state.0.spawn(move |index| payload.run(index.as_ref())).await?;
-    state.0.flush().await?;
-    Ok((StatusCode::OK, ""))
+    IfIndexVersion(expected_version): IfIndexVersion,
+    access: PropertyAccess,
+    audit: Audit,
+    Json(payload): Json<api::UnsetMany>,
+) -> VersionedAPIResult<&'static str> {
+    let change = payload.change();
+    access.check_change(&change)?;
+    let (_, version) = state
+        .0
+        .spawn_write(expected_version, move |index| payload.run(index.as_ref()))
+        .await?;
+    audit.record(&change);
+    state.0.commit(change).await?;
+    Ok(Versioned(version, (StatusCode::OK, "")))
 }
 
 pub async fn handler_get_bit(
     ExtractState(state): ExtractState<State>,
-    Json(payload): Json<operations::GetBit>,
+    access: PropertyAccess,
+    Json(payload): Json<api::GetBit>,
 ) -> JSONAPIResult<Vec<String>> {
-    Ok((
-        StatusCode::OK,
-        Json(state.0.spawn(move |index| payload.run(index.as_ref())).await?),
-    ))
+    let mut properties =
+        state.0.spawn(move |index| payload.run(index.as_ref())).await?;
+    properties.retain(|p| access.can_access(p));
+    Ok((StatusCode::OK, Json(properties)))
+}
+
+/// Properties for which each bit is set, in a single pass over the index.
+pub async fn handler_get_bits(
+    ExtractState(state): ExtractState<State>,
+    access: PropertyAccess,
+    Json(payload): Json<api::GetBits>,
+) -> JSONAPIResult<HashMap<u32, Vec<String>>> {
+    let mut bits =
+        state.0.spawn(move |index| payload.run(index.as_ref())).await?;
+    for properties in bits.values_mut() {
+        properties.retain(|p| access.can_access(p));
+    }
+    Ok((StatusCode::OK, Json(bits)))
+}
+
+/// Bits of several properties at once, see `api::GetProperties`.
+pub async fn handler_get_properties(
+    ExtractState(state): ExtractState<State>,
+    QueryCostLimit(max_cost): QueryCostLimit,
+    access: PropertyAccess,
+    Json(request): Json<api::GetProperties>,
+) -> JSONAPIResult<api::GetPropertiesResult> {
+    access.check(request.properties.iter().map(|p| p.as_str()))?;
+    let payload = operations::GetProperties { request, max_cost };
+    let result =
+        state.0.spawn(move |index| payload.run(index.as_ref())).await??;
+    Ok((StatusCode::OK, Json(result)))
 }
 
 pub async fn handler_set_bit(
     ExtractState(state): ExtractState<State>,
-    Json(payload): Json<operations::SetBit>,
-) -> StaticAPIResult {
-    if state.0.spawn(move |index| payload.run(index.as_ref())).await? {
-        state.0.flush().await?;
-        Ok((StatusCode::OK, ""))
+    IfIndexVersion(expected_version): IfIndexVersion,
+    access: PropertyAccess,
+    audit: Audit,
+    Json(payload): Json<api::SetBit>,
+) -> VersionedAPIResult<&'static str> {
+    let change = payload.change();
+    access.check_change(&change)?;
+    let (changed, version) = state
+        .0
+        .spawn_write(expected_version, move |index| payload.run(index.as_ref()))
+        .await?;
+    if changed? {
+        audit.record(&change);
+        state.0.commit(change).await?;
+        Ok(Versioned(version, (StatusCode::OK, "")))
     } else {
-        Ok((StatusCode::NO_CONTENT, ""))
+        Ok(Versioned(version, (StatusCode::NO_CONTENT, "")))
+    }
+}
+
+pub async fn handler_set_bits(
+    ExtractState(state): ExtractState<State>,
+    IfIndexVersion(expected_version): IfIndexVersion,
+    access: PropertyAccess,
+    audit: Audit,
+    Json(payload): Json<api::SetBits>,
+) -> VersionedAPIResult<&'static str> {
+    let change = payload.change();
+    access.check_change(&change)?;
+    let (changed, version) = state
+        .0
+        .spawn_write(expected_version, move |index| payload.run(index.as_ref()))
+        .await?;
+    if changed? {
+        audit.record(&change);
+        state.0.commit(change).await?;
+        Ok(Versioned(version, (StatusCode::OK, "")))
+    } else {
+        Ok(Versioned(version, (StatusCode::NO_CONTENT, "")))
     }
 }
 
 pub async fn handler_delete_bits(
     ExtractState(state): ExtractState<State>,
-    Json(payload): Json<operations::DeleteBits>,
-) -> StaticAPIResult {
-    if state.0.read_only {
-        return Err(operations::OperationError::ReadOnly.into());
+    IfIndexVersion(expected_version): IfIndexVersion,
+    access: PropertyAccess,
+    audit: Audit,
+    Json(payload): Json<api::DeleteBits>,
+) -> VersionedAPIResult<&'static str> {
+    let change = payload.change();
+    access.check_change(&change)?;
+    let (_, version) = state
+        .0
+        .spawn_write(expected_version, move |index| payload.run(index.as_ref()))
+        .await?;
+    audit.record(&change);
+    state.0.commit(change).await?;
+    Ok(Versioned(version, (StatusCode::OK, "")))
+}
+
+/// Clear a range of bits, e.g. to drop every element below a given id
+/// without listing them.
+pub async fn handler_delete_range(
+    ExtractState(state): ExtractState<State>,
+    IfIndexVersion(expected_version): IfIndexVersion,
+    access: PropertyAccess,
+    audit: Audit,
+    Json(payload): Json<api::DeleteRange>,
+) -> VersionedAPIResult<&'static str> {
+    let change = payload.change();
+    access.check_change(&change)?;
+    let (_, version) = state
+        .0
+        .spawn_write(expected_version, move |index| payload.run(index.as_ref()))
+        .await?;
+    audit.record(&change);
+    state.0.commit(change).await?;
+    Ok(Versioned(version, (StatusCode::OK, "")))
+}
+
+pub async fn handler_transaction(
+    ExtractState(state): ExtractState<State>,
+    IfIndexVersion(expected_version): IfIndexVersion,
+    access: PropertyAccess,
+    audit: Audit,
+    Json(payload): Json<operations::Transaction>,
+) -> VersionedAPIResult<Json<api::TransactionResult>> {
+    let changes = payload.changes();
+    for change in &changes {
+        access.check_change(change)?;
     }
+    let expected_version = expected_version.or(payload.0.expected_version);
+    let transaction = Arc::new(payload);
+    let (changed, version) = state
+        .0
+        .transaction(
+            expected_version,
+            {
+                let transaction = transaction.clone();
+                move |index| transaction.touched(index)
+            },
+            move |index| transaction.apply(index).map_err(APIError::from),
+        )
+        .await?;
 
-    state.0.spawn(move |index| payload.run(index.as_ref())).await?;
+    for change in changes {
+        audit.record(&change);
+        state.0.publish(change);
+    }
+
+    Ok(Versioned(
+        version,
+        (StatusCode::OK, Json(api::TransactionResult { version, changed })),
+    ))
+}
+
+/// Persist pending writes to the backend, mostly useful with the `manual`
+/// flush policy.
+pub async fn handler_flush(
+    ExtractState(state): ExtractState<State>,
+) -> StaticAPIResult {
     state.0.flush().await?;
     Ok((StatusCode::OK, ""))
 }
@@ -0,0 +1,116 @@
+use std::convert::Infallible;
+
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::header::{ACCEPT, CONTENT_TYPE};
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use crible_api_types::QueryResult;
+
+use super::errors::APIError;
+
+/// Media type of the Arrow IPC streaming format.
+static ARROW_STREAM: &str = "application/vnd.apache.arrow.stream";
+
+/// Whether the client asked for an Arrow IPC response through the `Accept`
+/// header, JSON is used otherwise.
+pub struct AcceptArrow(pub bool);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AcceptArrow
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self(parts.headers.get_all(ACCEPT).iter().any(|value| {
+            value.to_str().map_or(false, |value| {
+                value.split(',').any(|media_type| {
+                    media_type.split(';').next().unwrap_or("").trim()
+                        == ARROW_STREAM
+                })
+            })
+        })))
+    }
+}
+
+/// Encode query results as an Arrow IPC stream containing a single `values`
+/// column, cardinalities and facets are not included.
+pub fn query_response(result: QueryResult) -> Result<Response, APIError> {
+    let batch = crible_lib::arrow::values_batch(result.values)
+        .map_err(eyre::Report::from)?;
+    let mut body = Vec::new();
+    crible_lib::arrow::write_ipc(&mut body, &batch)
+        .map_err(eyre::Report::from)?;
+    Ok((StatusCode::OK, [(CONTENT_TYPE, ARROW_STREAM)], body).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use crible_lib::Index;
+    use parking_lot::Mutex;
+    use rstest::*;
+    use tower::ServiceExt;
+
+    use super::ARROW_STREAM;
+    use crate::backends::{Backend, Memory};
+    use crate::executor::{ExecutorBuilder, SharedIndex};
+    use crate::server::{router, Options, State};
+
+    fn state() -> State {
+        let index = Arc::new(SharedIndex::new(Index::of([
+            ("foo", vec![1, 2, 3]),
+            ("bar", vec![3, 4]),
+        ])));
+        let backend: Box<dyn Backend> = Box::new(Memory::default());
+        State::new(
+            ExecutorBuilder::new(index, Arc::new(Mutex::new(backend)))
+                .pool_size(1)
+                .build()
+                .unwrap(),
+        )
+    }
+
+    #[rstest]
+    #[case(ARROW_STREAM, true)]
+    #[case("application/json, application/vnd.apache.arrow.stream;q=0.9", true)]
+    #[case("application/json", false)]
+    #[tokio::test]
+    async fn test_query_arrow(#[case] accept: &str, #[case] arrow: bool) {
+        let state = state();
+        let response = router(state.clone(), &Options::default())
+            .oneshot(
+                Request::post("/query")
+                    .header("content-type", "application/json")
+                    .header("accept", accept)
+                    .body(Body::from(r#"{"query": "foo or bar"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let content_type = response.headers()["content-type"].clone();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        if arrow {
+            assert_eq!(content_type, ARROW_STREAM);
+            let batches = crible_lib::arrow::read_ipc(&body[..]).unwrap();
+            assert_eq!(batches.len(), 1);
+            assert_eq!(
+                batches[0],
+                crible_lib::arrow::values_batch(vec![1, 2, 3, 4]).unwrap()
+            );
+        } else {
+            assert_eq!(content_type, "application/json");
+        }
+    }
+}
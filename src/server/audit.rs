@@ -0,0 +1,239 @@
+use std::convert::Infallible;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use axum::extract::{FromRequestParts, Query};
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::{async_trait, Extension, Json};
+use eyre::Context;
+use parking_lot::Mutex;
+use serde_derive::{Deserialize, Serialize};
+
+use super::api::JSONAPIResult;
+use super::auth::Identity;
+use crate::changes::Change;
+
+/// Entries returned by the tail endpoint when no limit is provided.
+static DEFAULT_TAIL_LIMIT: usize = 100;
+static MAX_TAIL_LIMIT: usize = 10_000;
+static TAIL_CHUNK_SIZE: u64 = 64 * 1024;
+
+#[derive(Serialize, Debug)]
+struct Entry<'a> {
+    timestamp: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<&'a str>,
+    request_id: Option<&'a str>,
+    /// Subject of the authenticated identity, if any.
+    subject: Option<&'a str>,
+    operation: &'a str,
+    /// Affected properties, `None` when the mutation may have affected
+    /// every property.
+    properties: Option<&'a [String]>,
+    /// Number of affected bits.
+    bits: usize,
+}
+
+/// Append-only log of all mutations, stored as JSON lines.
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl std::fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuditLog").field("path", &self.path).finish()
+    }
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> eyre::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .wrap_err_with(|| {
+                format!("Failed to open audit log `{}`", path.display())
+            })?;
+        Ok(Self { path: path.to_owned(), file: Mutex::new(file) })
+    }
+
+    fn append(&self, entry: &Entry) -> eyre::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        // Single write so that lines are never interleaved.
+        self.file.lock().write_all(&line)?;
+        Ok(())
+    }
+
+    /// Last `n` entries, oldest first.
+    async fn tail(&self, n: usize) -> eyre::Result<Vec<serde_json::Value>> {
+        let path = self.path.clone();
+        let lines = tokio::task::spawn_blocking(move || {
+            tail_lines(&mut File::open(path)?, n, TAIL_CHUNK_SIZE)
+        })
+        .await??;
+        Ok(lines
+            .iter()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+}
+
+/// Read the last `n` lines of `file` without reading all of it, by reading
+/// chunks backwards from the end.
+fn tail_lines<F: Read + Seek>(
+    file: &mut F,
+    n: usize,
+    chunk_size: u64,
+) -> std::io::Result<Vec<String>> {
+    let mut pos = file.seek(SeekFrom::End(0))?;
+    let mut buffer: Vec<u8> = vec![];
+
+    // The file ends with a newline, so `n` complete lines need `n + 1`
+    // newlines unless the start of the file was reached.
+    while pos > 0 && buffer.iter().filter(|b| **b == b'\n').count() <= n {
+        let size = chunk_size.min(pos);
+        pos -= size;
+        file.seek(SeekFrom::Start(pos))?;
+        let mut chunk = vec![0; size as usize];
+        file.read_exact(&mut chunk)?;
+        chunk.extend_from_slice(&buffer);
+        buffer = chunk;
+    }
+
+    let text = String::from_utf8_lossy(&buffer);
+    let mut lines = text.lines().collect::<Vec<_>>();
+    if pos > 0 && !lines.is_empty() {
+        // Partial line.
+        lines.remove(0);
+    }
+    let skip = lines.len().saturating_sub(n);
+    Ok(lines.into_iter().skip(skip).map(|l| l.to_owned()).collect())
+}
+
+/// Audit log of the index served by a set of routes.
+#[derive(Clone)]
+pub struct Auditor {
+    pub log: Arc<AuditLog>,
+    pub tenant: Option<String>,
+}
+
+/// Records mutations made while handling a request along with who made
+/// them. Does nothing when the audit log is disabled.
+pub struct Audit {
+    auditor: Option<Auditor>,
+    request_id: Option<String>,
+    subject: Option<String>,
+}
+
+impl Audit {
+    pub fn new(
+        auditor: Option<Auditor>,
+        request_id: Option<String>,
+        identity: Option<&Identity>,
+    ) -> Self {
+        Self {
+            auditor,
+            request_id,
+            subject: identity.and_then(|i| i.subject.clone()),
+        }
+    }
+
+    pub fn record(&self, change: &Change) {
+        let auditor = match &self.auditor {
+            Some(auditor) => auditor,
+            None => return,
+        };
+
+        if let Change::Mutation { operation, properties, bits, range } = change
+        {
+            let entry = Entry {
+                timestamp: humantime::format_rfc3339_millis(SystemTime::now())
+                    .to_string(),
+                tenant: auditor.tenant.as_deref(),
+                request_id: self.request_id.as_deref(),
+                subject: self.subject.as_deref(),
+                operation,
+                properties: properties.as_deref(),
+                bits: bits.len() + range.as_ref().map_or(0, |r| r.len()),
+            };
+            // The mutation was already applied, failing the request would
+            // only lead to it being retried.
+            if let Err(e) = auditor.log.append(&entry) {
+                tracing::error!("Failed to write audit log entry: {:?}", e);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Audit
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self::new(
+            parts.extensions.get::<Auditor>().cloned(),
+            parts
+                .headers
+                .get("x-request-id")
+                .and_then(|hv| hv.to_str().ok())
+                .map(|hv| hv.to_owned()),
+            parts.extensions.get::<Identity>(),
+        ))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TailParams {
+    limit: Option<usize>,
+}
+
+/// Most recent audit log entries, oldest first.
+pub async fn handler_tail(
+    Extension(log): Extension<Arc<AuditLog>>,
+    Query(params): Query<TailParams>,
+) -> JSONAPIResult<Vec<serde_json::Value>> {
+    let limit = params.limit.unwrap_or(DEFAULT_TAIL_LIMIT).min(MAX_TAIL_LIMIT);
+    Ok((StatusCode::OK, Json(log.tail(limit).await?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rstest::*;
+
+    use super::tail_lines;
+
+    #[rstest]
+    #[case(0, vec![])]
+    #[case(1, vec!["e"])]
+    #[case(2, vec!["d", "e"])]
+    #[case(5, vec!["a", "bb", "ccc", "d", "e"])]
+    #[case(10, vec!["a", "bb", "ccc", "d", "e"])]
+    fn test_tail_lines(
+        #[case] n: usize,
+        #[case] expected: Vec<&str>,
+        #[values(1, 2, 3, 1024)] chunk_size: u64,
+    ) {
+        let mut file = Cursor::new(b"a\nbb\nccc\nd\ne\n".to_vec());
+        assert_eq!(tail_lines(&mut file, n, chunk_size).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_tail_lines_empty() {
+        let mut file = Cursor::new(vec![]);
+        assert!(tail_lines(&mut file, 10, 1024).unwrap().is_empty());
+    }
+}
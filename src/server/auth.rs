@@ -0,0 +1,631 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::extract::State as ExtractState;
+use axum::http::header::AUTHORIZATION;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use eyre::Context;
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Header, Validation};
+use serde_derive::Deserialize;
+use tokio::sync::RwLock;
+
+use super::errors::APIError;
+
+/// Minimum delay between two JWKS fetches triggered by unknown key ids, so
+/// that a client sending garbage tokens cannot hammer the identity provider.
+static JWKS_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Permissions are ordered, higher permissions imply all the lower ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    Read,
+    Write,
+    Admin,
+}
+
+impl FromStr for Permission {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.strip_prefix("crible:").unwrap_or(value) {
+            "read" => Ok(Permission::Read),
+            "write" => Ok(Permission::Write),
+            "admin" => Ok(Permission::Admin),
+            x => Err(eyre::Report::msg(format!("Unknown permission {:?}", x))),
+        }
+    }
+}
+
+/// Authenticated caller, available as a request extension on authenticated
+/// routes.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub subject: Option<String>,
+    pub permission: Permission,
+    /// Property prefixes this identity is restricted to, if any.
+    pub prefixes: Option<Vec<String>>,
+    /// Property prefixes this identity cannot access, even when allowed by
+    /// `prefixes`.
+    pub denied_prefixes: Vec<String>,
+    /// Overrides the server wide maximum estimated query cost.
+    pub max_query_cost: Option<u64>,
+}
+
+impl Identity {
+    /// Whether some properties are off limits for this identity.
+    pub fn is_restricted(&self) -> bool {
+        self.prefixes.is_some() || !self.denied_prefixes.is_empty()
+    }
+
+    /// Whether this identity can read and write `property`.
+    pub fn can_access(&self, property: &str) -> bool {
+        self.prefixes
+            .as_ref()
+            .map_or(true, |x| x.iter().any(|p| property.starts_with(p)))
+            && !self.denied_prefixes.iter().any(|p| property.starts_with(p))
+    }
+
+    /// Whether this identity can access every property starting with
+    /// `prefix`, as used by `any(...)` and `all(...)` queries.
+    pub fn can_access_prefix(&self, prefix: &str) -> bool {
+        self.prefixes
+            .as_ref()
+            .map_or(true, |x| x.iter().any(|p| prefix.starts_with(p)))
+            && !self
+                .denied_prefixes
+                .iter()
+                .any(|p| prefix.starts_with(p) || p.starts_with(prefix))
+    }
+}
+
+/// Claims used to build an `Identity`. Permissions can be provided either as
+/// a space separated `scope` claim or a `permissions` array, both accepting
+/// `read`, `write`, `admin` optionally prefixed with `crible:`. Values which
+/// are not recognised are ignored.
+///
+/// `prefixes` and `denied_prefixes` restrict the properties the identity can
+/// access, see `Identity::can_access`.
+#[derive(Deserialize, Debug)]
+struct Claims {
+    sub: Option<String>,
+    #[serde(default)]
+    scope: Option<String>,
+    #[serde(default)]
+    permissions: Option<Vec<String>>,
+    #[serde(default)]
+    prefixes: Option<Vec<String>>,
+    #[serde(default)]
+    denied_prefixes: Vec<String>,
+    #[serde(default)]
+    max_query_cost: Option<u64>,
+}
+
+impl Claims {
+    fn into_identity(self) -> Option<Identity> {
+        let scopes = self.scope.iter().flat_map(|s| s.split_whitespace());
+        let permissions = self.permissions.iter().flatten().map(|s| s.as_ref());
+        scopes
+            .chain(permissions)
+            .filter_map(|x: &str| x.parse::<Permission>().ok())
+            .max()
+            .map(|permission| Identity {
+                subject: self.sub.clone(),
+                permission,
+                prefixes: self.prefixes.clone(),
+                denied_prefixes: self.denied_prefixes.clone(),
+                max_query_cost: self.max_query_cost,
+            })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AuthOptions {
+    /// Shared secret used to validate HMAC signed tokens.
+    pub secret: Option<String>,
+    /// Url of a JWKS document used to validate asymmetrically signed tokens.
+    pub jwks_url: Option<url::Url>,
+    pub audience: Option<String>,
+    pub issuer: Option<String>,
+}
+
+struct JwksCache {
+    keys: JwkSet,
+    fetched_at: Instant,
+}
+
+enum Keys {
+    Secret(DecodingKey),
+    Jwks { url: url::Url, client: reqwest::Client, cache: RwLock<JwksCache> },
+}
+
+/// Token validation settings, replaced as a whole when reloaded.
+struct Verifier {
+    keys: Keys,
+    audience: Option<String>,
+    issuer: Option<String>,
+}
+
+impl std::fmt::Debug for Verifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.keys {
+            Keys::Secret(_) => write!(f, "Auth [shared secret]"),
+            Keys::Jwks { url, .. } => write!(f, "Auth [jwks: {}]", url),
+        }
+    }
+}
+
+/// Algorithms tokens signed by `jwk` can use: its own `alg` when set,
+/// otherwise the ones matching its key type. Never taken from the token's
+/// header, which the caller controls.
+fn jwk_algorithms(jwk: &Jwk) -> Vec<Algorithm> {
+    if let Some(alg) = jwk.common.algorithm {
+        return vec![alg];
+    }
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => vec![
+            Algorithm::RS256,
+            Algorithm::RS384,
+            Algorithm::RS512,
+            Algorithm::PS256,
+            Algorithm::PS384,
+            Algorithm::PS512,
+        ],
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => vec![Algorithm::ES256],
+            EllipticCurve::P384 => vec![Algorithm::ES384],
+            _ => vec![],
+        },
+        AlgorithmParameters::OctetKeyPair(_) => vec![Algorithm::EdDSA],
+        // Shared secrets are configured with `--jwt-secret` instead.
+        AlgorithmParameters::OctetKey(_) => vec![],
+    }
+}
+
+async fn fetch_jwks(
+    client: &reqwest::Client,
+    url: &url::Url,
+) -> eyre::Result<JwkSet> {
+    Ok(client
+        .get(url.clone())
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<JwkSet>()
+        .await?)
+}
+
+impl Verifier {
+    async fn new(options: &AuthOptions) -> eyre::Result<Option<Self>> {
+        let keys = match (&options.secret, &options.jwks_url) {
+            (None, None) => return Ok(None),
+            (Some(_), Some(_)) => {
+                return Err(eyre::Report::msg(
+                    "JWT secret and JWKS url are mutually exclusive",
+                ));
+            }
+            (Some(secret), None) => {
+                Keys::Secret(DecodingKey::from_secret(secret.as_bytes()))
+            }
+            (None, Some(url)) => {
+                let client = reqwest::Client::new();
+                let keys =
+                    fetch_jwks(&client, url).await.wrap_err_with(|| {
+                        format!("Failed to fetch JWKS from `{}`", url)
+                    })?;
+                Keys::Jwks {
+                    url: url.clone(),
+                    client,
+                    cache: RwLock::new(JwksCache {
+                        keys,
+                        fetched_at: Instant::now(),
+                    }),
+                }
+            }
+        };
+
+        Ok(Some(Self {
+            keys,
+            audience: options.audience.clone(),
+            issuer: options.issuer.clone(),
+        }))
+    }
+
+    fn validation(&self, algorithms: Vec<Algorithm>) -> Validation {
+        let mut validation = Validation::new(algorithms[0]);
+        validation.algorithms = algorithms;
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+        }
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        validation
+    }
+
+    async fn decoding_key(
+        &self,
+        header: &Header,
+    ) -> Result<(DecodingKey, Validation), APIError> {
+        match &self.keys {
+            Keys::Secret(key) => Ok((
+                key.clone(),
+                self.validation(vec![
+                    Algorithm::HS256,
+                    Algorithm::HS384,
+                    Algorithm::HS512,
+                ]),
+            )),
+            Keys::Jwks { url, client, cache } => {
+                let kid =
+                    header.kid.as_ref().ok_or(APIError::Unauthorized)?.clone();
+
+                let found = cache.read().await.keys.find(&kid).cloned();
+                let jwk = match found {
+                    Some(jwk) => jwk,
+                    None => {
+                        // Unknown key id, the provider may have rotated its
+                        // keys since we last fetched them.
+                        let mut guard = cache.write().await;
+                        let elapsed = guard.fetched_at.elapsed();
+                        if elapsed >= JWKS_MIN_REFRESH_INTERVAL {
+                            match fetch_jwks(client, url).await {
+                                Ok(keys) => {
+                                    guard.keys = keys;
+                                    guard.fetched_at = Instant::now();
+                                }
+                                Err(e) => {
+                                    tracing::error!(
+                                        "Failed to refresh JWKS: {:?}",
+                                        e
+                                    );
+                                }
+                            }
+                        }
+                        guard
+                            .keys
+                            .find(&kid)
+                            .cloned()
+                            .ok_or(APIError::Unauthorized)?
+                    }
+                };
+
+                let algorithms = jwk_algorithms(&jwk);
+                if !algorithms.contains(&header.alg) {
+                    tracing::debug!(
+                        "Rejected token: algorithm {:?} not allowed for key {}",
+                        header.alg,
+                        kid
+                    );
+                    return Err(APIError::Unauthorized);
+                }
+                let key = DecodingKey::from_jwk(&jwk)
+                    .map_err(|_| APIError::Unauthorized)?;
+                Ok((key, self.validation(algorithms)))
+            }
+        }
+    }
+}
+
+pub struct Auth {
+    verifier: parking_lot::RwLock<Arc<Verifier>>,
+}
+
+impl std::fmt::Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(&**self.verifier.read(), f)
+    }
+}
+
+impl Auth {
+    /// Build the authentication configuration. Returns `None` when no key
+    /// source is configured in which case authentication is disabled.
+    pub async fn new(options: &AuthOptions) -> eyre::Result<Option<Self>> {
+        Ok(Verifier::new(options).await?.map(|verifier| Self {
+            verifier: parking_lot::RwLock::new(Arc::new(verifier)),
+        }))
+    }
+
+    /// Replace the keys and claims used to validate tokens. Requests being
+    /// authenticated keep using the previous settings.
+    pub async fn update(&self, options: &AuthOptions) -> eyre::Result<()> {
+        let verifier = Verifier::new(options).await?.ok_or_else(|| {
+            eyre::Report::msg(
+                "Authentication cannot be disabled without restarting",
+            )
+        })?;
+        *self.verifier.write() = Arc::new(verifier);
+        Ok(())
+    }
+
+    pub async fn authenticate(
+        &self,
+        token: &str,
+    ) -> Result<Identity, APIError> {
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|_| APIError::Unauthorized)?;
+        let verifier = self.verifier.read().clone();
+        let (key, validation) = verifier.decoding_key(&header).await?;
+        let data = jsonwebtoken::decode::<Claims>(token, &key, &validation)
+            .map_err(|e| {
+                tracing::debug!("Rejected token: {}", e);
+                APIError::Unauthorized
+            })?;
+        data.claims.into_identity().ok_or(APIError::Forbidden)
+    }
+
+    /// Authenticate a raw `Authorization` header value and check that the
+    /// resulting identity has at least the `required` permission.
+    pub async fn authorize(
+        &self,
+        header: Option<&str>,
+        required: Permission,
+    ) -> Result<Identity, APIError> {
+        let token = header
+            .and_then(|hv| hv.strip_prefix("Bearer "))
+            .map(|token| token.trim())
+            .ok_or(APIError::Unauthorized)?;
+
+        let identity = self.authenticate(token).await?;
+
+        if identity.permission < required {
+            return Err(APIError::Forbidden);
+        }
+
+        Ok(identity)
+    }
+}
+
+async fn authorize<B>(
+    required: Permission,
+    auth: Arc<Auth>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, APIError> {
+    let header = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|hv| hv.to_str().ok())
+        .map(|hv| hv.to_owned());
+
+    let identity = auth.authorize(header.as_deref(), required).await?;
+
+    request.extensions_mut().insert(identity);
+    Ok(next.run(request).await)
+}
+
+pub async fn require_read<B>(
+    ExtractState(auth): ExtractState<Arc<Auth>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, APIError> {
+    authorize(Permission::Read, auth, request, next).await
+}
+
+pub async fn require_write<B>(
+    ExtractState(auth): ExtractState<Arc<Auth>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, APIError> {
+    authorize(Permission::Write, auth, request, next).await
+}
+
+pub async fn require_admin<B>(
+    ExtractState(auth): ExtractState<Arc<Auth>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, APIError> {
+    authorize(Permission::Admin, auth, request, next).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use crible_lib::Index;
+    use jsonwebtoken::jwk::JwkSet;
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use parking_lot::Mutex;
+    use rstest::*;
+    use tokio::sync::RwLock;
+    use tower::ServiceExt;
+
+    use super::{
+        jwk_algorithms, Auth, AuthOptions, Claims, Identity, JwksCache, Keys,
+        Permission, Verifier,
+    };
+    use crate::backends::{Backend, Memory};
+    use crate::executor::{ExecutorBuilder, SharedIndex};
+    use crate::server::{router, Options, State};
+
+    fn jwks() -> JwkSet {
+        serde_json::from_value(serde_json::json!({
+            "keys": [
+                {
+                    "kty": "RSA",
+                    "kid": "signing",
+                    "alg": "RS256",
+                    "n": "AQAB",
+                    "e": "AQAB",
+                },
+                {"kty": "RSA", "kid": "any", "n": "AQAB", "e": "AQAB"},
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn encode_token(header: Header, secret: &str, expires_in: i64) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        jsonwebtoken::encode(
+            &header,
+            &serde_json::json!({
+                "scope": "read",
+                "exp": now.as_secs() as i64 + expires_in,
+            }),
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    /// Status of a `/count` request authenticated with `auth`.
+    async fn count_status(auth: Auth, token: Option<String>) -> StatusCode {
+        let backend: Box<dyn Backend> = Box::new(Memory::default());
+        let state = State::new(
+            ExecutorBuilder::new(
+                Arc::new(SharedIndex::new(Index::of([("foo", vec![1])]))),
+                Arc::new(Mutex::new(backend)),
+            )
+            .pool_size(1)
+            .build()
+            .unwrap(),
+        );
+        let options =
+            Options { auth: Some(Arc::new(auth)), ..Default::default() };
+        let mut request =
+            Request::post("/count").header("content-type", "application/json");
+        if let Some(token) = token {
+            request =
+                request.header("authorization", format!("Bearer {}", token));
+        }
+        router(state, &options)
+            .oneshot(request.body(Body::from(r#"{"query": "foo"}"#)).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[rstest]
+    #[case("read", Permission::Read)]
+    #[case("crible:write", Permission::Write)]
+    #[case("admin", Permission::Admin)]
+    fn test_parse_permission(
+        #[case] value: &str,
+        #[case] expected: Permission,
+    ) {
+        assert_eq!(value.parse::<Permission>().unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case(Some("openid read"), None, Some(Permission::Read))]
+    #[case(Some("read crible:admin"), None, Some(Permission::Admin))]
+    #[case(None, Some(vec!["write", "other"]), Some(Permission::Write))]
+    #[case(Some("read"), Some(vec!["write"]), Some(Permission::Write))]
+    #[case(Some("openid profile"), None, None)]
+    #[case(None, None, None)]
+    fn test_claims_permission(
+        #[case] scope: Option<&str>,
+        #[case] permissions: Option<Vec<&str>>,
+        #[case] expected: Option<Permission>,
+    ) {
+        let claims = Claims {
+            sub: None,
+            scope: scope.map(|x| x.to_owned()),
+            permissions: permissions
+                .map(|x| x.into_iter().map(|x| x.to_owned()).collect()),
+            prefixes: None,
+            denied_prefixes: vec![],
+            max_query_cost: None,
+        };
+        assert_eq!(claims.into_identity().map(|i| i.permission), expected);
+    }
+
+    #[rstest]
+    #[case(None, &[], "tenantB:foo", true)]
+    #[case(Some(vec!["tenantA:"]), &[], "tenantA:foo", true)]
+    #[case(Some(vec!["tenantA:"]), &[], "tenantB:foo", false)]
+    #[case(None, &["tenantB:"], "tenantB:foo", false)]
+    #[case(None, &["tenantB:"], "tenantA:foo", true)]
+    #[case(Some(vec!["tenantA:"]), &["tenantA:s"], "tenantA:secret", false)]
+    #[case(Some(vec![]), &[], "tenantA:foo", false)]
+    fn test_can_access(
+        #[case] prefixes: Option<Vec<&str>>,
+        #[case] denied_prefixes: &[&str],
+        #[case] property: &str,
+        #[case] expected: bool,
+    ) {
+        let identity = Identity {
+            subject: None,
+            permission: Permission::Read,
+            prefixes: prefixes
+                .map(|x| x.into_iter().map(|x| x.to_owned()).collect()),
+            denied_prefixes: denied_prefixes
+                .iter()
+                .map(|x| (*x).to_owned())
+                .collect(),
+            max_query_cost: None,
+        };
+        assert_eq!(identity.can_access(property), expected);
+    }
+
+    #[test]
+    fn test_jwk_algorithms() {
+        let jwks = jwks();
+        assert_eq!(
+            jwk_algorithms(jwks.find("signing").unwrap()),
+            vec![Algorithm::RS256]
+        );
+        let any = jwk_algorithms(jwks.find("any").unwrap());
+        assert!(any.contains(&Algorithm::PS512));
+        assert!(!any.contains(&Algorithm::HS256));
+    }
+
+    #[rstest]
+    #[case(None, StatusCode::UNAUTHORIZED)]
+    #[case(Some(("secret", 3600)), StatusCode::OK)]
+    #[case(Some(("secret", -3600)), StatusCode::UNAUTHORIZED)]
+    #[case(Some(("other", 3600)), StatusCode::UNAUTHORIZED)]
+    #[tokio::test]
+    async fn test_require_read(
+        #[case] token_params: Option<(&str, i64)>,
+        #[case] expected: StatusCode,
+    ) {
+        let auth = Auth::new(&AuthOptions {
+            secret: Some("secret".to_owned()),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        let token = token_params.map(|(secret, expires_in)| {
+            encode_token(Header::default(), secret, expires_in)
+        });
+        assert_eq!(count_status(auth, token).await, expected);
+    }
+
+    #[rstest]
+    #[case("signing")]
+    #[case("any")]
+    #[tokio::test]
+    async fn test_require_read_jwks_algorithm(#[case] kid: &str) {
+        // Tokens signed with a shared secret using the public key's kid
+        // must not be accepted whatever their header says.
+        let auth = Auth {
+            verifier: parking_lot::RwLock::new(Arc::new(Verifier {
+                keys: Keys::Jwks {
+                    url: "http://localhost/jwks.json".parse().unwrap(),
+                    client: reqwest::Client::new(),
+                    cache: RwLock::new(JwksCache {
+                        keys: jwks(),
+                        fetched_at: Instant::now(),
+                    }),
+                },
+                audience: None,
+                issuer: None,
+            })),
+        };
+        let header = Header {
+            kid: Some(kid.to_owned()),
+            ..Header::new(Algorithm::HS256)
+        };
+        assert_eq!(
+            count_status(auth, Some(encode_token(header, "AQAB", 3600))).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
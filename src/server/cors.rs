@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use axum::http::{HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+static WILDCARD: &str = "*";
+
+#[derive(Debug, Clone, Default)]
+pub struct CorsOptions {
+    /// Allowed origins, `*` allows any origin.
+    pub allow_origins: Vec<String>,
+    /// Allowed methods, `*` allows any method.
+    pub allow_methods: Vec<String>,
+    /// Allowed request headers, `*` allows any header.
+    pub allow_headers: Vec<String>,
+    /// How long the results of a preflight request can be cached.
+    pub max_age: Option<Duration>,
+}
+
+impl CorsOptions {
+    pub fn layer(&self) -> eyre::Result<CorsLayer> {
+        let allow_origin = if self.allow_origins.iter().any(|x| x == WILDCARD) {
+            AllowOrigin::any()
+        } else {
+            AllowOrigin::list(
+                self.allow_origins
+                    .iter()
+                    .map(|x| HeaderValue::from_str(x))
+                    .collect::<Result<Vec<_>, _>>()?,
+            )
+        };
+
+        let allow_methods = if self.allow_methods.iter().any(|x| x == WILDCARD)
+        {
+            AllowMethods::any()
+        } else {
+            AllowMethods::list(
+                self.allow_methods
+                    .iter()
+                    .map(|x| x.to_uppercase().parse::<Method>())
+                    .collect::<Result<Vec<_>, _>>()?,
+            )
+        };
+
+        let allow_headers = if self.allow_headers.iter().any(|x| x == WILDCARD)
+        {
+            AllowHeaders::any()
+        } else {
+            AllowHeaders::list(
+                self.allow_headers
+                    .iter()
+                    .map(|x| x.parse::<HeaderName>())
+                    .collect::<Result<Vec<_>, _>>()?,
+            )
+        };
+
+        let mut layer = CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods(allow_methods)
+            .allow_headers(allow_headers);
+
+        if let Some(max_age) = self.max_age {
+            layer = layer.max_age(max_age);
+        }
+
+        Ok(layer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CorsOptions;
+
+    #[test]
+    fn test_layer_valid() {
+        let options = CorsOptions {
+            allow_origins: vec!["https://dashboard.example.com".to_owned()],
+            allow_methods: vec!["get".to_owned(), "POST".to_owned()],
+            allow_headers: vec!["*".to_owned()],
+            max_age: None,
+        };
+        assert!(options.layer().is_ok());
+    }
+
+    #[test]
+    fn test_layer_invalid_header() {
+        let options = CorsOptions {
+            allow_origins: vec!["*".to_owned()],
+            allow_methods: vec!["*".to_owned()],
+            allow_headers: vec!["not a header".to_owned()],
+            max_age: None,
+        };
+        assert!(options.layer().is_err());
+    }
+}
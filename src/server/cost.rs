@@ -0,0 +1,153 @@
+use std::convert::Infallible;
+
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+
+use super::auth::Identity;
+
+/// Server wide maximum estimated query cost, available as a request
+/// extension on data routes when configured.
+#[derive(Debug, Clone, Copy)]
+pub struct MaxQueryCost(pub u64);
+
+/// Maximum estimated cost of queries made by the caller: the
+/// `max_query_cost` claim of their token if any, otherwise the server wide
+/// limit. Queries above it are rejected with 422 HTTP status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueryCostLimit(pub Option<u64>);
+
+impl QueryCostLimit {
+    pub fn new(
+        server: Option<MaxQueryCost>,
+        identity: Option<&Identity>,
+    ) -> Self {
+        Self(
+            identity
+                .and_then(|i| i.max_query_cost)
+                .or_else(|| server.map(|m| m.0)),
+        )
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for QueryCostLimit
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self::new(
+            parts.extensions.get::<MaxQueryCost>().copied(),
+            parts.extensions.get::<Identity>(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use crible_lib::Index;
+    use parking_lot::Mutex;
+    use rstest::*;
+    use tower::ServiceExt;
+
+    use super::{MaxQueryCost, QueryCostLimit};
+    use crate::backends::{Backend, Memory};
+    use crate::executor::{ExecutorBuilder, SharedIndex};
+    use crate::server::auth::{Identity, Permission};
+    use crate::server::{router, Options, State};
+
+    fn identity(max_query_cost: Option<u64>) -> Identity {
+        Identity {
+            subject: None,
+            permission: Permission::Read,
+            prefixes: None,
+            denied_prefixes: vec![],
+            max_query_cost,
+        }
+    }
+
+    #[rstest]
+    #[case(None, None, None)]
+    #[case(Some(10), None, Some(10))]
+    #[case(Some(10), Some(identity(None)), Some(10))]
+    #[case(Some(10), Some(identity(Some(100))), Some(100))]
+    #[case(None, Some(identity(Some(5))), Some(5))]
+    fn test_limit(
+        #[case] server: Option<u64>,
+        #[case] identity: Option<Identity>,
+        #[case] expected: Option<u64>,
+    ) {
+        assert_eq!(
+            QueryCostLimit::new(server.map(MaxQueryCost), identity.as_ref()),
+            QueryCostLimit(expected)
+        );
+    }
+
+    #[rstest]
+    #[case("/count", r#"{"query": "foo"}"#, StatusCode::OK)]
+    #[case(
+        "/count",
+        r#"{"query": "not foo"}"#,
+        StatusCode::UNPROCESSABLE_ENTITY
+    )]
+    #[case("/count-many", r#"{"queries": ["foo", "bar"]}"#, StatusCode::OK)]
+    #[case(
+        "/count-many",
+        r#"{"queries": {"a": "foo", "b": "foo"}}"#,
+        StatusCode::UNPROCESSABLE_ENTITY
+    )]
+    #[case("/query", r#"{"query": "foo or bar"}"#, StatusCode::OK)]
+    #[case(
+        "/query",
+        r#"{"query": "foo", "include_cardinalities": true}"#,
+        StatusCode::UNPROCESSABLE_ENTITY
+    )]
+    #[case(
+        "/compare",
+        r#"{"left": "foo", "right": "foo or bar"}"#,
+        StatusCode::UNPROCESSABLE_ENTITY
+    )]
+    #[tokio::test]
+    async fn test_expensive_queries_are_rejected(
+        #[case] path: &str,
+        #[case] body: &'static str,
+        #[case] expected: StatusCode,
+    ) {
+        let backend: Box<dyn Backend> = Box::new(Memory::default());
+        let state = State::new(
+            ExecutorBuilder::new(
+                Arc::new(SharedIndex::new(Index::of([
+                    ("foo", vec![1, 2, 3]),
+                    ("bar", vec![3, 4]),
+                ]))),
+                Arc::new(Mutex::new(backend)),
+            )
+            .pool_size(1)
+            .build()
+            .unwrap(),
+        );
+        let options = Options { max_query_cost: Some(5), ..Default::default() };
+
+        let status = router(state, &options)
+            .oneshot(
+                Request::post(path)
+                    .header("content-type", "application/json")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+            .status();
+
+        assert_eq!(status, expected);
+    }
+}
@@ -3,7 +3,7 @@ use std::convert::From;
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::Json;
-use serde_json::json;
+use crible_api_types::ErrorBody;
 
 use crate::operations::OperationError;
 
@@ -11,12 +11,40 @@ use crate::operations::OperationError;
 pub enum APIError {
     Operation(OperationError),
     TooManyRequests,
+    Unauthorized,
+    Forbidden,
+    /// The caller cannot access this property, or every property when
+    /// `None`, see `auth::Identity::can_access`.
+    PropertyForbidden(Option<String>),
+    PayloadTooLarge(usize),
+    Timeout(std::time::Duration),
+    VersionMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    InvalidHeader(&'static str),
+    /// Invalid `/ingest` record, records before `applied` were still applied.
+    InvalidRecord {
+        record: u64,
+        applied: u64,
+        message: String,
+    },
+    IdempotencyKeyInUse,
+    IdempotencyKeyMismatch,
+    ShuttingDown,
+    BackendUnavailable,
+    /// No metadata is stored for this property.
+    MetadataNotFound(String),
+    /// Changes published after this generation are not kept anymore, see
+    /// `changes::ChangeLog::since`.
+    ChangesUnavailable(u64),
     Eyre(eyre::Report),
 }
 
-impl IntoResponse for APIError {
-    fn into_response(self) -> Response {
-        let (status, error_message) = match self {
+impl APIError {
+    /// HTTP status and user facing message for this error.
+    pub fn status_and_message(&self) -> (StatusCode, String) {
+        match self {
             APIError::Operation(e) => match e {
                 OperationError::ReadOnly => (
                     StatusCode::FORBIDDEN,
@@ -30,26 +58,148 @@ impl IntoResponse for APIError {
                     }
                 },
                 OperationError::Index(e) => match e {
-                    crible_lib::index::Error::PropertyDoesNotExist(p) => (
+                    crible_lib::index::Error::PropertyDoesNotExist {
+                        property,
+                        ..
+                    } => (
                         StatusCode::BAD_REQUEST,
-                        format!("Property {} does not exist", p),
+                        format!("Property {} does not exist", property),
+                    ),
+                    crible_lib::index::Error::Cancelled => (
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "Request was cancelled".to_owned(),
+                    ),
+                    crible_lib::index::Error::BitOutOfRange {
+                        bit,
+                        universe,
+                    } => (
+                        StatusCode::BAD_REQUEST,
+                        format!(
+                            "Bit {} is out of range, bits must be lower than \
+                             {}",
+                            bit, universe
+                        ),
                     ),
                 },
+                OperationError::TooExpensive { cost, max_cost } => (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!(
+                        "Query is too expensive: estimated cost is {}, \
+                         maximum is {}",
+                        cost, max_cost
+                    ),
+                ),
             },
             APIError::TooManyRequests => {
                 (StatusCode::TOO_MANY_REQUESTS, "".to_owned())
             }
-            _ => {
+            APIError::Unauthorized => (
+                StatusCode::UNAUTHORIZED,
+                "Missing or invalid token".to_owned(),
+            ),
+            APIError::Forbidden => {
+                (StatusCode::FORBIDDEN, "Insufficient permissions".to_owned())
+            }
+            APIError::PropertyForbidden(Some(property)) => (
+                StatusCode::FORBIDDEN,
+                format!("Access to property {} is not allowed", property),
+            ),
+            APIError::PropertyForbidden(None) => (
+                StatusCode::FORBIDDEN,
+                "Access to every property is not allowed".to_owned(),
+            ),
+            APIError::PayloadTooLarge(limit) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Request body exceeds {} bytes", limit),
+            ),
+            APIError::Timeout(timeout) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("Request timed out after {}ms", timeout.as_millis()),
+            ),
+            APIError::VersionMismatch { expected, actual } => (
+                StatusCode::CONFLICT,
+                format!("Index version is {}, expected {}", actual, expected),
+            ),
+            APIError::InvalidHeader(name) => {
+                (StatusCode::BAD_REQUEST, format!("Invalid {} header", name))
+            }
+            APIError::InvalidRecord { record, applied, message } => (
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Invalid record {}: {}, {} records were applied",
+                    record, message, applied
+                ),
+            ),
+            APIError::IdempotencyKeyInUse => (
+                StatusCode::CONFLICT,
+                "A request with this idempotency key is in progress".to_owned(),
+            ),
+            APIError::IdempotencyKeyMismatch => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Idempotency key was used for a different request".to_owned(),
+            ),
+            APIError::ShuttingDown => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Server is shutting down".to_owned(),
+            ),
+            APIError::BackendUnavailable => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Backend is unavailable, writes are rejected until pending \
+                 writes are persisted"
+                    .to_owned(),
+            ),
+            APIError::MetadataNotFound(property) => (
+                StatusCode::NOT_FOUND,
+                format!("No metadata for property {}", property),
+            ),
+            APIError::ChangesUnavailable(since) => (
+                StatusCode::GONE,
+                format!(
+                    "Changes since generation {} are not available, reload \
+                     the whole index",
+                    since
+                ),
+            ),
+            APIError::Eyre(e) => {
                 tracing::error!("Unhandled error: {0:?}", self);
+                super::reporting::capture_report(e);
                 (StatusCode::INTERNAL_SERVER_ERROR, "".to_owned())
             }
-        };
+        }
+    }
 
-        let body = Json(json!({
-            "error": error_message,
-        }));
+    /// Machine readable code for errors coming from the library, see
+    /// `crible_lib::index::Error::code`.
+    pub fn code(&self) -> Option<&'static str> {
+        match self {
+            APIError::Operation(OperationError::Expression(e)) => {
+                Some(e.code())
+            }
+            APIError::Operation(OperationError::Index(e)) => Some(e.code()),
+            APIError::InvalidRecord { .. } => Some("invalid_record"),
+            APIError::PropertyForbidden(_) => Some("property_forbidden"),
+            APIError::ChangesUnavailable(_) => Some("changes_unavailable"),
+            _ => None,
+        }
+    }
+}
 
-        (status, body).into_response()
+impl APIError {
+    /// HTTP status and body of the response for this error.
+    pub fn status_and_body(&self) -> (StatusCode, ErrorBody) {
+        let (status, error_message) = self.status_and_message();
+        let body = ErrorBody {
+            error: error_message,
+            code: self.code().map(|code| code.to_owned()),
+        };
+        (status, body)
+    }
+}
+
+impl IntoResponse for APIError {
+    fn into_response(self) -> Response {
+        let (status, body) = self.status_and_body();
+        (status, Json(body)).into_response()
     }
 }
 
@@ -71,6 +221,13 @@ impl From<crate::executor::Error> for APIError {
             crate::executor::Error::TooManyRequests => {
                 APIError::TooManyRequests
             }
+            crate::executor::Error::VersionMismatch { expected, actual } => {
+                APIError::VersionMismatch { expected, actual }
+            }
+            crate::executor::Error::ShuttingDown => APIError::ShuttingDown,
+            crate::executor::Error::BackendUnavailable => {
+                APIError::BackendUnavailable
+            }
             crate::executor::Error::Unknown(e) => APIError::Eyre(e),
         }
     }
@@ -0,0 +1,371 @@
+use std::sync::Arc;
+
+use async_graphql::{Context, EmptySubscription, Object, Schema, SimpleObject};
+use axum::{Extension, Json};
+use crible_api_types as api;
+
+use super::access::PropertyAccess;
+use super::audit::Audit;
+use super::auth::{Identity, Permission};
+use super::cost::QueryCostLimit;
+use super::errors::APIError;
+use super::timeout::RequestCancellation;
+use super::State;
+use crate::changes::Change;
+use crate::executor::SharedIndex;
+use crate::operations::{self, Mutate, Operation, OperationError};
+
+pub type CribleSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+
+pub fn schema(state: State) -> CribleSchema {
+    Schema::build(QueryRoot, MutationRoot, EmptySubscription)
+        .data(state)
+        .finish()
+}
+
+impl From<APIError> for async_graphql::Error {
+    fn from(e: APIError) -> Self {
+        let (status, message) = e.status_and_message();
+        async_graphql::Error::new(if message.is_empty() {
+            status.canonical_reason().unwrap_or("").to_owned()
+        } else {
+            message
+        })
+    }
+}
+
+async fn spawn<F, T>(ctx: &Context<'_>, func: F) -> async_graphql::Result<T>
+where
+    F: FnOnce(Arc<SharedIndex>) -> T + Send + 'static,
+    T: Sync + Send + 'static,
+{
+    let state = ctx.data::<State>()?;
+    Ok(state.0.spawn(func).await.map_err(APIError::from)?)
+}
+
+async fn spawn_write<F, T>(
+    ctx: &Context<'_>,
+    func: F,
+) -> async_graphql::Result<T>
+where
+    F: FnOnce(Arc<SharedIndex>) -> T + Send + 'static,
+    T: Sync + Send + 'static,
+{
+    let state = ctx.data::<State>()?;
+    Ok(state.0.spawn_write(None, func).await.map_err(APIError::from)?.0)
+}
+
+/// Mutations are exposed on the read routes as GraphQL uses a single
+/// endpoint, so write permissions are checked here instead.
+fn ensure_writable(ctx: &Context<'_>) -> async_graphql::Result<()> {
+    if let Some(identity) = ctx.data::<Option<Identity>>()? {
+        if identity.permission < Permission::Write {
+            return Err(APIError::Forbidden.into());
+        }
+    }
+    if ctx.data::<State>()?.0.read_only {
+        return Err(APIError::from(OperationError::ReadOnly).into());
+    }
+    Ok(())
+}
+
+async fn commit(
+    ctx: &Context<'_>,
+    change: Change,
+) -> async_graphql::Result<()> {
+    ctx.data::<Audit>()?.record(&change);
+    Ok(ctx.data::<State>()?.0.commit(change).await.map_err(APIError::from)?)
+}
+
+#[derive(SimpleObject)]
+pub struct Cardinality {
+    property: String,
+    count: u64,
+}
+
+#[derive(SimpleObject)]
+pub struct SearchResult {
+    values: Vec<u32>,
+    cardinalities: Option<Vec<Cardinality>>,
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Elements matching a query, optionally with the cardinality of the
+    /// intersection of the result with every property.
+    async fn search(
+        &self,
+        ctx: &Context<'_>,
+        query: String,
+        #[graphql(default)] include_cardinalities: bool,
+    ) -> async_graphql::Result<SearchResult> {
+        let access = ctx.data::<PropertyAccess>()?;
+        access.check_query(&query)?;
+        let payload = operations::Query {
+            request: api::Query {
+                query,
+                include_cardinalities: Some(include_cardinalities),
+                facets: None,
+                include_stats: None,
+                include_values: None,
+                ranks: None,
+                percentiles: None,
+            },
+            max_cost: ctx.data::<QueryCostLimit>()?.0,
+            cancellation: ctx.data::<RequestCancellation>()?.0.clone(),
+            facet_delimiter: operations::FacetDelimiter::default(),
+        };
+        let result = spawn(ctx, move |index| payload.run(index.as_ref()))
+            .await?
+            .map_err(APIError::from)?;
+        Ok(SearchResult {
+            values: result.values,
+            cardinalities: result.cardinalities.map(|c| {
+                let mut c = c
+                    .into_iter()
+                    .filter(|(property, _)| access.can_access(property))
+                    .map(|(property, count)| Cardinality { property, count })
+                    .collect::<Vec<_>>();
+                c.sort_by(|a, b| a.property.cmp(&b.property));
+                c
+            }),
+        })
+    }
+
+    /// Number of elements matching a query.
+    async fn count(
+        &self,
+        ctx: &Context<'_>,
+        query: String,
+    ) -> async_graphql::Result<u64> {
+        ctx.data::<PropertyAccess>()?.check_query(&query)?;
+        let payload = operations::Count {
+            request: api::Count { query },
+            max_cost: ctx.data::<QueryCostLimit>()?.0,
+            cancellation: ctx.data::<RequestCancellation>()?.0.clone(),
+        };
+        Ok(spawn(ctx, move |index| payload.run(index.as_ref()))
+            .await?
+            .map_err(APIError::from)?)
+    }
+
+    /// Cardinality of the intersection of a query with every property,
+    /// optionally restricted to properties starting with `prefix`.
+    async fn cardinalities(
+        &self,
+        ctx: &Context<'_>,
+        query: String,
+        prefix: Option<String>,
+    ) -> async_graphql::Result<Vec<Cardinality>> {
+        let access = ctx.data::<PropertyAccess>()?;
+        access.check_query(&query)?;
+        let expr = crible_lib::Expression::parse(&query)
+            .map_err(|e| APIError::from(OperationError::from(e)))?;
+        let max_cost = ctx.data::<QueryCostLimit>()?.0;
+        let cancellation = ctx.data::<RequestCancellation>()?.0.clone();
+        let mut result =
+            spawn(ctx, move |index| -> Result<_, OperationError> {
+                let idx = index.read();
+                // Computing cardinalities goes through every property.
+                operations::check_cost(
+                    &idx,
+                    &[&expr, &crible_lib::Expression::Root],
+                    max_cost,
+                )?;
+                let bm = idx.execute_cancellable(&expr, &cancellation)?;
+                Ok(idx.par_cardinalities_cancellable(
+                    &bm,
+                    prefix.as_deref(),
+                    &cancellation,
+                )?)
+            })
+            .await?
+            .map_err(APIError::from)?
+            .into_iter()
+            .filter(|(property, _)| access.can_access(property))
+            .map(|(property, count)| Cardinality { property, count })
+            .collect::<Vec<_>>();
+        result.sort_by(|a, b| a.property.cmp(&b.property));
+        Ok(result)
+    }
+
+    /// Sorted list of properties, optionally restricted to properties
+    /// starting with `prefix`.
+    async fn properties(
+        &self,
+        ctx: &Context<'_>,
+        prefix: Option<String>,
+    ) -> async_graphql::Result<Vec<String>> {
+        let access = ctx.data::<PropertyAccess>()?.clone();
+        spawn(ctx, move |index| {
+            let properties = index
+                .read()
+                .iter_prefix(prefix.as_deref().unwrap_or(""))
+                .filter(|(k, _)| access.can_access(k))
+                .map(|(k, _)| k.to_owned())
+                .collect::<Vec<_>>();
+            properties
+        })
+        .await
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    /// Set a bit for a property. Returns whether the index was modified.
+    async fn set(
+        &self,
+        ctx: &Context<'_>,
+        property: String,
+        bit: u32,
+    ) -> async_graphql::Result<bool> {
+        ensure_writable(ctx)?;
+        let payload = api::Set { property, bit };
+        let change = payload.change();
+        ctx.data::<PropertyAccess>()?.check_change(&change)?;
+        let changed =
+            spawn_write(ctx, move |index| payload.run(index.as_ref()))
+                .await?
+                .map_err(APIError::from)?;
+        if changed {
+            commit(ctx, change).await?;
+        }
+        Ok(changed)
+    }
+
+    /// Unset a bit for a property. Returns whether the index was modified.
+    async fn unset(
+        &self,
+        ctx: &Context<'_>,
+        property: String,
+        bit: u32,
+    ) -> async_graphql::Result<bool> {
+        ensure_writable(ctx)?;
+        let payload = api::Unset { property, bit };
+        let change = payload.change();
+        ctx.data::<PropertyAccess>()?.check_change(&change)?;
+        let changed =
+            spawn_write(ctx, move |index| payload.run(index.as_ref())).await?;
+        if changed {
+            commit(ctx, change).await?;
+        }
+        Ok(changed)
+    }
+
+    /// Set multiple bits for a property.
+    async fn set_many(
+        &self,
+        ctx: &Context<'_>,
+        property: String,
+        bits: Vec<u32>,
+    ) -> async_graphql::Result<bool> {
+        ensure_writable(ctx)?;
+        let payload = api::SetMany { values: [(property, bits)].into() };
+        let change = payload.change();
+        ctx.data::<PropertyAccess>()?.check_change(&change)?;
+        spawn_write(ctx, move |index| payload.run(index.as_ref()))
+            .await?
+            .map_err(APIError::from)?;
+        commit(ctx, change).await?;
+        Ok(true)
+    }
+
+    /// Unset multiple bits for a property.
+    async fn unset_many(
+        &self,
+        ctx: &Context<'_>,
+        property: String,
+        bits: Vec<u32>,
+    ) -> async_graphql::Result<bool> {
+        ensure_writable(ctx)?;
+        let payload = api::UnsetMany { values: [(property, bits)].into() };
+        let change = payload.change();
+        ctx.data::<PropertyAccess>()?.check_change(&change)?;
+        spawn_write(ctx, move |index| payload.run(index.as_ref())).await?;
+        commit(ctx, change).await?;
+        Ok(true)
+    }
+}
+
+pub async fn handler_graphql(
+    Extension(schema): Extension<CribleSchema>,
+    identity: Option<Extension<Identity>>,
+    access: PropertyAccess,
+    audit: Audit,
+    cost_limit: QueryCostLimit,
+    cancellation: RequestCancellation,
+    Json(request): Json<async_graphql::Request>,
+) -> Json<async_graphql::Response> {
+    let identity: Option<Identity> = identity.map(|Extension(x)| x);
+    Json(
+        schema
+            .execute(
+                request
+                    .data(identity)
+                    .data(access)
+                    .data(audit)
+                    .data(cost_limit)
+                    .data(cancellation),
+            )
+            .await,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use crible_lib::Index;
+    use parking_lot::Mutex;
+    use rstest::*;
+    use tower::ServiceExt;
+
+    use crate::backends::{Backend, Memory};
+    use crate::executor::{ExecutorBuilder, SharedIndex};
+    use crate::server::{router, Options, State};
+
+    #[rstest]
+    #[case(r#"mutation { set(property: "foo", bit: 10) }"#)]
+    #[case(r#"mutation { setMany(property: "bar", bits: [1, 10]) }"#)]
+    #[tokio::test]
+    async fn test_mutations_reject_bits_out_of_range(#[case] query: &str) {
+        let mut initial = Index::of([("foo", vec![1, 2])]);
+        initial.set_universe(Some(10)).unwrap();
+        let index = Arc::new(SharedIndex::new(initial.clone()));
+        let backend: Box<dyn Backend> = Box::new(Memory::default());
+        let state = State::new(
+            ExecutorBuilder::new(index.clone(), Arc::new(Mutex::new(backend)))
+                .pool_size(1)
+                .build()
+                .unwrap(),
+        );
+        let options = Options { graphql: true, ..Default::default() };
+
+        let response = router(state, &options)
+            .oneshot(
+                Request::post("/graphql")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        serde_json::json!({ "query": query }).to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body["errors"][0]["message"],
+            "Bit 10 is out of range, bits must be lower than 10"
+        );
+        assert!(*index.read() == initial);
+    }
+}
@@ -0,0 +1,437 @@
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crible_api_types as api;
+use crible_lib::Cancellation;
+use tonic::{Request, Response, Status, Streaming};
+
+use super::access::PropertyAccess;
+use super::audit::{Audit, AuditLog, Auditor};
+use super::auth::{Auth, Identity, Permission};
+use super::cost::{MaxQueryCost, QueryCostLimit};
+use super::errors::APIError;
+use super::timeout::CancelOnDrop;
+use super::State;
+use crate::changes::Change;
+use crate::executor::SharedIndex;
+use crate::operations::{self, Mutate, Operation, OperationError};
+
+#[allow(unused_qualifications, clippy::all)]
+pub mod proto {
+    tonic::include_proto!("crible.v1");
+}
+
+use self::proto::crible_server::{Crible, CribleServer};
+use self::proto::mutation::Kind;
+
+/// Number of streamed mutations applied per executor task.
+static MUTATE_BATCH_SIZE: usize = 1000;
+
+impl From<APIError> for Status {
+    fn from(e: APIError) -> Self {
+        match e {
+            APIError::Operation(e) => match e {
+                OperationError::ReadOnly => {
+                    Status::permission_denied("Server is in read-only mode")
+                }
+                OperationError::Expression(_) => {
+                    Status::invalid_argument("Invalid query")
+                }
+                OperationError::Index(e) => match e {
+                    crible_lib::index::Error::PropertyDoesNotExist {
+                        property,
+                        ..
+                    } => Status::invalid_argument(format!(
+                        "Property {} does not exist",
+                        property
+                    )),
+                    crible_lib::index::Error::Cancelled => {
+                        Status::cancelled("Request was cancelled")
+                    }
+                    crible_lib::index::Error::BitOutOfRange {
+                        bit,
+                        universe,
+                    } => Status::out_of_range(format!(
+                        "Bit {} is out of range, bits must be lower than {}",
+                        bit, universe
+                    )),
+                },
+                OperationError::TooExpensive { cost, max_cost } => {
+                    Status::resource_exhausted(format!(
+                        "Query is too expensive: estimated cost is {}, \
+                         maximum is {}",
+                        cost, max_cost
+                    ))
+                }
+            },
+            APIError::TooManyRequests => Status::resource_exhausted(""),
+            APIError::Unauthorized => {
+                Status::unauthenticated("Missing or invalid token")
+            }
+            APIError::Forbidden => {
+                Status::permission_denied("Insufficient permissions")
+            }
+            e @ APIError::PropertyForbidden(_) => {
+                Status::permission_denied(e.status_and_message().1)
+            }
+            APIError::PayloadTooLarge(limit) => Status::out_of_range(format!(
+                "Request body exceeds {} bytes",
+                limit
+            )),
+            APIError::Timeout(timeout) => Status::deadline_exceeded(format!(
+                "Request timed out after {}ms",
+                timeout.as_millis()
+            )),
+            APIError::VersionMismatch { expected, actual } => Status::aborted(
+                format!("Index version is {}, expected {}", actual, expected),
+            ),
+            APIError::InvalidHeader(name) => {
+                Status::invalid_argument(format!("Invalid {} header", name))
+            }
+            e @ APIError::InvalidRecord { .. } => {
+                Status::invalid_argument(e.status_and_message().1)
+            }
+            APIError::IdempotencyKeyInUse
+            | APIError::IdempotencyKeyMismatch => {
+                Status::aborted("Idempotency key conflict")
+            }
+            APIError::ShuttingDown => {
+                Status::unavailable("Server is shutting down")
+            }
+            APIError::BackendUnavailable => Status::unavailable(
+                "Backend is unavailable, writes are rejected until pending \
+                 writes are persisted",
+            ),
+            APIError::MetadataNotFound(property) => Status::not_found(format!(
+                "No metadata for property {}",
+                property
+            )),
+            e @ APIError::ChangesUnavailable(_) => {
+                Status::failed_precondition(e.status_and_message().1)
+            }
+            APIError::Eyre(e) => {
+                tracing::error!("Unhandled error: {0:?}", e);
+                super::reporting::capture_report(&e);
+                Status::internal("")
+            }
+        }
+    }
+}
+
+#[inline]
+fn metadata<T>(request: &Request<T>, key: &str) -> Option<String> {
+    request
+        .metadata()
+        .get(key)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_owned())
+}
+
+fn authorization<T>(request: &Request<T>) -> Option<String> {
+    metadata(request, "authorization")
+}
+
+/// Bits set by the batch are checked first, see `Index::check_bits`, so that
+/// nothing is applied if one of them is out of range.
+fn apply_mutations(
+    index: &SharedIndex,
+    batch: Vec<(Kind, proto::Mutation)>,
+) -> Result<(), OperationError> {
+    let idx = index.read();
+    for (kind, mutation) in &batch {
+        if *kind == Kind::Set {
+            idx.check_bits(&mutation.bits)?;
+        }
+    }
+    index.update(|idx| {
+        for (kind, mutation) in batch {
+            match kind {
+                Kind::Set => idx.set_many(&mutation.property, &mutation.bits),
+                Kind::Unset => {
+                    idx.unset_many(&mutation.property, &mutation.bits)
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Change made by applying `batch`.
+fn batch_change(batch: &[(Kind, proto::Mutation)]) -> Change {
+    let properties =
+        batch.iter().map(|(_, m)| m.property.clone()).collect::<BTreeSet<_>>();
+    let bits = batch
+        .iter()
+        .flat_map(|(_, m)| m.bits.iter().copied())
+        .collect::<BTreeSet<_>>();
+    Change::mutation(
+        "mutate",
+        Some(properties.into_iter().collect()),
+        bits.into_iter().collect(),
+    )
+}
+
+pub struct GrpcService {
+    state: State,
+    auth: Option<Arc<Auth>>,
+    audit: Option<Auditor>,
+    max_query_cost: Option<MaxQueryCost>,
+}
+
+impl GrpcService {
+    async fn authorize(
+        &self,
+        header: Option<String>,
+        required: Permission,
+    ) -> Result<Option<Identity>, Status> {
+        match &self.auth {
+            Some(auth) => {
+                Ok(Some(auth.authorize(header.as_deref(), required).await?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Authorize a read request, returning the maximum cost of its queries
+    /// and the properties they can use.
+    async fn authorize_read<T>(
+        &self,
+        request: &Request<T>,
+    ) -> Result<(Option<u64>, PropertyAccess), Status> {
+        let identity =
+            self.authorize(authorization(request), Permission::Read).await?;
+        Ok((
+            QueryCostLimit::new(self.max_query_cost, identity.as_ref()).0,
+            PropertyAccess::new(identity.as_ref()),
+        ))
+    }
+
+    /// Authorize a request modifying the index, returning the audit context
+    /// its mutations are recorded with and the properties they can modify.
+    async fn authorize_write<T>(
+        &self,
+        request: &Request<T>,
+    ) -> Result<(Audit, PropertyAccess), Status> {
+        let identity =
+            self.authorize(authorization(request), Permission::Write).await?;
+        self.ensure_writable()?;
+        Ok((
+            Audit::new(
+                self.audit.clone(),
+                metadata(request, "x-request-id"),
+                identity.as_ref(),
+            ),
+            PropertyAccess::new(identity.as_ref()),
+        ))
+    }
+
+    fn ensure_writable(&self) -> Result<(), Status> {
+        if self.state.0.read_only {
+            Err(APIError::from(OperationError::ReadOnly).into())
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn spawn<F, T>(&self, func: F) -> Result<T, Status>
+    where
+        F: FnOnce(Arc<SharedIndex>) -> T + Send + 'static,
+        T: Sync + Send + 'static,
+    {
+        Ok(self.state.0.spawn(func).await.map_err(APIError::from)?)
+    }
+
+    async fn spawn_write<F, T>(&self, func: F) -> Result<T, Status>
+    where
+        F: FnOnce(Arc<SharedIndex>) -> T + Send + 'static,
+        T: Sync + Send + 'static,
+    {
+        Ok(self
+            .state
+            .0
+            .spawn_write(None, func)
+            .await
+            .map_err(APIError::from)?
+            .0)
+    }
+
+    async fn commit(
+        &self,
+        audit: &Audit,
+        change: Change,
+    ) -> Result<(), Status> {
+        audit.record(&change);
+        Ok(self.state.0.commit(change).await.map_err(APIError::from)?)
+    }
+
+    /// Apply and commit a batch of streamed mutations.
+    async fn apply_batch(
+        &self,
+        audit: &Audit,
+        batch: Vec<(Kind, proto::Mutation)>,
+    ) -> Result<(), Status> {
+        let change = batch_change(&batch);
+        self.spawn_write(move |index| apply_mutations(&index, batch))
+            .await?
+            .map_err(APIError::from)?;
+        self.commit(audit, change).await
+    }
+}
+
+#[tonic::async_trait]
+impl Crible for GrpcService {
+    async fn query(
+        &self,
+        request: Request<proto::QueryRequest>,
+    ) -> Result<Response<proto::QueryResponse>, Status> {
+        let (max_cost, access) = self.authorize_read(&request).await?;
+        let request = request.into_inner();
+        access.check_query(&request.query)?;
+        // Tonic drops the handler when the client goes away.
+        let guard = CancelOnDrop(Cancellation::default());
+        let payload = operations::Query {
+            request: api::Query {
+                query: request.query,
+                include_cardinalities: Some(request.include_cardinalities),
+                facets: None,
+                include_stats: None,
+                include_values: None,
+                ranks: None,
+                percentiles: None,
+            },
+            max_cost,
+            cancellation: guard.0.clone(),
+            facet_delimiter: operations::FacetDelimiter::default(),
+        };
+        let result = self
+            .spawn(move |index| payload.run(index.as_ref()))
+            .await?
+            .map_err(APIError::from)?;
+        let mut cardinalities = result.cardinalities.unwrap_or_default();
+        cardinalities.retain(|k, _| access.can_access(k));
+        Ok(Response::new(proto::QueryResponse {
+            values: result.values,
+            cardinalities,
+        }))
+    }
+
+    async fn count(
+        &self,
+        request: Request<proto::CountRequest>,
+    ) -> Result<Response<proto::CountResponse>, Status> {
+        let (max_cost, access) = self.authorize_read(&request).await?;
+        let query = request.into_inner().query;
+        access.check_query(&query)?;
+        let guard = CancelOnDrop(Cancellation::default());
+        let payload = operations::Count {
+            request: api::Count { query },
+            max_cost,
+            cancellation: guard.0.clone(),
+        };
+        let count = self
+            .spawn(move |index| payload.run(index.as_ref()))
+            .await?
+            .map_err(APIError::from)?;
+        Ok(Response::new(proto::CountResponse { count }))
+    }
+
+    async fn set(
+        &self,
+        request: Request<proto::BitRequest>,
+    ) -> Result<Response<proto::MutationResponse>, Status> {
+        let (audit, access) = self.authorize_write(&request).await?;
+        let request = request.into_inner();
+        let payload = api::Set { property: request.property, bit: request.bit };
+        let change = payload.change();
+        access.check_change(&change)?;
+        let changed = self
+            .spawn_write(move |index| payload.run(index.as_ref()))
+            .await?
+            .map_err(APIError::from)?;
+        if changed {
+            self.commit(&audit, change).await?;
+        }
+        Ok(Response::new(proto::MutationResponse { changed }))
+    }
+
+    async fn unset(
+        &self,
+        request: Request<proto::BitRequest>,
+    ) -> Result<Response<proto::MutationResponse>, Status> {
+        let (audit, access) = self.authorize_write(&request).await?;
+        let request = request.into_inner();
+        let payload =
+            api::Unset { property: request.property, bit: request.bit };
+        let change = payload.change();
+        access.check_change(&change)?;
+        let changed =
+            self.spawn_write(move |index| payload.run(index.as_ref())).await?;
+        if changed {
+            self.commit(&audit, change).await?;
+        }
+        Ok(Response::new(proto::MutationResponse { changed }))
+    }
+
+    /// Mutations are applied and committed in batches as they are received:
+    /// when the stream fails the batches before the error are kept, and
+    /// published to subscribers, while the current one is discarded.
+    async fn mutate(
+        &self,
+        request: Request<Streaming<proto::Mutation>>,
+    ) -> Result<Response<proto::MutateSummary>, Status> {
+        let (audit, access) = self.authorize_write(&request).await?;
+
+        let mut stream = request.into_inner();
+        let mut mutations: u64 = 0;
+        let mut batch = Vec::with_capacity(MUTATE_BATCH_SIZE);
+
+        while let Some(mutation) = stream.message().await? {
+            let kind = Kind::from_i32(mutation.kind).ok_or_else(|| {
+                Status::invalid_argument(format!(
+                    "Invalid mutation kind {}",
+                    mutation.kind
+                ))
+            })?;
+            access.check([mutation.property.as_str()])?;
+            batch.push((kind, mutation));
+            mutations += 1;
+            if batch.len() >= MUTATE_BATCH_SIZE {
+                let chunk = std::mem::replace(
+                    &mut batch,
+                    Vec::with_capacity(MUTATE_BATCH_SIZE),
+                );
+                self.apply_batch(&audit, chunk).await?;
+            }
+        }
+
+        if !batch.is_empty() {
+            self.apply_batch(&audit, batch).await?;
+        }
+
+        Ok(Response::new(proto::MutateSummary { mutations }))
+    }
+}
+
+pub async fn run(
+    addr: &SocketAddr,
+    state: State,
+    auth: Option<Arc<Auth>>,
+    audit: Option<Arc<AuditLog>>,
+    max_query_cost: Option<u64>,
+) -> eyre::Result<()> {
+    let audit = audit.map(|log| Auditor { log, tenant: None });
+    tonic::transport::Server::builder()
+        .add_service(CribleServer::new(GrpcService {
+            state,
+            auth,
+            audit,
+            max_query_cost: max_query_cost.map(MaxQueryCost),
+        }))
+        .serve_with_shutdown(
+            *addr,
+            crate::utils::shutdown_signal("gRPC server task"),
+        )
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,322 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::body::{boxed, Body, Bytes, Full};
+use axum::extract::State as ExtractState;
+use axum::http::header::HeaderName;
+use axum::http::{HeaderMap, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+
+use super::auth::Identity;
+use super::errors::APIError;
+
+static IDEMPOTENCY_KEY: &str = "idempotency-key";
+/// Set on responses replayed from the cache.
+static IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+static MAX_KEY_LENGTH: usize = 255;
+
+#[derive(Debug, Clone)]
+pub struct IdempotencyOptions {
+    /// Maximum number of responses kept, the oldest ones are evicted first.
+    pub capacity: usize,
+    /// How long responses are kept.
+    pub ttl: Duration,
+}
+
+#[derive(Clone)]
+struct StoredResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+enum Entry {
+    InFlight {
+        fingerprint: [u8; 32],
+        inserted_at: Instant,
+    },
+    Done {
+        fingerprint: [u8; 32],
+        inserted_at: Instant,
+        response: StoredResponse,
+    },
+}
+
+impl Entry {
+    fn inserted_at(&self) -> Instant {
+        match self {
+            Entry::InFlight { inserted_at, .. }
+            | Entry::Done { inserted_at, .. } => *inserted_at,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<String, Entry>,
+    /// Keys in insertion order, used for eviction.
+    order: VecDeque<(String, Instant)>,
+}
+
+impl Entries {
+    /// Remove the oldest entry. Keys are only removed when the entry is the
+    /// one which was queued, as it may have been replaced since.
+    fn pop_oldest(&mut self) {
+        if let Some((key, inserted_at)) = self.order.pop_front() {
+            if self.by_key.get(&key).map(|e| e.inserted_at())
+                == Some(inserted_at)
+            {
+                self.by_key.remove(&key);
+            }
+        }
+    }
+}
+
+/// Bounded cache of responses to write requests by idempotency key, so that
+/// retried requests are not applied twice.
+pub struct IdempotencyCache {
+    options: IdempotencyOptions,
+    entries: Mutex<Entries>,
+}
+
+enum Lookup {
+    Proceed,
+    Replay(StoredResponse),
+}
+
+impl IdempotencyCache {
+    pub fn new(options: IdempotencyOptions) -> Self {
+        Self { options, entries: Mutex::new(Default::default()) }
+    }
+
+    fn lookup(
+        &self,
+        key: &str,
+        fingerprint: [u8; 32],
+        now: Instant,
+    ) -> Result<Lookup, APIError> {
+        let mut entries = self.entries.lock();
+
+        while let Some((_, inserted_at)) = entries.order.front() {
+            if now.duration_since(*inserted_at) < self.options.ttl {
+                break;
+            }
+            entries.pop_oldest();
+        }
+
+        match entries.by_key.get(key) {
+            Some(Entry::InFlight { .. }) => Err(APIError::IdempotencyKeyInUse),
+            Some(Entry::Done { fingerprint: f, response, .. }) => {
+                if *f == fingerprint {
+                    Ok(Lookup::Replay(response.clone()))
+                } else {
+                    Err(APIError::IdempotencyKeyMismatch)
+                }
+            }
+            None => {
+                while entries.by_key.len() >= self.options.capacity
+                    && !entries.order.is_empty()
+                {
+                    entries.pop_oldest();
+                }
+                entries.by_key.insert(
+                    key.to_owned(),
+                    Entry::InFlight { fingerprint, inserted_at: now },
+                );
+                entries.order.push_back((key.to_owned(), now));
+                Ok(Lookup::Proceed)
+            }
+        }
+    }
+
+    /// Record the response for an in flight request. Server errors are not
+    /// stored so that the request can be retried.
+    fn complete(&self, key: &str, response: Option<StoredResponse>) {
+        let mut entries = self.entries.lock();
+        match (entries.by_key.remove(key), response) {
+            (Some(Entry::InFlight { fingerprint, inserted_at }), Some(r)) => {
+                entries.by_key.insert(
+                    key.to_owned(),
+                    Entry::Done { fingerprint, inserted_at, response: r },
+                );
+            }
+            // The key expired and was reused while this request was in
+            // flight, keep the newer response.
+            (Some(entry @ Entry::Done { .. }), _) => {
+                entries.by_key.insert(key.to_owned(), entry);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn fingerprint<B>(request: &Request<B>, body: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(request.method().as_str());
+    hasher.update(b" ");
+    hasher.update(request.uri().path());
+    hasher.update(b"\n");
+    hasher.update(body);
+    hasher.finalize().into()
+}
+
+fn replay(stored: StoredResponse) -> Response {
+    let mut response = Response::new(boxed(Full::from(stored.body)));
+    *response.status_mut() = stored.status;
+    *response.headers_mut() = stored.headers;
+    response.headers_mut().insert(
+        HeaderName::from_static(IDEMPOTENT_REPLAYED),
+        HeaderValue::from_static("true"),
+    );
+    response
+}
+
+/// Replay the stored response for write requests carrying an already seen
+/// `Idempotency-Key` header instead of applying them again. Keys are scoped
+/// to the authenticated subject, if any, and reusing a key for a different
+/// request is rejected.
+pub async fn idempotency(
+    ExtractState(cache): ExtractState<Arc<IdempotencyCache>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, APIError> {
+    let key = match request.headers().get(IDEMPOTENCY_KEY) {
+        None => return Ok(next.run(request).await),
+        Some(value) => match value.to_str() {
+            Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => {
+                key.to_owned()
+            }
+            _ => return Err(APIError::InvalidHeader("Idempotency-Key")),
+        },
+    };
+
+    let key = match request
+        .extensions()
+        .get::<Identity>()
+        .and_then(|i| i.subject.as_ref())
+    {
+        Some(subject) => format!("{}:{}", subject, key),
+        None => format!(":{}", key),
+    };
+
+    let (parts, body) = request.into_parts();
+    let body = hyper::body::to_bytes(body)
+        .await
+        .map_err(|e| APIError::Eyre(eyre::Report::new(e)))?;
+    let request = Request::from_parts(parts, Body::from(body.clone()));
+
+    match cache.lookup(&key, fingerprint(&request, &body), Instant::now())? {
+        Lookup::Replay(stored) => return Ok(replay(stored)),
+        Lookup::Proceed => {}
+    }
+
+    let response = next.run(request).await;
+
+    if response.status().is_server_error() {
+        cache.complete(&key, None);
+        return Ok(response);
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => {
+            cache.complete(&key, None);
+            return Err(APIError::Eyre(eyre::Report::new(e)));
+        }
+    };
+    cache.complete(
+        &key,
+        Some(StoredResponse {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+        }),
+    );
+    Ok(Response::from_parts(parts, boxed(Full::from(body))))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use axum::body::Bytes;
+    use axum::http::{HeaderMap, StatusCode};
+
+    use super::{IdempotencyCache, IdempotencyOptions, Lookup, StoredResponse};
+    use crate::server::errors::APIError;
+
+    fn stored() -> StoredResponse {
+        StoredResponse {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+        }
+    }
+
+    #[test]
+    fn test_cache() {
+        let cache = IdempotencyCache::new(IdempotencyOptions {
+            capacity: 2,
+            ttl: Duration::from_secs(60),
+        });
+        let now = Instant::now();
+
+        assert!(matches!(cache.lookup("a", [0; 32], now), Ok(Lookup::Proceed)));
+        assert!(matches!(
+            cache.lookup("a", [0; 32], now),
+            Err(APIError::IdempotencyKeyInUse)
+        ));
+
+        cache.complete("a", Some(stored()));
+        assert!(matches!(
+            cache.lookup("a", [0; 32], now),
+            Ok(Lookup::Replay(_))
+        ));
+        assert!(matches!(
+            cache.lookup("a", [1; 32], now),
+            Err(APIError::IdempotencyKeyMismatch)
+        ));
+
+        // Failed requests can be retried.
+        assert!(matches!(cache.lookup("b", [0; 32], now), Ok(Lookup::Proceed)));
+        cache.complete("b", None);
+        assert!(matches!(cache.lookup("b", [0; 32], now), Ok(Lookup::Proceed)));
+        cache.complete("b", Some(stored()));
+
+        // Expired entries are evicted.
+        let later = now + Duration::from_secs(61);
+        assert!(matches!(
+            cache.lookup("a", [1; 32], later),
+            Ok(Lookup::Proceed)
+        ));
+    }
+
+    #[test]
+    fn test_cache_capacity() {
+        let cache = IdempotencyCache::new(IdempotencyOptions {
+            capacity: 2,
+            ttl: Duration::from_secs(60),
+        });
+        let now = Instant::now();
+
+        for key in ["a", "b", "c"] {
+            assert!(matches!(
+                cache.lookup(key, [0; 32], now),
+                Ok(Lookup::Proceed)
+            ));
+            cache.complete(key, Some(stored()));
+        }
+
+        // "a" was evicted to make room for "c".
+        assert!(matches!(cache.lookup("a", [1; 32], now), Ok(Lookup::Proceed)));
+        assert!(matches!(
+            cache.lookup("c", [0; 32], now),
+            Ok(Lookup::Replay(_))
+        ));
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::io;
+
+use axum::extract::{BodyStream, State as ExtractState};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, StatusCode};
+#[cfg(feature = "parquet")]
+use axum::Extension;
+use axum::Json;
+use crible_api_types::{IngestRecord, IngestResult};
+use crible_lib::expression::validate_property_name;
+use futures_util::TryStreamExt;
+use tokio::io::AsyncBufReadExt;
+use tokio_util::io::StreamReader;
+
+use super::access::PropertyAccess;
+use super::api::JSONAPIResult;
+use super::audit::Audit;
+use super::errors::APIError;
+#[cfg(feature = "parquet")]
+use super::limits::{BufferedBodyLimit, DEFAULT_MAX_BODY_SIZE};
+use super::State;
+use crate::operations::{self, Mutate, Operation};
+
+/// Number of values after which pending records are applied. The write lock
+/// is released between chunks so that other writes are not blocked for the
+/// whole duration of the request.
+static CHUNK_SIZE: usize = 1_000_000;
+
+#[cfg(feature = "parquet")]
+static PARQUET: &str = "application/vnd.apache.parquet";
+
+/// Accumulates records and applies them in chunks, without flushing.
+struct Ingest<'a> {
+    state: &'a State,
+    audit: &'a Audit,
+    access: &'a PropertyAccess,
+    chunk: HashMap<String, Vec<u32>>,
+    chunk_records: u64,
+    chunk_values: usize,
+    summary: IngestResult,
+}
+
+impl<'a> Ingest<'a> {
+    fn new(
+        state: &'a State,
+        audit: &'a Audit,
+        access: &'a PropertyAccess,
+    ) -> Self {
+        Self {
+            state,
+            audit,
+            access,
+            chunk: HashMap::new(),
+            chunk_records: 0,
+            chunk_values: 0,
+            summary: IngestResult::default(),
+        }
+    }
+
+    fn invalid(&self, message: String) -> APIError {
+        APIError::InvalidRecord {
+            record: self.summary.records + self.chunk_records + 1,
+            applied: self.summary.records,
+            message,
+        }
+    }
+
+    async fn push(&mut self, record: IngestRecord) -> Result<(), APIError> {
+        if !validate_property_name(&record.property) {
+            return Err(
+                self.invalid(format!("invalid property {:?}", record.property))
+            );
+        }
+        if !self.access.can_access(&record.property) {
+            return Err(self.invalid(format!(
+                "access to property {:?} is not allowed",
+                record.property
+            )));
+        }
+        self.chunk_records += 1;
+        self.chunk_values += record.values.len();
+        self.chunk.entry(record.property).or_default().extend(record.values);
+        if self.chunk_values >= CHUNK_SIZE {
+            self.apply().await?;
+        }
+        Ok(())
+    }
+
+    async fn apply(&mut self) -> Result<(), APIError> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+
+        let payload = operations::Ingest(std::mem::take(&mut self.chunk));
+        let change = payload.change();
+        let (result, version) = self
+            .state
+            .0
+            .spawn_write(None, move |index| payload.run(index.as_ref()))
+            .await?;
+        result?;
+        self.audit.record(&change);
+        self.state.0.stage(change);
+
+        self.summary.records += self.chunk_records;
+        self.summary.values += self.chunk_values as u64;
+        self.summary.chunks += 1;
+        self.summary.version = version;
+        self.chunk_records = 0;
+        self.chunk_values = 0;
+        tracing::debug!(
+            records = self.summary.records,
+            values = self.summary.values,
+            "Ingested chunk {}",
+            self.summary.chunks,
+        );
+        Ok(())
+    }
+
+    /// Apply the last chunk unless ingestion failed and flush everything
+    /// applied so far.
+    async fn finish(
+        mut self,
+        result: Result<(), APIError>,
+    ) -> Result<IngestResult, APIError> {
+        let result = match result {
+            Ok(()) => self.apply().await,
+            Err(e) => Err(e),
+        };
+        if self.summary.chunks > 0 {
+            self.state.0.flush().await?;
+        }
+        result.map(|()| self.summary)
+    }
+}
+
+async fn ingest_ndjson(
+    ingest: &mut Ingest<'_>,
+    body: BodyStream,
+) -> Result<(), APIError> {
+    let reader = StreamReader::new(
+        body.map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+    );
+    let mut lines = reader.lines();
+    while let Some(line) =
+        lines.next_line().await.map_err(|e| match e.kind() {
+            // The line is not valid UTF-8.
+            io::ErrorKind::InvalidData => ingest.invalid(e.to_string()),
+            _ => APIError::Eyre(
+                eyre::Report::new(e).wrap_err("Failed to read request body"),
+            ),
+        })?
+    {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str::<IngestRecord>(&line)
+            .map_err(|e| ingest.invalid(e.to_string()))?;
+        ingest.push(record).await?;
+    }
+    Ok(())
+}
+
+/// Parquet files cannot be read as a stream, the body is buffered up to
+/// `limit` bytes and decoded on a blocking thread while the records are
+/// being applied.
+#[cfg(feature = "parquet")]
+async fn ingest_parquet(
+    ingest: &mut Ingest<'_>,
+    mut body: BodyStream,
+    limit: usize,
+) -> Result<(), APIError> {
+    use axum::body::Bytes;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let mut buffer = Vec::new();
+    while let Some(chunk) = body.try_next().await.map_err(|e| {
+        eyre::Report::new(e).wrap_err("Failed to read request body")
+    })? {
+        if buffer.len() + chunk.len() > limit {
+            return Err(APIError::PayloadTooLarge(limit));
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+    let bytes = Bytes::from(buffer);
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+    let decode = tokio::task::spawn_blocking(move || -> Result<(), String> {
+        let reader = ParquetRecordBatchReaderBuilder::try_new(bytes)
+            .and_then(|builder| builder.build())
+            .map_err(|e| e.to_string())?;
+        for batch in reader {
+            let batch = batch.map_err(|e| e.to_string())?;
+            let records = crible_lib::arrow::records(&batch)
+                .map_err(|e| e.to_string())?;
+            if tx.blocking_send(records).is_err() {
+                // The request failed while applying a previous batch.
+                break;
+            }
+        }
+        Ok(())
+    });
+
+    while let Some(records) = rx.recv().await {
+        for (property, values) in records {
+            ingest.push(IngestRecord { property, values }).await?;
+        }
+    }
+    decode.await.map_err(eyre::Report::new)?.map_err(|e| ingest.invalid(e))
+}
+
+/// Set values from a streamed body of records, `application/x-ndjson` of
+/// `IngestRecord` by default. With the `parquet` feature, Parquet files with
+/// a `property` and `values` column are accepted as well. They are buffered
+/// whole and limited to the route's body size override, or the default
+/// maximum body size.
+///
+/// Records are applied in chunks as they are read and flushed once at the
+/// end. If a record is invalid, the chunks applied before it are kept.
+pub async fn handler_ingest(
+    ExtractState(state): ExtractState<State>,
+    access: PropertyAccess,
+    audit: Audit,
+    #[cfg(feature = "parquet")] limit: Option<Extension<BufferedBodyLimit>>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> JSONAPIResult<IngestResult> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|hv| hv.to_str().ok())
+        .and_then(|hv| hv.split(';').next())
+        .map(|hv| hv.trim());
+
+    let mut ingest = Ingest::new(&state, &audit, &access);
+    let result = match content_type {
+        None | Some("application/x-ndjson" | "application/json") => {
+            ingest_ndjson(&mut ingest, body).await
+        }
+        #[cfg(feature = "parquet")]
+        Some(content_type) if content_type == PARQUET => {
+            let limit =
+                limit.map_or(DEFAULT_MAX_BODY_SIZE, |Extension(limit)| limit.0);
+            ingest_parquet(&mut ingest, body, limit).await
+        }
+        Some(_) => Err(APIError::InvalidHeader("Content-Type")),
+    };
+    Ok((StatusCode::OK, Json(ingest.finish(result).await?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use crible_api_types::IngestResult;
+    use crible_lib::Index;
+    use parking_lot::Mutex;
+    use rstest::*;
+    use tower::ServiceExt;
+
+    use crate::backends::{Backend, Memory};
+    use crate::executor::{ExecutorBuilder, SharedIndex};
+    use crate::server::{router, BodyLimits, Options, State};
+
+    async fn ingest(body: &'static str) -> (StatusCode, Vec<u8>, Index) {
+        ingest_with(&Options::default(), "application/x-ndjson", body).await
+    }
+
+    async fn ingest_with(
+        options: &Options,
+        content_type: &str,
+        body: &'static str,
+    ) -> (StatusCode, Vec<u8>, Index) {
+        let mut initial = Index::of([("foo", vec![1])]);
+        initial.set_universe(Some(1000)).unwrap();
+        let index = Arc::new(SharedIndex::new(initial));
+        let backend: Box<dyn Backend> = Box::new(Memory::default());
+        let state = State::new(
+            ExecutorBuilder::new(index.clone(), Arc::new(Mutex::new(backend)))
+                .pool_size(1)
+                .build()
+                .unwrap(),
+        );
+        let response = router(state, options)
+            .oneshot(
+                Request::post("/ingest")
+                    .header("content-type", content_type)
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let mut index = Index::clone(&index.read());
+        index.set_universe(None).unwrap();
+        (status, body.to_vec(), index)
+    }
+
+    #[tokio::test]
+    async fn test_ingest() {
+        let (status, body, index) = ingest(
+            r#"{"property": "foo", "values": [2, 3]}
+
+{"property": "bar", "values": [1]}
+{"property": "foo", "values": [4]}
+"#,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            serde_json::from_slice::<IngestResult>(&body).unwrap(),
+            IngestResult { records: 3, values: 4, chunks: 1, version: 1 }
+        );
+        assert_eq!(
+            index,
+            Index::of([("foo", vec![1, 2, 3, 4]), ("bar", vec![1])])
+        );
+    }
+
+    #[rstest]
+    #[case(
+        r#"{"property": "foo", "values": [2]}
+{"property": "foo"}"#,
+        "invalid_record"
+    )]
+    #[case(r#"{"property": "foo bar", "values": [2]}"#, "invalid_record")]
+    #[case(
+        r#"{"property": "foo", "values": [2]}
+{"property": "bar", "values": [1000]}"#,
+        "bit_out_of_range"
+    )]
+    #[tokio::test]
+    async fn test_ingest_invalid(
+        #[case] body: &'static str,
+        #[case] code: &str,
+    ) {
+        let (status, body, index) = ingest(body).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], code);
+        // Records are applied in a single chunk here.
+        assert_eq!(index, Index::of([("foo", vec![1])]));
+    }
+
+    #[cfg(feature = "parquet")]
+    #[tokio::test]
+    async fn test_ingest_parquet_too_large() {
+        let options = Options {
+            body_limits: BodyLimits::new(16, &[]),
+            ..Default::default()
+        };
+        let (status, _, index) =
+            ingest_with(&options, super::PARQUET, "not a parquet file at all")
+                .await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(index, Index::of([("foo", vec![1])]));
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::extract::State as ExtractState;
+use axum::http::header::CONTENT_LENGTH;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use http_body::{LengthLimitError, Limited};
+
+use super::errors::APIError;
+
+/// Default maximum request body size, matching axum's own default.
+pub static DEFAULT_MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// Parse a human friendly byte size such as `512`, `64K`, `2M` or `1G` (using
+/// binary multiples).
+pub fn parse_byte_size(value: &str) -> eyre::Result<usize> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.chars().last() {
+        Some('k' | 'K') => (&value[..value.len() - 1], 1024),
+        Some('m' | 'M') => (&value[..value.len() - 1], 1024 * 1024),
+        Some('g' | 'G') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    digits
+        .trim()
+        .parse::<usize>()
+        .map_err(|_| eyre::Report::msg(format!("Invalid size {:?}", value)))?
+        .checked_mul(multiplier)
+        .ok_or_else(|| eyre::Report::msg(format!("Size {:?} too large", value)))
+}
+
+/// Per route override of the maximum body size, parsed from `<path>=<size>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteBodyLimit {
+    pub path: String,
+    pub limit: usize,
+}
+
+impl FromStr for RouteBodyLimit {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once('=') {
+            Some((path, size)) if path.starts_with('/') => Ok(Self {
+                path: path.to_owned(),
+                limit: parse_byte_size(size)?,
+            }),
+            _ => Err(eyre::Report::msg(format!(
+                "Invalid route body limit {:?}, expected <path>=<size>",
+                value
+            ))),
+        }
+    }
+}
+
+/// Routes reading their body as a stream, they are not subject to the
+/// default limit and their body is never buffered by `limit_body`.
+static STREAMING_ROUTES: &[&str] = &["/ingest"];
+
+/// Whether `path` is one of `STREAMING_ROUTES`, possibly under a tenant.
+pub fn is_streaming(path: &str) -> bool {
+    STREAMING_ROUTES.iter().any(|route| path.ends_with(route))
+}
+
+/// Maximum size of the body of a streaming route when it has to be buffered
+/// anyway, e.g. Parquet files sent to `/ingest`. Added to the request by
+/// `limit_body`, the route override if any or the default limit otherwise.
+#[derive(Debug, Clone, Copy)]
+pub struct BufferedBodyLimit(pub usize);
+
+#[derive(Debug, Clone)]
+pub struct BodyLimits {
+    pub default: usize,
+    pub routes: HashMap<String, usize>,
+    /// Names of the tenants, whose routes get the same limits as the default
+    /// index's unless overridden with their full path.
+    pub tenants: HashSet<String>,
+}
+
+impl Default for BodyLimits {
+    fn default() -> Self {
+        Self {
+            default: DEFAULT_MAX_BODY_SIZE,
+            routes: HashMap::new(),
+            tenants: HashSet::new(),
+        }
+    }
+}
+
+impl BodyLimits {
+    pub fn new(default: usize, routes: &[RouteBodyLimit]) -> Self {
+        Self {
+            default,
+            routes: routes.iter().map(|r| (r.path.clone(), r.limit)).collect(),
+            tenants: HashSet::new(),
+        }
+    }
+
+    pub fn with_tenants<I, S>(mut self, tenants: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.tenants = tenants.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Override for `path`, or for the route it is served by when it is
+    /// under a tenant.
+    fn route_limit(&self, path: &str) -> Option<usize> {
+        self.routes
+            .get(path)
+            .or_else(|| {
+                let (tenant, _) = path.get(1..)?.split_once('/')?;
+                if !self.tenants.contains(tenant) {
+                    return None;
+                }
+                self.routes.get(&path[tenant.len() + 1..])
+            })
+            .copied()
+    }
+
+    fn limit_for(&self, path: &str) -> Option<usize> {
+        match self.route_limit(path) {
+            Some(limit) => Some(limit),
+            None if is_streaming(path) => None,
+            None => Some(self.default),
+        }
+    }
+
+    fn buffered_limit_for(&self, path: &str) -> usize {
+        self.route_limit(path).unwrap_or(self.default)
+    }
+}
+
+/// Reject requests whose body is larger than the configured limit for the
+/// route with a 413 before the body is deserialized. Requests advertising
+/// their size through `Content-Length` are rejected without reading the body,
+/// others are buffered up to the limit.
+pub async fn limit_body(
+    ExtractState(limits): ExtractState<Arc<BodyLimits>>,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, APIError> {
+    if is_streaming(request.uri().path()) {
+        let limit = limits.buffered_limit_for(request.uri().path());
+        request.extensions_mut().insert(BufferedBodyLimit(limit));
+    }
+
+    let limit = match limits.limit_for(request.uri().path()) {
+        Some(limit) => limit,
+        None => return Ok(next.run(request).await),
+    };
+
+    let content_length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|hv| hv.to_str().ok())
+        .and_then(|hv| hv.parse::<u64>().ok());
+
+    let request = match content_length {
+        Some(length) if length > limit as u64 => {
+            return Err(APIError::PayloadTooLarge(limit));
+        }
+        // hyper ensures the body matches the advertised length.
+        Some(_) => request,
+        None => {
+            let (parts, body) = request.into_parts();
+            let bytes = hyper::body::to_bytes(Limited::new(body, limit))
+                .await
+                .map_err(|e| {
+                    if e.downcast_ref::<LengthLimitError>().is_some() {
+                        APIError::PayloadTooLarge(limit)
+                    } else {
+                        APIError::Eyre(eyre::Report::msg(e.to_string()))
+                    }
+                })?;
+            Request::from_parts(parts, Body::from(bytes))
+        }
+    };
+
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use crible_lib::Index;
+    use parking_lot::Mutex;
+    use rstest::*;
+    use tower::ServiceExt;
+
+    use super::{
+        parse_byte_size, BodyLimits, RouteBodyLimit, DEFAULT_MAX_BODY_SIZE,
+    };
+    use crate::backends::{Backend, Memory};
+    use crate::executor::{ExecutorBuilder, SharedIndex};
+    use crate::server::{router, Options, State, Tenant};
+
+    #[rstest]
+    #[case("512", 512)]
+    #[case("64K", 64 * 1024)]
+    #[case("2m", 2 * 1024 * 1024)]
+    #[case("1G", 1024 * 1024 * 1024)]
+    fn test_parse_byte_size(#[case] value: &str, #[case] expected: usize) {
+        assert_eq!(parse_byte_size(value).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case("")]
+    #[case("M")]
+    #[case("12T")]
+    #[case("-1")]
+    fn test_parse_byte_size_invalid(#[case] value: &str) {
+        assert!(parse_byte_size(value).is_err());
+    }
+
+    #[test]
+    fn test_route_body_limit() {
+        assert_eq!(
+            "/set-many=64M".parse::<RouteBodyLimit>().unwrap(),
+            RouteBodyLimit { path: "/set-many".to_owned(), limit: 64 << 20 },
+        );
+        assert!("set-many=64M".parse::<RouteBodyLimit>().is_err());
+        assert!("/set-many".parse::<RouteBodyLimit>().is_err());
+    }
+
+    #[rstest]
+    #[case("/set-many", Some(1024))]
+    #[case("/query", Some(512))]
+    #[case("/ingest", None)]
+    #[case("/tenant/ingest", None)]
+    #[case("/tenant/set-many", Some(1024))]
+    #[case("/tenant/query", Some(512))]
+    #[case("/unknown/set-many", Some(512))]
+    #[case("/other/ingest", Some(2048))]
+    fn test_limit_for(#[case] path: &str, #[case] expected: Option<usize>) {
+        let limits = BodyLimits::new(
+            512,
+            &[
+                "/set-many=1K".parse().unwrap(),
+                "/other/ingest=2K".parse().unwrap(),
+            ],
+        )
+        .with_tenants(["tenant", "other"]);
+        assert_eq!(limits.limit_for(path), expected);
+    }
+
+    #[rstest]
+    #[case("/set-many")]
+    #[case("/acme/set-many")]
+    #[tokio::test]
+    async fn test_limit_body_tenant(#[case] path: &str) {
+        let state = || {
+            let backend: Box<dyn Backend> = Box::new(Memory::default());
+            State::new(
+                ExecutorBuilder::new(
+                    Arc::new(SharedIndex::new(Index::default())),
+                    Arc::new(Mutex::new(backend)),
+                )
+                .pool_size(1)
+                .build()
+                .unwrap(),
+            )
+        };
+        let options = Options {
+            body_limits: BodyLimits::new(
+                DEFAULT_MAX_BODY_SIZE,
+                &["/set-many=16".parse().unwrap()],
+            ),
+            tenants: vec![Tenant {
+                name: "acme".to_owned(),
+                state: state(),
+                auth: None,
+                refresh: None,
+            }],
+            ..Default::default()
+        };
+        let response = router(state(), &options)
+            .oneshot(
+                Request::post(path)
+                    .header("content-type", "application/json")
+                    .body(Body::from(r#"{"foo": [1, 2, 3, 4, 5, 6]}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
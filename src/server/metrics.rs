@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::extract::{Query, State as ExtractState};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use crible_api_types as api;
+
+use super::errors::APIError;
+use super::State;
+use crate::executor::{ExecutorStats, LaneStats};
+use crate::metrics::HistogramSnapshot;
+use crate::operations::Operation;
+use crate::statsd::Statsd;
+
+type Gauge = fn(&LaneStats) -> u64;
+type Histogram = fn(&LaneStats) -> &HistogramSnapshot;
+
+static GAUGES: [(&str, &str, &str, Gauge); 7] = [
+    ("pool_size", "gauge", "Number of threads.", |s| s.pool_size as u64),
+    ("queue_size", "gauge", "Maximum number of queued tasks.", |s| {
+        s.queue_size as u64
+    }),
+    ("queued", "gauge", "Tasks running or waiting for a thread.", |s| {
+        s.queued as u64
+    }),
+    ("available", "gauge", "Remaining spots in the queue.", |s| {
+        s.available as u64
+    }),
+    ("executed_total", "counter", "Tasks run to completion.", |s| s.executed),
+    (
+        "rejected_total",
+        "counter",
+        "Tasks rejected as the queue was full.",
+        |s| s.rejected,
+    ),
+    (
+        "abandoned_total",
+        "counter",
+        "Tasks skipped as their caller went away before they started.",
+        |s| s.abandoned,
+    ),
+];
+
+static HISTOGRAMS: [(&str, &str, Histogram); 2] = [
+    (
+        "wait_seconds",
+        "Time between submitting a task and it starting to run.",
+        |s| &s.wait_time,
+    ),
+    ("run_seconds", "Time spent running tasks.", |s| &s.run_time),
+];
+
+fn write_histogram(
+    out: &mut String,
+    name: &str,
+    lane: &str,
+    histogram: &HistogramSnapshot,
+) {
+    for bucket in &histogram.buckets {
+        let _ = writeln!(
+            out,
+            "{}_bucket{{lane=\"{}\",le=\"{}\"}} {}",
+            name, lane, bucket.le, bucket.count
+        );
+    }
+    let _ = writeln!(
+        out,
+        "{}_bucket{{lane=\"{}\",le=\"+Inf\"}} {}",
+        name, lane, histogram.count
+    );
+    let _ =
+        writeln!(out, "{}_sum{{lane=\"{}\"}} {}", name, lane, histogram.sum);
+    let _ = writeln!(
+        out,
+        "{}_count{{lane=\"{}\"}} {}",
+        name, lane, histogram.count
+    );
+}
+
+/// Render executor stats in the Prometheus text format.
+fn render(stats: &ExecutorStats) -> String {
+    let lanes = [("read", &stats.read), ("write", &stats.write)];
+    let mut out = String::new();
+
+    for (name, kind, help, value) in GAUGES {
+        let _ = writeln!(out, "# HELP crible_executor_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE crible_executor_{} {}", name, kind);
+        for (lane, s) in lanes {
+            let _ = writeln!(
+                out,
+                "crible_executor_{}{{lane=\"{}\"}} {}",
+                name,
+                lane,
+                value(s)
+            );
+        }
+    }
+
+    for (name, help, histogram) in HISTOGRAMS {
+        let name = format!("crible_executor_{}", name);
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (lane, s) in lanes {
+            write_histogram(&mut out, &name, lane, histogram(s));
+        }
+    }
+
+    out
+}
+
+/// Metrics in the Prometheus text format.
+pub async fn handler_metrics(
+    ExtractState(state): ExtractState<State>,
+) -> impl IntoResponse {
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], render(&state.0.stats()))
+}
+
+/// Executor queues and timings, useful to tune `--threads` and
+/// `--queue-size`.
+pub async fn handler_executor(
+    ExtractState(state): ExtractState<State>,
+) -> Json<ExecutorStats> {
+    Json(state.0.stats())
+}
+
+/// Storage of each property by container type, e.g. to find properties
+/// which would benefit from run optimization, see `api::ContainerStats`.
+pub async fn handler_bitmap_stats(
+    ExtractState(state): ExtractState<State>,
+    Query(payload): Query<api::BitmapStatsRequest>,
+) -> Result<Json<api::BitmapStatsResult>, APIError> {
+    let stats = state.0.spawn(move |index| payload.run(index.as_ref())).await?;
+    Ok(Json(stats))
+}
+
+/// Send the duration of every request to StatsD, tagged with its method,
+/// route and status.
+pub async fn record_request<B>(
+    ExtractState(statsd): ExtractState<Arc<Statsd>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let start = Instant::now();
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+    let response = next.run(request).await;
+    // Keep the number of distinct tags bounded.
+    let route = match response.status() {
+        StatusCode::NOT_FOUND => "unmatched",
+        _ => &path,
+    };
+    statsd.timing(
+        "request.duration",
+        start.elapsed(),
+        &[
+            ("method", method.as_str()),
+            ("route", route),
+            ("status", response.status().as_str()),
+        ],
+    );
+    response
+}
+
+/// Periodically push executor stats to StatsD. Counters are sent as the
+/// increase since the previous report.
+pub async fn run_statsd_task(
+    state: State,
+    statsd: Arc<Statsd>,
+    every: Duration,
+) {
+    let mut interval = tokio::time::interval(every);
+    let mut previous = HashMap::<(&str, &str), u64>::new();
+
+    loop {
+        tokio::select! {
+            _ = crate::utils::shutdown_signal("StatsD task") => {
+                break;
+            },
+            _ = interval.tick() => {},
+        }
+
+        let stats = state.0.stats();
+        for (lane, s) in [("read", &stats.read), ("write", &stats.write)] {
+            let tags = [("lane", lane)];
+            for (name, kind, _, value) in GAUGES {
+                let metric = format!(
+                    "executor.{}",
+                    name.strip_suffix("_total").unwrap_or(name)
+                );
+                let value = value(s);
+                if kind == "counter" {
+                    let last =
+                        previous.insert((name, lane), value).unwrap_or(0);
+                    statsd.count(&metric, value.saturating_sub(last), &tags);
+                } else {
+                    statsd.gauge(&metric, value, &tags);
+                }
+            }
+        }
+        statsd.gauge("pending_writes", state.0.pending_writes() as u64, &[]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crible_lib::Index;
+    use parking_lot::Mutex;
+
+    use super::render;
+    use crate::backends::{Backend, Memory};
+    use crate::executor::{ExecutorBuilder, SharedIndex};
+
+    #[tokio::test]
+    async fn test_render() {
+        let backend: Box<dyn Backend> = Box::new(Memory::default());
+        let executor = ExecutorBuilder::new(
+            Arc::new(SharedIndex::new(Index::default())),
+            Arc::new(Mutex::new(backend)),
+        )
+        .pool_size(2)
+        .build()
+        .unwrap();
+        executor.spawn(|_| ()).await.unwrap();
+
+        let rendered = render(&executor.stats());
+
+        assert!(
+            rendered.contains("crible_executor_pool_size{lane=\"read\"} 2")
+        );
+        assert!(
+            rendered
+                .contains("crible_executor_executed_total{lane=\"read\"} 1")
+        );
+        assert!(
+            rendered
+                .contains("crible_executor_executed_total{lane=\"write\"} 0")
+        );
+        assert!(rendered.contains(
+            "crible_executor_run_seconds_bucket{lane=\"read\",le=\"+Inf\"} 1"
+        ));
+        assert!(rendered.contains("crible_executor_wait_seconds_count"));
+    }
+}
@@ -1,13 +1,18 @@
-use std::net::SocketAddr;
+use std::net::TcpListener;
 use std::sync::Arc;
 use std::time::Duration;
 
+use axum::extract::DefaultBodyLimit;
 use axum::http::header::HeaderName;
 use axum::http::Request;
 use axum::response::Response;
-use axum::routing::{get, post};
-use axum::{Router, Server};
+use axum::routing::{get, post, put};
+use axum::{middleware, Extension, Router, Server};
+use axum_server::AddrIncomingConfig;
 use color_eyre::Report;
+use crible_api_types as types;
+use sentry::integrations::tower::NewSentryLayer;
+use tokio::sync::watch;
 use tower::make::Shared;
 use tower::ServiceBuilder;
 use tower_http::catch_panic::CatchPanicLayer;
@@ -17,10 +22,57 @@ use tower_http::trace::TraceLayer;
 use tower_http::ServiceBuilderExt;
 use tracing::{Instrument, Span};
 
-use crate::executor::Executor;
+use self::read_only::{guarded, guarded_write};
+use crate::executor::{Executor, FlushPolicy};
+use crate::operations;
+use crate::statsd::Statsd;
 
+mod access;
+mod analytics;
 mod api;
+#[cfg(feature = "arrow")]
+mod arrow;
+mod audit;
+mod auth;
+mod config;
+mod cors;
+mod cost;
 mod errors;
+mod graphql;
+mod grpc;
+mod idempotency;
+mod ingest;
+mod limits;
+mod metrics;
+mod optimize;
+mod read_only;
+mod reporting;
+mod snapshots;
+mod subscribe;
+mod tenants;
+mod timeout;
+mod tls;
+mod version;
+mod webhooks;
+
+pub use self::analytics::AnalyticsLog;
+pub use self::audit::AuditLog;
+pub use self::auth::{Auth, AuthOptions};
+pub use self::config::{
+    config_path, load_config_into_env, resolve_options, run_reload_task,
+    Config, Defaults, Reloader, ResolvedOption, Settings,
+};
+pub use self::cors::CorsOptions;
+pub use self::grpc::run as run_grpc;
+pub use self::idempotency::IdempotencyOptions;
+pub use self::limits::{parse_byte_size, BodyLimits, RouteBodyLimit};
+pub use self::metrics::run_statsd_task;
+pub use self::optimize::{run_optimize_task, OptimizeOptions};
+pub use self::reporting::{capture_task_failure, init as init_reporting};
+pub use self::snapshots::{run_snapshot_task, SnapshotOptions};
+pub use self::tenants::{check_tenants, load_tenants, Tenant};
+pub use self::tls::TlsOptions;
+pub use self::webhooks::{run_webhooks_task, WebhookOptions};
 
 #[derive(Clone)]
 pub struct State(Arc<Executor>);
@@ -29,6 +81,11 @@ impl State {
     pub fn new(executor: Executor) -> Self {
         Self(Arc::new(executor))
     }
+
+    /// See `Executor::shutdown`.
+    pub async fn shutdown(&self) -> eyre::Result<()> {
+        self.0.shutdown().await
+    }
 }
 
 #[inline]
@@ -44,24 +101,387 @@ fn format_latency(latency: Duration) -> String {
     format!("{}μs", latency.as_micros())
 }
 
+#[derive(Debug, Default)]
+pub struct Options {
+    /// TCP keep-alive, disabled if unspecified.
+    pub keep_alive: Option<Duration>,
+    /// Serve over HTTPS when provided.
+    pub tls: Option<TlsOptions>,
+    /// Require bearer tokens on all data routes when provided.
+    pub auth: Option<Arc<Auth>>,
+    /// Add CORS headers and answer preflight requests when provided.
+    pub cors: Option<CorsOptions>,
+    /// Maximum request body sizes.
+    pub body_limits: BodyLimits,
+    /// Maximum time spent handling a request, including time spent queued
+    /// in the executor.
+    pub request_timeout: Option<Duration>,
+    /// Expose the GraphQL endpoint.
+    pub graphql: bool,
+    /// Additional indices served under `/<tenant>/...`.
+    pub tenants: Vec<Tenant>,
+    /// Replay responses to write requests with an already seen
+    /// `Idempotency-Key` header when provided.
+    pub idempotency: Option<IdempotencyOptions>,
+    /// Record all mutations to this log and expose it under `/admin/audit`
+    /// when provided.
+    pub audit: Option<Arc<AuditLog>>,
+    /// Record a sample of the queries to this log when provided.
+    pub analytics: Option<Arc<AnalyticsLog>>,
+    /// Expose `POST /admin/reload` to re-read the configuration file when
+    /// provided.
+    pub reloader: Option<Arc<Reloader>>,
+    /// Reject queries with a higher estimated cost unless the caller's token
+    /// sets its own limit.
+    pub max_query_cost: Option<u64>,
+    /// Used to group cardinalities into facets on `/query`.
+    pub facet_delimiter: operations::FacetDelimiter,
+    /// Send request durations to StatsD when provided.
+    pub statsd: Option<Arc<Statsd>>,
+    /// Expose these options under `GET /admin/config` when provided.
+    pub resolved_options: Option<Arc<Vec<ResolvedOption>>>,
+    /// Tag errors reported to Sentry with the context of the request they
+    /// happened in, see `init_reporting`.
+    pub sentry: bool,
+}
+
+/// First path segment of every route served by `router`, optional ones
+/// included. Tenants are nested under `/<name>` so they cannot use these
+/// names, see `tenants::validate_name`: update this list along with the
+/// routes.
+pub(crate) static ROUTE_SEGMENTS: &[&str] = &[
+    "healthz",
+    "readyz",
+    "metrics",
+    "admin",
+    // Data routes.
+    "query",
+    "count",
+    "count-many",
+    "compare",
+    "similarity",
+    "sync",
+    "stats",
+    "properties",
+    "get-bit",
+    "get-bits",
+    "get-properties",
+    "subscribe",
+    "changes",
+    "graphql",
+    "set",
+    "set-many",
+    "unset",
+    "unset-many",
+    "set-bit",
+    "set-bits",
+    "delete-bits",
+    "delete-range",
+    "transaction",
+    "ingest",
+    "flush",
+];
+
+/// Data routes for a single index.
+fn data_routes(
+    state: State,
+    tenant: Option<&str>,
+    auth: Option<&Arc<Auth>>,
+    options: &Options,
+) -> Router<State> {
+    let mut read_routes = Router::with_state(state.clone())
+        .route(
+            "/query",
+            guarded::<operations::Query>(&state, post(api::handler_query)),
+        )
+        .route(
+            "/count",
+            guarded::<operations::Count>(&state, post(api::handler_count)),
+        )
+        .route(
+            "/count-many",
+            guarded::<operations::CountMany>(
+                &state,
+                post(api::handler_count_many),
+            ),
+        )
+        .route(
+            "/compare",
+            guarded::<operations::Compare>(&state, post(api::handler_compare)),
+        )
+        .route(
+            "/similarity",
+            guarded::<operations::Similarity>(
+                &state,
+                post(api::handler_similarity),
+            ),
+        )
+        .route(
+            "/sync",
+            guarded::<operations::Sync>(&state, post(api::handler_sync)),
+        )
+        .route(
+            "/stats",
+            guarded::<operations::Stats>(&state, post(api::handler_stats)),
+        )
+        .route(
+            "/stats/distribution",
+            guarded::<types::DistributionRequest>(
+                &state,
+                post(api::handler_stats_distribution),
+            ),
+        )
+        .route(
+            "/properties",
+            guarded::<types::ListProperties>(
+                &state,
+                get(api::handler_properties),
+            ),
+        )
+        .route(
+            "/get-bit",
+            guarded::<types::GetBit>(&state, post(api::handler_get_bit)),
+        )
+        .route(
+            "/get-bits",
+            guarded::<types::GetBits>(&state, post(api::handler_get_bits)),
+        )
+        .route(
+            "/get-properties",
+            guarded::<operations::GetProperties>(
+                &state,
+                post(api::handler_get_properties),
+            ),
+        )
+        .route("/subscribe", get(subscribe::handler_subscribe))
+        .route("/changes", get(subscribe::handler_changes));
+
+    // Reading and writing metadata share a path, writes require the write
+    // permission on top of the read one applied to every read route.
+    let mut metadata_writes = guarded_write(
+        &state,
+        put(api::handler_put_metadata).delete(api::handler_delete_metadata),
+    );
+    if let Some(auth) = auth {
+        metadata_writes = metadata_writes.route_layer(
+            middleware::from_fn_with_state(auth.clone(), auth::require_write),
+        );
+    }
+    read_routes = read_routes
+        .route("/properties/meta", get(api::handler_list_metadata))
+        .route(
+            "/properties/:property/meta",
+            get(api::handler_get_metadata).merge(metadata_writes),
+        );
+
+    if options.graphql {
+        read_routes = read_routes.route(
+            "/graphql",
+            post(graphql::handler_graphql)
+                .layer(Extension(graphql::schema(state.clone()))),
+        );
+    }
+
+    let mut write_routes = Router::with_state(state.clone())
+        .route("/set", guarded::<types::Set>(&state, post(api::handler_set)))
+        .route(
+            "/set-many",
+            guarded::<types::SetMany>(&state, post(api::handler_set_many)),
+        )
+        .route(
+            "/unset",
+            guarded::<types::Unset>(&state, post(api::handler_unset)),
+        )
+        .route(
+            "/unset-many",
+            guarded::<types::UnsetMany>(&state, post(api::handler_unset_many)),
+        )
+        .route(
+            "/set-bit",
+            guarded::<types::SetBit>(&state, post(api::handler_set_bit)),
+        )
+        .route(
+            "/set-bits",
+            guarded::<types::SetBits>(&state, post(api::handler_set_bits)),
+        )
+        .route(
+            "/delete-bits",
+            guarded::<types::DeleteBits>(
+                &state,
+                post(api::handler_delete_bits),
+            ),
+        )
+        .route(
+            "/delete-range",
+            guarded::<types::DeleteRange>(
+                &state,
+                post(api::handler_delete_range),
+            ),
+        )
+        .route(
+            "/transaction",
+            guarded::<operations::Transaction>(
+                &state,
+                post(api::handler_transaction),
+            ),
+        )
+        .route(
+            "/ingest",
+            guarded::<operations::Ingest>(&state, post(ingest::handler_ingest)),
+        )
+        .route("/flush", guarded_write(&state, post(api::handler_flush)));
+
+    if let Some(idempotency) = &options.idempotency {
+        // Each index gets its own cache so keys do not clash across tenants.
+        write_routes =
+            write_routes.route_layer(middleware::from_fn_with_state(
+                Arc::new(idempotency::IdempotencyCache::new(
+                    idempotency.clone(),
+                )),
+                idempotency::idempotency,
+            ));
+    }
+
+    let read_routes = read_routes.route_layer(middleware::from_fn_with_state(
+        state,
+        version::add_version_header,
+    ));
+
+    let (read_routes, write_routes) = match auth {
+        None => (read_routes, write_routes),
+        Some(auth) => (
+            read_routes.route_layer(middleware::from_fn_with_state(
+                auth.clone(),
+                auth::require_read,
+            )),
+            write_routes.route_layer(middleware::from_fn_with_state(
+                auth.clone(),
+                auth::require_write,
+            )),
+        ),
+    };
+
+    let mut routes = read_routes.merge(write_routes);
+
+    if let Some(max_query_cost) = options.max_query_cost {
+        routes = routes.layer(Extension(cost::MaxQueryCost(max_query_cost)));
+    }
+    routes = routes.layer(Extension(options.facet_delimiter.clone()));
+    if let Some(log) = &options.analytics {
+        routes = routes.layer(Extension(analytics::Analyst {
+            log: log.clone(),
+            tenant: tenant.map(|t| t.to_owned()),
+        }));
+    }
+
+    match &options.audit {
+        None => routes,
+        Some(log) => routes.layer(Extension(audit::Auditor {
+            log: log.clone(),
+            tenant: tenant.map(|t| t.to_owned()),
+        })),
+    }
+}
+
+/// Routes served by `run`, without the tracing, request id, panic handling
+/// and CORS layers.
+pub fn router(state: State, options: &Options) -> Router<State> {
+    let mut app = Router::with_state(state.clone())
+        .route("/", get(api::handler_home))
+        .route("/healthz", get(api::handler_healthz))
+        .route("/readyz", get(api::handler_readyz))
+        .route("/metrics", get(metrics::handler_metrics))
+        .merge(data_routes(
+            state.clone(),
+            None,
+            options.auth.as_ref(),
+            options,
+        ));
+
+    for tenant in &options.tenants {
+        app = app.nest(
+            &format!("/{}", tenant.name),
+            data_routes(
+                tenant.state.clone(),
+                Some(&tenant.name),
+                tenant.auth.as_ref().or(options.auth.as_ref()),
+                options,
+            ),
+        );
+    }
+
+    let mut admin_routes = Router::with_state(state)
+        .route("/admin/executor", get(metrics::handler_executor))
+        .route("/admin/bitmap-stats", get(metrics::handler_bitmap_stats))
+        .route("/admin/optimize", post(optimize::handler_optimize));
+    if let Some(log) = &options.audit {
+        admin_routes = admin_routes.route(
+            "/admin/audit",
+            get(audit::handler_tail).layer(Extension(log.clone())),
+        );
+    }
+    if let Some(reloader) = &options.reloader {
+        admin_routes = admin_routes.route(
+            "/admin/reload",
+            post(config::handler_reload).layer(Extension(reloader.clone())),
+        );
+    }
+    if let Some(resolved) = &options.resolved_options {
+        admin_routes = admin_routes.route(
+            "/admin/config",
+            get(config::handler_config).layer(Extension(resolved.clone())),
+        );
+    }
+    if let Some(auth) = &options.auth {
+        admin_routes = admin_routes.route_layer(
+            middleware::from_fn_with_state(auth.clone(), auth::require_admin),
+        );
+    }
+    app = app.merge(admin_routes);
+
+    let app = app
+        .fallback(api::handler_not_found)
+        // Limits are enforced by `limits::limit_body` instead so they can
+        // vary per route.
+        .layer(DefaultBodyLimit::disable())
+        .layer(middleware::from_fn_with_state(
+            Arc::new(
+                options
+                    .body_limits
+                    .clone()
+                    .with_tenants(options.tenants.iter().map(|t| &t.name)),
+            ),
+            limits::limit_body,
+        ))
+        .layer(middleware::from_fn_with_state(
+            options.request_timeout,
+            timeout::enforce_timeout,
+        ));
+
+    let app = match &options.statsd {
+        None => app,
+        Some(statsd) => app.layer(middleware::from_fn_with_state(
+            statsd.clone(),
+            metrics::record_request,
+        )),
+    };
+
+    if options.sentry {
+        app.layer(middleware::from_fn(reporting::tag_request))
+    } else {
+        app
+    }
+}
+
+/// Serve on `listener`, which must be in non-blocking mode.
 pub async fn run(
-    addr: &SocketAddr,
-    keep_alive: Option<Duration>,
+    listener: TcpListener,
+    options: Options,
     state: State,
 ) -> Result<(), Report> {
-    let app = Router::with_state(state)
-        .route("/", get(api::handler_home))
-        .route("/query", post(api::handler_query))
-        .route("/count", post(api::handler_count))
-        .route("/stats", post(api::handler_stats))
-        .route("/set", post(api::handler_set))
-        .route("/set-many", post(api::handler_set_many))
-        .route("/unset", post(api::handler_unset))
-        .route("/unset-many", post(api::handler_unset_many))
-        .route("/get-bit", post(api::handler_get_bit))
-        .route("/set-bit", post(api::handler_set_bit))
-        .route("/delete-bits", post(api::handler_delete_bits))
-        .fallback(api::handler_not_found);
+    let app = router(state, &options);
+
+    let cors = options.cors.as_ref().map(|c| c.layer()).transpose()?;
 
     let svc = ServiceBuilder::new()
         .set_x_request_id(RequestIdBuilder::default())
@@ -101,15 +521,58 @@ pub async fn run(
                 ),
         )
         .propagate_x_request_id()
+        // Outside of `CatchPanicLayer` so that panics are reported with the
+        // request's tags.
+        .option_layer(options.sentry.then(
+            NewSentryLayer::<Request<hyper::Body>>::new_from_top,
+        ))
         .layer(CatchPanicLayer::new())
+        .option_layer(cors)
         .service(app);
 
-    Server::bind(addr)
-        .tcp_keepalive(keep_alive)
-        .serve(Shared::new(svc))
-        .with_graceful_shutdown(crate::utils::shutdown_signal("server task"))
-        .await
-        .unwrap();
+    match options.tls {
+        None => {
+            Server::from_tcp(listener)?
+                .tcp_keepalive(options.keep_alive)
+                .serve(Shared::new(svc))
+                .with_graceful_shutdown(crate::utils::shutdown_signal(
+                    "server task",
+                ))
+                .await
+                .unwrap();
+        }
+        Some(tls_options) => {
+            let config = tls_options.load().await?;
+
+            if let Some(every) = tls_options.reload_interval {
+                tokio::spawn(tls::run_reload_task(
+                    config.clone(),
+                    tls_options.clone(),
+                    every,
+                ));
+            }
+
+            let handle = axum_server::Handle::new();
+
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    crate::utils::shutdown_signal("server task").await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+
+            axum_server::from_tcp_rustls(listener, config)
+                .handle(handle)
+                .addr_incoming_config(
+                    AddrIncomingConfig::new()
+                        .tcp_keepalive(options.keep_alive)
+                        .build(),
+                )
+                .serve(Shared::new(svc))
+                .await?;
+        }
+    }
 
     Ok(())
 }
@@ -123,28 +586,75 @@ impl MakeRequestId for RequestIdBuilder {
     }
 }
 
-pub async fn run_refresh_task(state: State, every: Duration) {
-    tracing::info!(
-        "Starting refresh task. Will update backend every {:?}.",
-        every
-    );
+fn refresh_interval(
+    state: &State,
+    every: Option<Duration>,
+) -> Option<tokio::time::Interval> {
+    let every = every?;
+    if every.is_zero() {
+        // `tokio::time::interval` panics on a zero period.
+        tracing::warn!("Ignoring zero refresh interval.");
+        return None;
+    }
+    tracing::info!("Refreshing index from backend every {:?}.", every);
+    if !state.0.read_only {
+        tracing::warn!(
+            "Background refresh enabled in write mode. This is generally \
+             unsafe as backends are not guaranteed to be transactional."
+        );
+    }
+    Some(tokio::time::interval(every))
+}
 
-    let mut interval = tokio::time::interval(every);
+/// Periodically reload the index from the backend. The interval can be
+/// changed or disabled while running through `every`.
+pub async fn run_refresh_task(
+    state: State,
+    mut every: watch::Receiver<Option<Duration>>,
+) {
+    let mut interval = refresh_interval(&state, *every.borrow_and_update());
 
     loop {
+        let tick = async {
+            match interval.as_mut() {
+                Some(interval) => {
+                    interval.tick().await;
+                }
+                None => std::future::pending().await,
+            }
+        };
+
+        let changed = async {
+            match every.changed().await {
+                Ok(()) => *every.borrow_and_update(),
+                // The interval can no longer change.
+                Err(_) => std::future::pending().await,
+            }
+        };
+
         tokio::select! {
             _ = crate::utils::shutdown_signal("Backend task") => {
                 break;
             },
-            _ = interval.tick() => {
+            current = changed => {
+                if current.is_none() {
+                    tracing::info!("Disabled index refresh.");
+                }
+                interval = refresh_interval(&state, current);
+            },
+            _ = tick => {
                 async {
-                    match state.0.reload().await
+                    match state.0.reload_if_modified().await
                     {
-                        Ok(_) => {
+                        Ok(true) => {
                             tracing::info!("Reloaded index.");
                         }
+                        Ok(false) => {
+                            tracing::debug!("Index unchanged, skipped reload.");
+                        }
                         Err(e) => {
                             tracing::error!("Failed to reload index data: {}", e);
+                            capture_task_failure("refresh", &e);
                         }
                     }
                 }
@@ -154,3 +664,66 @@ pub async fn run_refresh_task(state: State, every: Duration) {
         }
     }
 }
+
+fn flush_interval(policy: FlushPolicy) -> Option<tokio::time::Interval> {
+    match policy {
+        FlushPolicy::Interval(every) => Some(tokio::time::interval(every)),
+        FlushPolicy::OnWrite
+        | FlushPolicy::AfterWrites(_)
+        | FlushPolicy::Manual => None,
+    }
+}
+
+/// How often the flush task tries again once flushing failed, whatever the
+/// flush policy.
+static FLUSH_FAILURE_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Persist pending writes in the background according to the executor's
+/// flush policy, picking up changes to the policy as they happen.
+pub async fn run_flush_task(state: State) {
+    let mut policy = state.0.flush_policy();
+    let mut interval = flush_interval(policy);
+
+    tracing::info!("Starting flush task with policy {:?}.", policy);
+
+    loop {
+        let tick = async {
+            match interval.as_mut() {
+                Some(interval) => {
+                    interval.tick().await;
+                }
+                None => std::future::pending().await,
+            }
+        };
+
+        let flush = tokio::select! {
+            _ = crate::utils::shutdown_signal("Flush task") => {
+                break;
+            },
+            _ = tick => true,
+            _ = state.0.flush_requested() => true,
+            // Writes may not trigger a flush by themselves, e.g. with the
+            // `manual` policy, so retry until the backend is back.
+            _ = tokio::time::sleep(FLUSH_FAILURE_RETRY_INTERVAL) => {
+                state.0.is_flush_failing()
+            },
+        };
+
+        let current = state.0.flush_policy();
+        if current != policy {
+            tracing::info!("Flush policy changed to {:?}.", current);
+            policy = current;
+            interval = flush_interval(policy);
+        }
+
+        if flush {
+            if let Err(e) = state.0.flush_pending().await {
+                tracing::error!("Failed to flush index: {:?}", e);
+                capture_task_failure("flush", &e);
+            }
+        }
+    }
+
+    // The final flush happens in `Executor::shutdown` once all requests
+    // have completed.
+}
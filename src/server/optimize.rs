@@ -0,0 +1,180 @@
+use std::time::Duration;
+
+use axum::extract::{Query, State as ExtractState};
+use axum::Json;
+use serde_derive::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::Instrument;
+
+use super::errors::APIError;
+use super::State;
+
+/// Default of `OptimizeRequest::batch_size`, same as `--optimize-batch-size`.
+static DEFAULT_BATCH_SIZE: usize = 100;
+
+#[derive(Debug, Clone)]
+pub struct OptimizeOptions {
+    /// Time between two passes over the whole index.
+    pub interval: Duration,
+    /// Number of properties optimized at once while holding the write lock.
+    pub batch_size: usize,
+    /// Skip a pass when more writes per second than this were applied since
+    /// the previous one.
+    pub max_write_rate: Option<f64>,
+}
+
+#[inline]
+fn write_rate(writes: u64, elapsed: Duration) -> f64 {
+    writes as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct OptimizeSummary {
+    pub properties: usize,
+    /// Memory released once every property was optimized, see
+    /// `Index::shrink_to_fit`.
+    pub reclaimed_bytes: usize,
+}
+
+/// Optimize every property of the index, one batch at a time so that writes
+/// are not held back for long, then release unused memory.
+async fn optimize_pass(
+    state: &State,
+    batch_size: usize,
+) -> eyre::Result<OptimizeSummary> {
+    let mut properties = state
+        .0
+        .spawn(|index| {
+            index.read().iter().map(|(k, _)| k.to_owned()).collect::<Vec<_>>()
+        })
+        .await?;
+    properties.sort_unstable();
+
+    for batch in properties.chunks(batch_size.max(1)) {
+        state.0.optimize(batch.to_vec()).await?;
+    }
+
+    let reclaimed_bytes = state.0.shrink().await?;
+
+    tracing::info!(
+        "Optimized {} properties, reclaimed {} bytes.",
+        properties.len(),
+        reclaimed_bytes
+    );
+    Ok(OptimizeSummary { properties: properties.len(), reclaimed_bytes })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OptimizeRequest {
+    /// See `OptimizeOptions::batch_size`.
+    batch_size: Option<usize>,
+}
+
+/// Run a pass of the background optimization immediately, e.g. after a bulk
+/// import.
+pub async fn handler_optimize(
+    ExtractState(state): ExtractState<State>,
+    Query(request): Query<OptimizeRequest>,
+) -> Result<Json<OptimizeSummary>, APIError> {
+    let batch_size = request.batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+    Ok(Json(optimize_pass(&state, batch_size).await?))
+}
+
+/// Periodically optimize the storage of the index, long running servers
+/// otherwise accumulate poorly compressed bitmaps as they are modified.
+pub async fn run_optimize_task(state: State, options: OptimizeOptions) {
+    tracing::info!(
+        "Optimizing index every {:?} in batches of {} properties.",
+        options.interval,
+        options.batch_size
+    );
+
+    let mut interval = tokio::time::interval(options.interval);
+    // The first tick completes immediately.
+    interval.tick().await;
+
+    let mut last_version = state.0.version();
+    let mut last_pass = Instant::now();
+
+    loop {
+        tokio::select! {
+            _ = crate::utils::shutdown_signal("Optimize task") => {
+                break;
+            },
+            _ = interval.tick() => {},
+        }
+
+        let version = state.0.version();
+        let rate = write_rate(
+            version.saturating_sub(last_version),
+            last_pass.elapsed(),
+        );
+        last_version = version;
+        last_pass = Instant::now();
+
+        if let Some(max) = options.max_write_rate {
+            if rate > max {
+                tracing::info!(
+                    "Skipping index optimization, {:.1} writes/s above {}.",
+                    rate,
+                    max
+                );
+                continue;
+            }
+        }
+
+        if let Err(e) = optimize_pass(&state, options.batch_size)
+            .instrument(tracing::info_span!("optimize_index"))
+            .await
+        {
+            tracing::error!("Failed to optimize index: {:?}", e);
+            super::capture_task_failure("optimize", &e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crible_lib::Index;
+    use parking_lot::Mutex;
+
+    use super::{optimize_pass, write_rate};
+    use crate::backends::{Backend, Memory};
+    use crate::executor::{ExecutorBuilder, SharedIndex};
+    use crate::server::State;
+
+    #[test]
+    fn test_write_rate() {
+        assert_eq!(write_rate(50, Duration::from_secs(10)), 5.0);
+        assert_eq!(write_rate(0, Duration::ZERO), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_optimize_pass() {
+        let index = Index::of([
+            ("foo", (0..1000).collect::<Vec<_>>()),
+            ("bar", vec![1, 2, 3]),
+            ("baz", vec![]),
+        ]);
+        let backend: Box<dyn Backend> = Box::new(Memory::default());
+        let state = State::new(
+            ExecutorBuilder::new(
+                Arc::new(SharedIndex::new(index.clone())),
+                Arc::new(Mutex::new(backend)),
+            )
+            .pool_size(1)
+            .build()
+            .unwrap(),
+        );
+
+        let summary = optimize_pass(&state, 2).await.unwrap();
+        assert_eq!(summary.properties, 3);
+
+        let optimized = state.0.spawn(|index| index.read()).await.unwrap();
+        assert!(*optimized == index);
+        assert_eq!(state.0.version(), 0);
+    }
+}
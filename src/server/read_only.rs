@@ -0,0 +1,180 @@
+use axum::body::Body;
+use axum::extract::State as ExtractState;
+use axum::http::Request;
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::routing::MethodRouter;
+
+use super::errors::APIError;
+use super::State;
+use crate::operations::{Operation, OperationError};
+
+/// Reject operations which modify the index when the server is read-only,
+/// before the request body is even read.
+pub async fn guard<O: Operation, B>(
+    state: ExtractState<State>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, APIError> {
+    if O::MUTATES {
+        reject_writes(state, request, next).await
+    } else {
+        Ok(next.run(request).await)
+    }
+}
+
+/// Reject every request when the server is read-only, see `guarded_write`.
+pub async fn reject_writes<B>(
+    ExtractState(state): ExtractState<State>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, APIError> {
+    if state.0.read_only {
+        return Err(OperationError::ReadOnly.into());
+    }
+    Ok(next.run(request).await)
+}
+
+/// Wrap the route handling operation `O` so that read-only enforcement does
+/// not depend on individual handlers.
+pub fn guarded<O: Operation + 'static>(
+    state: &State,
+    route: MethodRouter<State>,
+) -> MethodRouter<State> {
+    route.route_layer(middleware::from_fn_with_state(
+        state.clone(),
+        guard::<O, Body>,
+    ))
+}
+
+/// Same as `guarded` for routes which write without running an operation,
+/// e.g. metadata updates and flushes.
+pub fn guarded_write(
+    state: &State,
+    route: MethodRouter<State>,
+) -> MethodRouter<State> {
+    route.route_layer(middleware::from_fn_with_state(
+        state.clone(),
+        reject_writes::<Body>,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::Body;
+    use axum::http::{Method, Request, StatusCode};
+    use crible_lib::Index;
+    use parking_lot::Mutex;
+    use rstest::*;
+    use tower::ServiceExt;
+
+    use crate::backends::{Backend, Memory};
+    use crate::executor::{ExecutorBuilder, SharedIndex};
+    use crate::server::{router, Options, State};
+
+    fn index() -> Arc<SharedIndex> {
+        Arc::new(SharedIndex::new(Index::of([
+            ("foo", vec![1, 2, 3]),
+            ("bar", vec![3, 4]),
+        ])))
+    }
+
+    fn read_only_state(index: Arc<SharedIndex>) -> State {
+        let backend: Box<dyn Backend> = Box::new(Memory::default());
+        State::new(
+            ExecutorBuilder::new(index, Arc::new(Mutex::new(backend)))
+                .read_only(true)
+                .pool_size(1)
+                .build()
+                .unwrap(),
+        )
+    }
+
+    async fn post(state: State, path: &str, body: &'static str) -> StatusCode {
+        send(state, Method::POST, path, body).await
+    }
+
+    async fn send(
+        state: State,
+        method: Method,
+        path: &str,
+        body: &'static str,
+    ) -> StatusCode {
+        router(state, &Options::default())
+            .oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri(path)
+                    .header("content-type", "application/json")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+            .status()
+    }
+
+    // Every route running an operation with `MUTATES` set must be listed
+    // here.
+    #[rstest]
+    #[case("/set", r#"{"property": "baz", "bit": 1}"#)]
+    #[case("/set-many", r#"{"values": {"baz": [1, 2]}}"#)]
+    #[case("/unset", r#"{"property": "foo", "bit": 1}"#)]
+    #[case("/unset-many", r#"{"values": {"foo": [1, 2]}}"#)]
+    #[case("/set-bit", r#"{"bit": 1, "properties": ["bar"]}"#)]
+    #[case("/set-bits", r#"{"bits": {"1": ["bar"], "3": []}}"#)]
+    #[case("/delete-bits", r#"{"bits": [3]}"#)]
+    #[case("/delete-range", r#"{"start": 0, "end": 3}"#)]
+    #[case("/ingest", r#"{"property": "baz", "values": [1]}"#)]
+    #[case(
+        "/transaction",
+        r#"{"operations": [{"type": "set", "property": "baz", "bit": 1}]}"#
+    )]
+    #[tokio::test]
+    async fn test_mutating_routes_are_rejected(
+        #[case] path: &str,
+        #[case] body: &'static str,
+    ) {
+        let index = index();
+        let before = index.read();
+
+        let status = post(read_only_state(index.clone()), path, body).await;
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(*index.read() == *before);
+    }
+
+    // Routes which write outside of the index.
+    #[rstest]
+    #[case(Method::PUT, "/properties/foo/meta", r#"{"owner": "search"}"#)]
+    #[case(Method::DELETE, "/properties/foo/meta", "")]
+    #[case(Method::POST, "/flush", "")]
+    #[tokio::test]
+    async fn test_other_writes_are_rejected(
+        #[case] method: Method,
+        #[case] path: &str,
+        #[case] body: &'static str,
+    ) {
+        let status = send(read_only_state(index()), method, path, body).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[rstest]
+    #[case("/query", r#"{"query": "foo"}"#)]
+    #[case("/count", r#"{"query": "foo or bar"}"#)]
+    #[case("/count-many", r#"{"queries": ["foo", "bar"]}"#)]
+    #[case("/compare", r#"{"left": "foo", "right": "bar"}"#)]
+    #[case("/stats", "{}")]
+    #[case("/get-bit", r#"{"bit": 3}"#)]
+    #[case("/get-bits", r#"{"bits": [1, 3]}"#)]
+    #[tokio::test]
+    async fn test_read_routes_are_allowed(
+        #[case] path: &str,
+        #[case] body: &'static str,
+    ) {
+        let status = post(read_only_state(index()), path, body).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
@@ -0,0 +1,56 @@
+//! Report unexpected errors to Sentry. Until `init` is called, all of this is
+//! a no-op and errors are only logged.
+
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use sentry::ClientInitGuard;
+
+use super::x_request_id;
+
+/// Start reporting to the project identified by `dsn`. Reporting stops when
+/// the returned guard is dropped, after sending pending events.
+///
+/// Panics are reported as they happen. Panics caught while handling a request
+/// are tagged with the request's context, see `tag_request`.
+pub fn init(
+    dsn: &str,
+    environment: Option<&str>,
+) -> eyre::Result<ClientInitGuard> {
+    let dsn = dsn
+        .parse::<sentry::types::Dsn>()
+        .map_err(|e| eyre::eyre!("Invalid Sentry DSN: {}", e))?;
+    Ok(sentry::init(sentry::ClientOptions {
+        dsn: Some(dsn),
+        release: sentry::release_name!(),
+        environment: environment.map(|e| e.to_owned().into()),
+        ..Default::default()
+    }))
+}
+
+/// Report an error which could not be handled, with the context of the
+/// current request if any.
+pub fn capture_report(e: &eyre::Report) {
+    sentry::capture_error(&**e);
+}
+
+/// Report the failure of a background task, `task` is used as tag to group
+/// failures of the same task.
+pub fn capture_task_failure(task: &'static str, e: &eyre::Report) {
+    sentry::with_scope(
+        |scope| scope.set_tag("task", task),
+        || capture_report(e),
+    );
+}
+
+/// Tag everything reported while handling the request with its id, method
+/// and path. Each request must run in its own hub for tags not to leak
+/// across requests, see `sentry::integrations::tower::NewSentryLayer`.
+pub async fn tag_request<B>(request: Request<B>, next: Next<B>) -> Response {
+    sentry::configure_scope(|scope| {
+        scope.set_tag("request_id", x_request_id(&request));
+        scope.set_tag("method", request.method());
+        scope.set_tag("path", request.uri().path());
+    });
+    next.run(request).await
+}
@@ -0,0 +1,119 @@
+use std::time::{Duration, SystemTime};
+
+use crible_lib::Encoder;
+use tracing::Instrument;
+
+use super::State;
+use crate::snapshots::{Retention, Snapshot, SnapshotStore};
+
+#[derive(Debug, Clone)]
+pub struct SnapshotOptions {
+    pub store: SnapshotStore,
+    pub encoder: Encoder,
+    /// Time between two snapshots.
+    pub interval: Duration,
+    pub retention: Retention,
+}
+
+/// Write a snapshot of the index then delete the snapshots which are not
+/// retained anymore.
+async fn snapshot_pass(
+    state: &State,
+    options: &SnapshotOptions,
+) -> eyre::Result<(Snapshot, Vec<Snapshot>)> {
+    let SnapshotOptions { store, encoder, retention, .. } = options.clone();
+    let (snapshot, pruned) = state
+        .0
+        .spawn(move |index| -> eyre::Result<_> {
+            let now = SystemTime::now();
+            let snapshot = store.write(&index.read(), encoder, now)?;
+            Ok((snapshot, store.prune_retained(&retention, now)?))
+        })
+        .await??;
+
+    tracing::info!(
+        "Wrote snapshot `{}`, deleted {} old snapshots.",
+        snapshot.path.display(),
+        pruned.len()
+    );
+    Ok((snapshot, pruned))
+}
+
+/// Periodically write a snapshot of the index, independently of the backend
+/// it is flushed to, so that there is always some history to restore from.
+pub async fn run_snapshot_task(state: State, options: SnapshotOptions) {
+    tracing::info!(
+        "Writing snapshots every {:?}, keeping {:?}.",
+        options.interval,
+        options.retention
+    );
+
+    let mut interval = tokio::time::interval(options.interval);
+    // The first tick completes immediately.
+    interval.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = crate::utils::shutdown_signal("Snapshot task") => {
+                break;
+            },
+            _ = interval.tick() => {},
+        }
+
+        if let Err(e) = snapshot_pass(&state, &options)
+            .instrument(tracing::info_span!("snapshot_index"))
+            .await
+        {
+            tracing::error!("Failed to write snapshot: {:?}", e);
+            super::capture_task_failure("snapshot", &e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crible_lib::{Encoder, Index};
+    use parking_lot::Mutex;
+
+    use super::{snapshot_pass, SnapshotOptions};
+    use crate::backends::{Backend, Memory};
+    use crate::executor::{ExecutorBuilder, SharedIndex};
+    use crate::server::State;
+    use crate::snapshots::{Retention, SnapshotStore};
+
+    #[tokio::test]
+    async fn test_snapshot_pass() {
+        let dir = std::env::temp_dir().join(ulid::Ulid::new().to_string());
+        let index = Index::of([("foo", vec![1, 2]), ("bar", vec![3])]);
+        let backend: Box<dyn Backend> = Box::new(Memory::default());
+        let state = State::new(
+            ExecutorBuilder::new(
+                Arc::new(SharedIndex::new(index.clone())),
+                Arc::new(Mutex::new(backend)),
+            )
+            .pool_size(1)
+            .build()
+            .unwrap(),
+        );
+        let options = SnapshotOptions {
+            store: SnapshotStore::new(&dir, None),
+            encoder: Encoder::Bin,
+            interval: Duration::from_secs(60),
+            retention: Retention { keep_last: 1, keep_daily: 0 },
+        };
+
+        let (first, pruned) = snapshot_pass(&state, &options).await.unwrap();
+        assert!(pruned.is_empty());
+        assert_eq!(first.load().unwrap(), index);
+
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let (second, pruned) = snapshot_pass(&state, &options).await.unwrap();
+        assert_eq!(pruned, vec![first]);
+        assert_eq!(options.store.list().unwrap(), vec![second]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,648 @@
+use std::collections::{BTreeMap, HashMap};
+
+use axum::body::StreamBody;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State as ExtractState};
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use crible_api_types::{ChangesRequest, Notification, SubscriptionRequest};
+use crible_lib::Expression;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+
+use super::access::PropertyAccess;
+use super::cost::QueryCostLimit;
+use super::errors::APIError;
+use super::State;
+use crate::changes::{Change, Event};
+use crate::operations::{check_cost, OperationError};
+
+/// Changes only include the properties the caller can access, changes to
+/// none of them are not notified.
+fn notification(
+    subscription: &SubscriptionRequest,
+    access: &PropertyAccess,
+    change: &Change,
+) -> Option<Notification> {
+    let prefixes = &subscription.prefixes;
+    match change {
+        Change::Reload => Some(Notification::IndexReloaded),
+        Change::Mutation { operation, properties, bits, range } => {
+            if !prefixes.iter().any(|p| change.affects_prefix(p)) {
+                return None;
+            }
+            let properties = properties.as_ref().map(|properties| {
+                properties
+                    .iter()
+                    .filter(|p| prefixes.iter().any(|x| p.starts_with(x)))
+                    .filter(|p| access.can_access(p))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            });
+            if properties.as_ref().map_or(false, |p| p.is_empty()) {
+                return None;
+            }
+            Some(Notification::PropertyChanged {
+                operation: operation.to_string(),
+                properties,
+                bits: bits.clone(),
+                range: range.clone(),
+            })
+        }
+    }
+}
+
+/// Same as `notification` for `/changes`: mutations only list the
+/// properties the caller can access and are skipped when there are none.
+fn visible_event(access: &PropertyAccess, mut event: Event) -> Option<Event> {
+    if let Change::Mutation { properties: Some(properties), .. } =
+        &mut event.change
+    {
+        properties.retain(|p| access.can_access(p));
+        if properties.is_empty() {
+            return None;
+        }
+    }
+    Some(event)
+}
+
+/// Position of a `/changes` stream.
+struct Cursor {
+    state: State,
+    access: PropertyAccess,
+    changes: Receiver<Change>,
+    since: u64,
+    pending: Option<Vec<Event>>,
+}
+
+impl Cursor {
+    /// NDJSON lines of the next changes visible to the caller, waiting for
+    /// them if needed. `None` ends the stream, e.g. when the caller fell
+    /// behind the change log: it then gets an error when resuming.
+    async fn next(&mut self) -> Option<Result<String, serde_json::Error>> {
+        loop {
+            let events = match self.pending.take() {
+                Some(events) => events,
+                None => {
+                    tokio::select! {
+                        _ = crate::utils::shutdown_signal("Changes") => {
+                            return None;
+                        },
+                        received = self.changes.recv() => {
+                            if let Err(RecvError::Closed) = received {
+                                return None;
+                            }
+                        },
+                    }
+                    self.state.0.changes_since(self.since)?
+                }
+            };
+            if let Some(last) = events.last() {
+                self.since = last.generation;
+            }
+
+            let mut lines = String::new();
+            for event in events {
+                if let Some(event) = visible_event(&self.access, event) {
+                    lines.push_str(&match serde_json::to_string(&event) {
+                        Ok(line) => line,
+                        Err(e) => return Some(Err(e)),
+                    });
+                    lines.push('\n');
+                }
+            }
+            if !lines.is_empty() {
+                return Some(Ok(lines));
+            }
+        }
+    }
+}
+
+/// Stream the changes published after `since` as NDJSON, one change per
+/// line along with its generation, until the client disconnects. Clients
+/// resume from the generation of the last change they processed, they must
+/// reload the whole index when that is answered with 410.
+pub async fn handler_changes(
+    ExtractState(state): ExtractState<State>,
+    access: PropertyAccess,
+    Query(request): Query<ChangesRequest>,
+) -> Result<Response, APIError> {
+    // Subscribed first so that no change is missed between reading the log
+    // and waiting for the next change.
+    let changes = state.0.subscribe();
+    let since = request.since.unwrap_or_else(|| state.0.generation());
+    let pending = state
+        .0
+        .changes_since(since)
+        .ok_or(APIError::ChangesUnavailable(since))?;
+
+    let cursor =
+        Cursor { state, access, changes, since, pending: Some(pending) };
+    let body = futures_util::stream::unfold(cursor, |mut cursor| async move {
+        cursor.next().await.map(|lines| (lines, cursor))
+    });
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        StreamBody::new(body),
+    )
+        .into_response())
+}
+
+/// Whether `change` may have modified the result of `expression`.
+fn may_change(expression: &Expression, change: &Change) -> bool {
+    match change {
+        Change::Mutation { properties: Some(properties), .. }
+            if !expression.uses_root() =>
+        {
+            let names = expression.properties();
+            properties.iter().any(|p| names.contains(p.as_str()))
+                || expression
+                    .prefixes()
+                    .iter()
+                    .any(|p| change.affects_prefix(p))
+        }
+        _ => true,
+    }
+}
+
+/// Saved queries along with their last known count.
+#[derive(Default)]
+struct Queries {
+    expressions: Vec<(String, Expression)>,
+    counts: HashMap<String, u64>,
+    /// Maximum estimated cost of all the queries together, checked on every
+    /// refresh as the cost grows with the index.
+    max_cost: Option<u64>,
+}
+
+impl Queries {
+    fn parse(
+        queries: &BTreeMap<String, String>,
+        access: &PropertyAccess,
+        cost_limit: QueryCostLimit,
+    ) -> Result<Self, String> {
+        let expressions = queries
+            .iter()
+            .map(|(name, query)| -> Result<_, String> {
+                let expr = Expression::parse(query)
+                    .map_err(|e| format!("Invalid query {:?}: {}", name, e))?;
+                access.check_query(query).map_err(|e| {
+                    format!(
+                        "Invalid query {:?}: {}",
+                        name,
+                        e.status_and_message().1
+                    )
+                })?;
+                Ok((name.clone(), expr))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { expressions, counts: HashMap::new(), max_cost: cost_limit.0 })
+    }
+
+    /// Recompute the counts `change` may have modified, all of them when
+    /// `None`, returning notifications for the ones which changed since the
+    /// last refresh.
+    async fn refresh(
+        &mut self,
+        state: &State,
+        change: Option<&Change>,
+    ) -> Result<Vec<Notification>, APIError> {
+        let expressions = self
+            .expressions
+            .iter()
+            .filter(|(_, expr)| change.map_or(true, |c| may_change(expr, c)))
+            .cloned()
+            .collect::<Vec<_>>();
+        if expressions.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let max_cost = self.max_cost;
+        let counts = state
+            .0
+            .spawn(move |index| {
+                let idx = index.read();
+                check_cost(
+                    &idx,
+                    &expressions.iter().map(|(_, e)| e).collect::<Vec<_>>(),
+                    max_cost,
+                )?;
+                expressions
+                    .into_iter()
+                    .map(|(name, expr)| {
+                        idx.execute_borrowed(&expr)
+                            .map(|bm| (name, bm.cardinality()))
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(OperationError::from)
+            })
+            .await??;
+
+        Ok(counts
+            .into_iter()
+            .filter(|(name, count)| self.counts.get(name) != Some(count))
+            .map(|(name, count)| {
+                self.counts.insert(name.clone(), count);
+                Notification::CountChanged { query: name, count }
+            })
+            .collect())
+    }
+}
+
+pub async fn handler_subscribe(
+    ExtractState(state): ExtractState<State>,
+    access: PropertyAccess,
+    cost_limit: QueryCostLimit,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(move |socket| async move {
+        if let Err(e) = run(socket, state, access, cost_limit).await {
+            tracing::debug!("Subscription closed: {}", e);
+        }
+    })
+}
+
+async fn send(
+    socket: &mut WebSocket,
+    notification: &Notification,
+) -> eyre::Result<()> {
+    socket.send(Message::Text(serde_json::to_string(notification)?)).await?;
+    Ok(())
+}
+
+/// Refresh `queries` and send the counts which changed, or the error.
+/// Returns whether the refresh succeeded.
+async fn send_counts(
+    socket: &mut WebSocket,
+    queries: &mut Queries,
+    state: &State,
+    change: Option<&Change>,
+) -> eyre::Result<bool> {
+    match queries.refresh(state, change).await {
+        Ok(notifications) => {
+            for notification in &notifications {
+                send(socket, notification).await?;
+            }
+            Ok(true)
+        }
+        Err(e) => {
+            let (_, message) = e.status_and_message();
+            send(socket, &Notification::Error { message }).await?;
+            Ok(false)
+        }
+    }
+}
+
+async fn run(
+    mut socket: WebSocket,
+    state: State,
+    access: PropertyAccess,
+    cost_limit: QueryCostLimit,
+) -> eyre::Result<()> {
+    let mut changes = state.0.subscribe();
+    let mut subscription = SubscriptionRequest::default();
+    let mut queries = Queries::default();
+
+    loop {
+        tokio::select! {
+            _ = crate::utils::shutdown_signal("Subscription") => {
+                socket.send(Message::Close(None)).await?;
+                break;
+            },
+            message = socket.recv() => {
+                let text = match message {
+                    None => break,
+                    Some(message) => match message? {
+                        Message::Text(text) => text,
+                        Message::Close(_) => break,
+                        _ => continue,
+                    },
+                };
+
+                let parsed = serde_json::from_str::<SubscriptionRequest>(&text)
+                    .map_err(|e| e.to_string())
+                    .and_then(|s| {
+                        let q =
+                            Queries::parse(&s.queries, &access, cost_limit)?;
+                        Ok((q, s))
+                    });
+
+                match parsed {
+                    Ok((mut q, s)) => {
+                        // Initial counts for the new queries, which are
+                        // rejected along with the subscription when they
+                        // cannot be computed, e.g. are too expensive.
+                        if send_counts(&mut socket, &mut q, &state, None)
+                            .await?
+                        {
+                            queries = q;
+                            subscription = s;
+                        }
+                    }
+                    Err(message) => {
+                        send(&mut socket, &Notification::Error { message })
+                            .await?;
+                    }
+                }
+            },
+            change = changes.recv() => {
+                // Every count is refreshed after missed changes.
+                let change = match change {
+                    Ok(change) => {
+                        if let Some(n) =
+                            notification(&subscription, &access, &change)
+                        {
+                            send(&mut socket, &n).await?;
+                        }
+                        Some(change)
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        send(&mut socket, &Notification::Lagged { missed })
+                            .await?;
+                        None
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                send_counts(&mut socket, &mut queries, &state, change.as_ref())
+                    .await?;
+            },
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use crible_api_types::{Notification, SubscriptionRequest};
+    use crible_lib::{Expression, Index};
+    use hyper::body::HttpBody;
+    use parking_lot::Mutex;
+    use rstest::*;
+    use tower::ServiceExt;
+
+    use super::{may_change, notification, Queries};
+    use crate::backends::{Backend, Memory};
+    use crate::changes::Change;
+    use crate::executor::{ExecutorBuilder, SharedIndex};
+    use crate::operations::OperationError;
+    use crate::server::access::PropertyAccess;
+    use crate::server::auth::{Identity, Permission};
+    use crate::server::cost::QueryCostLimit;
+    use crate::server::errors::APIError;
+    use crate::server::{router, Options, State};
+
+    fn state(index: Arc<SharedIndex>) -> State {
+        let backend: Box<dyn Backend> = Box::new(Memory::default());
+        State::new(
+            ExecutorBuilder::new(index, Arc::new(Mutex::new(backend)))
+                .pool_size(1)
+                .build()
+                .unwrap(),
+        )
+    }
+
+    #[rstest]
+    #[case(
+        Change::mutation(
+            "set",
+            Some(vec!["country:fr".to_owned(), "lang:fr".to_owned()]),
+            vec![1],
+        ),
+        Some(Notification::PropertyChanged {
+            operation: "set".to_owned(),
+            properties: Some(vec!["country:fr".to_owned()]),
+            bits: vec![1],
+            range: None,
+        }),
+    )]
+    #[case(
+        Change::mutation("set", Some(vec!["lang:fr".to_owned()]), vec![1]),
+        None
+    )]
+    #[case(
+        Change::mutation("delete-bits", None, vec![1, 2]),
+        Some(Notification::PropertyChanged {
+            operation: "delete-bits".to_owned(),
+            properties: None,
+            bits: vec![1, 2],
+            range: None,
+        }),
+    )]
+    #[case(
+        Change::range_mutation("delete-range", None, 0..10),
+        Some(Notification::PropertyChanged {
+            operation: "delete-range".to_owned(),
+            properties: None,
+            bits: vec![],
+            range: Some(0..10),
+        }),
+    )]
+    #[case(Change::Reload, Some(Notification::IndexReloaded))]
+    fn test_notification(
+        #[case] change: Change,
+        #[case] expected: Option<Notification>,
+    ) {
+        let subscription = SubscriptionRequest {
+            prefixes: vec!["country:".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(
+            notification(&subscription, &PropertyAccess::default(), &change),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_notification_access() {
+        let subscription = SubscriptionRequest {
+            prefixes: vec!["country:".to_owned()],
+            ..Default::default()
+        };
+        let access = PropertyAccess::new(Some(&Identity {
+            subject: None,
+            permission: Permission::Read,
+            prefixes: None,
+            denied_prefixes: vec!["country:fr".to_owned()],
+            max_query_cost: None,
+        }));
+        let change = |properties: &[&str]| {
+            Change::mutation(
+                "set",
+                Some(properties.iter().map(|p| (*p).to_owned()).collect()),
+                vec![1],
+            )
+        };
+
+        assert_eq!(
+            notification(&subscription, &access, &change(&["country:fr"])),
+            None
+        );
+        assert_eq!(
+            notification(
+                &subscription,
+                &access,
+                &change(&["country:fr", "country:de"])
+            ),
+            Some(Notification::PropertyChanged {
+                operation: "set".to_owned(),
+                properties: Some(vec!["country:de".to_owned()]),
+                bits: vec![1],
+                range: None,
+            })
+        );
+    }
+
+    fn set(property: &str) -> Change {
+        Change::mutation("set", Some(vec![property.to_owned()]), vec![1])
+    }
+
+    #[rstest]
+    #[case("foo or bar", set("foo"), true)]
+    #[case("foo or bar", set("baz"), false)]
+    #[case("foo", set("foobar"), false)]
+    #[case("any(tag:)", set("tag:a"), true)]
+    #[case("foo - not bar", set("baz"), true)]
+    #[case("foo", Change::mutation("delete-bits", None, vec![1]), true)]
+    #[case("foo", Change::Reload, true)]
+    fn test_may_change(
+        #[case] query: &str,
+        #[case] change: Change,
+        #[case] expected: bool,
+    ) {
+        assert_eq!(
+            may_change(&Expression::parse(query).unwrap(), &change),
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn test_queries_refresh_affected() {
+        let index = Arc::new(SharedIndex::new(Index::of([
+            ("foo", vec![1, 2, 3]),
+            ("bar", vec![3, 4]),
+        ])));
+        let state = state(index.clone());
+        let mut queries = Queries::parse(
+            &[
+                ("foo".to_owned(), "foo".to_owned()),
+                ("bar".to_owned(), "bar".to_owned()),
+            ]
+            .into_iter()
+            .collect(),
+            &PropertyAccess::default(),
+            QueryCostLimit(None),
+        )
+        .unwrap();
+        assert_eq!(queries.refresh(&state, None).await.unwrap().len(), 2);
+
+        index.update(|idx| {
+            idx.set("foo", 5);
+            idx.set("bar", 5);
+        });
+        let change =
+            Change::mutation("set", Some(vec!["foo".to_owned()]), vec![5]);
+        assert_eq!(
+            queries.refresh(&state, Some(&change)).await.unwrap(),
+            vec![Notification::CountChanged {
+                query: "foo".to_owned(),
+                count: 4,
+            }]
+        );
+    }
+
+    #[rstest]
+    #[case(None, Ok(vec![("both", 4)]))]
+    #[case(Some(5), Ok(vec![("both", 4)]))]
+    #[case(Some(4), Err((5, 4)))]
+    #[tokio::test]
+    async fn test_queries_cost_limit(
+        #[case] max_cost: Option<u64>,
+        #[case] expected: Result<Vec<(&str, u64)>, (u64, u64)>,
+    ) {
+        let state = state(Arc::new(SharedIndex::new(Index::of([
+            ("foo", vec![1, 2, 3]),
+            ("bar", vec![3, 4]),
+        ]))));
+        let mut queries = Queries::parse(
+            &[("both".to_owned(), "foo or bar".to_owned())]
+                .into_iter()
+                .collect(),
+            &PropertyAccess::default(),
+            QueryCostLimit(max_cost),
+        )
+        .unwrap();
+
+        match (queries.refresh(&state, None).await, expected) {
+            (Ok(notifications), Ok(expected)) => assert_eq!(
+                notifications,
+                expected
+                    .into_iter()
+                    .map(|(query, count)| Notification::CountChanged {
+                        query: query.to_owned(),
+                        count,
+                    })
+                    .collect::<Vec<_>>()
+            ),
+            (
+                Err(APIError::Operation(OperationError::TooExpensive {
+                    cost,
+                    max_cost,
+                })),
+                Err(expected),
+            ) => assert_eq!((cost, max_cost), expected),
+            (result, _) => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_changes() {
+        let backend: Box<dyn Backend> = Box::new(Memory::default());
+        let state = State::new(
+            ExecutorBuilder::new(
+                Arc::new(SharedIndex::new(Index::default())),
+                Arc::new(Mutex::new(backend)),
+            )
+            .pool_size(1)
+            .change_log_size(2)
+            .build()
+            .unwrap(),
+        );
+        for bit in 1..=3 {
+            state.0.publish(Change::mutation(
+                "set",
+                Some(vec!["foo".to_owned()]),
+                vec![bit],
+            ));
+        }
+        let get = |uri: &str| {
+            router(state.clone(), &Options::default())
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+
+        let mut response = get("/changes?since=2").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let chunk = response.body_mut().data().await.unwrap().unwrap();
+        let lines = String::from_utf8_lossy(&chunk)
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect::<Vec<serde_json::Value>>();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["generation"], 3);
+        assert_eq!(lines[0]["bits"], serde_json::json!([3]));
+
+        state.0.publish(Change::Reload);
+        let chunk = response.body_mut().data().await.unwrap().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&chunk),
+            "{\"generation\":4,\"type\":\"reload\"}\n"
+        );
+
+        let response = get("/changes?since=0").await.unwrap();
+        assert_eq!(response.status(), StatusCode::GONE);
+    }
+}
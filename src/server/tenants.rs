@@ -0,0 +1,303 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use eyre::Context;
+use parking_lot::Mutex;
+use serde_derive::Deserialize;
+
+use super::auth::{Auth, AuthOptions};
+use super::{State, ROUTE_SEGMENTS};
+use crate::backends::BackendOptions;
+use crate::executor::{ExecutorBuilder, FlushPolicy, QueuePolicy, SharedIndex};
+
+/// Tenants configuration file, e.g.:
+///
+/// ```toml
+/// [tenants.acme]
+/// backend = "redis://localhost:6379?prefix=acme"
+/// read_only = true
+/// refresh = 5000
+///
+/// [tenants.globex]
+/// backend = "fs:///var/lib/crible/globex.bin"
+/// jwt_secret = "..."
+/// ```
+///
+/// Other top level keys are ignored so that tenants can be declared in the
+/// `--config` file.
+#[derive(Deserialize, Debug)]
+struct TenantsConfig {
+    #[serde(default)]
+    tenants: BTreeMap<String, TenantConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct TenantConfig {
+    /// Backend configuration url.
+    backend: String,
+    #[serde(default)]
+    read_only: bool,
+    /// Refresh interval in milliseconds.
+    refresh: Option<u64>,
+    /// Number of executor threads used for queries. Tenants share the
+    /// default index's threads when unset.
+    threads: Option<usize>,
+    queue_size: Option<usize>,
+    /// Number of executor threads used for writes, see `threads`.
+    write_threads: Option<usize>,
+    write_queue_size: Option<usize>,
+    /// See `--queue-policy`, defaults to `reject`.
+    queue_policy: Option<String>,
+    /// See `--flush-policy`, defaults to `on-write`.
+    flush_policy: Option<String>,
+    /// See `--flush-retries`.
+    flush_retries: Option<u32>,
+    /// See `--reject-writes-when-unflushed`.
+    #[serde(default)]
+    reject_writes_when_unflushed: bool,
+    /// See `--universe`.
+    universe: Option<u32>,
+    /// Authentication settings, the server wide settings are used when none
+    /// of `jwt_secret` or `jwt_jwks_url` is set.
+    jwt_secret: Option<String>,
+    jwt_jwks_url: Option<String>,
+    jwt_audience: Option<String>,
+    jwt_issuer: Option<String>,
+}
+
+/// Index served under `/<name>/...` alongside the default one.
+pub struct Tenant {
+    pub name: String,
+    pub state: State,
+    /// Overrides the server wide authentication settings when provided.
+    pub auth: Option<Arc<Auth>>,
+    pub refresh: Option<Duration>,
+}
+
+impl std::fmt::Debug for Tenant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tenant")
+            .field("name", &self.name)
+            .field("auth", &self.auth)
+            .field("refresh", &self.refresh)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Tenant names become the first path segment so they cannot clash with the
+/// existing routes.
+fn validate_name(name: &str) -> eyre::Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Err(eyre::Report::msg(format!(
+            "Invalid tenant name {:?}, only alphanumeric characters, `-` and \
+             `_` are allowed",
+            name
+        )))
+    } else if ROUTE_SEGMENTS.contains(&name) {
+        Err(eyre::Report::msg(format!("Reserved tenant name {:?}", name)))
+    } else {
+        Ok(())
+    }
+}
+
+impl TenantConfig {
+    /// Validate the settings without loading the index.
+    fn check(&self, name: &str) -> eyre::Result<()> {
+        validate_name(name)?;
+        self.backend.parse::<BackendOptions>().wrap_err("Invalid backend")?;
+        if let Some(policy) = &self.queue_policy {
+            policy.parse::<QueuePolicy>()?;
+        }
+        if let Some(policy) = &self.flush_policy {
+            policy.parse::<FlushPolicy>()?;
+        }
+        if let Some(url) = &self.jwt_jwks_url {
+            url.parse::<url::Url>().wrap_err("Invalid JWKS url")?;
+        }
+        Ok(())
+    }
+
+    async fn build(
+        self,
+        name: String,
+        default: &State,
+    ) -> eyre::Result<Tenant> {
+        validate_name(&name)?;
+
+        let backend = self
+            .backend
+            .parse::<BackendOptions>()?
+            .build()
+            .wrap_err("Invalid backend")?;
+        let mut index = backend.load().wrap_err("Failed to load index")?;
+        index
+            .set_universe(self.universe)
+            .wrap_err("Index does not fit in the universe")?;
+
+        let mut builder = ExecutorBuilder::new(
+            Arc::new(SharedIndex::new(index)),
+            Arc::new(Mutex::new(backend)),
+        )
+        .read_only(self.read_only)
+        .reject_writes_when_unflushed(self.reject_writes_when_unflushed)
+        .share_threads(&default.0);
+
+        if let Some(n) = self.flush_retries {
+            builder = builder.flush_retries(n);
+        }
+
+        if let Some(policy) = self.queue_policy {
+            builder = builder.queue_policy(policy.parse::<QueuePolicy>()?);
+        }
+
+        if let Some(policy) = self.flush_policy {
+            builder = builder.flush_policy(policy.parse::<FlushPolicy>()?);
+        }
+
+        if let Some(c) = self.threads {
+            builder = builder.pool_size(c);
+        }
+
+        if let Some(c) = self.queue_size {
+            builder = builder.queue_size(c);
+        }
+
+        if let Some(c) = self.write_threads {
+            builder = builder.write_pool_size(c);
+        }
+
+        if let Some(c) = self.write_queue_size {
+            builder = builder.write_queue_size(c);
+        }
+
+        let auth = Auth::new(&AuthOptions {
+            secret: self.jwt_secret,
+            jwks_url: self
+                .jwt_jwks_url
+                .map(|url| url.parse::<url::Url>())
+                .transpose()
+                .wrap_err("Invalid JWKS url")?,
+            audience: self.jwt_audience,
+            issuer: self.jwt_issuer,
+        })
+        .await
+        .wrap_err("Invalid authentication configuration")?
+        .map(Arc::new);
+
+        Ok(Tenant {
+            name,
+            state: State::new(builder.build()?),
+            auth,
+            refresh: self.refresh.map(Duration::from_millis),
+        })
+    }
+}
+
+async fn read_tenants_config(path: &Path) -> eyre::Result<TenantsConfig> {
+    let content =
+        tokio::fs::read_to_string(path).await.wrap_err_with(|| {
+            format!("Failed to read tenants file `{}`", path.display())
+        })?;
+    toml::from_str(&content)
+        .wrap_err_with(|| format!("Invalid tenants file `{}`", path.display()))
+}
+
+/// Load the tenants declared in `path`, loading their index in the process.
+/// Their executors share the threads of `default` unless configured
+/// otherwise.
+pub async fn load_tenants(
+    path: &Path,
+    default: &State,
+) -> eyre::Result<Vec<Tenant>> {
+    let config = read_tenants_config(path).await?;
+
+    let mut tenants = Vec::with_capacity(config.tenants.len());
+    for (name, tenant) in config.tenants {
+        tracing::info!("Loading tenant {:?}", name);
+        tenants.push(
+            tenant
+                .build(name.clone(), default)
+                .await
+                .wrap_err_with(|| format!("Invalid tenant {:?}", name))?,
+        );
+    }
+    Ok(tenants)
+}
+
+/// Validate the tenants declared in `path` without loading their index.
+/// Returns the number of tenants.
+pub async fn check_tenants(path: &Path) -> eyre::Result<usize> {
+    let config = read_tenants_config(path).await?;
+    for (name, tenant) in &config.tenants {
+        tenant
+            .check(name)
+            .wrap_err_with(|| format!("Invalid tenant {:?}", name))?;
+    }
+    Ok(config.tenants.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::*;
+
+    use super::{validate_name, TenantsConfig};
+
+    #[rstest]
+    #[case("acme", true)]
+    #[case("acme-eu_1", true)]
+    #[case("", false)]
+    #[case("acme/eu", false)]
+    #[case("query", false)]
+    #[case("healthz", false)]
+    #[case("count-many", false)]
+    #[case("get-bits", false)]
+    #[case("ingest", false)]
+    #[case("metrics", false)]
+    fn test_validate_name(#[case] name: &str, #[case] valid: bool) {
+        assert_eq!(validate_name(name).is_ok(), valid);
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config: TenantsConfig = toml::from_str(
+            r#"
+            [tenants.acme]
+            backend = "memory://"
+            read_only = true
+            refresh = 5000
+
+            [tenants.globex]
+            backend = "memory://"
+            jwt_secret = "secret"
+            "#,
+        )
+        .unwrap();
+
+        let acme = &config.tenants["acme"];
+        assert!(acme.read_only);
+        assert_eq!(acme.refresh, Some(5000));
+        assert!(acme.jwt_secret.is_none());
+
+        let globex = &config.tenants["globex"];
+        assert!(!globex.read_only);
+        assert_eq!(globex.jwt_secret.as_deref(), Some("secret"));
+    }
+
+    #[rstest]
+    #[case("backend = \"memory://\"", true)]
+    #[case("backend = \"nope://\"", false)]
+    #[case("backend = \"memory://\"\nflush_policy = \"sometimes\"", false)]
+    #[case("backend = \"memory://\"\nqueue_policy = \"wait:abc\"", false)]
+    fn test_check_config(#[case] tenant: &str, #[case] valid: bool) {
+        let config: TenantsConfig =
+            toml::from_str(&format!("[tenants.acme]\n{}", tenant)).unwrap();
+        assert_eq!(config.tenants["acme"].check("acme").is_ok(), valid);
+    }
+}
@@ -0,0 +1,144 @@
+use std::convert::Infallible;
+use std::time::{Duration, Instant};
+
+use axum::async_trait;
+use axum::extract::{FromRequestParts, State as ExtractState};
+use axum::http::header::HeaderName;
+use axum::http::request::Parts;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use crible_lib::Cancellation;
+
+use super::errors::APIError;
+use super::limits;
+use crate::executor::Handoff;
+
+/// Header through which clients can provide their own deadline in
+/// milliseconds. It can only shorten the server side timeout.
+static REQUEST_TIMEOUT_HEADER: &str = "x-request-timeout";
+
+#[inline]
+fn effective_timeout(
+    configured: Option<Duration>,
+    requested: Option<Duration>,
+) -> Option<Duration> {
+    match (configured, requested) {
+        (Some(c), Some(r)) => Some(c.min(r)),
+        (c, r) => c.or(r),
+    }
+}
+
+/// Cancels the request's executor tasks when dropped, which happens when the
+/// request completes or is abandoned.
+pub struct CancelOnDrop(pub Cancellation);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// Cancellation of the current request, passed on to executor tasks so that
+/// they stop once the request has timed out or been abandoned instead of
+/// running to completion.
+#[derive(Debug, Clone, Default)]
+pub struct RequestCancellation(pub Cancellation);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for RequestCancellation
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self(
+            parts.extensions.get::<Cancellation>().cloned().unwrap_or_default(),
+        ))
+    }
+}
+
+/// Bound the time spent handling a request, including time spent waiting in
+/// the executor queue. Writes which already started when the timeout expires
+/// are not interrupted, the request then completes normally so that clients
+/// never get an error for a write which was applied.
+pub async fn enforce_timeout<B>(
+    ExtractState(configured): ExtractState<Option<Duration>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, APIError> {
+    let requested = request
+        .headers()
+        .get(HeaderName::from_static(REQUEST_TIMEOUT_HEADER))
+        .and_then(|hv| hv.to_str().ok())
+        .and_then(|hv| hv.parse::<u64>().ok())
+        .map(Duration::from_millis);
+
+    // Streaming routes can legitimately run for a long time, only the
+    // client's own deadline applies to them.
+    let configured = if limits::is_streaming(request.uri().path()) {
+        None
+    } else {
+        configured
+    };
+    let timeout = effective_timeout(configured, requested);
+    let cancellation = match timeout {
+        Some(t) => Cancellation::with_deadline(Instant::now() + t),
+        None => Cancellation::default(),
+    };
+    request.extensions_mut().insert(cancellation.clone());
+    let _guard = CancelOnDrop(cancellation);
+
+    match timeout {
+        None => Ok(next.run(request).await),
+        Some(timeout) => {
+            let handoff = Handoff::default();
+            let response = handoff.scope(next.run(request));
+            tokio::pin!(response);
+            match tokio::time::timeout(timeout, &mut response).await {
+                Ok(response) => Ok(response),
+                Err(_) if !handoff.abandon() => Ok(response.await),
+                Err(_) => Err(APIError::Timeout(timeout)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crible_lib::Cancellation;
+    use rstest::*;
+
+    use super::{effective_timeout, CancelOnDrop};
+
+    fn ms(x: u64) -> Option<Duration> {
+        Some(Duration::from_millis(x))
+    }
+
+    #[rstest]
+    #[case(None, None, None)]
+    #[case(ms(100), None, ms(100))]
+    #[case(None, ms(50), ms(50))]
+    #[case(ms(100), ms(50), ms(50))]
+    #[case(ms(100), ms(500), ms(100))]
+    fn test_effective_timeout(
+        #[case] configured: Option<Duration>,
+        #[case] requested: Option<Duration>,
+        #[case] expected: Option<Duration>,
+    ) {
+        assert_eq!(effective_timeout(configured, requested), expected);
+    }
+
+    #[test]
+    fn test_cancel_on_drop() {
+        let cancellation = Cancellation::default();
+        drop(CancelOnDrop(cancellation.clone()));
+        assert!(cancellation.is_cancelled());
+    }
+}
@@ -0,0 +1,75 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use axum_server::tls_rustls::RustlsConfig;
+use eyre::Context;
+use tracing::Instrument;
+
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// When set, certificates are re-read from disk on this interval so they
+    /// can be rotated without restarting the server.
+    pub reload_interval: Option<Duration>,
+}
+
+impl TlsOptions {
+    pub async fn load(&self) -> eyre::Result<RustlsConfig> {
+        RustlsConfig::from_pem_file(&self.cert, &self.key).await.wrap_err_with(
+            || {
+                format!(
+                    "Failed to load TLS certificate `{}` and key `{}`",
+                    self.cert.display(),
+                    self.key.display()
+                )
+            },
+        )
+    }
+}
+
+pub async fn run_reload_task(
+    config: RustlsConfig,
+    options: TlsOptions,
+    every: Duration,
+) {
+    tracing::info!(
+        "Starting TLS reload task. Will reload certificates every {:?}.",
+        every
+    );
+
+    let mut interval = tokio::time::interval(every);
+    // The first tick completes immediately and certificates were just loaded.
+    interval.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = crate::utils::shutdown_signal("TLS reload task") => {
+                break;
+            },
+            _ = interval.tick() => {
+                async {
+                    match config
+                        .reload_from_pem_file(&options.cert, &options.key)
+                        .await
+                    {
+                        Ok(_) => {
+                            tracing::info!("Reloaded TLS certificates.");
+                        }
+                        Err(e) => {
+                            tracing::error!(
+                                "Failed to reload TLS certificates: {}", e
+                            );
+                            super::capture_task_failure(
+                                "reload_tls",
+                                &eyre::Report::new(e),
+                            );
+                        }
+                    }
+                }
+                .instrument(tracing::info_span!("reload_tls"))
+                .await;
+            }
+        }
+    }
+}
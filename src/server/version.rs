@@ -0,0 +1,97 @@
+use axum::async_trait;
+use axum::extract::{FromRequestParts, State as ExtractState};
+use axum::http::header::{HeaderName, LAST_MODIFIED};
+use axum::http::request::Parts;
+use axum::http::{HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use super::errors::APIError;
+use super::State;
+
+/// Index version the data in the response is consistent with.
+static INDEX_VERSION: &str = "x-index-version";
+/// Index version a write is conditional on.
+static IF_INDEX_VERSION: &str = "if-index-version";
+
+/// Extract the optional `If-Index-Version` request header. Writes carrying it
+/// are rejected with 409 HTTP status if the index has been modified since.
+pub struct IfIndexVersion(pub Option<u64>);
+
+fn parse_version(value: &HeaderValue) -> Option<u64> {
+    value.to_str().ok()?.trim().trim_matches('"').parse().ok()
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for IfIndexVersion
+where
+    S: Send + Sync,
+{
+    type Rejection = APIError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> Result<Self, Self::Rejection> {
+        match parts.headers.get(IF_INDEX_VERSION) {
+            None => Ok(Self(None)),
+            Some(value) => parse_version(value)
+                .map(|version| Self(Some(version)))
+                .ok_or(APIError::InvalidHeader("If-Index-Version")),
+        }
+    }
+}
+
+/// Response carrying the index version resulting from a write.
+pub struct Versioned<T>(pub u64, pub T);
+
+impl<T: IntoResponse> IntoResponse for Versioned<T> {
+    fn into_response(self) -> Response {
+        let mut response = self.1.into_response();
+        response.headers_mut().insert(
+            HeaderName::from_static(INDEX_VERSION),
+            HeaderValue::from(self.0),
+        );
+        response
+    }
+}
+
+/// Add the index version and `Last-Modified` headers to read responses. The
+/// version is read before handling the request so that it is never newer
+/// than the data returned, using it for a conditional write can only cause
+/// spurious conflicts and never accept a write based on outdated data.
+pub async fn add_version_header<B>(
+    ExtractState(state): ExtractState<State>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let version = state.0.version();
+    let last_modified = httpdate::fmt_http_date(state.0.last_modified());
+    let mut response =
+        Versioned(version, next.run(request).await).into_response();
+    if let Ok(value) = HeaderValue::from_str(&last_modified) {
+        response.headers_mut().insert(LAST_MODIFIED, value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+    use rstest::*;
+
+    use super::parse_version;
+
+    #[rstest]
+    #[case("12", Some(12))]
+    #[case("\"12\"", Some(12))]
+    #[case(" 0 ", Some(0))]
+    #[case("-1", None)]
+    #[case("abc", None)]
+    fn test_parse_version(#[case] value: &str, #[case] expected: Option<u64>) {
+        assert_eq!(
+            parse_version(&HeaderValue::from_str(value).unwrap()),
+            expected
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use serde_derive::Serialize;
+use sha2::Sha256;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::Instant;
+
+use super::State;
+use crate::changes::Change;
+
+/// Header carrying the hex encoded HMAC-SHA256 signature of the request body
+/// when a secret is configured.
+static SIGNATURE_HEADER: &str = "x-crible-signature";
+
+/// Delay before the first retry, doubled on every subsequent attempt.
+static RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone)]
+pub struct WebhookOptions {
+    pub urls: Vec<url::Url>,
+    /// Secret used to sign request bodies.
+    pub secret: Option<String>,
+    /// When set, changes are accumulated for this long after the first one
+    /// and delivered as a single event.
+    pub debounce: Option<Duration>,
+    /// Number of retries for failed deliveries.
+    pub max_retries: u32,
+}
+
+#[derive(Serialize, Debug)]
+struct Event<'a> {
+    id: String,
+    changes: &'a [Change],
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+async fn deliver(
+    client: reqwest::Client,
+    url: url::Url,
+    body: Arc<Vec<u8>>,
+    signature: Option<Arc<String>>,
+    max_retries: u32,
+) {
+    let mut attempt = 0;
+    loop {
+        let mut request = client
+            .post(url.clone())
+            .header("content-type", "application/json")
+            .body(body.as_ref().clone());
+        if let Some(signature) = &signature {
+            request = request.header(SIGNATURE_HEADER, signature.as_str());
+        }
+
+        let error = match request.send().await {
+            Ok(response) => match response.error_for_status() {
+                Ok(_) => return,
+                Err(e) => e,
+            },
+            Err(e) => e,
+        };
+
+        if attempt >= max_retries {
+            tracing::error!(
+                "Failed to deliver webhook to {} after {} attempts: {}",
+                url,
+                attempt + 1,
+                error
+            );
+            super::capture_task_failure("webhook", &eyre::Report::new(error));
+            return;
+        }
+
+        tracing::warn!("Failed to deliver webhook to {}: {}", url, error);
+        tokio::time::sleep(RETRY_BASE_DELAY * 2u32.pow(attempt)).await;
+        attempt += 1;
+    }
+}
+
+fn dispatch(
+    client: &reqwest::Client,
+    options: &WebhookOptions,
+    changes: &[Change],
+) {
+    let event = Event { id: ulid::Ulid::new().to_string(), changes };
+    let body = match serde_json::to_vec(&event) {
+        Ok(body) => Arc::new(body),
+        Err(e) => {
+            tracing::error!("Failed to serialize webhook event: {:?}", e);
+            return;
+        }
+    };
+    let signature =
+        options.secret.as_ref().map(|secret| Arc::new(sign(secret, &body)));
+
+    // Deliveries run concurrently so a slow receiver does not delay others
+    // or block the task from consuming changes.
+    for url in &options.urls {
+        tokio::spawn(deliver(
+            client.clone(),
+            url.clone(),
+            body.clone(),
+            signature.clone(),
+            options.max_retries,
+        ));
+    }
+}
+
+pub async fn run_webhooks_task(state: State, options: WebhookOptions) {
+    tracing::info!(
+        "Starting webhooks task. Will notify {} url(s).",
+        options.urls.len()
+    );
+
+    let client = reqwest::Client::new();
+    let mut changes = state.0.subscribe();
+    let mut pending: Vec<Change> = vec![];
+    let mut deadline: Option<Instant> = None;
+
+    loop {
+        let flush_at = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            _ = crate::utils::shutdown_signal("Webhooks task") => {
+                break;
+            },
+            _ = flush_at => {
+                dispatch(&client, &options, &pending);
+                pending.clear();
+                deadline = None;
+            },
+            change = changes.recv() => {
+                match change {
+                    Ok(change) => pending.push(change),
+                    Err(RecvError::Lagged(missed)) => {
+                        tracing::warn!("Webhooks missed {} changes", missed);
+                        // Receivers cannot know what was missed, signal that
+                        // anything may have changed.
+                        pending.push(Change::Reload);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+                match options.debounce {
+                    None => {
+                        dispatch(&client, &options, &pending);
+                        pending.clear();
+                    }
+                    Some(debounce) => {
+                        if deadline.is_none() {
+                            deadline = Some(Instant::now() + debounce);
+                        }
+                    }
+                }
+            },
+        }
+    }
+
+    if !pending.is_empty() {
+        dispatch(&client, &options, &pending);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sign;
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c7\
+             5a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
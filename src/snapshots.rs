@@ -0,0 +1,284 @@
+//! Point in time copies of an index stored as timestamped files in a
+//! directory, independently of the backend the index is served from.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use crible_lib::{Encoder, Index};
+use eyre::Context;
+
+use crate::backends::{Backend, FSBackend};
+
+static DEFAULT_PREFIX: &str = "crible";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub path: PathBuf,
+    /// UTC creation time formatted as `YYYYMMDDTHHMMSSmmmZ` so that sorting
+    /// timestamps sorts snapshots chronologically.
+    pub timestamp: String,
+    pub encoder: Encoder,
+}
+
+impl Snapshot {
+    pub fn load(&self) -> eyre::Result<Index> {
+        FSBackend::new(&self.path, self.encoder).read().wrap_err_with(|| {
+            format!("Failed to load snapshot `{}`", self.path.display())
+        })
+    }
+}
+
+/// Which snapshots are kept when pruning, see `SnapshotStore::prune_retained`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Retention {
+    /// Number of most recent snapshots kept.
+    pub keep_last: usize,
+    /// Also keep the most recent snapshot of each of the last this many
+    /// days, in UTC, including the current one.
+    pub keep_daily: usize,
+}
+
+impl Retention {
+    /// Snapshots from `snapshots`, oldest first, which are not retained at
+    /// `now`.
+    fn expired(
+        &self,
+        snapshots: Vec<Snapshot>,
+        now: SystemTime,
+    ) -> Vec<Snapshot> {
+        let cutoff = format_timestamp(
+            now.checked_sub(Duration::from_secs(
+                86_400 * self.keep_daily as u64,
+            ))
+            .unwrap_or(SystemTime::UNIX_EPOCH),
+        );
+        let count = snapshots.len();
+        let mut previous_day: Option<String> = None;
+        let mut expired = vec![];
+        // Newest first so that the first snapshot of a day is its latest.
+        for (i, snapshot) in snapshots.into_iter().enumerate().rev() {
+            let day = snapshot.timestamp[..8].to_owned();
+            let daily = day.as_str() > &cutoff[..8]
+                && previous_day.as_deref() != Some(day.as_str());
+            if count - i > self.keep_last && !daily {
+                expired.push(snapshot);
+            }
+            previous_day = Some(day);
+        }
+        expired.reverse();
+        expired
+    }
+}
+
+/// Snapshots named `<prefix>-<timestamp>.<bin|json>` in a directory.
+#[derive(Debug, Clone)]
+pub struct SnapshotStore {
+    dir: PathBuf,
+    prefix: String,
+}
+
+fn extension(encoder: Encoder) -> &'static str {
+    match encoder {
+        Encoder::Bin => "bin",
+        Encoder::Json => "json",
+    }
+}
+
+/// Compact, fixed width version of a RFC 3339 timestamp.
+fn format_timestamp(at: SystemTime) -> String {
+    humantime::format_rfc3339_millis(at)
+        .to_string()
+        .replace(['-', ':', '.'], "")
+}
+
+impl SnapshotStore {
+    pub fn new<P: Into<PathBuf>>(dir: P, prefix: Option<&str>) -> Self {
+        Self {
+            dir: dir.into(),
+            prefix: prefix.unwrap_or(DEFAULT_PREFIX).to_owned(),
+        }
+    }
+
+    /// Write a new snapshot of `index` taken at `at`.
+    pub fn write(
+        &self,
+        index: &Index,
+        encoder: Encoder,
+        at: SystemTime,
+    ) -> eyre::Result<Snapshot> {
+        let timestamp = format_timestamp(at);
+        let path = self.dir.join(format!(
+            "{}-{}.{}",
+            self.prefix,
+            timestamp,
+            extension(encoder)
+        ));
+        FSBackend::new(&path, encoder).dump(index).wrap_err_with(|| {
+            format!("Failed to write snapshot `{}`", path.display())
+        })?;
+        Ok(Snapshot { path, timestamp, encoder })
+    }
+
+    /// Existing snapshots, oldest first.
+    pub fn list(&self) -> eyre::Result<Vec<Snapshot>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(vec![]);
+            }
+            x => x.wrap_err_with(|| {
+                format!("Failed to list snapshots in `{}`", self.dir.display())
+            })?,
+        };
+
+        let mut snapshots = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            if let Some(snapshot) = self.parse(&path) {
+                snapshots.push(snapshot);
+            }
+        }
+        snapshots.sort_unstable_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        Ok(snapshots)
+    }
+
+    pub fn latest(&self) -> eyre::Result<Option<Snapshot>> {
+        Ok(self.list()?.pop())
+    }
+
+    /// Delete all but the `keep` most recent snapshots, returning the deleted
+    /// ones.
+    pub fn prune(&self, keep: usize) -> eyre::Result<Vec<Snapshot>> {
+        self.prune_retained(
+            &Retention { keep_last: keep, keep_daily: 0 },
+            SystemTime::now(),
+        )
+    }
+
+    /// Delete the snapshots which are not retained by `retention` at `now`,
+    /// returning the deleted ones.
+    pub fn prune_retained(
+        &self,
+        retention: &Retention,
+        now: SystemTime,
+    ) -> eyre::Result<Vec<Snapshot>> {
+        let pruned = retention.expired(self.list()?, now);
+        for snapshot in &pruned {
+            fs::remove_file(&snapshot.path).wrap_err_with(|| {
+                format!("Failed to delete `{}`", snapshot.path.display())
+            })?;
+        }
+        Ok(pruned)
+    }
+
+    fn parse(&self, path: &Path) -> Option<Snapshot> {
+        let name = path.file_name()?.to_str()?;
+        let (stem, ext) = name.rsplit_once('.')?;
+        let timestamp = stem.strip_prefix(&self.prefix)?.strip_prefix('-')?;
+        let valid = timestamp.len() == 19
+            && timestamp.ends_with('Z')
+            && timestamp.chars().nth(8) == Some('T');
+        if !valid {
+            return None;
+        }
+        Some(Snapshot {
+            path: path.to_owned(),
+            timestamp: timestamp.to_owned(),
+            encoder: Encoder::from_str(ext).ok()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    use crible_lib::{Encoder, Index};
+    use rstest::rstest;
+
+    use super::{format_timestamp, Retention, SnapshotStore};
+
+    #[test]
+    fn test_format_timestamp() {
+        let at =
+            SystemTime::UNIX_EPOCH + Duration::from_millis(1_666_000_000_123);
+        assert_eq!(format_timestamp(at), "20221017T094640123Z");
+    }
+
+    #[rstest]
+    #[case("crible-20221017T094640123Z.bin", Some(Encoder::Bin))]
+    #[case("crible-20221017T094640123Z.json", Some(Encoder::Json))]
+    #[case("crible-20221017T094640123Z.bin.tmp", None)]
+    #[case("crible-20221017T094640123Z.csv", None)]
+    #[case("other-20221017T094640123Z.bin", None)]
+    #[case("crible-latest.bin", None)]
+    fn test_parse(#[case] name: &str, #[case] expected: Option<Encoder>) {
+        let store = SnapshotStore::new("/snapshots", None);
+        assert_eq!(
+            store.parse(&Path::new("/snapshots").join(name)).map(|s| s.encoder),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_write_list_prune() {
+        let dir = std::env::temp_dir().join(ulid::Ulid::new().to_string());
+        let store = SnapshotStore::new(&dir, Some("test"));
+        let index = Index::of([("foo", vec![1, 2])]);
+
+        assert!(store.latest().unwrap().is_none());
+
+        for i in 0..3 {
+            let at = SystemTime::UNIX_EPOCH + Duration::from_secs(1000 + i);
+            store.write(&index, Encoder::Bin, at).unwrap();
+        }
+        assert_eq!(store.list().unwrap().len(), 3);
+
+        let pruned = store.prune(2).unwrap();
+        assert_eq!(pruned.len(), 1);
+        assert_eq!(pruned[0].timestamp, "19700101T001640000Z");
+
+        let latest = store.latest().unwrap().unwrap();
+        assert_eq!(latest.timestamp, "19700101T001642000Z");
+        assert_eq!(latest.load().unwrap(), index);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[rstest]
+    #[case(Retention { keep_last: 2, keep_daily: 0 }, vec![4, 5])]
+    #[case(Retention { keep_last: 0, keep_daily: 2 }, vec![3, 5])]
+    #[case(Retention { keep_last: 1, keep_daily: 3 }, vec![1, 3, 5])]
+    #[case(Retention { keep_last: 0, keep_daily: 0 }, vec![])]
+    #[case(Retention { keep_last: 10, keep_daily: 0 }, vec![0, 1, 2, 3, 4, 5])]
+    fn test_retention(#[case] retention: Retention, #[case] kept: Vec<usize>) {
+        let day = Duration::from_secs(86_400);
+        let start = SystemTime::UNIX_EPOCH + 10 * day;
+        let store = SnapshotStore::new("/snapshots", None);
+        // Two snapshots a day over three days.
+        let snapshots =
+            [0, 1, 86_400, 86_401, 172_800, 172_801]
+                .into_iter()
+                .map(|secs| {
+                    let at = start + Duration::from_secs(secs);
+                    store
+                        .parse(&Path::new("/snapshots").join(format!(
+                            "crible-{}.bin",
+                            format_timestamp(at)
+                        )))
+                        .unwrap()
+                })
+                .collect::<Vec<_>>();
+        let now = start + 2 * day + Duration::from_secs(2);
+
+        let expired = retention.expired(snapshots.clone(), now);
+        assert_eq!(
+            (0..snapshots.len())
+                .filter(|i| !expired.contains(&snapshots[*i]))
+                .collect::<Vec<_>>(),
+            kept
+        );
+    }
+}
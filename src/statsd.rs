@@ -0,0 +1,179 @@
+//! Push metrics to a StatsD or DogStatsD agent, for environments without a
+//! Prometheus scraper. Metrics are sent over UDP as they happen, send
+//! failures are only logged so that an unavailable agent never impacts
+//! requests.
+
+use std::fmt::Write;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Tag attached to every metric, parsed from `<key>:<value>`. Tags are a
+/// DogStatsD extension, plain StatsD agents may reject them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    pub key: String,
+    pub value: String,
+}
+
+impl FromStr for Tag {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            Some((key, value)) if !key.is_empty() => {
+                Ok(Self { key: key.to_owned(), value: value.to_owned() })
+            }
+            _ => Err(eyre::Report::msg(format!(
+                "Invalid tag {:?}, expected <key>:<value>",
+                value
+            ))),
+        }
+    }
+}
+
+pub struct Statsd {
+    socket: UdpSocket,
+    prefix: String,
+    tags: Vec<Tag>,
+}
+
+impl std::fmt::Debug for Statsd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Statsd")
+            .field("addr", &self.socket.peer_addr().ok())
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+impl Statsd {
+    /// Metric names are prefixed with `<prefix>.` unless `prefix` is empty.
+    pub fn new(addr: &str, prefix: &str, tags: Vec<Tag>) -> io::Result<Self> {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "Unknown StatsD address")
+        })?;
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket, prefix: prefix.to_owned(), tags })
+    }
+
+    pub fn count(&self, name: &str, value: u64, tags: &[(&str, &str)]) {
+        self.send(name, &value.to_string(), "c", tags)
+    }
+
+    pub fn gauge(&self, name: &str, value: u64, tags: &[(&str, &str)]) {
+        self.send(name, &value.to_string(), "g", tags)
+    }
+
+    /// Send `duration` in milliseconds.
+    pub fn timing(
+        &self,
+        name: &str,
+        duration: Duration,
+        tags: &[(&str, &str)],
+    ) {
+        let ms = duration.as_secs_f64() * 1000.0;
+        self.send(name, &format!("{:.3}", ms), "ms", tags)
+    }
+
+    fn send(&self, name: &str, value: &str, kind: &str, tags: &[(&str, &str)]) {
+        let line = self.format(name, value, kind, tags);
+        if let Err(e) = self.socket.send(line.as_bytes()) {
+            tracing::debug!("Failed to send metric to StatsD: {}", e);
+        }
+    }
+
+    fn format(
+        &self,
+        name: &str,
+        value: &str,
+        kind: &str,
+        tags: &[(&str, &str)],
+    ) -> String {
+        let mut line = String::new();
+        if !self.prefix.is_empty() {
+            let _ = write!(line, "{}.", self.prefix);
+        }
+        let _ = write!(line, "{}:{}|{}", name, value, kind);
+
+        let tags = self
+            .tags
+            .iter()
+            .map(|t| (t.key.as_str(), t.value.as_str()))
+            .chain(tags.iter().copied());
+        for (i, (key, value)) in tags.enumerate() {
+            line.push_str(if i == 0 { "|#" } else { "," });
+            let _ = write!(line, "{}:{}", key, value);
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use rstest::*;
+
+    use super::{Statsd, Tag};
+
+    #[rstest]
+    #[case("crible", vec![], &[], "crible.flush:1|c")]
+    #[case("", vec![], &[], "flush:1|c")]
+    #[case("crible", vec!["env:prod"], &[], "crible.flush:1|c|#env:prod")]
+    #[case(
+        "crible",
+        vec!["env:prod"],
+        &[("outcome", "success")],
+        "crible.flush:1|c|#env:prod,outcome:success"
+    )]
+    fn test_format(
+        #[case] prefix: &str,
+        #[case] tags: Vec<&str>,
+        #[case] extra: &[(&str, &str)],
+        #[case] expected: &str,
+    ) {
+        let statsd = Statsd::new(
+            "127.0.0.1:8125",
+            prefix,
+            tags.iter().map(|t| t.parse().unwrap()).collect(),
+        )
+        .unwrap();
+        assert_eq!(statsd.format("flush", "1", "c", extra), expected);
+    }
+
+    #[test]
+    fn test_tag() {
+        assert_eq!(
+            "env:prod".parse::<Tag>().unwrap(),
+            Tag { key: "env".to_owned(), value: "prod".to_owned() }
+        );
+        assert!("env".parse::<Tag>().is_err());
+        assert!(":prod".parse::<Tag>().is_err());
+    }
+
+    #[test]
+    fn test_send() {
+        let agent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        agent.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let statsd = Statsd::new(
+            &agent.local_addr().unwrap().to_string(),
+            "crible",
+            vec![],
+        )
+        .unwrap();
+
+        statsd.timing("request", Duration::from_micros(1500), &[]);
+        let mut buffer = [0; 64];
+        let n = agent.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"crible.request:1.500|ms");
+    }
+}
@@ -0,0 +1,63 @@
+//! Integration with systemd when running as a service: socket activation,
+//! readiness notification and watchdog pings. All of this is a no-op when
+//! the process was not started by systemd.
+
+use std::io;
+use std::net::TcpListener;
+use std::time::Duration;
+
+use listenfd::ListenFd;
+use sd_notify::NotifyState;
+
+/// Socket passed by systemd through socket activation (`LISTEN_FDS`), if
+/// any. Only the first socket is used.
+pub fn listener() -> io::Result<Option<TcpListener>> {
+    let mut fds = ListenFd::from_env();
+    if fds.len() > 1 {
+        tracing::warn!(
+            "Received {} sockets from systemd, only the first one is used.",
+            fds.len()
+        );
+    }
+    fds.take_tcp_listener(0)
+}
+
+fn notify(state: NotifyState) {
+    if let Err(e) = sd_notify::notify(false, &[state]) {
+        tracing::warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+/// Signal that the index is loaded and the server is about to accept
+/// connections, for services with `Type=notify`.
+pub fn notify_ready() {
+    notify(NotifyState::Ready)
+}
+
+/// Signal that the server stopped accepting connections and is flushing
+/// pending writes before exiting.
+pub fn notify_stopping() {
+    notify(NotifyState::Stopping)
+}
+
+/// How often systemd expects watchdog pings, set through `WatchdogSec=`.
+pub fn watchdog_interval() -> Option<Duration> {
+    let mut usec = 0;
+    sd_notify::watchdog_enabled(false, &mut usec)
+        .then(|| Duration::from_micros(usec))
+}
+
+/// Ping the watchdog twice per `timeout` until shutdown. Pings are sent from
+/// the server's runtime so they stop if it hangs, in which case systemd
+/// restarts the service.
+pub async fn run_watchdog_task(timeout: Duration) {
+    let mut interval = tokio::time::interval(timeout / 2);
+    loop {
+        tokio::select! {
+            _ = crate::utils::shutdown_signal("Watchdog task") => {
+                break;
+            },
+            _ = interval.tick() => notify(NotifyState::Watchdog),
+        }
+    }
+}
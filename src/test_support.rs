@@ -0,0 +1,231 @@
+//! Helpers to run the crible API in-process from integration tests, enabled
+//! through the `test-support` feature.
+//!
+//! `TestServer` serves the API on an ephemeral local port for clients which
+//! need a real address while `service` returns the router for tests driving
+//! it directly as a `tower::Service`. Both are backed by an in-memory index.
+
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+
+use axum::{Router, Server};
+use crible_lib::Index;
+use eyre::Report;
+use parking_lot::Mutex;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tower::make::Shared;
+use url::Url;
+
+use crate::backends::{Backend, Memory};
+use crate::executor::{ExecutorBuilder, SharedIndex};
+use crate::server::{router, Options, State};
+
+#[derive(Default)]
+pub struct TestServerBuilder {
+    index: Index,
+    options: Options,
+    read_only: bool,
+    pool_size: Option<usize>,
+}
+
+impl TestServerBuilder {
+    /// Initial content of the index, empty by default.
+    pub fn index(mut self, index: Index) -> Self {
+        self.index = index;
+        self
+    }
+
+    /// Server options, e.g. to enable authentication or tenants.
+    pub fn options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Number of executor threads, defaults to the number of CPU cores.
+    pub fn pool_size(mut self, pool_size: usize) -> Self {
+        self.pool_size = Some(pool_size);
+        self
+    }
+
+    fn build(self) -> Result<(Router<State>, State, Arc<SharedIndex>), Report> {
+        let index = Arc::new(SharedIndex::new(self.index));
+        let backend: Box<dyn Backend> = Box::new(Memory::default());
+        let mut executor =
+            ExecutorBuilder::new(index.clone(), Arc::new(Mutex::new(backend)))
+                .read_only(self.read_only);
+        if let Some(pool_size) = self.pool_size {
+            executor = executor.pool_size(pool_size);
+        }
+        let state = State::new(executor.build()?);
+        Ok((router(state.clone(), &self.options), state, index))
+    }
+
+    /// Build the router without binding to a port.
+    pub fn service(self) -> Result<Router<State>, Report> {
+        Ok(self.build()?.0)
+    }
+
+    /// Bind to an ephemeral port on localhost and start serving.
+    pub async fn start(self) -> Result<TestServer, Report> {
+        let (app, state, index) = self.build()?;
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        let task = tokio::spawn(async move {
+            Server::from_tcp(listener)?
+                .serve(Shared::new(app))
+                .with_graceful_shutdown(async {
+                    stopped.await.ok();
+                })
+                .await
+        });
+
+        Ok(TestServer { addr, state, index, shutdown: Some(shutdown), task })
+    }
+}
+
+/// Router serving `index` from memory, see `TestServerBuilder` for more
+/// options.
+pub fn service(index: Index) -> Result<Router<State>, Report> {
+    TestServer::builder().index(index).service()
+}
+
+/// A crible server running on the current tokio runtime. It stops when
+/// dropped, `stop` can be used to wait for pending requests to complete.
+pub struct TestServer {
+    addr: SocketAddr,
+    state: State,
+    index: Arc<SharedIndex>,
+    shutdown: Option<oneshot::Sender<()>>,
+    task: JoinHandle<Result<(), hyper::Error>>,
+}
+
+impl TestServer {
+    pub fn builder() -> TestServerBuilder {
+        TestServerBuilder::default()
+    }
+
+    /// Serve `index` with the default options.
+    pub async fn start(index: Index) -> Result<Self, Report> {
+        Self::builder().index(index).start().await
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Base url of the API, e.g. `http://127.0.0.1:34567/`.
+    pub fn url(&self) -> Url {
+        format!("http://{}/", self.addr).parse().unwrap()
+    }
+
+    /// Current content of the index, including writes made through the API.
+    pub fn index(&self) -> Arc<Index> {
+        self.index.read()
+    }
+
+    /// Stop accepting connections, wait for in-flight requests and shut the
+    /// executor down.
+    pub async fn stop(mut self) -> Result<(), Report> {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).ok();
+        }
+        (&mut self.task).await??;
+        self.state.shutdown().await
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use crible_lib::Index;
+    use tower::ServiceExt;
+
+    use super::{service, TestServer};
+
+    fn index() -> Index {
+        Index::of([("foo", vec![1, 2, 3]), ("bar", vec![3, 4])])
+    }
+
+    #[tokio::test]
+    async fn test_server() {
+        let server = TestServer::start(index()).await.unwrap();
+        let client = reqwest::Client::new();
+
+        let response: serde_json::Value = client
+            .post(server.url().join("count").unwrap())
+            .json(&serde_json::json!({ "query": "foo or bar" }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(response, 4);
+
+        let status = client
+            .post(server.url().join("set").unwrap())
+            .json(&serde_json::json!({ "property": "baz", "bit": 5 }))
+            .send()
+            .await
+            .unwrap()
+            .status();
+        assert!(status.is_success());
+        assert!(server.index().get_property("baz").is_some());
+
+        server.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_only_server() {
+        let server = TestServer::builder()
+            .index(index())
+            .read_only(true)
+            .start()
+            .await
+            .unwrap();
+
+        let status = reqwest::Client::new()
+            .post(server.url().join("set").unwrap())
+            .json(&serde_json::json!({ "property": "baz", "bit": 5 }))
+            .send()
+            .await
+            .unwrap()
+            .status();
+        assert_eq!(status, reqwest::StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_service() {
+        let status = service(index())
+            .unwrap()
+            .oneshot(
+                Request::post("/query")
+                    .header("content-type", "application/json")
+                    .body(Body::from(r#"{"query": "foo"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+            .status();
+        assert_eq!(status, StatusCode::OK);
+    }
+}
@@ -5,6 +5,10 @@ use tokio::signal;
 use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+/// Used to change the log filter at runtime.
+pub type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
 
 pub fn set_env_var_default(name: &str, default: &str) {
     if std::env::var(name).is_err() {
@@ -12,7 +16,7 @@ pub fn set_env_var_default(name: &str, default: &str) {
     }
 }
 
-pub fn setup_logging(debug: bool) {
+pub fn setup_logging(debug: bool) -> LogFilterHandle {
     if debug {
         set_env_var_default("RUST_LIB_BACKTRACE", "1");
         set_env_var_default("RUST_BACKTRACE", "1");
@@ -21,10 +25,12 @@ pub fn setup_logging(debug: bool) {
         set_env_var_default("RUST_LOG", "warn,crible=info,crible_lib=info");
     }
 
+    let (filter, handle) = reload::Layer::new(EnvFilter::from_default_env());
+
     if debug {
         color_eyre::install().unwrap();
         tracing_subscriber::registry()
-            .with(tracing_subscriber::EnvFilter::from_default_env())
+            .with(filter)
             .with(
                 tracing_subscriber::fmt::layer()
                     .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE),
@@ -32,10 +38,12 @@ pub fn setup_logging(debug: bool) {
             .init();
     } else {
         tracing_subscriber::registry()
-            .with(tracing_subscriber::EnvFilter::from_default_env())
+            .with(filter)
             .with(tracing_subscriber::fmt::layer().json().with_span_list(true))
             .init();
     }
+
+    handle
 }
 
 pub async fn shutdown_signal(ctx: &'static str) {
@@ -77,3 +85,58 @@ pub fn tmp_path<T: AsRef<Path>>(path: &T) -> PathBuf {
     add_extension(&mut pb, "tmp");
     pb
 }
+
+/// Whether `value` matches `pattern`, where `*` matches any sequence of
+/// characters, including none, and every other character matches itself.
+pub fn glob_match(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+    let (mut p, mut v) = (0, 0);
+    // Position of the last `*` and of the value when it was reached, to
+    // backtrack to when the rest of the pattern does not match.
+    let mut star: Option<(usize, usize)> = None;
+
+    while v < value.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, v));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == value[v] {
+            p += 1;
+            v += 1;
+        } else if let Some((star_p, star_v)) = star {
+            p = star_p + 1;
+            v = star_v + 1;
+            star = Some((star_p, v));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::*;
+
+    use super::glob_match;
+
+    #[rstest]
+    #[case("country:*", "country:fr", true)]
+    #[case("country:*", "country:", true)]
+    #[case("country:*", "lang:fr", false)]
+    #[case("*:error", "http:error", true)]
+    #[case("*:error", "http:errors", false)]
+    #[case("*:*:fr", "a:b:fr", true)]
+    #[case("a*b*c", "aXbYbc", true)]
+    #[case("a*b*c", "aXbYbd", false)]
+    #[case("*", "", true)]
+    #[case("foo", "foo", true)]
+    #[case("foo", "fo", false)]
+    fn test_glob_match(
+        #[case] pattern: &str,
+        #[case] value: &str,
+        #[case] expected: bool,
+    ) {
+        assert_eq!(glob_match(pattern, value), expected);
+    }
+}